@@ -0,0 +1,1014 @@
+// Import of highlights made on other devices
+// Kindle: "My Clippings.txt" from the documents folder of the device
+// KOReader: metadata.<ext>.lua sidecar files inside each book's .sdr folder
+
+use std::{collections::HashMap, fs, path::Path};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::annotations::{all_book_hashes, BOOKMARK_EXCERPT_LENGTH};
+use crate::cfi::{collapse_whitespace, doc_fragment, Cfi, CfiPath, CfiResolver, CfiStep};
+use crate::merge;
+use crate::{bookmarkData, book_state, current_time_millis, get_epub_path, highlightData};
+
+const DEFAULT_COLOR: &str = "#FFD600";
+
+// Words Kindle uses in clipping headers, in English, German, French, Spanish, Italian and Portuguese
+const KINDLE_NOTE: [&str; 3] = ["note", "notiz", "nota"];
+const KINDLE_HIGHLIGHT: [&str; 6] = ["highlight", "markierung", "surlignement", "subrayado", "evidenziazione", "destaque"];
+const KINDLE_BOOKMARK: [&str; 5] = ["bookmark", "lesezeichen", "signet", "marcador", "segnalibro"];
+const KINDLE_LOCATION: [&str; 7] = ["location", "loc.", "position", "emplacement", "posición", "posizione", "posição"];
+
+#[derive(Debug, Clone, Default)]
+struct ImportedAnnotation {
+    title: String,
+    author: String,
+    text: String,
+    note: String,
+    chapter: String,
+    color: String,
+    created: u64,
+    is_bookmark: bool,
+    // 1 based spine position when the source knows it
+    spine_hint: Option<usize>,
+    // KOReader xpointer of the start, empty for Kindle clippings
+    position: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    highlights: usize,
+    bookmarks: usize,
+    duplicates: usize,
+    // Annotations deleted in Alexandria after they were made, they stay deleted
+    deleted: usize,
+    // Titles that could not be matched to a single book in the library
+    unmatched_books: Vec<String>,
+    // Highlights whose text could not be found in the matched book
+    unmatched_highlights: Vec<String>,
+}
+
+fn parse_date(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let formats = [
+        "%A, %B %d, %Y %I:%M:%S %p",
+        "%A, %d %B %Y %H:%M:%S",
+        "%A, %B %d, %Y %H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+    ];
+    for format in formats {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(Utc.from_utc_datetime(&date).timestamp_millis() as u64);
+        }
+    }
+    return None;
+}
+
+// Kindle's "Title (Author)" header, the author is the last parenthesised group
+fn split_kindle_title(line: &str) -> (String, String) {
+    let line = line.trim_start_matches('\u{feff}').trim();
+    if line.ends_with(')') {
+        if let Some(open) = line.rfind('(') {
+            let title = line[..open].trim().to_string();
+            let author = line[open + 1..line.len() - 1].trim().to_string();
+            return (title, author);
+        }
+    }
+    return (line.to_string(), String::new());
+}
+
+fn contains_any(value: &str, words: &[&str]) -> bool {
+    return words.iter().any(|word| value.contains(word));
+}
+
+// Location ranges look like "Location 123-125", "Loc. 123" or "bei Position 123-125"
+fn kindle_location(meta: &str) -> Option<(u64, u64)> {
+    for part in meta.split('|') {
+        let part = part.trim();
+        let lower = part.to_lowercase();
+        if !contains_any(&lower, &KINDLE_LOCATION) {
+            continue;
+        }
+        let range = part.split_whitespace().last()?;
+        let mut bounds = range.split('-').filter_map(|bound| bound.parse::<u64>().ok());
+        let start = bounds.next()?;
+        let end = bounds.next().unwrap_or(start);
+        return Some((start, end));
+    }
+    return None;
+}
+
+fn parse_kindle_clippings(contents: &str) -> Vec<ImportedAnnotation> {
+    let mut annotations: Vec<ImportedAnnotation> = Vec::new();
+    // Notes are stored as their own clipping, keyed here by book and location
+    let mut notes: Vec<(String, u64, String)> = Vec::new();
+    let mut locations: Vec<Option<(u64, u64)>> = Vec::new();
+
+    for clipping in contents.split("==========") {
+        let lines: Vec<&str> = clipping.trim().lines().collect();
+        if lines.len() < 2 {
+            continue;
+        }
+        let (title, author) = split_kindle_title(lines[0]);
+        let meta = lines[1].trim().trim_start_matches('-').trim();
+        let body = collapse_whitespace(&lines[2..].join("\n"));
+
+        // The kind is in the first part, the date in the last one could contain the same letters
+        let kind = meta.split('|').next().unwrap_or("").to_lowercase();
+        // Only English dates are parsed, clippings in other languages get the import time
+        let created = meta
+            .split('|')
+            .next_back()
+            .and_then(|added| added.trim().strip_prefix("Added on"))
+            .and_then(parse_date)
+            .unwrap_or(0);
+        let location = kindle_location(meta);
+
+        if contains_any(&kind, &KINDLE_NOTE) {
+            if let Some((start, _)) = location {
+                notes.push((title, start, body));
+            }
+        } else if contains_any(&kind, &KINDLE_HIGHLIGHT) {
+            annotations.push(ImportedAnnotation {
+                title,
+                author,
+                text: body,
+                color: DEFAULT_COLOR.to_string(),
+                created,
+                ..Default::default()
+            });
+            locations.push(location);
+        } else if contains_any(&kind, &KINDLE_BOOKMARK) {
+            annotations.push(ImportedAnnotation {
+                title,
+                author,
+                created,
+                is_bookmark: true,
+                ..Default::default()
+            });
+            locations.push(location);
+        }
+    }
+
+    // Attach each note to the highlight that covers its location
+    for (title, position, note) in notes {
+        let highlight = annotations.iter_mut().zip(locations.iter()).find(|(annotation, location)| {
+            !annotation.is_bookmark
+                && annotation.title == title
+                && match location {
+                    Some((start, end)) => position >= *start && position <= *end,
+                    None => false,
+                }
+        });
+        if let Some((annotation, _)) = highlight {
+            annotation.note = note;
+        }
+    }
+    return annotations;
+}
+
+// Just enough of a Lua parser to read the table KOReader writes with dump()
+struct LuaParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> LuaParser<'a> {
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.chars.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.chars.next();
+                }
+                Some('-') => {
+                    // Comments run to the end of the line, a single dash is a negative number
+                    let mut lookahead = self.chars.clone();
+                    lookahead.next();
+                    if lookahead.peek() != Some(&'-') {
+                        return;
+                    }
+                    while let Some(c) = self.chars.next() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Option<()> {
+        self.skip_whitespace();
+        if self.chars.next()? == expected {
+            return Some(());
+        }
+        return None;
+    }
+
+    fn identifier(&mut self) -> String {
+        let mut identifier = String::new();
+        while let Some(c) = self.chars.peek() {
+            if c.is_alphanumeric() || *c == '_' {
+                identifier.push(*c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        return identifier;
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let quote = self.chars.next()?;
+        let mut value = String::new();
+        loop {
+            let c = self.chars.next()?;
+            if c == quote {
+                return Some(value);
+            }
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            match self.chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                '\n' => value.push('\n'),
+                c if c.is_ascii_digit() => {
+                    let mut code = c.to_digit(10)?;
+                    for _ in 0..2 {
+                        match self.chars.peek().and_then(|c| c.to_digit(10)) {
+                            Some(digit) => {
+                                code = code * 10 + digit;
+                                self.chars.next();
+                            }
+                            None => break,
+                        }
+                    }
+                    value.push(char::from_u32(code)?);
+                }
+                other => value.push(other),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_whitespace();
+        match *self.chars.peek()? {
+            '{' => self.table(),
+            '"' | '\'' => Some(Value::String(self.string()?)),
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if c.is_ascii_alphanumeric() || *c == '.' || *c == '-' || *c == '+' {
+                        number.push(*c);
+                        self.chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let number: f64 = number.parse().ok()?;
+                return Some(serde_json::json!(number));
+            }
+            _ => match self.identifier().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                "nil" => Some(Value::Null),
+                _ => None,
+            },
+        }
+    }
+
+    // Tables become objects, numeric keys are kept as strings so order can be restored
+    fn table(&mut self) -> Option<Value> {
+        self.expect('{')?;
+        let mut table = serde_json::Map::new();
+        let mut position = 1;
+        loop {
+            self.skip_whitespace();
+            match *self.chars.peek()? {
+                '}' => {
+                    self.chars.next();
+                    return Some(Value::Object(table));
+                }
+                ',' | ';' => {
+                    self.chars.next();
+                }
+                '[' => {
+                    self.chars.next();
+                    let key = match self.value()? {
+                        Value::String(key) => key,
+                        Value::Number(key) => key.as_f64().map(|key| (key as i64).to_string())?,
+                        _ => return None,
+                    };
+                    self.expect(']')?;
+                    self.expect('=')?;
+                    table.insert(key, self.value()?);
+                }
+                c if c.is_alphabetic() || c == '_' => {
+                    let key = self.identifier();
+                    self.skip_whitespace();
+                    if self.chars.peek() == Some(&'=') {
+                        self.chars.next();
+                        table.insert(key, self.value()?);
+                    } else {
+                        let value = match key.as_str() {
+                            "true" => Value::Bool(true),
+                            "false" => Value::Bool(false),
+                            _ => Value::Null,
+                        };
+                        table.insert(position.to_string(), value);
+                        position += 1;
+                    }
+                }
+                _ => {
+                    table.insert(position.to_string(), self.value()?);
+                    position += 1;
+                }
+            }
+        }
+    }
+}
+
+fn parse_lua_table(contents: &str) -> Option<Value> {
+    let start = contents.find("return")? + "return".len();
+    let mut parser = LuaParser {
+        chars: contents[start..].chars().peekable(),
+    };
+    return parser.value();
+}
+
+// Values of a Lua table in numeric key order
+fn lua_list(value: &Value) -> Vec<&Value> {
+    let mut entries: Vec<(i64, &Value)> = match value.as_object() {
+        Some(table) => table
+            .iter()
+            .filter_map(|(key, value)| Some((key.parse::<i64>().ok()?, value)))
+            .collect(),
+        None => Vec::new(),
+    };
+    entries.sort_by_key(|(key, _)| *key);
+    return entries.into_iter().map(|(_, value)| value).collect();
+}
+
+fn lua_string(value: &Value, key: &str) -> String {
+    return value.get(key).and_then(Value::as_str).unwrap_or("").to_string();
+}
+
+fn koreader_color(color: &str) -> String {
+    let color = match color {
+        "red" => "red",
+        "orange" => "orange",
+        "green" | "olive" => "#00FF29",
+        "cyan" | "blue" => "cyan",
+        _ => DEFAULT_COLOR,
+    };
+    return color.to_string();
+}
+
+fn parse_koreader_metadata(contents: &str) -> Result<Vec<ImportedAnnotation>, String> {
+    let metadata = parse_lua_table(contents).ok_or("Error: Could not parse KOReader metadata")?;
+
+    let properties = metadata.get("doc_props").or(metadata.get("stats")).cloned().unwrap_or(Value::Null);
+    let title = lua_string(&properties, "title");
+    let author = lua_string(&properties, "authors");
+
+    let mut annotations: Vec<ImportedAnnotation> = Vec::new();
+
+    // Current format, highlights and bookmarks share the annotations table
+    if let Some(entries) = metadata.get("annotations") {
+        for entry in lua_list(entries) {
+            let position = entry.get("pos0").or(entry.get("page")).and_then(Value::as_str).unwrap_or("");
+            let is_bookmark = entry.get("pos0").is_none();
+            annotations.push(ImportedAnnotation {
+                title: title.clone(),
+                author: author.clone(),
+                text: if is_bookmark { String::new() } else { lua_string(entry, "text") },
+                note: lua_string(entry, "note"),
+                chapter: lua_string(entry, "chapter"),
+                color: koreader_color(&lua_string(entry, "color")),
+                created: parse_date(&lua_string(entry, "datetime")).unwrap_or(0),
+                is_bookmark,
+                spine_hint: doc_fragment(position),
+                position: position.to_string(),
+            });
+        }
+        return Ok(annotations);
+    }
+
+    // Older files keep highlights per page and mirror them in bookmarks, which also hold the notes
+    let mut notes: HashMap<String, String> = HashMap::new();
+    if let Some(bookmarks) = metadata.get("bookmarks") {
+        for bookmark in lua_list(bookmarks) {
+            let highlighted = bookmark.get("highlighted").and_then(Value::as_bool).unwrap_or(false);
+            if highlighted {
+                let note = lua_string(bookmark, "text");
+                let highlighted_text = lua_string(bookmark, "notes");
+                // Without a user note KOReader fills text with "Page N <highlight> @ <date>"
+                let is_generated = note.starts_with("Page ") || collapse_whitespace(&note) == collapse_whitespace(&highlighted_text);
+                if note.len() > 0 && !is_generated {
+                    notes.insert(lua_string(bookmark, "pos0"), note);
+                }
+                continue;
+            }
+            annotations.push(ImportedAnnotation {
+                title: title.clone(),
+                author: author.clone(),
+                chapter: lua_string(bookmark, "chapter"),
+                created: parse_date(&lua_string(bookmark, "datetime")).unwrap_or(0),
+                is_bookmark: true,
+                spine_hint: doc_fragment(&lua_string(bookmark, "page")),
+                position: lua_string(bookmark, "page"),
+                ..Default::default()
+            });
+        }
+    }
+    if let Some(pages) = metadata.get("highlight") {
+        for page in lua_list(pages) {
+            for highlight in lua_list(page) {
+                let position = lua_string(highlight, "pos0");
+                annotations.push(ImportedAnnotation {
+                    title: title.clone(),
+                    author: author.clone(),
+                    text: lua_string(highlight, "text"),
+                    note: notes.get(&position).cloned().unwrap_or_default(),
+                    chapter: lua_string(highlight, "chapter"),
+                    color: koreader_color(&lua_string(highlight, "color")),
+                    created: parse_date(&lua_string(highlight, "datetime")).unwrap_or(0),
+                    is_bookmark: false,
+                    spine_hint: doc_fragment(&position),
+                    position,
+                });
+            }
+        }
+    }
+    return Ok(annotations);
+}
+
+fn normalize(value: &str) -> String {
+    return value.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect();
+}
+
+// Kindle writes "Last, First", so authors are compared by their sorted name parts
+fn normalize_author(value: &str) -> String {
+    let mut parts: Vec<String> = value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| part.len() > 0)
+        .map(|part| part.to_lowercase())
+        .collect();
+    parts.sort();
+    return parts.concat();
+}
+
+struct LibraryBook {
+    hash: String,
+    title: String,
+    author: String,
+}
+
+// A title that matches several books is left unmatched rather than imported into the wrong one
+fn find_book<'a>(library: &'a [LibraryBook], title: &str, author: &str) -> Option<&'a LibraryBook> {
+    let title = normalize(title);
+    let author = normalize_author(author);
+    if title.len() == 0 {
+        return None;
+    }
+    let author_matches = |book: &LibraryBook| {
+        let book_author = normalize_author(&book.author);
+        author.len() == 0 || book_author.len() == 0 || book_author.contains(&author) || author.contains(&book_author)
+    };
+    let pick = |candidates: Vec<&'a LibraryBook>| {
+        // A book with the same known author wins over one without an author
+        let known: Vec<&'a LibraryBook> = candidates
+            .iter()
+            .copied()
+            .filter(|book| author.len() > 0 && normalize_author(&book.author).len() > 0)
+            .collect();
+        let candidates = if known.len() > 0 { known } else { candidates };
+        if candidates.len() == 1 {
+            return Some(candidates[0]);
+        }
+        return None;
+    };
+
+    let exact: Vec<&LibraryBook> = library.iter().filter(|book| normalize(&book.title) == title && author_matches(book)).collect();
+    if exact.len() > 0 {
+        return pick(exact);
+    }
+    // Kindle titles often carry a subtitle or series suffix the epub metadata lacks
+    let similar: Vec<&LibraryBook> = library
+        .iter()
+        .filter(|book| {
+            let book_title = normalize(&book.title);
+            book_title.len() > 0 && (book_title.contains(&title) || title.contains(&book_title)) && author_matches(book)
+        })
+        .collect();
+    // "Dune Messiah: Book Two" is closer to "Dune Messiah" than to "Dune"
+    let longest = similar.iter().map(|book| normalize(&book.title).len()).max().unwrap_or(0);
+    return pick(similar.into_iter().filter(|book| normalize(&book.title).len() == longest).collect());
+}
+
+// Search the book for the quoted text, starting at the hinted spine item
+fn locate_text(resolver: &mut CfiResolver, text: &str, spine_hint: Option<usize>) -> Option<String> {
+    let mut order: Vec<usize> = (0..resolver.spine_len()).collect();
+    if let Some(hint) = spine_hint {
+        if hint > 0 && hint <= order.len() {
+            order.retain(|index| *index != hint - 1);
+            order.insert(0, hint - 1);
+        }
+    }
+    for spine_index in order {
+        let spine_id = resolver.spine_id(spine_index);
+        let document = match resolver.document(spine_index) {
+            Some(document) => document,
+            None => continue,
+        };
+        if let Some((start, end)) = document.find_text(text) {
+            return Some(document.range_cfi(spine_index, spine_id, start, end).to_string());
+        }
+    }
+    return None;
+}
+
+// Bookmarks without an xpointer only know their chapter, so they point at the start of it
+fn chapter_cfi(resolver: &CfiResolver, spine_hint: Option<usize>) -> Option<String> {
+    let spine_index = spine_hint?.checked_sub(1)?;
+    let cfi = Cfi {
+        package: vec![
+            CfiStep {
+                index: 6,
+                assertion: None,
+            },
+            CfiStep {
+                index: (spine_index + 1) * 2,
+                assertion: resolver.spine_id(spine_index),
+            },
+        ],
+        parent: CfiPath {
+            steps: vec![CfiStep {
+                index: 4,
+                assertion: None,
+            }],
+            offset: None,
+        },
+        start: None,
+        end: None,
+    };
+    return Some(cfi.to_string());
+}
+
+fn read_source(path: &Path) -> Result<(String, bool), String> {
+    // A KOReader .sdr folder can be passed directly
+    let file = if path.is_dir() {
+        fs::read_dir(path)
+            .map_err(|e| format!("Error: Could not read \"{}\" : {}", path.display(), e))?
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|entry| {
+                let name = entry.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                name.starts_with("metadata.") && name.ends_with(".lua")
+            })
+            .ok_or(format!("Error: No KOReader metadata found in \"{}\"", path.display()))?
+    } else {
+        path.to_path_buf()
+    };
+    let is_lua = file.extension().map(|ext| ext == "lua").unwrap_or(false);
+    let contents = fs::read_to_string(&file).map_err(|e| format!("Error: Could not read \"{}\" : {}", file.display(), e))?;
+    return Ok((contents, is_lua));
+}
+
+#[tauri::command]
+pub fn import_annotations(path: String, hash: Option<String>) -> Result<ImportReport, String> {
+    let (contents, is_lua) = read_source(Path::new(&path))?;
+    let imported = if is_lua {
+        parse_koreader_metadata(&contents)?
+    } else {
+        parse_kindle_clippings(&contents)
+    };
+
+    let mut library: Vec<LibraryBook> = Vec::new();
+    for book_hash in all_book_hashes() {
+        if let Ok(payload) = book_state::get(&book_hash) {
+            library.push(LibraryBook {
+                hash: book_hash,
+                title: payload.title,
+                author: payload.author,
+            });
+        }
+    }
+
+    // Group by target book so each book is opened and written once
+    let mut by_book: HashMap<String, Vec<ImportedAnnotation>> = HashMap::new();
+    let mut report = ImportReport::default();
+    for annotation in imported {
+        let target = match &hash {
+            Some(hash) => Some(hash.clone()),
+            None => find_book(&library, &annotation.title, &annotation.author).map(|book| book.hash.clone()),
+        };
+        match target {
+            Some(target) => by_book.entry(target).or_default().push(annotation),
+            None => {
+                if !report.unmatched_books.contains(&annotation.title) {
+                    report.unmatched_books.push(annotation.title.clone());
+                }
+            }
+        }
+    }
+
+    let now = current_time_millis();
+    for (book_hash, annotations) in by_book {
+        // Searching the book is slow, it is done before the book's data is locked for the merge
+        let imported = resolve_annotations(&book_hash, annotations, now, &mut report);
+
+        // Goes through the shared book state so open reader views pick up the imported highlights
+        book_state::update_journaled(&book_hash, None, |payload, previous| {
+            // Imported annotations are merged like another copy of the book, matching ones keep the newest edit
+            let mut incoming = payload.clone();
+            incoming.data.highlights = imported.highlights.clone();
+            incoming.data.bookmarks = imported.bookmarks.clone();
+            incoming.data.deletedHighlights.clear();
+            incoming.data.deletedBookmarks.clear();
+
+            for cfi in incoming.data.highlights.keys() {
+                match (previous.data.highlights.contains_key(cfi), previous.data.deletedHighlights.get(cfi)) {
+                    (true, _) => report.duplicates += 1,
+                    (false, Some(deleted)) if *deleted > incoming.data.highlights[cfi].modified => report.deleted += 1,
+                    _ => report.highlights += 1,
+                }
+            }
+            for bookmark in incoming.data.bookmarks.iter() {
+                match (previous.data.bookmarks.iter().any(|other| other.cfi == bookmark.cfi), previous.data.deletedBookmarks.get(&bookmark.cfi)) {
+                    (true, _) => report.duplicates += 1,
+                    (false, Some(deleted)) if *deleted > bookmark.created => report.deleted += 1,
+                    _ => report.bookmarks += 1,
+                }
+            }
+            *payload = merge::merge_book(None, previous, &incoming);
+            return Ok(());
+        })?;
+    }
+
+    return Ok(report);
+}
+
+struct ResolvedAnnotations {
+    highlights: HashMap<String, highlightData>,
+    bookmarks: Vec<bookmarkData>,
+}
+
+// Finds where each annotation is in the book, those that can not be placed go in the report
+fn resolve_annotations(book_hash: &str, annotations: Vec<ImportedAnnotation>, now: u64, report: &mut ImportReport) -> ResolvedAnnotations {
+    let mut resolved = ResolvedAnnotations {
+        highlights: HashMap::new(),
+        bookmarks: Vec::new(),
+    };
+    let mut resolver = get_epub_path(book_hash).and_then(|path| CfiResolver::open(&path));
+
+    for annotation in annotations {
+        let resolver = match resolver.as_mut() {
+            Some(resolver) => resolver,
+            None => {
+                if !annotation.is_bookmark {
+                    report.unmatched_highlights.push(annotation.text);
+                }
+                continue;
+            }
+        };
+
+        if annotation.is_bookmark {
+            let cfi = Some(&annotation.position)
+                .filter(|position| position.len() > 0)
+                .and_then(|position| resolver.cfi_for_xpointer(position))
+                .or_else(|| chapter_cfi(resolver, annotation.spine_hint));
+            match cfi {
+                Some(cfi) if resolved.bookmarks.iter().any(|bookmark| bookmark.cfi == cfi) => report.duplicates += 1,
+                Some(cfi) => {
+                    let chapter = if annotation.chapter.len() > 0 {
+                        annotation.chapter
+                    } else {
+                        resolver.chapter_for(&cfi).unwrap_or_default()
+                    };
+                    resolved.bookmarks.push(bookmarkData {
+                        excerpt: resolver.excerpt(&cfi, BOOKMARK_EXCERPT_LENGTH).unwrap_or_default(),
+                        label: String::new(),
+                        chapter,
+                        created: if annotation.created > 0 { annotation.created } else { now },
+                        cfi,
+                        ..Default::default()
+                    });
+                }
+                None => {}
+            }
+            continue;
+        }
+
+        let cfi = match locate_text(resolver, &annotation.text, annotation.spine_hint) {
+            Some(cfi) => cfi,
+            None => {
+                report.unmatched_highlights.push(annotation.text);
+                continue;
+            }
+        };
+        if resolved.highlights.contains_key(&cfi) {
+            report.duplicates += 1;
+            continue;
+        }
+
+        let chapter = if annotation.chapter.len() > 0 {
+            annotation.chapter
+        } else {
+            resolver.chapter_for(&cfi).unwrap_or_default()
+        };
+        let created = if annotation.created > 0 { annotation.created } else { now };
+        resolved.highlights.insert(
+            cfi,
+            highlightData {
+                color: annotation.color,
+                note: annotation.note,
+                text: annotation.text,
+                chapter,
+                created,
+                modified: created,
+                ..Default::default()
+            },
+        );
+    }
+    return resolved;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIPPINGS: &str = "\u{feff}A Book: The Subtitle (Doe, Jane)\r
+- Your Highlight on page 3 | Location 40-42 | Added on Monday, January 1, 2024 10:00:00 AM\r
+\r
+First   highlighted\r
+text\r
+==========\r
+A Book: The Subtitle (Doe, Jane)\r
+- Your Note on page 3 | Location 42 | Added on Monday, January 1, 2024 10:01:00 AM\r
+\r
+A note on the first highlight\r
+==========\r
+A Book: The Subtitle (Doe, Jane)\r
+- Your Note on page 9 | Location 90 | Added on Monday, January 1, 2024 10:02:00 AM\r
+\r
+A note without a highlight\r
+==========\r
+Ein Buch (Max Mustermann)
+- Ihre Markierung auf Seite 7 | bei Position 100-104 | Hinzugefügt am Montag, 1. Januar 2024 10:00:00
+
+Deutscher Text
+==========
+Ein Buch (Max Mustermann)
+- Ihre Notiz auf Seite 7 | bei Position 104 | Hinzugefügt am Montag, 1. Januar 2024 10:01:00
+
+Deutsche Notiz
+==========
+Un Livre (Jean Dupont)
+- Votre signet à l'emplacement 55 | Ajouté le lundi 1 janvier 2024 10:00:00
+
+
+==========
+Un Livro (João Silva)
+- Seu destaque na posição 10-12 | Adicionado: segunda-feira, 1 de janeiro de 2024 10:00:00
+
+Texto em português
+==========
+Un Libro (Ana García)
+- Tu subrayado en la posición 20-25 | Añadido el lunes, 1 de enero de 2024 10:00:00
+
+Texto en español
+==========
+Un Libro (Ana García)
+- Tu nota en la posición 22 | Añadido el lunes, 1 de enero de 2024 10:01:00
+
+Nota en español
+==========
+";
+
+    #[test]
+    fn parses_kindle_clippings() {
+        let annotations = parse_kindle_clippings(CLIPPINGS);
+        assert_eq!(annotations.len(), 5);
+
+        let first = &annotations[0];
+        assert_eq!((first.title.as_str(), first.author.as_str()), ("A Book: The Subtitle", "Doe, Jane"));
+        assert_eq!(first.text, "First highlighted text");
+        assert_eq!(first.note, "A note on the first highlight");
+        assert_eq!(first.color, DEFAULT_COLOR);
+        assert_eq!(first.created, 1704103200000);
+        assert!(!first.is_bookmark);
+
+        let german = &annotations[1];
+        assert_eq!((german.title.as_str(), german.author.as_str()), ("Ein Buch", "Max Mustermann"));
+        assert_eq!(german.text, "Deutscher Text");
+        assert_eq!(german.note, "Deutsche Notiz");
+        assert_eq!(german.created, 0);
+
+        let french = &annotations[2];
+        assert!(french.is_bookmark);
+        assert_eq!(french.title, "Un Livre");
+
+        assert_eq!(annotations[3].text, "Texto em português");
+        assert_eq!(annotations[3].note, "");
+        assert_eq!(annotations[4].text, "Texto en español");
+        assert_eq!(annotations[4].note, "Nota en español");
+    }
+
+    #[test]
+    fn notes_only_attach_to_the_same_book() {
+        let clippings = "Book One (Author)
+- Your Highlight on page 1 | Location 10-20 | Added on Monday, January 1, 2024 10:00:00 AM
+
+One
+==========
+Book Two (Author)
+- Your Note on page 1 | Location 15 | Added on Monday, January 1, 2024 10:00:00 AM
+
+Belongs to book two
+==========
+";
+        let annotations = parse_kindle_clippings(clippings);
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].note, "");
+    }
+
+    #[test]
+    fn reads_kindle_locations() {
+        assert_eq!(kindle_location("Your Highlight on page 3 | Location 40-42 | Added on"), Some((40, 42)));
+        assert_eq!(kindle_location("Your Bookmark at Loc. 12 | Added on"), Some((12, 12)));
+        assert_eq!(kindle_location("Votre surlignement à l'emplacement 10-14 | Ajouté le"), Some((10, 14)));
+        assert_eq!(kindle_location("Your Highlight on page 3 | Added on"), None);
+    }
+
+    #[test]
+    fn parses_koreader_annotations() {
+        let metadata = r#"-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [2] = {
+            ["chapter"] = "Chapter Two",
+            ["datetime"] = "2024-01-03 11:00:00",
+            ["page"] = "/body/DocFragment[5]/body/p[1]",
+            ["text"] = "in Chapter Two",
+        },
+        [1] = {
+            ["chapter"] = "Chapter \"One\"",
+            ["color"] = "green",
+            ["datetime"] = "2024-01-02 10:00:00",
+            ["note"] = "Line one\
+Line two\tindented \065",
+            ["pos0"] = "/body/DocFragment[3]/body/p[2]/text().0",
+            ["pos1"] = "/body/DocFragment[3]/body/p[2]/text().20",
+            ["text"] = 'It was a \'dark\' night',
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Jane Doe",
+        ["title"] = "A Book",
+    },
+    ["stats"] = {
+        ["pages"] = 200,
+        ["progress"] = -0.5,
+    },
+    ["summary"] = { ["status"] = "reading", ["modified"] = "2024-01-03" },
+    ["flags"] = { true, false, nil },
+}
+"#;
+        let annotations = parse_koreader_metadata(metadata).unwrap();
+        assert_eq!(annotations.len(), 2);
+
+        let highlight = &annotations[0];
+        assert!(!highlight.is_bookmark);
+        assert_eq!((highlight.title.as_str(), highlight.author.as_str()), ("A Book", "Jane Doe"));
+        assert_eq!(highlight.text, "It was a 'dark' night");
+        assert_eq!(highlight.note, "Line one\nLine two\tindented A");
+        assert_eq!(highlight.chapter, "Chapter \"One\"");
+        assert_eq!(highlight.color, "#00FF29");
+        assert_eq!(highlight.spine_hint, Some(3));
+        assert_eq!(highlight.position, "/body/DocFragment[3]/body/p[2]/text().0");
+        assert_eq!(highlight.created, 1704189600000);
+
+        let bookmark = &annotations[1];
+        assert!(bookmark.is_bookmark);
+        assert_eq!(bookmark.text, "");
+        assert_eq!(bookmark.color, DEFAULT_COLOR);
+        assert_eq!(bookmark.spine_hint, Some(5));
+    }
+
+    #[test]
+    fn parses_legacy_koreader_highlights() {
+        let metadata = r#"return {
+    ["bookmarks"] = {
+        [1] = {
+            ["datetime"] = "2023-05-01 09:00:00",
+            ["highlighted"] = true,
+            ["notes"] = "the highlighted text",
+            ["pos0"] = "/body/DocFragment[2]/body/p[4]/text().0",
+            ["text"] = "My own note",
+        },
+        [2] = {
+            ["highlighted"] = true,
+            ["notes"] = "other text",
+            ["pos0"] = "/body/DocFragment[6]/body/p[1]/text().0",
+            ["text"] = "Page 12 other text @ 2023-05-02 09:00:00",
+        },
+        [3] = {
+            ["chapter"] = "Two",
+            ["datetime"] = "2023-05-03 09:00:00",
+            ["notes"] = "Page 30",
+            ["page"] = "/body/DocFragment[4]/body/p[1]",
+            ["text"] = "",
+        },
+    },
+    ["highlight"] = {
+        [12] = {
+            [1] = {
+                ["chapter"] = "Six",
+                ["color"] = "red",
+                ["pos0"] = "/body/DocFragment[6]/body/p[1]/text().0",
+                ["text"] = "other text",
+            },
+        },
+        [3] = {
+            [1] = {
+                ["chapter"] = "One",
+                ["datetime"] = "2023-05-01 09:00:00",
+                ["pos0"] = "/body/DocFragment[2]/body/p[4]/text().0",
+                ["text"] = "the highlighted text",
+            },
+        },
+    },
+    ["stats"] = {
+        ["authors"] = "John Roe",
+        ["highlights"] = 2,
+        ["title"] = "Old Book",
+    },
+}"#;
+        let annotations = parse_koreader_metadata(metadata).unwrap();
+        assert_eq!(annotations.len(), 3);
+
+        let bookmark = &annotations[0];
+        assert!(bookmark.is_bookmark);
+        assert_eq!(bookmark.chapter, "Two");
+        assert_eq!(bookmark.spine_hint, Some(4));
+        assert_eq!((bookmark.title.as_str(), bookmark.author.as_str()), ("Old Book", "John Roe"));
+
+        // Pages are read in numeric order, the note comes from the matching bookmark
+        assert_eq!(annotations[1].text, "the highlighted text");
+        assert_eq!(annotations[1].note, "My own note");
+        assert_eq!(annotations[1].spine_hint, Some(2));
+        assert_eq!(annotations[2].text, "other text");
+        assert_eq!(annotations[2].note, "");
+        assert_eq!(annotations[2].color, "red");
+    }
+
+    #[test]
+    fn rejects_malformed_koreader_metadata() {
+        assert!(parse_koreader_metadata("return { [\"annotations\"] = { [1] = { \"unterminated } }").is_err());
+        assert!(parse_koreader_metadata("not lua at all").is_err());
+    }
+
+    fn library() -> Vec<LibraryBook> {
+        let book = |hash: &str, title: &str, author: &str| LibraryBook {
+            hash: hash.to_string(),
+            title: title.to_string(),
+            author: author.to_string(),
+        };
+        return vec![
+            book("dune", "Dune", "Frank Herbert"),
+            book("dune-messiah", "Dune Messiah", "Frank Herbert"),
+            book("emma-austen", "Emma", "Jane Austen"),
+            book("emma-other", "Emma", "Someone Else"),
+            book("notes", "Notes", ""),
+        ];
+    }
+
+    fn found(title: &str, author: &str) -> Option<String> {
+        let library = library();
+        return find_book(&library, title, author).map(|book| book.hash.clone());
+    }
+
+    #[test]
+    fn matches_books_by_title_and_author() {
+        assert_eq!(found("DUNE", "Herbert, Frank").as_deref(), Some("dune"));
+        assert_eq!(found("Dune", "").as_deref(), Some("dune"));
+        assert_eq!(found("Dune Messiah: Book Two", "Frank Herbert").as_deref(), Some("dune-messiah"));
+        assert_eq!(found("Emma", "Jane Austen").as_deref(), Some("emma-austen"));
+        assert_eq!(found("Notes", "Anyone").as_deref(), Some("notes"));
+    }
+
+    #[test]
+    fn leaves_unknown_and_ambiguous_books_unmatched() {
+        assert_eq!(found("Unknown Book", "Nobody"), None);
+        assert_eq!(found("", "Frank Herbert"), None);
+        assert_eq!(found("Dune", "Someone Else"), None);
+        // Two books called Emma and no author to tell them apart
+        assert_eq!(found("Emma", ""), None);
+    }
+}
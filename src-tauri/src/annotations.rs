@@ -0,0 +1,835 @@
+use std::{fs, path::Path};
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cfi::{Cfi, CfiResolver};
+use crate::{
+    book_state, bookmarkData, current_time_millis, get_config_path, get_epub_path, storage, updateBookPayload,
+};
+
+pub const BOOKMARK_EXCERPT_LENGTH: usize = 120;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AnnotationEntry {
+    pub hash: String,
+    pub title: String,
+    pub author: String,
+    // Either "highlight" or "bookmark"
+    pub kind: String,
+    pub cfi: String,
+    pub text: String,
+    pub chapter: String,
+    pub color: String,
+    pub note: String,
+    // Name given to a bookmark, empty for highlights
+    pub label: String,
+    pub tags: Vec<String>,
+    pub created: u64,
+    pub modified: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct BookAnnotations {
+    hash: String,
+    title: String,
+    author: String,
+    annotations: Vec<AnnotationEntry>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ExportReport {
+    pub exported: usize,
+    // Books whose data could not be read, they are left out of the export
+    pub skipped_books: Vec<String>,
+}
+
+enum ExportFormat {
+    Markdown,
+    Json,
+    Csv,
+    Readwise,
+}
+
+impl ExportFormat {
+    fn from_str(format: &str) -> Result<ExportFormat, String> {
+        match format.to_lowercase().as_str() {
+            "markdown" | "md" | "obsidian" => Ok(ExportFormat::Markdown),
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "readwise" => Ok(ExportFormat::Readwise),
+            _ => Err(format!("Error: Unknown export format \"{}\"", format)),
+        }
+    }
+}
+
+pub fn all_book_hashes() -> Vec<String> {
+    let mut hashes = Vec::new();
+    if let Ok(folders) = fs::read_dir(get_config_path().join("books")) {
+        for folder in folders.flatten() {
+            if folder.path().is_dir() {
+                hashes.push(folder.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    hashes.sort();
+    return hashes;
+}
+
+// Opens the epub the first time a CFI actually needs resolving
+pub struct LazyResolver {
+    hash: String,
+    opened: bool,
+    resolver: Option<CfiResolver>,
+}
+
+impl LazyResolver {
+    pub fn new(hash: &str) -> LazyResolver {
+        return LazyResolver {
+            hash: hash.to_string(),
+            opened: false,
+            resolver: None,
+        };
+    }
+
+    pub fn get(&mut self) -> Option<&mut CfiResolver> {
+        if !self.opened {
+            self.resolver = get_epub_path(&self.hash).and_then(|path| CfiResolver::open(&path));
+            self.opened = true;
+        }
+        return self.resolver.as_mut();
+    }
+}
+
+// Collect the highlights and bookmarks of a single book in reading order
+// The epub is only opened for annotations saved before text and chapters were stored
+pub fn collect_book_annotations(hash: &str) -> Result<Vec<AnnotationEntry>, String> {
+    let payload = book_state::get(hash)?;
+
+    let mut resolver = LazyResolver::new(hash);
+
+    let mut entries: Vec<AnnotationEntry> = Vec::new();
+    for (cfi, highlight) in payload.data.highlights.iter() {
+        let mut text = highlight.text.clone();
+        let mut chapter = highlight.chapter.clone();
+        if text.len() == 0 || chapter.len() == 0 {
+            if let Some(resolver) = resolver.get() {
+                if text.len() == 0 {
+                    text = resolver.text(cfi).unwrap_or_default();
+                }
+                if chapter.len() == 0 {
+                    chapter = resolver.chapter_for(cfi).unwrap_or_default();
+                }
+            }
+        }
+        entries.push(AnnotationEntry {
+            hash: hash.to_string(),
+            title: payload.title.clone(),
+            author: payload.author.clone(),
+            kind: "highlight".to_string(),
+            cfi: cfi.clone(),
+            text,
+            chapter,
+            color: highlight.color.clone(),
+            note: highlight.note.clone(),
+            label: String::new(),
+            tags: highlight.tags.clone(),
+            created: highlight.created,
+            modified: highlight.modified,
+        });
+    }
+    for bookmark in payload.data.bookmarks.iter() {
+        let mut chapter = bookmark.chapter.clone();
+        if chapter.len() == 0 {
+            if let Some(resolver) = resolver.get() {
+                chapter = resolver.chapter_for(&bookmark.cfi).unwrap_or_default();
+            }
+        }
+        entries.push(AnnotationEntry {
+            hash: hash.to_string(),
+            title: payload.title.clone(),
+            author: payload.author.clone(),
+            kind: "bookmark".to_string(),
+            cfi: bookmark.cfi.clone(),
+            text: bookmark.excerpt.clone(),
+            chapter,
+            color: String::new(),
+            note: String::new(),
+            label: bookmark.label.clone(),
+            tags: Vec::new(),
+            created: bookmark.created,
+            modified: bookmark.created,
+        });
+    }
+
+    entries.sort_by(|a, b| compare_cfi(&a.cfi, &b.cfi));
+    return Ok(entries);
+}
+
+// Carry text, chapter, timestamps and tags over from the previously saved data
+// New highlights get their text and chapter resolved from the epub
+pub fn fill_highlight_metadata(hash: &str, payload: &mut updateBookPayload, previous: Option<&updateBookPayload>) {
+    let now = current_time_millis();
+    let mut resolver = LazyResolver::new(hash);
+
+    for (cfi, highlight) in payload.data.highlights.iter_mut() {
+        let old = previous.and_then(|previous| previous.data.highlights.get(cfi));
+        match old {
+            Some(old) => {
+                if highlight.text.len() == 0 {
+                    highlight.text = old.text.clone();
+                }
+                if highlight.chapter.len() == 0 {
+                    highlight.chapter = old.chapter.clone();
+                }
+                if highlight.tags.len() == 0 {
+                    highlight.tags = old.tags.clone();
+                }
+                if highlight.extra.len() == 0 {
+                    highlight.extra = old.extra.clone();
+                }
+                if highlight.created == 0 {
+                    highlight.created = old.created;
+                }
+                if highlight.modified == 0 {
+                    let changed = highlight.color != old.color || highlight.note != old.note || highlight.tags != old.tags;
+                    highlight.modified = if changed { now } else { old.modified };
+                }
+            }
+            None => {
+                if highlight.created == 0 {
+                    highlight.created = now;
+                }
+                if highlight.modified == 0 {
+                    highlight.modified = highlight.created;
+                }
+                if highlight.text.len() == 0 || highlight.chapter.len() == 0 {
+                    if let Some(resolver) = resolver.get() {
+                        if highlight.text.len() == 0 {
+                            highlight.text = resolver.text(cfi).unwrap_or_default();
+                        }
+                        if highlight.chapter.len() == 0 {
+                            highlight.chapter = resolver.chapter_for(cfi).unwrap_or_default();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Bookmarks arrive from the reader as bare CFIs, keep labels and the saved order
+// New bookmarks get an excerpt and chapter from the epub
+pub fn fill_bookmark_metadata(hash: &str, payload: &mut updateBookPayload, previous: Option<&updateBookPayload>) {
+    let now = current_time_millis();
+    let mut resolver = LazyResolver::new(hash);
+
+    let mut incoming: Vec<bookmarkData> = payload.data.bookmarks.drain(..).collect();
+    let mut bookmarks: Vec<bookmarkData> = Vec::new();
+
+    if let Some(previous) = previous {
+        for old in previous.data.bookmarks.iter() {
+            if let Some(index) = incoming.iter().position(|bookmark| bookmark.cfi == old.cfi) {
+                let bookmark = incoming.remove(index);
+                bookmarks.push(bookmarkData {
+                    cfi: bookmark.cfi,
+                    label: if bookmark.label.len() > 0 { bookmark.label } else { old.label.clone() },
+                    excerpt: if bookmark.excerpt.len() > 0 { bookmark.excerpt } else { old.excerpt.clone() },
+                    chapter: if bookmark.chapter.len() > 0 { bookmark.chapter } else { old.chapter.clone() },
+                    created: if bookmark.created > 0 { bookmark.created } else { old.created },
+                    extra: if bookmark.extra.len() > 0 { bookmark.extra } else { old.extra.clone() },
+                });
+            }
+        }
+    }
+
+    for mut bookmark in incoming {
+        if bookmark.created == 0 {
+            bookmark.created = now;
+        }
+        if bookmark.excerpt.len() == 0 || bookmark.chapter.len() == 0 {
+            if let Some(resolver) = resolver.get() {
+                if bookmark.excerpt.len() == 0 {
+                    bookmark.excerpt = resolver.excerpt(&bookmark.cfi, BOOKMARK_EXCERPT_LENGTH).unwrap_or_default();
+                }
+                if bookmark.chapter.len() == 0 {
+                    bookmark.chapter = resolver.chapter_for(&bookmark.cfi).unwrap_or_default();
+                }
+            }
+        }
+        bookmarks.push(bookmark);
+    }
+
+    payload.data.bookmarks = bookmarks;
+}
+
+#[tauri::command]
+pub fn rename_bookmark(hash: String, cfi: String, label: String) -> Result<(), String> {
+    book_state::update(&hash, None, |payload, _previous| {
+        let bookmark = payload
+            .data
+            .bookmarks
+            .iter_mut()
+            .find(|bookmark| bookmark.cfi == cfi)
+            .ok_or(format!("Error: Bookmark not found - {}", cfi))?;
+        bookmark.label = label;
+        return Ok(());
+    })?;
+    return Ok(());
+}
+
+// `order` lists bookmark CFIs in their new order, any left out keep their relative order at the end
+#[tauri::command]
+pub fn reorder_bookmarks(hash: String, order: Vec<String>) -> Result<Vec<bookmarkData>, String> {
+    let payload = book_state::update(&hash, None, |payload, _previous| {
+        let mut remaining: Vec<bookmarkData> = payload.data.bookmarks.drain(..).collect();
+        let mut bookmarks: Vec<bookmarkData> = Vec::new();
+        for cfi in order.iter() {
+            if let Some(index) = remaining.iter().position(|bookmark| &bookmark.cfi == cfi) {
+                bookmarks.push(remaining.remove(index));
+            }
+        }
+        bookmarks.extend(remaining);
+        payload.data.bookmarks = bookmarks;
+        return Ok(());
+    })?;
+    return Ok(payload.data.bookmarks);
+}
+
+pub fn format_timestamp(milliseconds: u64) -> String {
+    if milliseconds == 0 {
+        return String::new();
+    }
+    return match Utc.timestamp_millis_opt(milliseconds as i64).single() {
+        Some(date) => date.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => String::new(),
+    };
+}
+
+// Orders CFIs by their parsed steps and offset, which follows reading order
+// Unparseable CFIs sort last
+pub fn compare_cfi(a: &str, b: &str) -> std::cmp::Ordering {
+    fn position(cfi: &str) -> Option<(Vec<usize>, Vec<usize>, usize)> {
+        let cfi = Cfi::parse(cfi)?;
+        let start = cfi.start_path();
+        let package = cfi.package.iter().map(|step| step.index).collect();
+        let steps = start.steps.iter().map(|step| step.index).collect();
+        return Some((package, steps, start.offset.unwrap_or(0)));
+    }
+    let (a_position, b_position) = (position(a), position(b));
+    return a_position
+        .is_none()
+        .cmp(&b_position.is_none())
+        .then_with(|| a_position.cmp(&b_position))
+        .then_with(|| a.cmp(b));
+}
+
+fn default_page_size() -> usize {
+    50
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AnnotationQuery {
+    // Empty filters match everything
+    #[serde(default)]
+    hashes: Vec<String>,
+    #[serde(default)]
+    kinds: Vec<String>,
+    #[serde(default)]
+    colors: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    // Millisecond timestamps, compared against the last modification
+    #[serde(default)]
+    from: Option<u64>,
+    #[serde(default)]
+    to: Option<u64>,
+    // Matched case insensitively against the note and highlighted text
+    #[serde(default)]
+    search: String,
+    // "modified", "created", "book" or "position"
+    #[serde(default)]
+    sortBy: String,
+    // "asc" or "desc"
+    #[serde(default)]
+    sortDirection: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_size")]
+    limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AnnotationPage {
+    total: usize,
+    offset: usize,
+    annotations: Vec<AnnotationEntry>,
+}
+
+impl AnnotationQuery {
+    fn matches(&self, annotation: &AnnotationEntry) -> bool {
+        if self.kinds.len() > 0 && !self.kinds.contains(&annotation.kind) {
+            return false;
+        }
+        if self.colors.len() > 0 && !self.colors.contains(&annotation.color) {
+            return false;
+        }
+        if self.tags.len() > 0 && !self.tags.iter().any(|tag| annotation.tags.contains(tag)) {
+            return false;
+        }
+
+        let date = annotation.modified.max(annotation.created);
+        if let Some(from) = self.from {
+            if date < from {
+                return false;
+            }
+        }
+        if let Some(to) = self.to {
+            if date > to {
+                return false;
+            }
+        }
+
+        if self.search.len() > 0 {
+            let search = self.search.to_lowercase();
+            let found = annotation.note.to_lowercase().contains(&search)
+                || annotation.text.to_lowercase().contains(&search)
+                || annotation.label.to_lowercase().contains(&search);
+            if !found {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+#[tauri::command]
+pub fn list_annotations(query: Option<AnnotationQuery>) -> Result<AnnotationPage, String> {
+    let query = query.unwrap_or(AnnotationQuery {
+        limit: default_page_size(),
+        ..Default::default()
+    });
+
+    let hashes = if query.hashes.len() > 0 {
+        query.hashes.clone()
+    } else {
+        all_book_hashes()
+    };
+
+    let mut results: Vec<AnnotationEntry> = Vec::new();
+    for hash in hashes {
+        // A single unreadable book should not hide the rest of the library
+        let annotations = match collect_book_annotations(&hash) {
+            Ok(annotations) => annotations,
+            Err(error) => {
+                println!("Skipping annotations of {}: {}", hash, error);
+                continue;
+            }
+        };
+        results.extend(annotations.into_iter().filter(|annotation| query.matches(annotation)));
+    }
+
+    match query.sortBy.as_str() {
+        "created" => results.sort_by(|a, b| a.created.cmp(&b.created)),
+        "book" => results.sort_by(|a, b| {
+            a.title
+                .to_lowercase()
+                .cmp(&b.title.to_lowercase())
+                .then_with(|| compare_cfi(&a.cfi, &b.cfi))
+        }),
+        "position" => results.sort_by(|a, b| a.hash.cmp(&b.hash).then_with(|| compare_cfi(&a.cfi, &b.cfi))),
+        _ => results.sort_by(|a, b| a.modified.max(a.created).cmp(&b.modified.max(b.created))),
+    }
+    // Newest first unless asked otherwise
+    let ascending = match query.sortDirection.as_str() {
+        "asc" => true,
+        "desc" => false,
+        _ => query.sortBy == "book" || query.sortBy == "position",
+    };
+    if !ascending {
+        results.reverse();
+    }
+
+    let total = results.len();
+    let annotations = results.into_iter().skip(query.offset).take(query.limit).collect();
+
+    return Ok(AnnotationPage {
+        total,
+        offset: query.offset,
+        annotations,
+    });
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') || value.contains('\r') {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+fn to_markdown(books: &Vec<BookAnnotations>) -> String {
+    let mut output = String::new();
+    for book in books {
+        // Frontmatter keeps each book usable as a standalone Obsidian note
+        if books.len() == 1 {
+            output.push_str("---\n");
+            output.push_str(&format!("title: \"{}\"\n", book.title.replace('"', "\\\"")));
+            output.push_str(&format!("author: \"{}\"\n", book.author.replace('"', "\\\"")));
+            output.push_str(&format!("hash: {}\n", book.hash));
+            output.push_str("---\n\n");
+        }
+        output.push_str(&format!("# {}\n", book.title));
+        if book.author.len() > 0 {
+            output.push_str(&format!("*{}*\n", book.author));
+        }
+        output.push('\n');
+
+        let mut current_chapter = String::new();
+        for annotation in book.annotations.iter() {
+            if annotation.chapter.len() > 0 && annotation.chapter != current_chapter {
+                current_chapter = annotation.chapter.clone();
+                output.push_str(&format!("## {}\n\n", current_chapter));
+            }
+            if annotation.kind == "bookmark" {
+                let label = if annotation.label.len() > 0 { annotation.label.clone() } else { "Bookmark".to_string() };
+                if annotation.text.len() > 0 {
+                    output.push_str(&format!("- **{}**: {}\n\n", label, annotation.text));
+                } else {
+                    output.push_str(&format!("- **{}** `{}`\n\n", label, annotation.cfi));
+                }
+                continue;
+            }
+            let text = if annotation.text.len() > 0 {
+                annotation.text.clone()
+            } else {
+                format!("`{}`", annotation.cfi)
+            };
+            for line in text.lines() {
+                output.push_str(&format!("> {}\n", line));
+            }
+            if annotation.note.len() > 0 {
+                output.push_str(&format!("\n{}\n", annotation.note));
+            }
+            if annotation.tags.len() > 0 {
+                let tags: Vec<String> = annotation.tags.iter().map(|tag| format!("#{}", tag.replace(' ', "_"))).collect();
+                output.push_str(&format!("\n{}\n", tags.join(" ")));
+            }
+            output.push('\n');
+        }
+    }
+    return output;
+}
+
+fn to_csv(books: &Vec<BookAnnotations>) -> String {
+    let mut output = String::from("hash,title,author,kind,chapter,text,note,label,color,tags,created,modified,cfi\n");
+    for book in books {
+        for annotation in book.annotations.iter() {
+            let row = [
+                annotation.hash.clone(),
+                annotation.title.clone(),
+                annotation.author.clone(),
+                annotation.kind.clone(),
+                annotation.chapter.clone(),
+                annotation.text.clone(),
+                annotation.note.clone(),
+                annotation.label.clone(),
+                annotation.color.clone(),
+                annotation.tags.join(";"),
+                format_timestamp(annotation.created),
+                format_timestamp(annotation.modified),
+                annotation.cfi.clone(),
+            ]
+            .iter()
+            .map(|value| escape_csv(value))
+            .collect::<Vec<String>>()
+            .join(",");
+            output.push_str(&row);
+            output.push('\n');
+        }
+    }
+    return output;
+}
+
+// https://readwise.io/import_bulk
+// Readwise has no notion of bookmarks, so only highlights are written
+fn to_readwise(books: &Vec<BookAnnotations>) -> String {
+    let mut output = String::from("Highlight,Title,Author,URL,Note,Location,Date,Tags\n");
+    for book in books {
+        for (location, annotation) in book.annotations.iter().filter(|a| a.kind == "highlight").enumerate() {
+            let highlight = if annotation.text.len() > 0 { &annotation.text } else { &annotation.cfi };
+            let row = [
+                highlight.to_string(),
+                annotation.title.clone(),
+                annotation.author.clone(),
+                String::new(),
+                annotation.note.clone(),
+                (location + 1).to_string(),
+                format_timestamp(annotation.created),
+                annotation.tags.join(","),
+            ]
+            .iter()
+            .map(|value| escape_csv(value))
+            .collect::<Vec<String>>()
+            .join(",");
+            output.push_str(&row);
+            output.push('\n');
+        }
+    }
+    return output;
+}
+
+// Annotations of the given books in an export format, and how many there are
+pub fn render_annotations(hashes: Option<Vec<String>>, format: &str) -> Result<(String, ExportReport), String> {
+    let format = ExportFormat::from_str(format)?;
+
+    // No hashes means every book in the library
+    let hashes = match hashes {
+        Some(hashes) if hashes.len() > 0 => hashes,
+        _ => all_book_hashes(),
+    };
+
+    let mut books: Vec<BookAnnotations> = Vec::new();
+    let mut report = ExportReport::default();
+    for hash in hashes {
+        // Like list_annotations, one unreadable book does not stop the export of the others
+        let annotations = match collect_book_annotations(&hash) {
+            Ok(annotations) => annotations,
+            Err(error) => {
+                println!("Skipping annotations of {}: {}", hash, error);
+                report.skipped_books.push(hash);
+                continue;
+            }
+        };
+        if annotations.len() == 0 {
+            continue;
+        }
+        report.exported += annotations.len();
+        books.push(BookAnnotations {
+            hash,
+            title: annotations[0].title.clone(),
+            author: annotations[0].author.clone(),
+            annotations,
+        });
+    }
+
+    let output = match format {
+        ExportFormat::Markdown => to_markdown(&books),
+        ExportFormat::Json => serde_json::to_string_pretty(&books).map_err(|e| format!("Error: {}", e))?,
+        ExportFormat::Csv => to_csv(&books),
+        ExportFormat::Readwise => to_readwise(&books),
+    };
+
+    return Ok((output, report));
+}
+
+#[tauri::command]
+pub fn export_annotations(hashes: Option<Vec<String>>, format: String, path: String) -> Result<ExportReport, String> {
+    let (output, report) = render_annotations(hashes, &format)?;
+    storage::write_atomic(Path::new(&path), output.as_bytes()).map_err(|e| format!("Error: Could not write \"{}\" : {}", path, e))?;
+
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(kind: &str, cfi: &str, chapter: &str, text: &str, note: &str) -> AnnotationEntry {
+        return AnnotationEntry {
+            hash: "hash".to_string(),
+            title: "A \"Quoted\" Book".to_string(),
+            author: "Jane Doe".to_string(),
+            kind: kind.to_string(),
+            cfi: cfi.to_string(),
+            text: text.to_string(),
+            chapter: chapter.to_string(),
+            color: "yellow".to_string(),
+            note: note.to_string(),
+            label: String::new(),
+            tags: Vec::new(),
+            created: 1704103200000,
+            modified: 1704103200000,
+        };
+    }
+
+    fn books() -> Vec<BookAnnotations> {
+        let mut tagged = entry("highlight", "epubcfi(/6/2!/4/2,/1:0,/1:5)", "One", "First line\nsecond line", "A note");
+        tagged.tags = vec!["big idea".to_string(), "quote".to_string()];
+        let mut bookmark = entry("bookmark", "epubcfi(/6/4!/4/2)", "Two", "Where two starts", "");
+        bookmark.label = "Start".to_string();
+        return vec![BookAnnotations {
+            hash: "hash".to_string(),
+            title: "A \"Quoted\" Book".to_string(),
+            author: "Jane Doe".to_string(),
+            annotations: vec![
+                tagged,
+                entry("highlight", "epubcfi(/6/2!/4/4,/1:0,/1:5)", "One", "", ""),
+                bookmark,
+                entry("bookmark", "epubcfi(/6/4!/4/6)", "Two", "", ""),
+            ],
+        }];
+    }
+
+    #[test]
+    fn escapes_csv_values() {
+        assert_eq!(escape_csv("plain"), "plain");
+        assert_eq!(escape_csv("a, b"), "\"a, b\"");
+        assert_eq!(escape_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv("two\nlines"), "\"two\nlines\"");
+        assert_eq!(escape_csv("carriage\rreturn"), "\"carriage\rreturn\"");
+        assert_eq!(escape_csv(""), "");
+    }
+
+    #[test]
+    fn renders_markdown() {
+        let markdown = to_markdown(&books());
+        assert_eq!(
+            markdown,
+            "---
+title: \"A \\\"Quoted\\\" Book\"
+author: \"Jane Doe\"
+hash: hash
+---
+
+# A \"Quoted\" Book
+*Jane Doe*
+
+## One
+
+> First line
+> second line
+
+A note
+
+#big_idea #quote
+
+> `epubcfi(/6/2!/4/4,/1:0,/1:5)`
+
+## Two
+
+- **Start**: Where two starts
+
+- **Bookmark** `epubcfi(/6/4!/4/6)`
+
+"
+        );
+
+        // Frontmatter is only written for single book exports
+        let mut two = books();
+        two.extend(books());
+        assert!(!to_markdown(&two).starts_with("---"));
+    }
+
+    #[test]
+    fn renders_csv() {
+        let csv = to_csv(&books());
+        let lines: Vec<&str> = csv.split('\n').collect();
+        assert_eq!(lines[0], "hash,title,author,kind,chapter,text,note,label,color,tags,created,modified,cfi");
+        assert_eq!(lines[1], "hash,\"A \"\"Quoted\"\" Book\",Jane Doe,highlight,One,\"First line");
+        assert_eq!(lines[2], "second line\",A note,,yellow,big idea;quote,2024-01-01 10:00:00,2024-01-01 10:00:00,\"epubcfi(/6/2!/4/2,/1:0,/1:5)\"");
+        assert_eq!(lines[5], "hash,\"A \"\"Quoted\"\" Book\",Jane Doe,bookmark,Two,,,,yellow,,2024-01-01 10:00:00,2024-01-01 10:00:00,epubcfi(/6/4!/4/6)");
+        assert_eq!(csv.matches("\nhash,").count(), 4);
+    }
+
+    #[test]
+    fn renders_readwise() {
+        let csv = to_readwise(&books());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "Highlight,Title,Author,URL,Note,Location,Date,Tags");
+        assert_eq!(lines[1], "\"First line");
+        assert_eq!(lines[2], "second line\",\"A \"\"Quoted\"\" Book\",Jane Doe,,A note,1,2024-01-01 10:00:00,\"big idea,quote\"");
+        // Highlights without text fall back to their CFI, bookmarks are left out
+        assert_eq!(lines[3], "\"epubcfi(/6/2!/4/4,/1:0,/1:5)\",\"A \"\"Quoted\"\" Book\",Jane Doe,,,2,2024-01-01 10:00:00,");
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn skips_unreadable_books_on_export() {
+        let _library = crate::shared_test_library();
+        let good = crate::test_book(
+            "export",
+            json!({"title": "Export", "data": {"progress": 0.0, "highlights": {"epubcfi(/6/2!/4/2,/1:0,/1:5)": {"color": "red", "text": "Kept"}}}}),
+        );
+        let broken = crate::test_book("export-broken", json!({"title": "Broken", "data": {"progress": 0.0}}));
+        fs::write(get_config_path().join("books").join(&broken).join(format!("{}.json", broken)), "{").unwrap();
+
+        let path = std::env::temp_dir().join(format!("alexandria-export-{}-{}.md", current_time_millis(), rand::random::<u32>()));
+        let report = export_annotations(Some(vec![broken.clone(), good]), "markdown".to_string(), path.display().to_string()).unwrap();
+        assert_eq!(report.exported, 1);
+        assert_eq!(report.skipped_books, vec![broken.clone()]);
+        assert!(fs::read_to_string(&path).unwrap().contains("> Kept"));
+
+        assert!(export_annotations(None, "pdf".to_string(), path.display().to_string()).is_err());
+
+        // Backups refuse a library with damaged books
+        fs::remove_dir_all(get_config_path().join("books").join(&broken)).unwrap();
+        book_state::evict(&broken);
+    }
+
+    fn library_page(hash: &str, query: serde_json::Value) -> (usize, Vec<String>) {
+        let mut query = query.as_object().unwrap().clone();
+        query.insert("hashes".to_string(), json!([hash]));
+        let page = list_annotations(Some(serde_json::from_value(serde_json::Value::Object(query)).unwrap())).unwrap();
+        return (page.total, page.annotations.into_iter().map(|annotation| annotation.text).collect());
+    }
+
+    #[test]
+    fn filters_and_pages_annotations() {
+        let _library = crate::shared_test_library();
+        let hash = crate::test_book(
+            "browse",
+            json!({"title": "Browse", "data": {"progress": 0.0, "highlights": {
+                "epubcfi(/6/2!/4/2,/1:0,/1:5)": {"color": "yellow", "text": "first", "note": "Lorem note", "tags": ["idea"], "created": 1000, "modified": 2000},
+                "epubcfi(/6/4!/4/2,/1:0,/1:5)": {"color": "red", "text": "second", "tags": ["quote"], "created": 3000, "modified": 3000},
+                "epubcfi(/6/6!/4/2,/1:0,/1:5)": {"color": "yellow", "text": "third about LOREM", "created": 5000}
+            }, "bookmarks": [{"cfi": "epubcfi(/6/8!/4/2)", "label": "Lorem mark", "excerpt": "mark", "created": 4000}]}}),
+        );
+        let texts = |texts: &[&str]| texts.iter().map(|text| text.to_string()).collect::<Vec<String>>();
+
+        // Newest change first by default
+        assert_eq!(library_page(&hash, json!({})), (4, texts(&["third about LOREM", "mark", "second", "first"])));
+        assert_eq!(library_page(&hash, json!({"sortBy": "position"})), (4, texts(&["first", "second", "third about LOREM", "mark"])));
+        assert_eq!(library_page(&hash, json!({"colors": ["yellow"], "sortBy": "position"})).1, texts(&["first", "third about LOREM"]));
+        assert_eq!(library_page(&hash, json!({"tags": ["idea", "quote"], "sortBy": "position"})).1, texts(&["first", "second"]));
+        assert_eq!(library_page(&hash, json!({"kinds": ["bookmark"]})).1, texts(&["mark"]));
+        // Dates are the later of created and modified, both ends included
+        assert_eq!(library_page(&hash, json!({"from": 2500, "to": 4500})).1, texts(&["mark", "second"]));
+        assert_eq!(library_page(&hash, json!({"from": 2000, "to": 2000})).1, texts(&["first"]));
+        // Notes, highlighted text and bookmark labels, ignoring case
+        assert_eq!(library_page(&hash, json!({"search": "lorem", "sortBy": "position"})).1, texts(&["first", "third about LOREM", "mark"]));
+
+        assert_eq!(library_page(&hash, json!({"offset": 1, "limit": 2})), (4, texts(&["mark", "second"])));
+        assert_eq!(library_page(&hash, json!({"offset": 3, "limit": 2})), (4, texts(&["first"])));
+        assert_eq!(library_page(&hash, json!({"offset": 10})), (4, Vec::new()));
+        assert_eq!(library_page(&hash, json!({"limit": 0})), (4, Vec::new()));
+        assert_eq!(library_page(&hash, json!({"search": "missing"})), (0, Vec::new()));
+    }
+
+    #[test]
+    fn renames_and_reorders_bookmarks() {
+        let _library = crate::shared_test_library();
+        let hash = crate::test_book(
+            "bookmarks",
+            json!({"title": "Bookmarks", "data": {"progress": 0.0, "bookmarks": [
+                {"cfi": "a", "label": "A", "created": 1},
+                {"cfi": "b", "label": "B", "created": 2},
+                {"cfi": "c", "label": "C", "created": 3}
+            ]}}),
+        );
+        let labels = || book_state::get(&hash).unwrap().data.bookmarks.into_iter().map(|bookmark| bookmark.label).collect::<Vec<String>>();
+
+        rename_bookmark(hash.clone(), "b".to_string(), "Renamed".to_string()).unwrap();
+        assert!(rename_bookmark(hash.clone(), "missing".to_string(), "Lost".to_string()).is_err());
+        assert_eq!(labels(), vec!["A", "Renamed", "C"]);
+
+        // Unknown and repeated CFIs are ignored, bookmarks left out keep their order at the end
+        let reordered = reorder_bookmarks(hash.clone(), vec!["c".to_string(), "missing".to_string(), "a".to_string(), "c".to_string()]).unwrap();
+        assert_eq!(reordered.iter().map(|bookmark| bookmark.cfi.as_str()).collect::<Vec<&str>>(), vec!["c", "a", "b"]);
+        reorder_bookmarks(hash.clone(), Vec::new()).unwrap();
+        assert_eq!(labels(), vec!["C", "A", "Renamed"]);
+        assert_eq!(book_state::get(&hash).unwrap().data.bookmarks[0].created, 3);
+
+        assert!(reorder_bookmarks("missing-book".to_string(), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+// Local HTTP server for book, cover and font files
+// Only used on linux, where the asset protocol can not stream large files to the webview
+// https://github.com/tauri-apps/tauri/issues/3725
+// Every launch picks a random token that prefixes every path, so other programs and web pages
+// on the machine can not read the library. The port is the `assetServerPort` setting, or any free
+// port when it is 0 or taken. While the server is not running the frontend uses the asset protocol.
+// ServeDir answers byte range requests, so large comics and PDFs are streamed in parts, and
+// cache headers let the webview reuse files it has already loaded.
+// The webview caches by URL, which includes the token and port, so cached files are only reused
+// until the app is restarted. Keeping the token private matters more than caching across launches.
+
+use std::{net::TcpListener, path::Path, sync::Mutex};
+
+use axum::{
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::sync::oneshot;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::services::ServeDir;
+
+use crate::{get_config_path, settings};
+
+const TOKEN_LENGTH: usize = 32;
+
+// Origins of the app's own webview, requests from anywhere else are refused
+const ALLOWED_ORIGINS: [&str; 3] = ["tauri://localhost", "https://tauri.localhost", "http://localhost:9000"];
+
+// Files in the served folders that are app data rather than assets
+pub const PRIVATE_EXTENSIONS: [&str; 3] = [".json", ".bak", ".tmp"];
+
+// Book files are named by their content hash, so the same URL always has the same bytes
+const CACHE_IMMUTABLE: &str = "private, max-age=31536000, immutable";
+// Covers and fonts can be replaced, they are revalidated with the Last-Modified date from ServeDir
+const CACHE_REVALIDATE: &str = "private, no-cache";
+
+struct AssetServer {
+    port: u16,
+    token: String,
+    shutdown: oneshot::Sender<()>,
+}
+
+// None while the server is not running
+static asset_server: Mutex<Option<AssetServer>> = Mutex::new(None);
+
+fn is_allowed_origin(origin: &HeaderValue) -> bool {
+    return ALLOWED_ORIGINS.iter().any(|allowed| origin.as_bytes() == allowed.as_bytes());
+}
+
+async fn guard<B>(request: Request<B>, next: Next<B>) -> Response {
+    if let Some(origin) = request.headers().get(header::ORIGIN) {
+        if !is_allowed_origin(origin) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+    let path = request.uri().path().to_lowercase();
+    if PRIVATE_EXTENSIONS.iter().any(|extension| path.ends_with(extension)) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    return next.run(request).await;
+}
+
+// Tag for a book file in books/<hash>/, the folder is named after the book's content hash
+// so the hash and file name always refer to the same bytes. Covers can be replaced, they get no tag
+fn book_entity_tag(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    if segments.len() != 4 || segments[1] != "books" || segments[3] == "cover.jpg" {
+        return None;
+    }
+    return Some(format!("\"{}-{}\"", segments[2], segments[3].replace('"', "")));
+}
+
+fn matches_tag(header_value: &HeaderValue, tag: &str) -> bool {
+    let value = header_value.to_str().unwrap_or("");
+    return value
+        .split(',')
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == "*" || candidate == tag);
+}
+
+async fn cache_headers<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let path = request.uri().path().to_string();
+    let tag = book_entity_tag(&path);
+    let cache_control = if tag.is_some() { CACHE_IMMUTABLE } else { CACHE_REVALIDATE };
+    let not_modified = match (&tag, request.headers().get(header::IF_NONE_MATCH)) {
+        (Some(tag), Some(value)) => matches_tag(value, tag),
+        _ => false,
+    };
+
+    // Ranges are only served when the client has parts of this same file, ServeDir ignores If-Range
+    let if_range = request.headers().get(header::IF_RANGE).cloned();
+    if let Some(if_range) = if_range {
+        let same_file = match &tag {
+            Some(tag) => if_range.to_str().unwrap_or("") == tag,
+            None => false,
+        };
+        if !same_file {
+            request.headers_mut().remove(header::RANGE);
+        }
+    }
+
+    // ServeDir answers first, so a 304 is only sent for files that exist
+    let mut response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    if not_modified {
+        response = StatusCode::NOT_MODIFIED.into_response();
+    }
+    if let Some(tag) = tag.and_then(|tag| HeaderValue::from_str(&tag).ok()) {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    return response;
+}
+
+fn router(token: &str, config: &Path) -> Router {
+    return Router::new()
+        .nest_service(&format!("/{}/books", token), ServeDir::new(config.join("books")))
+        .nest_service(&format!("/{}/fonts", token), ServeDir::new(config.join("fonts")))
+        .layer(middleware::from_fn(cache_headers))
+        .layer(middleware::from_fn(guard))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::predicate(|origin, _request| is_allowed_origin(origin)))
+                .allow_methods([Method::GET]),
+        );
+}
+
+fn bind(port: u16) -> Result<TcpListener, String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Error: Could not bind asset server to port {} : {}", port, e))?;
+    listener.set_nonblocking(true).map_err(|e| format!("Error: {}", e))?;
+    return Ok(listener);
+}
+
+// Binds the server and serves it on the tokio runtime, must be called after the config path is set
+// Errors leave the server stopped, they should not keep the app from starting
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub fn start() -> Result<(), String> {
+    if asset_server.lock().unwrap().is_some() {
+        return Err("Error: Asset server already started".to_string());
+    }
+    let configured = settings::load().assetServerPort;
+    let listener = match bind(configured) {
+        Ok(listener) => listener,
+        Err(error) if configured != 0 => {
+            println!("{}, using a free port instead", error);
+            bind(0)?
+        }
+        Err(error) => return Err(error),
+    };
+    let port = listener.local_addr().map_err(|e| format!("Error: {}", e))?.port();
+    let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect();
+
+    let app = router(&token, &get_config_path());
+    let server = axum::Server::from_tcp(listener).map_err(|e| format!("Error: Could not start asset server : {}", e))?;
+
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    *asset_server.lock().unwrap() = Some(AssetServer { port, token, shutdown });
+    println!("Serving assets on port {}", port);
+    tokio::spawn(async move {
+        let result = server
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                stopped.await.ok();
+            })
+            .await;
+        if let Err(error) = result {
+            println!("Error: Asset server stopped : {}", error);
+            // Fall back to the asset protocol for anything loaded from now on
+            asset_server.lock().unwrap().take();
+        }
+    });
+    return Ok(());
+}
+
+// Stops accepting connections and lets requests in flight finish, called when the app exits
+pub fn stop() {
+    if let Some(server) = asset_server.lock().unwrap().take() {
+        server.shutdown.send(()).ok();
+        println!("Stopped asset server on port {}", server.port);
+    }
+}
+
+// Base URL for files below Alexandria_Data, e.g. base + "books/<hash>/<file>"
+// None when the server is not running and the asset protocol should be used
+#[tauri::command]
+pub fn get_asset_server_url() -> Option<String> {
+    return asset_server
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|server| format!("http://127.0.0.1:{}/{}/", server.port, server.token));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const TOKEN: &str = "token";
+    const BOOK: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn library() -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("alexandria-assets-{}-{}", crate::current_time_millis(), rand::random::<u32>()));
+        fs::create_dir_all(folder.join("books").join("hash")).unwrap();
+        fs::create_dir_all(folder.join("fonts")).unwrap();
+        fs::write(folder.join("books").join("hash").join("book.epub"), BOOK).unwrap();
+        fs::write(folder.join("books").join("hash").join("cover.jpg"), "cover").unwrap();
+        fs::write(folder.join("books").join("hash").join("book.json"), "{}").unwrap();
+        fs::write(folder.join("fonts").join("Font.ttf"), "font").unwrap();
+        return folder;
+    }
+
+    // Serves the folder on a free port and returns the base URL including the token
+    fn serve(config: &Path) -> String {
+        let listener = bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = axum::Server::from_tcp(listener).unwrap().serve(router(TOKEN, config).into_make_service());
+        tokio::spawn(server);
+        return format!("http://127.0.0.1:{}/{}/", port, TOKEN);
+    }
+
+    fn tag() -> &'static str {
+        return "\"hash-book.epub\"";
+    }
+
+    #[test]
+    fn tags_only_book_files() {
+        assert_eq!(book_entity_tag("/token/books/hash/book.epub").as_deref(), Some(tag()));
+        assert_eq!(book_entity_tag("/token/books/hash/cover.jpg"), None);
+        assert_eq!(book_entity_tag("/token/fonts/Font.ttf"), None);
+        assert!(matches_tag(&HeaderValue::from_static("\"other\", W/\"hash-book.epub\""), tag()));
+        assert!(!matches_tag(&HeaderValue::from_static("\"other\""), tag()));
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let base = serve(&library());
+        let response = reqwest::Client::new()
+            .get(format!("{}books/hash/book.epub", base))
+            .header(header::RANGE, "bytes=10-19")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::ETAG], tag());
+        assert_eq!(response.headers()[header::CACHE_CONTROL], CACHE_IMMUTABLE);
+        assert_eq!(response.text().await.unwrap(), "abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn if_range_needs_the_same_file() {
+        let base = serve(&library());
+        let client = reqwest::Client::new();
+        let url = format!("{}books/hash/book.epub", base);
+
+        let same = client.get(&url).header(header::RANGE, "bytes=0-9").header(header::IF_RANGE, tag()).send().await.unwrap();
+        assert_eq!(same.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(same.text().await.unwrap(), "0123456789");
+
+        let stale = client.get(&url).header(header::RANGE, "bytes=0-9").header(header::IF_RANGE, "\"old\"").send().await.unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.text().await.unwrap(), BOOK);
+
+        // Covers have no tag, a dated If-Range always gets the whole file
+        let cover = client
+            .get(format!("{}books/hash/cover.jpg", base))
+            .header(header::RANGE, "bytes=0-1")
+            .header(header::IF_RANGE, "Fri, 09 Aug 1996 14:21:40 GMT")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(cover.status(), StatusCode::OK);
+        assert_eq!(cover.text().await.unwrap(), "cover");
+    }
+
+    #[tokio::test]
+    async fn answers_if_none_match() {
+        let base = serve(&library());
+        let client = reqwest::Client::new();
+
+        let cached = client.get(format!("{}books/hash/book.epub", base)).header(header::IF_NONE_MATCH, tag()).send().await.unwrap();
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[header::ETAG], tag());
+
+        let changed = client.get(format!("{}books/hash/book.epub", base)).header(header::IF_NONE_MATCH, "\"old\"").send().await.unwrap();
+        assert_eq!(changed.status(), StatusCode::OK);
+
+        // A matching tag does not hide that the file is gone
+        let missing = client.get(format!("{}books/gone/book.epub", base)).header(header::IF_NONE_MATCH, "*").send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn revalidates_covers_and_fonts() {
+        let base = serve(&library());
+        let client = reqwest::Client::new();
+        for path in ["books/hash/cover.jpg", "fonts/Font.ttf"] {
+            let response = client.get(format!("{}{}", base, path)).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CACHE_CONTROL], CACHE_REVALIDATE);
+            assert!(response.headers().get(header::ETAG).is_none());
+            assert!(response.headers().get(header::LAST_MODIFIED).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_origins() {
+        let base = serve(&library());
+        let client = reqwest::Client::new();
+        let url = format!("{}books/hash/book.epub", base);
+
+        let foreign = client.get(&url).header(header::ORIGIN, "https://example.com").send().await.unwrap();
+        assert_eq!(foreign.status(), StatusCode::FORBIDDEN);
+        let lookalike = client.get(&url).header(header::ORIGIN, "tauri://localhost.example.com").send().await.unwrap();
+        assert_eq!(lookalike.status(), StatusCode::FORBIDDEN);
+
+        let own = client.get(&url).header(header::ORIGIN, "tauri://localhost").send().await.unwrap();
+        assert_eq!(own.status(), StatusCode::OK);
+        assert_eq!(own.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "tauri://localhost");
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_tokens() {
+        let base = serve(&library());
+        let client = reqwest::Client::new();
+        for url in [base.replace(TOKEN, "guess") + "books/hash/book.epub", base.replace(&format!("{}/", TOKEN), "") + "books/hash/book.epub"] {
+            let response = client.get(url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn hides_app_data() {
+        let config = library();
+        fs::write(config.join("books").join("hash").join("book.json.bak"), "{}").unwrap();
+        fs::write(config.join("books").join("hash").join("book.JSON.tmp"), "{}").unwrap();
+        let base = serve(&config);
+        let client = reqwest::Client::new();
+        for path in ["books/hash/book.json", "books/hash/book.json.bak", "books/hash/book.JSON.tmp", "books/hash/BOOK.JSON"] {
+            let response = client.get(format!("{}{}", base, path)).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// Periodic snapshots of the reading state in Alexandria_Data
+// Snapshots are data backups (see backup.rs) written to backups/ or the `backupPath` setting,
+// the oldest are deleted once there are more than `backupCount`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use chrono::Local;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::settings::{self, Settings};
+use crate::{backup, book_state, current_time_millis, get_config_path, migrations, storage};
+
+const SNAPSHOT_PREFIX: &str = "alexandria-";
+// How often the scheduler checks whether a snapshot is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug)]
+pub struct BackupEntry {
+    name: String,
+    path: String,
+    created: u64,
+    size: u64,
+    books: usize,
+}
+
+fn backup_folder(settings: &Settings) -> PathBuf {
+    if settings.backupPath.len() > 0 {
+        return PathBuf::from(&settings.backupPath);
+    }
+    return get_config_path().join("backups");
+}
+
+// Manifest of a data backup written by Alexandria, None for any other archive
+fn read_snapshot_manifest(path: &Path) -> Option<backup::BackupManifest> {
+    let manifest = backup::open_archive(path).and_then(|mut archive| backup::read_manifest(&mut archive)).ok()?;
+    if manifest.format != backup::BACKUP_FORMAT || manifest.kind != "data" {
+        return None;
+    }
+    return Some(manifest);
+}
+
+fn read_entry_info(path: &Path) -> Option<BackupEntry> {
+    let name = path.file_name()?.to_string_lossy().to_string();
+    if !name.starts_with(SNAPSHOT_PREFIX) || !name.ends_with(".zip") {
+        return None;
+    }
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_millis() as u64)
+        .unwrap_or(0);
+    let manifest = read_snapshot_manifest(path);
+    return Some(BackupEntry {
+        name,
+        path: path.display().to_string(),
+        created: manifest.as_ref().map(|manifest| manifest.created).unwrap_or(modified),
+        size: metadata.len(),
+        books: manifest.map(|manifest| manifest.books.len()).unwrap_or(0),
+    });
+}
+
+fn snapshots(folder: &Path) -> Vec<BackupEntry> {
+    let mut entries: Vec<BackupEntry> = match fs::read_dir(folder) {
+        Ok(files) => files.flatten().filter_map(|file| read_entry_info(&file.path())).collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort_by(|a, b| b.created.cmp(&a.created));
+    return entries;
+}
+
+// Newest first
+#[tauri::command]
+pub fn list_backups() -> Vec<BackupEntry> {
+    return snapshots(&backup_folder(&settings::load()));
+}
+
+// `keep` names a snapshot that must survive the cleanup, such as the one being restored
+fn create_snapshot(settings: &Settings, keep: Option<&str>) -> Result<BackupEntry, String> {
+    let folder = backup_folder(settings);
+    fs::create_dir_all(&folder).map_err(|e| format!("Error: Could not create \"{}\" : {}", folder.display(), e))?;
+
+    let stamp = Local::now().format("%Y-%m-%d_%H-%M-%S-%3f").to_string();
+    let mut name = format!("{}{}.zip", SNAPSHOT_PREFIX, stamp);
+    let mut copy = 1;
+    while folder.join(&name).exists() {
+        copy += 1;
+        name = format!("{}{}_{}.zip", SNAPSHOT_PREFIX, stamp, copy);
+    }
+    let path = folder.join(&name);
+    backup::export_data_backup(path.display().to_string())?;
+
+    // Only archives Alexandria wrote are deleted, the folder may be shared with other files
+    let own: Vec<BackupEntry> = snapshots(&folder)
+        .into_iter()
+        .filter(|entry| Some(entry.name.as_str()) != keep && read_snapshot_manifest(Path::new(&entry.path)).is_some())
+        .collect();
+    for old in own.into_iter().skip(settings.backupCount.max(1) as usize) {
+        if let Err(error) = fs::remove_file(&old.path) {
+            println!("Error: Could not delete old backup {} : {}", old.name, error);
+        }
+    }
+    return read_entry_info(&path).ok_or(format!("Error: Could not read backup {}", name));
+}
+
+// Take a snapshot now, regardless of the schedule
+#[tauri::command]
+pub fn create_backup() -> Result<BackupEntry, String> {
+    return create_snapshot(&settings::load(), None);
+}
+
+// Put the library's reading state back to a snapshot
+// Unlike importing a backup nothing is merged, books and settings are set to the snapshot's state.
+// A snapshot of the current state is taken first so a restore can itself be undone.
+#[tauri::command]
+pub fn restore_backup(name: String) -> Result<backup::DataBackupReport, String> {
+    let settings = settings::load();
+    let path = backup_folder(&settings).join(&name);
+    if name.contains('/') || name.contains('\\') || !path.exists() {
+        return Err(format!("Error: Backup not found - {}", name));
+    }
+
+    let mut archive = backup::open_archive(&path)?;
+    let manifest = backup::read_manifest(&mut archive)?;
+    // Full backups are imported instead, they bring books that are not in the library
+    if manifest.kind != "data" {
+        return Err(format!("Error: {} is not a data backup, import it instead", name));
+    }
+    create_snapshot(&settings, Some(&name))?;
+
+    let config_path = get_config_path();
+    let mut report = backup::DataBackupReport::default();
+    for book in manifest.books.iter() {
+        if !config_path.join("books").join(&book.hash).exists() {
+            report.books_missing.push(if book.title.len() > 0 { book.title.clone() } else { book.hash.clone() });
+            continue;
+        }
+        let contents = backup::read_entry(&mut archive, &format!("books/{}.json", book.hash))?;
+        let restored = migrations::book_data_from_slice(&contents)?;
+        book_state::update(&book.hash, None, |payload, _previous| {
+            *payload = restored;
+            return Ok(());
+        })?;
+        report.books_updated += 1;
+    }
+    for name in backup::STATE_FILES {
+        let contents = match backup::read_entry(&mut archive, name) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        if name == "settings.json" {
+            // Snapshots have no secrets, the current ones are kept
+            let mut object: Map<String, Value> = serde_json::from_slice(&contents).map_err(|e| format!("Malformed Data: {}", e))?;
+            settings::keep_secrets(&mut object, &settings);
+            storage::write_json(&config_path.join(name), &object)?;
+        } else {
+            storage::write_atomic(&config_path.join(name), &contents).map_err(|e| format!("Error: Could not restore {} : {}", name, e))?;
+        }
+    }
+    migrations::run()?;
+    settings::apply_change(&settings, &settings::load());
+
+    println!("Restored backup {} : {:?}", name, report);
+    return Ok(report);
+}
+
+// Runs for the lifetime of the app on the tokio runtime started in main
+// The settings are read on every check so changes apply without a restart
+pub async fn run_scheduler() {
+    loop {
+        let settings = settings::load();
+        if settings.backupInterval > 0 {
+            let last = snapshots(&backup_folder(&settings)).first().map(|entry| entry.created).unwrap_or(0);
+            let due = last + settings.backupInterval * 60 * 1000 <= current_time_millis();
+            if due {
+                match tokio::task::spawn_blocking(move || create_snapshot(&settings, None)).await {
+                    Ok(Ok(entry)) => println!("Created backup {}", entry.name),
+                    Ok(Err(error)) => println!("{}", error),
+                    Err(error) => println!("Error: Backup task failed : {}", error),
+                }
+            }
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn folder() -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("alexandria-snapshots-{}-{}", current_time_millis(), rand::random::<u32>()));
+        fs::create_dir_all(&folder).unwrap();
+        return folder;
+    }
+
+    fn settings_in(folder: &Path, count: u64) -> Settings {
+        return Settings {
+            backupPath: folder.display().to_string(),
+            backupCount: count,
+            ..Settings::default()
+        };
+    }
+
+    // Snapshots are ordered by their creation time in milliseconds
+    fn snapshot(settings: &Settings, keep: Option<&str>) -> String {
+        std::thread::sleep(Duration::from_millis(2));
+        return create_snapshot(settings, keep).unwrap().name;
+    }
+
+    fn names(folder: &Path) -> Vec<String> {
+        return snapshots(folder).into_iter().map(|entry| entry.name).collect();
+    }
+
+    #[test]
+    fn keeps_the_newest_snapshots() {
+        let _library = crate::exclusive_test_library();
+        let folder = folder();
+        fs::write(folder.join("alexandria-notes.zip"), "not an archive").unwrap();
+        fs::write(folder.join("other.zip"), "not an archive").unwrap();
+        let settings = settings_in(&folder, 2);
+
+        let created: Vec<String> = (0..4).map(|_| snapshot(&settings, None)).collect();
+        let mut unique = created.clone();
+        unique.dedup();
+        assert_eq!(unique.len(), 4);
+
+        let remaining = names(&folder);
+        assert!(remaining.contains(&created[3]) && remaining.contains(&created[2]));
+        assert!(!remaining.contains(&created[1]) && !remaining.contains(&created[0]));
+        // Files Alexandria did not write are left alone
+        assert!(remaining.contains(&"alexandria-notes.zip".to_string()));
+        assert!(folder.join("other.zip").exists());
+    }
+
+    #[test]
+    fn keeps_the_snapshot_being_restored() {
+        let _library = crate::exclusive_test_library();
+        let folder = folder();
+        let settings = settings_in(&folder, 1);
+
+        let first = snapshot(&settings, None);
+        let second = snapshot(&settings, Some(&first));
+        assert_eq!(names(&folder), vec![second.clone(), first.clone()]);
+
+        let third = snapshot(&settings, None);
+        assert_eq!(names(&folder), vec![third]);
+    }
+
+    #[test]
+    fn restores_a_snapshot() {
+        let _library = crate::exclusive_test_library();
+        let config = crate::test_config_path();
+        storage::write_json(&config.join("settings.json"), &json!({"backupCount": 1, "backupInterval": 0})).unwrap();
+
+        let hash = crate::test_book("restore", json!({"title": "Restore", "data": {"progress": 0.1, "highlights": {"a": {"color": "yellow"}}}}));
+        let removed = crate::test_book("removed", json!({"title": "Removed", "data": {"progress": 0.2}}));
+        let name = snapshot(&settings::load(), None);
+
+        book_state::update(&hash, None, |payload, _previous| {
+            payload.data.progress = 0.9;
+            payload.data.highlights.remove("a");
+            return Ok(());
+        })
+        .unwrap();
+        fs::remove_dir_all(config.join("books").join(&removed)).unwrap();
+        book_state::evict(&removed);
+
+        let report = restore_backup(name.clone()).unwrap();
+        assert!(report.books_updated >= 1);
+        assert_eq!(report.books_missing, vec!["Removed".to_string()]);
+        let restored = book_state::get(&hash).unwrap();
+        assert_eq!(restored.data.progress, 0.1);
+        assert_eq!(restored.data.highlights["a"].color, "yellow");
+        assert_eq!(crate::read_book_data(&hash).unwrap().data.progress, 0.1);
+
+        // The restored snapshot survives the snapshot taken before restoring, which undoes it
+        let listed = list_backups();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[1].name, name);
+        restore_backup(listed[0].name.clone()).unwrap();
+        assert_eq!(book_state::get(&hash).unwrap().data.progress, 0.9);
+
+        fs::remove_dir_all(config.join("backups")).unwrap();
+        fs::remove_file(config.join("settings.json")).unwrap();
+        fs::remove_file(storage::backup_path(&config.join("settings.json"))).ok();
+    }
+
+    #[test]
+    fn refuses_unknown_snapshots() {
+        let _library = crate::shared_test_library();
+        assert!(restore_backup("../settings.json".to_string()).is_err());
+        assert!(restore_backup("alexandria-missing.zip".to_string()).is_err());
+    }
+}
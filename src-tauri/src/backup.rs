@@ -0,0 +1,813 @@
+// Library backups in a single zip archive
+// Full archives mirror Alexandria_Data: books/<hash>/..., fonts/..., settings.json,
+// GlobalThemes.json and ReaderThemes.json, plus a manifest.json describing its contents.
+// Data archives only hold the per book JSON and the settings and theme files.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{book_state, current_time_millis, get_config_path, merge, migrations, settings, storage};
+
+pub const BACKUP_FORMAT: &str = "alexandria-backup";
+// Bump when the archive layout changes, older versions refuse archives newer than they know
+pub const BACKUP_SCHEMA_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+pub const STATE_FILES: [&str; 3] = ["settings.json", "GlobalThemes.json", "ReaderThemes.json"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupBook {
+    pub hash: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub author: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupManifest {
+    pub format: String,
+    pub schema_version: u32,
+    // "full" archives contain the book files, "data" archives only the reading state
+    pub kind: String,
+    pub created: u64,
+    #[serde(default)]
+    pub app_version: String,
+    #[serde(default)]
+    pub books: Vec<BackupBook>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct BackupReport {
+    pub books_added: usize,
+    // Books that were already in the library, their data was merged
+    pub books_merged: usize,
+    pub fonts_added: usize,
+    pub files_restored: usize,
+}
+
+pub fn new_manifest(kind: &str, books: Vec<BackupBook>) -> BackupManifest {
+    return BackupManifest {
+        format: BACKUP_FORMAT.to_string(),
+        schema_version: BACKUP_SCHEMA_VERSION,
+        kind: kind.to_string(),
+        created: current_time_millis(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        books,
+    };
+}
+
+pub fn library_books() -> Vec<BackupBook> {
+    let mut books = Vec::new();
+    let entries = match fs::read_dir(get_config_path().join("books")) {
+        Ok(entries) => entries,
+        Err(_) => return books,
+    };
+    for entry in entries.flatten() {
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let hash = entry.file_name().to_string_lossy().to_string();
+        let (title, author) = match book_state::get(&hash) {
+            Ok(payload) => (payload.title, payload.author),
+            Err(_) => (String::new(), String::new()),
+        };
+        books.push(BackupBook { hash, title, author });
+    }
+    books.sort_by(|a, b| a.hash.cmp(&b.hash));
+    return books;
+}
+
+fn zip_error(error: impl std::fmt::Display) -> String {
+    return format!("Error: Could not write backup: {}", error);
+}
+
+// Temporary and backup copies made by the storage module are not part of a library
+fn is_transient(path: &Path) -> bool {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    return name.ends_with(".tmp") || name.ends_with(".bak");
+}
+
+fn add_file(zip: &mut ZipWriter<File>, path: &Path, name: &str) -> Result<(), String> {
+    // Books and fonts are already compressed, only the JSON files are worth deflating
+    let is_json = path.extension().map(|extension| extension == "json").unwrap_or(false);
+    let options = FileOptions::default().compression_method(if is_json {
+        CompressionMethod::Deflated
+    } else {
+        CompressionMethod::Stored
+    });
+    // Books can be large, they are copied through without reading them into memory
+    let mut file = File::open(path).map_err(|e| format!("Error: Could not read \"{}\" : {}", path.display(), e))?;
+    zip.start_file(name, options).map_err(zip_error)?;
+    io::copy(&mut file, zip).map_err(|e| format!("Error: Could not read \"{}\" : {}", path.display(), e))?;
+    return Ok(());
+}
+
+// Settings go in without their passwords and keys, see settings.rs
+pub fn add_state_file(zip: &mut ZipWriter<File>, path: &Path, name: &str) -> Result<(), String> {
+    if name == "settings.json" {
+        let mut object: Map<String, Value> = storage::read_json(path)?;
+        settings::strip_secrets(&mut object);
+        return add_json(zip, name, &object);
+    }
+    return add_file(zip, path, name);
+}
+
+pub fn add_directory(zip: &mut ZipWriter<File>, directory: &Path, prefix: &str) -> Result<usize, String> {
+    let mut count = 0;
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(0),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if path.is_dir() {
+            count += add_directory(zip, &path, &name)?;
+        } else if !is_transient(&path) {
+            add_file(zip, &path, &name)?;
+            count += 1;
+        }
+    }
+    return Ok(count);
+}
+
+pub fn add_json<T: Serialize>(zip: &mut ZipWriter<File>, name: &str, value: &T) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(value).map_err(zip_error)?;
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(name, options).map_err(zip_error)?;
+    zip.write_all(contents.as_bytes()).map_err(zip_error)?;
+    return Ok(());
+}
+
+// Archives are written next to the destination and renamed into place once complete
+pub fn write_archive<F>(path: &Path, fill: F) -> Result<(), String>
+where
+    F: FnOnce(&mut ZipWriter<File>) -> Result<(), String>,
+{
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let file = File::create(&temp_path).map_err(|e| format!("Error: Could not create \"{}\" : {}", temp_path.display(), e))?;
+    let mut zip = ZipWriter::new(file);
+
+    let result = fill(&mut zip).and_then(|_| zip.finish().map(|_| ()).map_err(zip_error));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(error);
+    }
+    return fs::rename(&temp_path, path).map_err(|e| format!("Error: Could not write \"{}\" : {}", path.display(), e));
+}
+
+#[tauri::command]
+pub fn export_backup(path: String) -> Result<BackupManifest, String> {
+    // Progress and annotations waiting for a deferred write belong in the backup
+    book_state::flush_all();
+
+    let manifest = new_manifest("full", library_books());
+    let config = get_config_path();
+    write_archive(Path::new(&path), |zip| {
+        add_json(zip, MANIFEST_FILE, &manifest)?;
+        add_directory(zip, &config.join("books"), "books")?;
+        add_directory(zip, &config.join("fonts"), "fonts")?;
+        for name in STATE_FILES {
+            let file = config.join(name);
+            if file.exists() {
+                add_state_file(zip, &file, name)?;
+            }
+        }
+        return Ok(());
+    })?;
+
+    println!("Backed up {} books to {}", manifest.books.len(), path);
+    return Ok(manifest);
+}
+
+pub fn open_archive(path: &Path) -> Result<ZipArchive<File>, String> {
+    let file = File::open(path).map_err(|e| format!("Error: Could not open \"{}\" : {}", path.display(), e))?;
+    return ZipArchive::new(file).map_err(|e| format!("Error: \"{}\" is not a backup archive : {}", path.display(), e));
+}
+
+pub fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>, String> {
+    let mut entry = archive.by_name(name).map_err(|e| format!("Error: Backup is missing {} : {}", name, e))?;
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents).map_err(|e| format!("Error: Could not read {} : {}", name, e))?;
+    return Ok(contents);
+}
+
+pub fn read_manifest(archive: &mut ZipArchive<File>) -> Result<BackupManifest, String> {
+    let contents = read_entry(archive, MANIFEST_FILE)?;
+    let manifest: BackupManifest = serde_json::from_slice(&contents).map_err(|e| format!("Error: Invalid backup manifest : {}", e))?;
+    if manifest.format != BACKUP_FORMAT {
+        return Err(format!("Error: Not an Alexandria backup - {}", manifest.format));
+    }
+    if manifest.schema_version > BACKUP_SCHEMA_VERSION {
+        return Err(format!(
+            "Error: Backup was made by a newer version of Alexandria (schema {}), please update",
+            manifest.schema_version
+        ));
+    }
+    return Ok(manifest);
+}
+
+// Adds every key missing from `local`, nested objects are merged the same way
+// Values already present locally are kept
+pub fn merge_missing(local: &mut Value, incoming: Value) {
+    match (local, incoming) {
+        (Value::Object(local), Value::Object(incoming)) => {
+            for (key, value) in incoming {
+                match local.get_mut(&key) {
+                    Some(existing) => merge_missing(existing, value),
+                    None => {
+                        local.insert(key, value);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn merge_json_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut incoming: Value = serde_json::from_slice(contents).map_err(|e| format!("Malformed Data: {}", e))?;
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    if let Some(kind) = migrations::kind_for_file(&name) {
+        migrations::upgrade(kind, &mut incoming)?;
+    }
+    let mut local: Value = if path.exists() {
+        storage::read_json(path).unwrap_or(Value::Object(Default::default()))
+    } else {
+        Value::Object(Default::default())
+    };
+    merge_missing(&mut local, incoming);
+    return storage::write_json(path, &local);
+}
+
+// Merges a copy of a book's data into the library's, going through the shared book state
+pub fn merge_book_data(hash: &str, contents: &[u8]) -> Result<(), String> {
+    let incoming = migrations::book_data_from_slice(contents)?;
+    book_state::update(hash, None, |payload, _previous| {
+        *payload = merge::merge_copies(payload, &incoming);
+        return Ok(());
+    })?;
+    return Ok(());
+}
+
+fn write_entry(path: &Path, contents: &mut impl Read) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Error: Could not create \"{}\" : {}", parent.display(), e))?;
+    }
+    let mut file = File::create(path).map_err(|e| format!("Error: Could not write \"{}\" : {}", path.display(), e))?;
+    io::copy(contents, &mut file).map_err(|e| format!("Error: Could not write \"{}\" : {}", path.display(), e))?;
+    return Ok(());
+}
+
+// Only the JSON files that are merged are read into memory, everything else is streamed
+fn read_all(entry: &mut impl Read, name: &Path) -> Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents).map_err(|e| format!("Error: Could not read {} : {}", name.display(), e))?;
+    return Ok(contents);
+}
+
+fn existing_books() -> HashSet<String> {
+    return library_books().into_iter().map(|book| book.hash).collect();
+}
+
+const STAGING_FOLDER: &str = ".backup_restore";
+// Books a merge adds are extracted here and moved into the library once complete
+const MERGE_FOLDER: &str = ".backup_merge";
+// The library a restore replaces is kept here until the restored one is in place
+const PREVIOUS_FOLDER: &str = ".backup_previous";
+// Present in the previous folder while entries are being swapped
+const INCOMPLETE_MARKER: &str = ".incomplete";
+
+fn is_empty_dir(path: &Path) -> bool {
+    return fs::read_dir(path).map(|mut entries| entries.next().is_none()).unwrap_or(true);
+}
+
+// Puts every entry of the previous folder back in place, dropping what was restored over it
+fn roll_back(config: &Path, previous: &Path) -> Result<(), String> {
+    let entries = fs::read_dir(previous).map_err(|e| format!("Error: Could not read \"{}\" : {}", previous.display(), e))?;
+    for entry in entries.flatten() {
+        if entry.file_name() == INCOMPLETE_MARKER {
+            continue;
+        }
+        let current = config.join(entry.file_name());
+        if current.is_dir() {
+            fs::remove_dir_all(&current).map_err(|e| format!("Error: Could not remove \"{}\" : {}", current.display(), e))?;
+        } else if current.exists() {
+            fs::remove_file(&current).map_err(|e| format!("Error: Could not remove \"{}\" : {}", current.display(), e))?;
+        }
+        fs::rename(entry.path(), &current).map_err(|e| format!("Error: Could not move back \"{}\" : {}", current.display(), e))?;
+    }
+    fs::remove_dir_all(previous).map_err(|e| format!("Error: Could not remove \"{}\" : {}", previous.display(), e))?;
+    book_state::evict_all();
+    return Ok(());
+}
+
+// A restore that stopped while swapping leaves the library it replaced in the previous folder
+// It is put back unless the restored library is complete. Returns whether it was put back.
+pub fn recover_restore() -> Result<bool, String> {
+    let config = get_config_path();
+    let previous = config.join(PREVIOUS_FOLDER);
+    if !previous.exists() {
+        return Ok(false);
+    }
+    let interrupted = previous.join(INCOMPLETE_MARKER).exists();
+    let books_lost = is_empty_dir(&config.join("books")) && !is_empty_dir(&previous.join("books"));
+    if interrupted || books_lost {
+        roll_back(&config, &previous)?;
+        println!("Put back the library replaced by an interrupted restore");
+        return Ok(true);
+    }
+    // The restore finished, only removing the old library did not
+    fs::remove_dir_all(&previous).map_err(|e| format!("Error: Could not remove \"{}\" : {}", previous.display(), e))?;
+    return Ok(false);
+}
+
+// Moves the library's entries aside and the staged ones in their place
+fn swap_in(config: &Path, staging: &Path, previous: &Path) -> Result<(), String> {
+    let mut names: Vec<&str> = vec!["books", "fonts"];
+    names.extend(STATE_FILES);
+    for name in names {
+        let current = config.join(name);
+        let staged = staging.join(name);
+        // A backup copy of the replaced file must not win over the restored one, it goes aside too
+        let current_backup = storage::backup_path(&current);
+        if current_backup.exists() {
+            let aside = storage::backup_path(&previous.join(name));
+            fs::rename(&current_backup, &aside).map_err(|e| format!("Error: Could not move \"{}\" : {}", current_backup.display(), e))?;
+        }
+        if current.exists() {
+            fs::rename(&current, previous.join(name)).map_err(|e| format!("Error: Could not move \"{}\" : {}", current.display(), e))?;
+        }
+        if staged.exists() {
+            fs::rename(&staged, &current).map_err(|e| format!("Error: Could not restore \"{}\" : {}", current.display(), e))?;
+        } else if STATE_FILES.contains(&name) {
+            storage::write_atomic(&current, b"{}").map_err(|e| format!("Error: {}", e))?;
+        }
+    }
+    return Ok(());
+}
+
+// Replace the whole library with the archive's contents
+// Everything is extracted and upgraded in a staging folder first so a broken archive leaves the
+// library untouched. If swapping fails partway the replaced entries are moved back.
+fn restore_archive(archive: &mut ZipArchive<File>) -> Result<BackupReport, String> {
+    let config = get_config_path();
+    let staging = config.join(STAGING_FOLDER);
+    let previous = config.join(PREVIOUS_FOLDER);
+    recover_restore()?;
+    let _ = fs::remove_dir_all(&staging);
+
+    let mut report = BackupReport::default();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| format!("Error: Could not read backup : {}", e))?;
+        let name = match entry.enclosed_name() {
+            Some(name) => name.to_path_buf(),
+            None => continue,
+        };
+        if entry.is_dir() || name == Path::new(MANIFEST_FILE) {
+            continue;
+        }
+        write_entry(&staging.join(&name), &mut entry)?;
+        report.files_restored += 1;
+    }
+    fs::create_dir_all(staging.join("books")).map_err(|e| format!("Error: {}", e))?;
+    fs::create_dir_all(staging.join("fonts")).map_err(|e| format!("Error: {}", e))?;
+
+    // Archives from older releases are upgraded like the library would have been, and archives
+    // from newer ones are refused before anything is replaced
+    // Backups do not hold secrets, the ones this library has are kept
+    if let Err(error) = migrations::run_in(&staging).and_then(|_| settings::keep_secrets_in(&staging.join("settings.json"))) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(error);
+    }
+
+    report.books_added = fs::read_dir(staging.join("books")).map(|entries| entries.count()).unwrap_or(0);
+    report.fonts_added = fs::read_dir(staging.join("fonts"))
+        .map(|entries| entries.flatten().filter(|entry| entry.path().is_dir()).count())
+        .unwrap_or(0);
+
+    // Nothing may be cached or waiting to be written for the books being replaced
+    book_state::flush_all();
+    book_state::evict_all();
+
+    fs::create_dir_all(&previous).map_err(|e| format!("Error: {}", e))?;
+    fs::write(previous.join(INCOMPLETE_MARKER), b"").map_err(|e| format!("Error: {}", e))?;
+    if let Err(error) = swap_in(&config, &staging, &previous) {
+        if let Err(rollback_error) = roll_back(&config, &previous) {
+            return Err(format!("{} - the previous library is kept in \"{}\" : {}", error, previous.display(), rollback_error));
+        }
+        let _ = fs::remove_dir_all(&staging);
+        return Err(error);
+    }
+    let _ = fs::remove_file(previous.join(INCOMPLETE_MARKER));
+
+    let _ = fs::remove_dir_all(&staging);
+    let _ = fs::remove_dir_all(&previous);
+    book_state::evict_all();
+    return Ok(report);
+}
+
+// Add what the library is missing, books with the same content hash have their data merged
+fn merge_archive(archive: &mut ZipArchive<File>) -> Result<BackupReport, String> {
+    let config = get_config_path();
+    let staging = config.join(MERGE_FOLDER);
+    let _ = fs::remove_dir_all(&staging);
+    let existing = existing_books();
+    let mut report = BackupReport::default();
+    let mut added_books: HashSet<String> = HashSet::new();
+    let mut merged_books: HashSet<String> = HashSet::new();
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| format!("Error: Could not read backup : {}", e))?;
+        let name = match entry.enclosed_name() {
+            Some(name) => name.to_path_buf(),
+            None => continue,
+        };
+        if entry.is_dir() || name == Path::new(MANIFEST_FILE) {
+            continue;
+        }
+        let parts: Vec<&str> = name.iter().filter_map(|part| part.to_str()).collect();
+        let destination = config.join(&name);
+        match parts.as_slice() {
+            ["books", hash, file] => {
+                if !existing.contains(*hash) {
+                    write_entry(&staging.join(&name), &mut entry)?;
+                    added_books.insert(hash.to_string());
+                    report.files_restored += 1;
+                } else if *file == format!("{}.json", hash) {
+                    merge_book_data(hash, &read_all(&mut entry, &name)?)?;
+                    merged_books.insert(hash.to_string());
+                }
+                // Same hash means the same book file, the local cover and history are kept
+            }
+            ["fonts", "fonts.json"] => {
+                merge_json_file(&destination, &read_all(&mut entry, &name)?)?;
+            }
+            ["fonts", font, ..] => {
+                if !destination.exists() {
+                    if !config.join("fonts").join(font).exists() {
+                        report.fonts_added += 1;
+                    }
+                    write_entry(&destination, &mut entry)?;
+                    report.files_restored += 1;
+                }
+            }
+            [file] if STATE_FILES.contains(file) => {
+                merge_json_file(&destination, &read_all(&mut entry, &name)?)?;
+            }
+            _ => {
+                println!("Skipping unknown backup entry {}", name.display());
+            }
+        }
+    }
+
+    // Data of the added books was copied as is, it is upgraded like the rest of the library
+    // A half extracted book would count as present on the next merge, so books only move in complete
+    let moved = migrations::run_in(&staging).and_then(|_| {
+        fs::create_dir_all(config.join("books")).map_err(|e| format!("Error: {}", e))?;
+        for hash in added_books.iter() {
+            let book = config.join("books").join(hash);
+            fs::rename(staging.join("books").join(hash), &book).map_err(|e| format!("Error: Could not add \"{}\" : {}", book.display(), e))?;
+        }
+        return Ok(());
+    });
+    let _ = fs::remove_dir_all(&staging);
+    moved?;
+
+    report.books_added = added_books.len();
+    report.books_merged = merged_books.len();
+    return Ok(report);
+}
+
+// `mode` is "restore" to replace the library or "merge" to combine it with the backup
+#[tauri::command]
+pub fn import_backup(path: String, mode: Option<String>) -> Result<BackupReport, String> {
+    let mut archive = open_archive(Path::new(&path))?;
+    let manifest = read_manifest(&mut archive)?;
+    if manifest.kind != "full" {
+        return Err(format!("Error: {} is a \"{}\" backup, not a full library backup", path, manifest.kind));
+    }
+
+    let report = match mode.as_deref().unwrap_or("merge") {
+        "restore" => restore_archive(&mut archive)?,
+        "merge" => merge_archive(&mut archive)?,
+        other => return Err(format!("Error: Unknown backup import mode - {}", other)),
+    };
+    println!("Imported backup {} : {:?}", path, report);
+    return Ok(report);
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DataBackupReport {
+    pub books_updated: usize,
+    // Books in the backup that are not in this library, by title or hash when untitled
+    pub books_missing: Vec<String>,
+    // Books whose data is missing from the backup or could not be read, and why
+    pub books_skipped: Vec<String>,
+}
+
+// Reading state only, no book files, fonts or covers
+// Per book data is stored as books/<hash>.json so it can be applied to the same books elsewhere
+#[tauri::command]
+pub fn export_data_backup(path: String) -> Result<BackupManifest, String> {
+    book_state::flush_all();
+
+    let manifest = new_manifest("data", library_books());
+    let config = get_config_path();
+    write_archive(Path::new(&path), |zip| {
+        add_json(zip, MANIFEST_FILE, &manifest)?;
+        for book in manifest.books.iter() {
+            let payload = book_state::get(&book.hash)?;
+            add_json(zip, &format!("books/{}.json", book.hash), &payload)?;
+        }
+        for name in STATE_FILES {
+            let file = config.join(name);
+            if file.exists() {
+                add_state_file(zip, &file, name)?;
+            }
+        }
+        return Ok(());
+    })?;
+
+    println!("Backed up reading data of {} books to {}", manifest.books.len(), path);
+    return Ok(manifest);
+}
+
+// Merges the reading state of every book the library also has, matched by content hash
+#[tauri::command]
+pub fn import_data_backup(path: String) -> Result<DataBackupReport, String> {
+    let mut archive = open_archive(Path::new(&path))?;
+    let manifest = read_manifest(&mut archive)?;
+    if manifest.kind != "data" {
+        return Err(format!("Error: {} is a \"{}\" backup, not a reading data backup", path, manifest.kind));
+    }
+
+    let config = get_config_path();
+    let existing = existing_books();
+    let mut report = DataBackupReport::default();
+    for book in manifest.books.iter() {
+        if !existing.contains(&book.hash) {
+            report.books_missing.push(if book.title.len() > 0 { book.title.clone() } else { book.hash.clone() });
+            continue;
+        }
+        // One damaged book does not stop the others from being imported
+        let merged = read_entry(&mut archive, &format!("books/{}.json", book.hash)).and_then(|contents| merge_book_data(&book.hash, &contents));
+        if let Err(error) = merged {
+            println!("Skipping reading data of {} : {}", book.hash, error);
+            report.books_skipped.push(format!("{} : {}", if book.title.len() > 0 { &book.title } else { &book.hash }, error));
+            continue;
+        }
+        report.books_updated += 1;
+    }
+    for name in STATE_FILES {
+        if let Ok(contents) = read_entry(&mut archive, name) {
+            merge_json_file(&config.join(name), &contents)?;
+        }
+    }
+
+    println!("Imported reading data {} : {:?}", path, report);
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("alexandria-{}-{}-{}", name, current_time_millis(), rand::random::<u32>()));
+        fs::create_dir_all(&folder).unwrap();
+        return folder;
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        return fs::read_to_string(path).unwrap();
+    }
+
+    fn highlights(hash: &str) -> Vec<String> {
+        let mut highlights: Vec<String> = book_state::get(hash).unwrap().data.highlights.keys().cloned().collect();
+        highlights.sort();
+        return highlights;
+    }
+
+    fn add_highlight(hash: &str, cfi: &str) {
+        book_state::update(hash, None, |payload, _previous| {
+            payload.data.highlights.insert(cfi.to_string(), serde_json::from_value(json!({"color": "yellow"})).unwrap());
+            return Ok(());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn merges_missing_keys() {
+        let mut local = json!({"kept": 1, "nested": {"kept": "local", "other": true}, "replaced": {"a": 1}});
+        merge_missing(&mut local, json!({"kept": 2, "added": 3, "nested": {"kept": "incoming", "added": [1]}, "replaced": 5}));
+        assert_eq!(
+            local,
+            json!({"kept": 1, "added": 3, "nested": {"kept": "local", "other": true, "added": [1]}, "replaced": {"a": 1}})
+        );
+    }
+
+    #[test]
+    fn swaps_in_the_staged_library() {
+        let config = temp_folder("swap-config");
+        let staging = temp_folder("swap-staging");
+        let previous = temp_folder("swap-previous");
+        write(&config.join("books/old/old.json"), "old");
+        write(&config.join("settings.json"), "old");
+        write(&config.join("settings.json.bak"), "old backup");
+        write(&config.join("ReaderThemes.json"), "old");
+        write(&staging.join("books/new/new.json"), "new");
+        write(&staging.join("settings.json"), "new");
+
+        swap_in(&config, &staging, &previous).unwrap();
+        assert_eq!(read(&config.join("books/new/new.json")), "new");
+        assert_eq!(read(&config.join("settings.json")), "new");
+        // Files the backup does not have start out empty, and no backup copy of a replaced file is left behind
+        assert_eq!(read(&config.join("ReaderThemes.json")), "{}");
+        assert_eq!(read(&config.join("GlobalThemes.json")), "{}");
+        assert!(!config.join("settings.json.bak").exists());
+        assert_eq!(read(&previous.join("books/old/old.json")), "old");
+        assert_eq!(read(&previous.join("settings.json.bak")), "old backup");
+        assert!(!config.join("books/old").exists());
+    }
+
+    #[test]
+    fn rolls_back_a_failed_swap() {
+        // Rolling back drops every cached book
+        let _library = crate::exclusive_test_library();
+        let config = temp_folder("rollback-config");
+        let staging = temp_folder("rollback-staging");
+        let previous = temp_folder("rollback-previous");
+        write(&config.join("books/old/old.json"), "old");
+        write(&config.join("fonts/fonts.json"), "old");
+        write(&config.join("settings.json"), "old");
+        write(&config.join("GlobalThemes.json"), "old");
+        write(&config.join("ReaderThemes.json"), "old");
+        write(&config.join("ReaderThemes.json.bak"), "old backup");
+        write(&staging.join("books/new/new.json"), "new");
+        write(&staging.join("settings.json"), "new");
+        write(&staging.join("GlobalThemes.json"), "new");
+        write(&previous.join(INCOMPLETE_MARKER), "");
+        // The last file can not be moved aside
+        write(&previous.join("ReaderThemes.json.bak").join("blocking"), "");
+
+        assert!(swap_in(&config, &staging, &previous).is_err());
+        assert_eq!(read(&config.join("books/new/new.json")), "new");
+
+        roll_back(&config, &previous).unwrap();
+        assert!(!previous.exists());
+        assert!(!config.join("books/new").exists());
+        assert_eq!(read(&config.join("books/old/old.json")), "old");
+        assert_eq!(read(&config.join("fonts/fonts.json")), "old");
+        assert_eq!(read(&config.join("settings.json")), "old");
+        assert_eq!(read(&config.join("GlobalThemes.json")), "old");
+        assert_eq!(read(&config.join("ReaderThemes.json")), "old");
+        assert!(!config.join(INCOMPLETE_MARKER).exists());
+    }
+
+    #[test]
+    fn recovers_an_interrupted_restore() {
+        let _library = crate::exclusive_test_library();
+        let config = get_config_path();
+        let previous = config.join(PREVIOUS_FOLDER);
+        let hash = crate::test_book("recover", json!({"title": "Recover", "data": {"progress": 0.3}}));
+        assert_eq!(recover_restore(), Ok(false));
+
+        // Stopped while swapping, after the books were replaced
+        fs::create_dir_all(&previous).unwrap();
+        write(&previous.join(INCOMPLETE_MARKER), "");
+        fs::rename(config.join("books"), previous.join("books")).unwrap();
+        write(&config.join("books/partial/partial.json"), "{");
+        write(&previous.join("settings.json"), "{\"sortBy\": \"progress\"}");
+        write(&config.join("settings.json"), "{\"sortBy\": \"modified\"}");
+        assert_eq!(recover_restore(), Ok(true));
+        assert!(!previous.exists());
+        assert!(!config.join("books/partial").exists());
+        assert_eq!(book_state::get(&hash).unwrap().data.progress, 0.3);
+        assert_eq!(settings::load().sortBy, "progress");
+
+        // Stopped before the marker was removed is the same as an empty library
+        fs::create_dir_all(&previous).unwrap();
+        fs::rename(config.join("books"), previous.join("books")).unwrap();
+        fs::create_dir_all(config.join("books")).unwrap();
+        assert_eq!(recover_restore(), Ok(true));
+        assert!(config.join("books").join(&hash).exists());
+
+        // Only removing the replaced library was left to do
+        write(&previous.join("books/replaced/replaced.json"), "{}");
+        assert_eq!(recover_restore(), Ok(false));
+        assert!(!previous.exists());
+        assert!(config.join("books").join(&hash).exists());
+
+        fs::remove_file(config.join("settings.json")).unwrap();
+    }
+
+    #[test]
+    fn restores_and_merges_full_backups() {
+        let _library = crate::exclusive_test_library();
+        let config = get_config_path();
+        let hash = crate::test_book("full", json!({"title": "Full", "data": {"progress": 0.2, "highlights": {"a": {"color": "red"}}}}));
+        let path = temp_folder("full-backup").join("backup.zip");
+        let manifest = export_backup(path.display().to_string()).unwrap();
+        assert!(manifest.books.iter().any(|book| book.hash == hash && book.title == "Full"));
+        assert!(import_data_backup(path.display().to_string()).is_err());
+
+        let added = crate::test_book("added", json!({"title": "Added", "data": {"progress": 0.0}}));
+        add_highlight(&hash, "b");
+        let report = import_backup(path.display().to_string(), Some("restore".to_string())).unwrap();
+        assert!(report.books_added >= 1);
+        assert!(!config.join("books").join(&added).exists());
+        assert_eq!(highlights(&hash), vec!["a".to_string()]);
+        assert!(!config.join(STAGING_FOLDER).exists() && !config.join(PREVIOUS_FOLDER).exists());
+
+        // Merging adds the missing book and combines the data of the one that is present
+        fs::remove_dir_all(config.join("books").join(&hash)).unwrap();
+        book_state::evict(&hash);
+        let report = import_backup(path.display().to_string(), Some("merge".to_string())).unwrap();
+        assert_eq!(report.books_added, 1);
+        assert_eq!(highlights(&hash), vec!["a".to_string()]);
+        assert!(!config.join(MERGE_FOLDER).exists());
+
+        add_highlight(&hash, "c");
+        let report = import_backup(path.display().to_string(), Some("merge".to_string())).unwrap();
+        assert_eq!(report.books_added, 0);
+        assert!(report.books_merged >= 1);
+        assert_eq!(highlights(&hash), vec!["a".to_string(), "c".to_string()]);
+
+        assert!(import_backup(path.display().to_string(), Some("replace".to_string())).is_err());
+        for name in STATE_FILES {
+            fs::remove_file(config.join(name)).ok();
+            fs::remove_file(storage::backup_path(&config.join(name))).ok();
+        }
+    }
+
+    #[test]
+    fn round_trips_reading_data() {
+        let _library = crate::exclusive_test_library();
+        let config = get_config_path();
+        let hash = crate::test_book("data", json!({"title": "Data", "data": {"progress": 0.4, "highlights": {"a": {"color": "red"}}}}));
+        storage::write_json(&config.join("settings.json"), &json!({"sortBy": "progress"})).unwrap();
+        let path = temp_folder("data-backup").join("data.zip");
+        let manifest = export_data_backup(path.display().to_string()).unwrap();
+        assert_eq!(manifest.kind, "data");
+        assert!(import_backup(path.display().to_string(), None).is_err());
+
+        add_highlight(&hash, "b");
+        storage::write_json(&config.join("settings.json"), &json!({"readerMargins": 50})).unwrap();
+        let report = import_data_backup(path.display().to_string()).unwrap();
+        assert!(report.books_updated >= 1);
+        assert!(report.books_skipped.is_empty());
+        assert_eq!(highlights(&hash), vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(book_state::get(&hash).unwrap().data.progress, 0.4);
+        let settings = settings::load();
+        assert_eq!((settings.sortBy.as_str(), settings.readerMargins), ("progress", 50));
+
+        fs::remove_file(config.join("settings.json")).unwrap();
+        fs::remove_file(storage::backup_path(&config.join("settings.json"))).ok();
+    }
+
+    #[test]
+    fn skips_books_missing_from_a_data_backup() {
+        let _library = crate::shared_test_library();
+        let present = crate::test_book("skipped", json!({"title": "Skipped", "data": {"progress": 0.0}}));
+        let books = vec![
+            BackupBook {
+                hash: present.clone(),
+                title: "Skipped".to_string(),
+                author: String::new(),
+            },
+            BackupBook {
+                hash: "not-in-library".to_string(),
+                title: String::new(),
+                author: String::new(),
+            },
+        ];
+        let path = temp_folder("data-partial").join("data.zip");
+        write_archive(&path, |zip| {
+            add_json(zip, MANIFEST_FILE, &new_manifest("data", books))?;
+            return Ok(());
+        })
+        .unwrap();
+
+        let report = import_data_backup(path.display().to_string()).unwrap();
+        assert_eq!(report.books_updated, 0);
+        assert_eq!(report.books_missing, vec!["not-in-library".to_string()]);
+        assert_eq!(report.books_skipped.len(), 1);
+        assert!(report.books_skipped[0].starts_with("Skipped : Error: Backup is missing"));
+    }
+}
@@ -0,0 +1,584 @@
+// The backend owns the per book data so several reader views can save the same book safely.
+// Every write bumps a revision number. A view that saves with an older revision than the
+// current one is merged against the snapshot it started from instead of overwriting
+// highlights made elsewhere. Changes are broadcast with the "book-data-changed" event.
+// Reading progress changes with every page turn, it only touches the cached data and is written
+// to disk by a single writer thread once the book has been quiet for WRITE_DELAY. Everything
+// else, highlights and bookmarks included, is written right away.
+// Each book has its own lock, so reading an EPUB to fill in a highlight or writing one book's
+// files never holds up changes to another book.
+// At most MAX_CACHED_BOOKS are kept, the least recently used books nobody is working on are dropped.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tauri::Manager;
+
+use crate::{annotations, history, merge};
+use crate::{app_handle, bookmarkData, current_time_millis, highlightData, read_book_data, themePayload, updateBookPayload, write_book_data};
+
+// Snapshots kept per book to merge writes from views that are behind
+const MAX_SNAPSHOTS: usize = 32;
+
+// Books kept in memory, listing the library reads every book
+const MAX_CACHED_BOOKS: usize = 64;
+
+// How long a book has to go without changes before deferred writes reach the disk
+const WRITE_DELAY: Duration = Duration::from_millis(1500);
+
+pub const BOOK_DATA_CHANGED: &str = "book-data-changed";
+
+struct BookState {
+    payload: updateBookPayload,
+    snapshots: VecDeque<(u64, updateBookPayload)>,
+    // Set while the cached payload is newer than the file on disk
+    dirty: bool,
+}
+
+#[derive(Default)]
+struct Slot {
+    // None until the book's data is read from disk
+    state: Option<BookState>,
+    // Set when the book's files are replaced, changes that were waiting for the slot fail
+    evicted: bool,
+}
+
+type BookSlot = Arc<Mutex<Slot>>;
+
+struct CachedSlot {
+    slot: BookSlot,
+    // Value of `uses` when the slot was last looked up
+    used: u64,
+}
+
+#[derive(Default)]
+struct BookCache {
+    slots: HashMap<String, CachedSlot>,
+    uses: u64,
+}
+
+// Only held to look up a book's slot
+static book_states: OnceLock<Mutex<BookCache>> = OnceLock::new();
+
+// Books with deferred changes are sent to the writer thread, started by the first one
+static deferred_writes: OnceLock<Mutex<Sender<String>>> = OnceLock::new();
+
+#[derive(Serialize, Clone)]
+struct BookDataChanged {
+    hash: String,
+    revision: u64,
+    // Identifier the writing view passed in, so it can ignore its own changes
+    source: Option<String>,
+}
+
+fn states() -> &'static Mutex<BookCache> {
+    return book_states.get_or_init(|| Mutex::new(BookCache::default()));
+}
+
+fn slot(hash: &str) -> Result<BookSlot, String> {
+    let mut cache = states().lock().map_err(|_| "Error: Book state is poisoned".to_string())?;
+    cache.uses += 1;
+    let uses = cache.uses;
+    let cached = cache.slots.entry(hash.to_string()).or_insert_with(|| CachedSlot {
+        slot: BookSlot::default(),
+        used: 0,
+    });
+    cached.used = uses;
+    let slot = cached.slot.clone();
+    trim(&mut cache);
+    return Ok(slot);
+}
+
+// Drops the least recently used books until at most MAX_CACHED_BOOKS are left
+// Slots are only cloned while the cache is locked, so a slot only the cache holds is not in use
+// and no second slot can be made for a book while another thread still works on the first one.
+// Books with deferred changes stay until they are written.
+fn trim(cache: &mut BookCache) {
+    if cache.slots.len() <= MAX_CACHED_BOOKS {
+        return;
+    }
+    let mut unused: Vec<(u64, String)> = cache
+        .slots
+        .iter()
+        .filter(|(_, cached)| Arc::strong_count(&cached.slot) == 1)
+        .filter(|(_, cached)| match cached.slot.try_lock() {
+            Ok(slot) => !slot.state.as_ref().map(|state| state.dirty).unwrap_or(false),
+            Err(_) => false,
+        })
+        .map(|(hash, cached)| (cached.used, hash.clone()))
+        .collect();
+    unused.sort();
+    let excess = cache.slots.len() - MAX_CACHED_BOOKS;
+    for (_, hash) in unused.into_iter().take(excess) {
+        cache.slots.remove(&hash);
+    }
+}
+
+fn lock_slot(slot: &BookSlot) -> Result<MutexGuard<'_, Slot>, String> {
+    let guard = slot.lock().map_err(|_| "Error: Book state is poisoned".to_string())?;
+    if guard.evicted {
+        return Err("Error: The book's data was replaced, reload the book".to_string());
+    }
+    return Ok(guard);
+}
+
+// Every slot, so they can be locked one at a time without holding the map
+fn all_slots() -> Vec<(String, BookSlot)> {
+    return match states().lock() {
+        Ok(cache) => cache.slots.iter().map(|(hash, cached)| (hash.clone(), cached.slot.clone())).collect(),
+        Err(_) => Vec::new(),
+    };
+}
+
+fn current_revision(payload: &updateBookPayload) -> u64 {
+    return payload.revision.unwrap_or(0);
+}
+
+fn load_state<'a>(slot: &'a mut Slot, hash: &str) -> Result<&'a mut BookState, String> {
+    let slot = &mut slot.state;
+    if slot.is_none() {
+        let mut payload = read_book_data(hash)?;
+        // Views send back the revision they loaded, files written before revisions start at 0
+        payload.revision = Some(current_revision(&payload));
+        let mut snapshots = VecDeque::new();
+        snapshots.push_back((current_revision(&payload), payload.clone()));
+        *slot = Some(BookState {
+            payload,
+            snapshots,
+            dirty: false,
+        });
+    }
+    return Ok(slot.as_mut().unwrap());
+}
+
+// Current data of a book, read from disk the first time
+pub fn get(hash: &str) -> Result<updateBookPayload, String> {
+    let slot = slot(hash)?;
+    let mut guard = lock_slot(&slot)?;
+    let state = load_state(&mut guard, hash)?;
+    return Ok(state.payload.clone());
+}
+
+// Write out every book with deferred changes, called before the app exits
+pub fn flush_all() {
+    for (hash, slot) in all_slots() {
+        let mut guard = match lock_slot(&slot) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+        if let Some(state) = guard.state.as_mut() {
+            if state.dirty {
+                match write_book_data(&hash, &state.payload) {
+                    Ok(_) => state.dirty = false,
+                    Err(error) => println!("{}", error),
+                }
+            }
+        }
+    }
+}
+
+// Books with deferred changes are never dropped from the cache, so a book that is not cached
+// has nothing to write
+fn write_if_dirty(hash: &str) {
+    let slot = match states().lock() {
+        Ok(cache) => match cache.slots.get(hash) {
+            Some(cached) => cached.slot.clone(),
+            None => return,
+        },
+        Err(_) => return,
+    };
+    let mut guard = match lock_slot(&slot) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    if let Some(state) = guard.state.as_mut() {
+        if state.dirty {
+            match write_book_data(hash, &state.payload) {
+                Ok(_) => state.dirty = false,
+                Err(error) => println!("{}", error),
+            }
+        }
+    }
+}
+
+// Each book is written once no change arrived for it in WRITE_DELAY
+fn run_writer(receiver: mpsc::Receiver<String>) {
+    let mut due: HashMap<String, Instant> = HashMap::new();
+    loop {
+        let received = match due.values().min() {
+            Some(next) => receiver.recv_timeout(next.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(hash) => {
+                due.insert(hash, Instant::now() + WRITE_DELAY);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        let ready: Vec<String> = due.iter().filter(|(_, at)| **at <= now).map(|(hash, _)| hash.clone()).collect();
+        for hash in ready {
+            due.remove(&hash);
+            write_if_dirty(&hash);
+        }
+    }
+}
+
+fn schedule_write(hash: &str) {
+    let sender = deferred_writes.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run_writer(receiver));
+        return Mutex::new(sender);
+    });
+    let sent = sender.lock().map(|sender| sender.send(hash.to_string()).is_ok()).unwrap_or(false);
+    if !sent {
+        write_if_dirty(hash);
+    }
+}
+
+// Marks the slot so threads that looked it up before the eviction can not write old data
+fn invalidate(slot: BookSlot) {
+    let mut guard = match slot.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    guard.evicted = true;
+    guard.state = None;
+}
+
+// Forget every cached book, used when the whole library is replaced
+pub fn evict_all() {
+    let slots: Vec<BookSlot> = match states().lock() {
+        Ok(mut cache) => cache.slots.drain().map(|(_, cached)| cached.slot).collect(),
+        Err(_) => return,
+    };
+    for slot in slots {
+        invalidate(slot);
+    }
+}
+
+// Forget the cached data of a book, used when its files are replaced or removed
+pub fn evict(hash: &str) {
+    let slot = match states().lock() {
+        Ok(mut cache) => cache.slots.remove(hash),
+        Err(_) => return,
+    };
+    if let Some(cached) = slot {
+        invalidate(cached.slot);
+    }
+}
+
+// Apply a change to a book's data, persist it and notify every window
+// The closure receives the data to change and the data as it was before the change
+pub fn update<F>(hash: &str, source: Option<String>, change: F) -> Result<updateBookPayload, String>
+where
+    F: FnOnce(&mut updateBookPayload, &updateBookPayload) -> Result<(), String>,
+{
+    return update_with_snapshots(hash, source, false, |payload, previous, _snapshots| change(payload, previous), |_, _, _| Ok(()));
+}
+
+// Same as `update`, but the disk write is delayed and merged with later changes
+// Only for changes that are cheap to lose, such as the reading position
+pub fn update_deferred<F>(hash: &str, source: Option<String>, change: F) -> Result<updateBookPayload, String>
+where
+    F: FnOnce(&mut updateBookPayload, &updateBookPayload) -> Result<(), String>,
+{
+    return update_with_snapshots(hash, source, true, |payload, previous, _snapshots| change(payload, previous), |_, _, _| Ok(()));
+}
+
+// Same as `update`, `after` runs once the data is on disk and before the book is unlocked
+// It receives the data before and after the change and the value the change returned
+// Its errors are printed, the change is already saved
+pub fn update_then<T, F, A>(hash: &str, source: Option<String>, change: F, after: A) -> Result<updateBookPayload, String>
+where
+    F: FnOnce(&mut updateBookPayload, &updateBookPayload) -> Result<T, String>,
+    A: FnOnce(&updateBookPayload, &updateBookPayload, T) -> Result<(), String>,
+{
+    return update_with_snapshots(hash, source, false, |payload, previous, _snapshots| change(payload, previous), after);
+}
+
+// Same as `update`, and the highlight changes are added to the book's history once saved
+pub fn update_journaled<F>(hash: &str, source: Option<String>, change: F) -> Result<updateBookPayload, String>
+where
+    F: FnOnce(&mut updateBookPayload, &updateBookPayload) -> Result<(), String>,
+{
+    return update_then(hash, source, change, |previous, payload, _| history::record_changes(hash, Some(previous), payload));
+}
+
+fn update_with_snapshots<T, F, A>(hash: &str, source: Option<String>, defer: bool, change: F, after: A) -> Result<updateBookPayload, String>
+where
+    F: FnOnce(&mut updateBookPayload, &updateBookPayload, &VecDeque<(u64, updateBookPayload)>) -> Result<T, String>,
+    A: FnOnce(&updateBookPayload, &updateBookPayload, T) -> Result<(), String>,
+{
+    // Changes to this book wait for each other, other books are not held up
+    let slot = slot(hash)?;
+    let mut guard = lock_slot(&slot)?;
+    let state = load_state(&mut guard, hash)?;
+
+    let previous = state.payload.clone();
+    let mut payload = state.payload.clone();
+    let changed = change(&mut payload, &previous, &state.snapshots)?;
+    merge::record_tombstones(&previous, &mut payload, current_time_millis());
+
+    let revision = current_revision(&previous) + 1;
+    payload.revision = Some(revision);
+    if defer {
+        state.dirty = true;
+    } else {
+        write_book_data(hash, &payload)?;
+        state.dirty = false;
+    }
+
+    state.payload = payload.clone();
+    state.snapshots.push_back((revision, payload.clone()));
+    while state.snapshots.len() > MAX_SNAPSHOTS {
+        state.snapshots.pop_front();
+    }
+    if let Err(error) = after(&previous, &payload, changed) {
+        println!("{}", error);
+    }
+    drop(guard);
+    if defer {
+        schedule_write(hash);
+    }
+
+    if let Some(handle) = app_handle.get() {
+        let event = BookDataChanged {
+            hash: hash.to_string(),
+            revision,
+            source,
+        };
+        if let Err(error) = handle.emit_all(BOOK_DATA_CHANGED, event) {
+            println!("Error: Could not broadcast book change: {:?}", error);
+        }
+    }
+    return Ok(payload);
+}
+
+// Save a full payload from a reader view
+// Writes based on the current revision, or without any revision, replace the data as before
+pub fn save(hash: &str, incoming: updateBookPayload, source: Option<String>) -> Result<updateBookPayload, String> {
+    let journal = |previous: &updateBookPayload, payload: &updateBookPayload, _| history::record_changes(hash, Some(previous), payload);
+    return update_with_snapshots(hash, source, false, move |payload, previous, snapshots| {
+        match incoming.revision {
+            // Stale, keep what other views changed since this one loaded
+            Some(revision) if revision < current_revision(previous) => {
+                let base = snapshots
+                    .iter()
+                    .find(|(snapshot_revision, _)| *snapshot_revision == revision)
+                    .map(|(_, snapshot)| snapshot);
+                *payload = merge::merge_book(base, previous, &incoming);
+            }
+            // Up to date, the view's payload is the new state
+            _ => {
+                *payload = incoming;
+            }
+        }
+
+        carry_unknown_fields(payload, previous);
+        // The reader only tracks color and note, keep what we already know about each annotation
+        annotations::fill_highlight_metadata(hash, payload, Some(previous));
+        annotations::fill_bookmark_metadata(hash, payload, Some(previous));
+        return Ok(());
+    }, journal);
+}
+
+fn carry_extra(extra: &mut HashMap<String, serde_json::Value>, previous: &HashMap<String, serde_json::Value>) {
+    for (key, value) in previous.iter() {
+        extra.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+}
+
+// Views only send the fields they know about, keep the rest from the saved data
+fn carry_unknown_fields(payload: &mut updateBookPayload, previous: &updateBookPayload) {
+    carry_extra(&mut payload.extra, &previous.extra);
+    carry_extra(&mut payload.data.extra, &previous.data.extra);
+    carry_extra(&mut payload.data.theme.extra, &previous.data.theme.extra);
+}
+
+// Partial updates, each patches a single part of the book data instead of the whole payload
+// They return the new revision like `update_data_by_hash`
+
+#[tauri::command]
+pub fn set_progress(hash: String, progress: f64, cfi: String, source: Option<String>) -> Result<u64, String> {
+    let payload = update_deferred(&hash, source, |payload, _previous| {
+        payload.data.progress = progress;
+        payload.data.cfi = cfi;
+        // The library sorts by it and sync compares it
+        payload.modified = current_time_millis();
+        return Ok(());
+    })?;
+    return Ok(current_revision(&payload));
+}
+
+// Adds a highlight or changes its color and note, text, chapter and timestamps are kept or filled in
+#[tauri::command]
+pub fn upsert_highlight(hash: String, cfi: String, highlight: highlightData, source: Option<String>) -> Result<u64, String> {
+    let payload = update_journaled(&hash, source, |payload, previous| {
+        let mut highlight = highlight;
+        // Recomputed from the previous version
+        highlight.modified = 0;
+        payload.data.highlights.insert(cfi, highlight);
+        annotations::fill_highlight_metadata(&hash, payload, Some(previous));
+        return Ok(());
+    })?;
+    return Ok(current_revision(&payload));
+}
+
+#[tauri::command]
+pub fn delete_highlight(hash: String, cfi: String, source: Option<String>) -> Result<u64, String> {
+    let payload = update_journaled(&hash, source, |payload, _previous| {
+        if payload.data.highlights.remove(&cfi).is_none() {
+            return Err(format!("Error: Highlight not found - {}", cfi));
+        }
+        return Ok(());
+    })?;
+    return Ok(current_revision(&payload));
+}
+
+// Removes the bookmark at `cfi` or adds one with its excerpt and chapter filled in
+#[tauri::command]
+pub fn toggle_bookmark(hash: String, cfi: String, source: Option<String>) -> Result<u64, String> {
+    let payload = update(&hash, source, |payload, previous| {
+        match payload.data.bookmarks.iter().position(|bookmark| bookmark.cfi == cfi) {
+            Some(index) => {
+                payload.data.bookmarks.remove(index);
+            }
+            None => {
+                payload.data.bookmarks.push(bookmarkData {
+                    cfi,
+                    ..Default::default()
+                });
+                annotations::fill_bookmark_metadata(&hash, payload, Some(previous));
+            }
+        }
+        return Ok(());
+    })?;
+    return Ok(current_revision(&payload));
+}
+
+#[tauri::command]
+pub fn set_book_theme(hash: String, theme: themePayload, source: Option<String>) -> Result<u64, String> {
+    let payload = update(&hash, source, |payload, _previous| {
+        let mut theme = theme;
+        carry_extra(&mut theme.extra, &payload.data.theme.extra);
+        payload.data.theme = theme;
+        return Ok(());
+    })?;
+    return Ok(current_revision(&payload));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn book() -> String {
+        return crate::test_book("state", json!({"title": "State", "data": {"progress": 0.0, "cfi": "epubcfi(/6/2!/4)"}}));
+    }
+
+    fn with_highlight(payload: &updateBookPayload, cfi: &str, color: &str) -> updateBookPayload {
+        let mut payload = payload.clone();
+        payload.data.highlights.insert(
+            cfi.to_string(),
+            highlightData {
+                color: color.to_string(),
+                ..Default::default()
+            },
+        );
+        return payload;
+    }
+
+    fn is_cached(hash: &str) -> bool {
+        return states().lock().unwrap().slots.contains_key(hash);
+    }
+
+    #[test]
+    fn stale_saves_merge() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        let loaded = get(&hash).unwrap();
+        assert_eq!(loaded.revision, Some(0));
+
+        // Two views loaded revision 0, the first one saves a highlight
+        let first = save(&hash, with_highlight(&loaded, "a", "yellow"), Some("view-1".to_string())).unwrap();
+        assert_eq!(first.revision, Some(1));
+
+        // The second one is behind, its highlight is merged with the first one's
+        let second = save(&hash, with_highlight(&loaded, "b", "red"), Some("view-2".to_string())).unwrap();
+        assert_eq!(second.revision, Some(2));
+        assert_eq!(second.data.highlights["a"].color, "yellow");
+        assert_eq!(second.data.highlights["b"].color, "red");
+
+        let saved = crate::read_book_data(&hash).unwrap();
+        assert_eq!(saved.revision, Some(2));
+        assert_eq!(saved.data.highlights.len(), 2);
+
+        // A view that is up to date replaces the data, removing a highlight
+        let mut current = second.clone();
+        current.data.highlights.remove("a");
+        let third = save(&hash, current, None).unwrap();
+        assert!(!third.data.highlights.contains_key("a"));
+        assert!(third.data.deletedHighlights.contains_key("a"));
+    }
+
+    #[test]
+    fn evicted_slots_refuse_late_writes() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        get(&hash).unwrap();
+        let held = slot(&hash).unwrap();
+
+        // The book's files are replaced while another thread still holds its slot
+        let mut replaced = crate::read_book_data(&hash).unwrap();
+        replaced.data.progress = 0.5;
+        crate::write_book_data(&hash, &replaced).unwrap();
+        evict(&hash);
+        assert!(lock_slot(&held).is_err());
+
+        // New changes start from the replaced data
+        let payload = update(&hash, None, |payload, _previous| {
+            payload.title = "Changed".to_string();
+            return Ok(());
+        })
+        .unwrap();
+        assert_eq!(payload.data.progress, 0.5);
+        assert_eq!(crate::read_book_data(&hash).unwrap().title, "Changed");
+    }
+
+    #[test]
+    fn keeps_a_bounded_cache() {
+        let _library = crate::shared_test_library();
+        let hashes: Vec<String> = (0..MAX_CACHED_BOOKS + 10).map(|_| book()).collect();
+        for hash in hashes.iter() {
+            get(hash).unwrap();
+        }
+        assert!(!is_cached(&hashes[0]));
+        assert!(is_cached(hashes.last().unwrap()));
+        // Dropped books are read from disk again
+        assert_eq!(get(&hashes[0]).unwrap().title, "State");
+    }
+
+    #[test]
+    fn keeps_deferred_changes_while_trimming() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        set_progress(hash.clone(), 0.75, "epubcfi(/6/4!/4)".to_string(), None).unwrap();
+        for _ in 0..MAX_CACHED_BOOKS + 5 {
+            get(&book()).unwrap();
+        }
+        let payload = get(&hash).unwrap();
+        assert_eq!(payload.data.progress, 0.75);
+        assert_eq!(payload.data.cfi, "epubcfi(/6/4!/4)");
+    }
+}
@@ -0,0 +1,335 @@
+// Import of a Calibre library
+// Reads metadata.db and the book folders next to it, imports one file of each book through
+// import_book and keeps Calibre's title, authors, series, tags, rating and cover in the book data.
+// Series and tags also become the book's collections. A dry run only reports what would happen.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{book_state, get_config_path, get_hash, import_book};
+
+// Formats Alexandria can open, best first
+const DEFAULT_FORMATS: [&str; 13] = ["EPUB", "AZW3", "MOBI", "AZW", "PRC", "PDB", "FB2", "FBZ", "CBZ", "CBR", "CB7", "CBT", "TXT"];
+
+#[derive(Default)]
+struct CalibreBook {
+    id: i64,
+    title: String,
+    authors: Vec<String>,
+    // Folder of the book relative to the library
+    path: String,
+    has_cover: bool,
+    series: Option<String>,
+    series_index: f64,
+    tags: Vec<String>,
+    // Calibre stores 0 to 10, in half stars
+    rating: Option<i64>,
+    // Format and file name without extension
+    formats: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CalibreBookReport {
+    calibre_id: i64,
+    title: String,
+    authors: String,
+    format: String,
+    hash: String,
+    reason: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CalibreReport {
+    dry_run: bool,
+    total: usize,
+    // Books that would be imported on a dry run
+    imported: Vec<CalibreBookReport>,
+    skipped: Vec<CalibreBookReport>,
+    failed: Vec<CalibreBookReport>,
+}
+
+fn linked_names(connection: &Connection, sql: &str, book: i64) -> rusqlite::Result<Vec<String>> {
+    let mut statement = connection.prepare_cached(sql)?;
+    let names = statement.query_map([book], |row| row.get::<_, String>(0))?;
+    return names.collect();
+}
+
+fn read_books(connection: &Connection) -> rusqlite::Result<Vec<CalibreBook>> {
+    let mut statement = connection.prepare("SELECT id, title, path, has_cover, series_index FROM books ORDER BY id")?;
+    let mut books = statement
+        .query_map([], |row| {
+            return Ok(CalibreBook {
+                id: row.get(0)?,
+                title: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                path: row.get(2)?,
+                has_cover: row.get::<_, Option<bool>>(3)?.unwrap_or(false),
+                series_index: row.get::<_, Option<f64>>(4)?.unwrap_or(1.0),
+                ..Default::default()
+            });
+        })?
+        .collect::<rusqlite::Result<Vec<CalibreBook>>>()?;
+
+    for book in books.iter_mut() {
+        book.authors = linked_names(
+            connection,
+            "SELECT authors.name FROM authors JOIN books_authors_link ON authors.id = books_authors_link.author
+             WHERE books_authors_link.book = ?1 ORDER BY books_authors_link.id",
+            book.id,
+        )?;
+        book.series = linked_names(
+            connection,
+            "SELECT series.name FROM series JOIN books_series_link ON series.id = books_series_link.series
+             WHERE books_series_link.book = ?1",
+            book.id,
+        )?
+        .into_iter()
+        .next();
+        book.tags = linked_names(
+            connection,
+            "SELECT tags.name FROM tags JOIN books_tags_link ON tags.id = books_tags_link.tag
+             WHERE books_tags_link.book = ?1 ORDER BY tags.name",
+            book.id,
+        )?;
+        book.rating = connection
+            .query_row(
+                "SELECT ratings.rating FROM ratings JOIN books_ratings_link ON ratings.id = books_ratings_link.rating
+                 WHERE books_ratings_link.book = ?1",
+                [book.id],
+                |row| row.get::<_, Option<i64>>(0),
+            )
+            .optional()?
+            .flatten()
+            .filter(|rating| *rating > 0);
+
+        let mut formats = connection.prepare_cached("SELECT format, name FROM data WHERE book = ?1")?;
+        book.formats = formats
+            .query_map([book.id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+    }
+    return Ok(books);
+}
+
+// First format in the order of preference that the book has, with its file
+fn preferred_file(library: &Path, book: &CalibreBook, preference: &[String]) -> Option<(String, PathBuf)> {
+    for wanted in preference {
+        if let Some((format, name)) = book.formats.iter().find(|(format, _)| format.eq_ignore_ascii_case(wanted)) {
+            let file = library.join(&book.path).join(format!("{}.{}", name, format.to_lowercase()));
+            return Some((format.to_uppercase(), file));
+        }
+    }
+    return None;
+}
+
+fn apply_metadata(book: &CalibreBook, hash: &str, library: &Path) -> Result<(), String> {
+    let mut collections: Vec<String> = book.series.iter().cloned().collect();
+    collections.extend(book.tags.iter().filter(|tag| !collections.contains(tag)).cloned().collect::<Vec<String>>());
+
+    book_state::update(hash, None, |payload, _previous| {
+        if book.title.len() > 0 {
+            payload.title = book.title.clone();
+        }
+        if book.authors.len() > 0 {
+            payload.author = book.authors.join(" & ");
+        }
+        if let Some(series) = &book.series {
+            payload.extra.insert("series".to_string(), Value::from(series.clone()));
+            payload.extra.insert("seriesIndex".to_string(), Value::from(book.series_index));
+        }
+        if book.tags.len() > 0 {
+            payload.extra.insert("tags".to_string(), Value::from(book.tags.clone()));
+        }
+        if let Some(rating) = book.rating {
+            // Stars out of 5
+            payload.extra.insert("rating".to_string(), Value::from(rating as f64 / 2.0));
+        }
+        if collections.len() > 0 {
+            payload.extra.insert("collections".to_string(), Value::from(collections.clone()));
+        }
+        return Ok(());
+    })?;
+
+    // Calibre's cover is the one the user picked, it replaces the one found in the file
+    let cover = library.join(&book.path).join("cover.jpg");
+    if book.has_cover && cover.exists() {
+        let target = get_config_path().join("books").join(hash).join("cover.jpg");
+        fs::copy(&cover, &target).map_err(|e| format!("Error: Could not copy cover of {} : {}", book.title, e))?;
+    }
+    return Ok(());
+}
+
+fn import_library(library: &Path, dry_run: bool, preference: &[String]) -> Result<CalibreReport, String> {
+    let database = library.join("metadata.db");
+    if !database.exists() {
+        return Err(format!("Error: \"{}\" is not a Calibre library, metadata.db is missing", library.display()));
+    }
+    // Read only, Calibre may have the library open
+    let connection = Connection::open_with_flags(&database, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        .map_err(|e| format!("Error: Could not open \"{}\" : {}", database.display(), e))?;
+    let books = read_books(&connection).map_err(|e| format!("Malformed Data: Could not read Calibre library : {}", e))?;
+
+    let mut report = CalibreReport {
+        dry_run,
+        total: books.len(),
+        ..Default::default()
+    };
+    for book in books.iter() {
+        let mut entry = CalibreBookReport {
+            calibre_id: book.id,
+            title: book.title.clone(),
+            authors: book.authors.join(" & "),
+            ..Default::default()
+        };
+        let (format, file) = match preferred_file(library, book, preference) {
+            Some(preferred) => preferred,
+            None => {
+                let formats: Vec<&str> = book.formats.iter().map(|(format, _)| format.as_str()).collect();
+                entry.reason = format!("No supported format, has {:?}", formats);
+                report.skipped.push(entry);
+                continue;
+            }
+        };
+        entry.format = format;
+
+        let contents = match fs::read(&file) {
+            Ok(contents) => contents,
+            Err(error) => {
+                entry.reason = format!("Could not read \"{}\" : {}", file.display(), error);
+                report.failed.push(entry);
+                continue;
+            }
+        };
+        entry.hash = get_hash(&contents);
+        drop(contents);
+        if get_config_path().join("books").join(&entry.hash).exists() {
+            entry.reason = "Already in the library".to_string();
+            report.skipped.push(entry);
+            continue;
+        }
+        if dry_run {
+            report.imported.push(entry);
+            continue;
+        }
+
+        let imported = import_book(file.display().to_string()).and_then(|hydrate| apply_metadata(book, &hydrate.hash, library));
+        match imported {
+            Ok(_) => report.imported.push(entry),
+            Err(error) => {
+                entry.reason = error;
+                report.failed.push(entry);
+            }
+        }
+    }
+    println!(
+        "Calibre import of \"{}\" : {} imported, {} skipped, {} failed{}",
+        library.display(),
+        report.imported.len(),
+        report.skipped.len(),
+        report.failed.len(),
+        if dry_run { " (dry run)" } else { "" }
+    );
+    return Ok(report);
+}
+
+// `path` is the library folder that holds metadata.db
+// `formats` overrides the order in which formats are preferred, e.g. ["AZW3", "EPUB"]
+#[tauri::command]
+pub async fn import_calibre_library(path: String, dry_run: bool, formats: Option<Vec<String>>) -> Result<CalibreReport, String> {
+    let preference = formats.unwrap_or(DEFAULT_FORMATS.iter().map(|format| format.to_string()).collect());
+    return tokio::task::spawn_blocking(move || import_library(Path::new(&path), dry_run, &preference))
+        .await
+        .map_err(|e| format!("Error: Calibre import failed : {}", e))?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The parts of Calibre's schema that read_books uses
+    fn library() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT NOT NULL, has_cover BOOL, series_index REAL);
+                 CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT);
+                 CREATE TABLE books_authors_link (id INTEGER PRIMARY KEY, book INTEGER, author INTEGER);
+                 CREATE TABLE series (id INTEGER PRIMARY KEY, name TEXT);
+                 CREATE TABLE books_series_link (id INTEGER PRIMARY KEY, book INTEGER, series INTEGER);
+                 CREATE TABLE tags (id INTEGER PRIMARY KEY, name TEXT);
+                 CREATE TABLE books_tags_link (id INTEGER PRIMARY KEY, book INTEGER, tag INTEGER);
+                 CREATE TABLE ratings (id INTEGER PRIMARY KEY, rating INTEGER);
+                 CREATE TABLE books_ratings_link (id INTEGER PRIMARY KEY, book INTEGER, rating INTEGER);
+                 CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, name TEXT);
+
+                 INSERT INTO books VALUES (1, 'The Book', 'Second Author/The Book (1)', 1, 2.0);
+                 INSERT INTO books VALUES (2, 'Plain', 'Someone/Plain (2)', 0, NULL);
+                 INSERT INTO authors VALUES (1, 'First Author'), (2, 'Second Author'), (3, 'Someone');
+                 INSERT INTO books_authors_link VALUES (1, 1, 2), (2, 1, 1), (3, 2, 3);
+                 INSERT INTO series VALUES (1, 'Saga');
+                 INSERT INTO books_series_link VALUES (1, 1, 1);
+                 INSERT INTO tags VALUES (1, 'Fantasy'), (2, 'Adventure');
+                 INSERT INTO books_tags_link VALUES (1, 1, 1), (2, 1, 2);
+                 INSERT INTO ratings VALUES (1, 8), (2, 0);
+                 INSERT INTO books_ratings_link VALUES (1, 1, 1), (2, 2, 2);
+                 INSERT INTO data VALUES (1, 1, 'MOBI', 'The Book - Second Author'), (2, 1, 'EPUB', 'The Book - Second Author');
+                 INSERT INTO data VALUES (3, 2, 'PDF', 'Plain - Someone');",
+            )
+            .unwrap();
+        return connection;
+    }
+
+    fn preference(formats: &[&str]) -> Vec<String> {
+        return formats.iter().map(|format| format.to_string()).collect();
+    }
+
+    #[test]
+    fn reads_books_with_their_metadata() {
+        let books = read_books(&library()).unwrap();
+        assert_eq!(books.len(), 2);
+
+        let book = &books[0];
+        assert_eq!(book.title, "The Book");
+        // Authors keep Calibre's order, not the alphabetical one
+        assert_eq!(book.authors, vec!["Second Author", "First Author"]);
+        assert_eq!(book.series.as_deref(), Some("Saga"));
+        assert_eq!(book.series_index, 2.0);
+        assert_eq!(book.tags, vec!["Adventure", "Fantasy"]);
+        assert_eq!(book.rating, Some(8));
+        assert!(book.has_cover);
+        assert_eq!(book.formats.len(), 2);
+
+        let plain = &books[1];
+        assert_eq!(plain.series, None);
+        assert_eq!(plain.series_index, 1.0);
+        // Calibre stores "no rating" as 0
+        assert_eq!(plain.rating, None);
+    }
+
+    #[test]
+    fn picks_the_preferred_format() {
+        let books = read_books(&library()).unwrap();
+        let library = Path::new("/library");
+
+        let (format, file) = preferred_file(library, &books[0], &preference(&DEFAULT_FORMATS)).unwrap();
+        assert_eq!(format, "EPUB");
+        assert_eq!(file, library.join("Second Author/The Book (1)").join("The Book - Second Author.epub"));
+
+        let (format, _) = preferred_file(library, &books[0], &preference(&["mobi", "epub"])).unwrap();
+        assert_eq!(format, "MOBI");
+        assert!(preferred_file(library, &books[1], &preference(&DEFAULT_FORMATS)).is_none());
+    }
+
+    #[test]
+    fn refuses_folders_without_a_library() {
+        let folder = std::env::temp_dir().join(format!("alexandria-calibre-{}", crate::current_time_millis()));
+        fs::create_dir_all(&folder).unwrap();
+        let error = import_library(&folder, true, &preference(&DEFAULT_FORMATS)).unwrap_err();
+        assert!(error.contains("metadata.db is missing"));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,831 @@
+// Minimal EPUB CFI support for the backend.
+// epub.js stores highlights keyed by range CFIs such as
+// epubcfi(/6/14[chap05]!/4/2/10,/1:0,/1:120)
+// This module resolves those back into text and chapter names without the webview.
+// It also converts positions to and from KOReader's xpointers, see kosync.rs.
+// Offsets are counted in UTF-16 code units to match what epub.js produces.
+// Spec: https://idpf.org/epub/linking/cfi/epub-cfi.html
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use epub::doc::EpubDoc;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CfiStep {
+    pub index: usize,
+    pub assertion: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CfiPath {
+    pub steps: Vec<CfiStep>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfi {
+    // Steps into the package document, /6/N selects the spine item
+    pub package: Vec<CfiStep>,
+    pub parent: CfiPath,
+    pub start: Option<CfiPath>,
+    pub end: Option<CfiPath>,
+}
+
+impl Cfi {
+    pub fn parse(cfi: &str) -> Option<Cfi> {
+        let inner = cfi.trim().strip_prefix("epubcfi(")?.strip_suffix(')')?;
+
+        let parts = split_top_level(inner, ',');
+        let (package, parent) = parts.get(0)?.split_once('!')?;
+        let package = parse_path(package)?;
+
+        let cfi = Cfi {
+            package: package.steps,
+            parent: parse_path(parent)?,
+            start: match parts.get(1) {
+                Some(start) => Some(parse_path(start)?),
+                None => None,
+            },
+            end: match parts.get(2) {
+                Some(end) => Some(parse_path(end)?),
+                None => None,
+            },
+        };
+        return Some(cfi);
+    }
+
+    // Index into the spine, /6/4 is the second itemref
+    pub fn spine_index(&self) -> Option<usize> {
+        let step = self.package.get(1)?;
+        if step.index < 2 {
+            return None;
+        }
+        return Some(step.index / 2 - 1);
+    }
+
+    fn full_path(&self, local: &Option<CfiPath>) -> CfiPath {
+        let mut path = CfiPath {
+            steps: self.parent.steps.clone(),
+            offset: self.parent.offset,
+        };
+        if let Some(local) = local {
+            path.steps.extend(local.steps.iter().cloned());
+            path.offset = local.offset;
+        }
+        return path;
+    }
+
+    pub fn start_path(&self) -> CfiPath {
+        return self.full_path(&self.start);
+    }
+
+    pub fn end_path(&self) -> CfiPath {
+        if self.end.is_none() {
+            return self.start_path();
+        }
+        return self.full_path(&self.end);
+    }
+}
+
+impl std::fmt::Display for Cfi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "epubcfi({}!{}", format_steps(&self.package), format_path(&self.parent))?;
+        if let (Some(start), Some(end)) = (&self.start, &self.end) {
+            write!(f, ",{},{}", format_path(start), format_path(end))?;
+        }
+        return write!(f, ")");
+    }
+}
+
+fn split_top_level(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut escaped = false;
+    let mut last = 0;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '^' => escaped = true,
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ if c == separator && depth == 0 => {
+                parts.push(&value[last..i]);
+                last = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[last..]);
+    return parts;
+}
+
+fn parse_path(path: &str) -> Option<CfiPath> {
+    let mut result = CfiPath::default();
+    for step in split_top_level(path, '/').into_iter().skip(1) {
+        // Strip temporal and spatial offsets, they are not used for text
+        let step = split_top_level(step, '~')[0];
+        let step = split_top_level(step, '@')[0];
+
+        let (step, offset) = match step.split_once(':') {
+            Some((step, offset)) => (step, Some(offset)),
+            None => (step, None),
+        };
+        let (index, assertion) = match step.split_once('[') {
+            Some((index, assertion)) => (index, Some(assertion.trim_end_matches(']').to_string())),
+            None => (step, None),
+        };
+        result.steps.push(CfiStep {
+            index: index.parse().ok()?,
+            assertion,
+        });
+        if let Some(offset) = offset {
+            let offset = split_top_level(offset, '[')[0];
+            result.offset = Some(offset.parse().ok()?);
+        }
+    }
+    return Some(result);
+}
+
+fn format_steps(steps: &[CfiStep]) -> String {
+    let mut result = String::new();
+    for step in steps {
+        result.push_str(&format!("/{}", step.index));
+        if let Some(assertion) = &step.assertion {
+            result.push_str(&format!("[{}]", assertion));
+        }
+    }
+    return result;
+}
+
+fn format_path(path: &CfiPath) -> String {
+    let mut result = format_steps(&path.steps);
+    if let Some(offset) = path.offset {
+        result.push_str(&format!(":{}", offset));
+    }
+    return result;
+}
+
+#[derive(Debug)]
+enum Node {
+    Element {
+        name: String,
+        id: Option<String>,
+        children: Vec<Node>,
+        start: usize,
+        end: usize,
+    },
+    Text {
+        start: usize,
+    },
+}
+
+// A content document flattened into its text, with the tree kept for path lookups
+pub struct ContentDocument {
+    root: Node,
+    text: Vec<u16>,
+}
+
+// XHTML files regularly use HTML entities that a plain XML parser rejects
+const HTML_ENTITIES: [(&str, &str); 12] = [
+    ("&nbsp;", "&#160;"),
+    ("&ensp;", "&#8194;"),
+    ("&emsp;", "&#8195;"),
+    ("&thinsp;", "&#8201;"),
+    ("&ndash;", "&#8211;"),
+    ("&mdash;", "&#8212;"),
+    ("&lsquo;", "&#8216;"),
+    ("&rsquo;", "&#8217;"),
+    ("&ldquo;", "&#8220;"),
+    ("&rdquo;", "&#8221;"),
+    ("&hellip;", "&#8230;"),
+    ("&copy;", "&#169;"),
+];
+
+impl ContentDocument {
+    pub fn parse(xhtml: &str) -> Option<ContentDocument> {
+        let mut source = xhtml.to_string();
+        for (entity, replacement) in HTML_ENTITIES {
+            source = source.replace(entity, replacement);
+        }
+
+        let config = ParserConfig::new()
+            .trim_whitespace(false)
+            .whitespace_to_characters(true)
+            .cdata_to_characters(true)
+            .ignore_comments(true)
+            .coalesce_characters(true);
+        let reader = EventReader::new_with_config(source.as_bytes(), config);
+
+        let mut text: Vec<u16> = Vec::new();
+        // Stack of open elements, the first entry collects the document element
+        let mut stack: Vec<Node> = vec![Node::Element {
+            name: String::new(),
+            id: None,
+            children: Vec::new(),
+            start: 0,
+            end: 0,
+        }];
+
+        for event in reader {
+            match event.ok()? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let id = attributes
+                        .iter()
+                        .find(|attribute| attribute.name.local_name == "id")
+                        .map(|attribute| attribute.value.clone());
+                    stack.push(Node::Element {
+                        name: name.local_name.to_lowercase(),
+                        id,
+                        children: Vec::new(),
+                        start: text.len(),
+                        end: text.len(),
+                    });
+                }
+                XmlEvent::EndElement { .. } => {
+                    let mut node = stack.pop()?;
+                    if let Node::Element { end, .. } = &mut node {
+                        *end = text.len();
+                    }
+                    if let Some(Node::Element { children, .. }) = stack.last_mut() {
+                        children.push(node);
+                    }
+                }
+                XmlEvent::Characters(characters) => {
+                    if let Some(Node::Element { children, .. }) = stack.last_mut() {
+                        children.push(Node::Text { start: text.len() });
+                    }
+                    text.extend(characters.encode_utf16());
+                }
+                _ => {}
+            }
+        }
+
+        let document = match stack.pop()? {
+            Node::Element { mut children, .. } => children.drain(..).find(|child| matches!(child, Node::Element { .. }))?,
+            Node::Text { .. } => return None,
+        };
+
+        return Some(ContentDocument {
+            root: document,
+            text,
+        });
+    }
+
+    // Position in the flattened text that a content path points to
+    pub fn resolve(&self, path: &CfiPath) -> Option<usize> {
+        let mut current = &self.root;
+        let mut position = node_start(current);
+
+        // Steps start at the document element, /4 is usually <body>
+        for step in path.steps.iter() {
+            let children = match current {
+                Node::Element { children, .. } => children,
+                Node::Text { .. } => return None,
+            };
+
+            if step.index % 2 == 0 {
+                let element_index = step.index / 2;
+                let mut seen = 0;
+                let mut found = None;
+                for child in children {
+                    if let Node::Element { .. } = child {
+                        seen += 1;
+                        if seen == element_index {
+                            found = Some(child);
+                            break;
+                        }
+                    }
+                }
+                // Prefer the id assertion if the index no longer matches
+                if let Some(assertion) = &step.assertion {
+                    let matches_assertion = match found {
+                        Some(Node::Element { id: Some(id), .. }) => id == assertion,
+                        _ => false,
+                    };
+                    if !matches_assertion {
+                        found = find_by_id(&self.root, assertion).or(found);
+                    }
+                }
+                current = found?;
+                position = node_start(current);
+            } else {
+                position = chunk_start(current, step.index)?;
+                break;
+            }
+        }
+
+        if let Some(offset) = path.offset {
+            position += offset;
+        }
+        return Some(position.min(self.text.len()));
+    }
+
+    pub fn text_between(&self, start: usize, end: usize) -> String {
+        let start = start.min(self.text.len());
+        let end = end.max(start).min(self.text.len());
+        return collapse_whitespace(&String::from_utf16_lossy(&self.text[start..end]));
+    }
+
+    pub fn text_for(&self, cfi: &Cfi) -> Option<String> {
+        let start = self.resolve(&cfi.start_path())?;
+        let end = self.resolve(&cfi.end_path())?;
+        return Some(self.text_between(start, end));
+    }
+
+    // Roughly `length` characters of text following a position, cut at a word boundary
+    pub fn excerpt_at(&self, position: usize, length: usize) -> String {
+        let position = position.min(self.text.len());
+        let end = (position + length * 2).min(self.text.len());
+        let text = collapse_whitespace(&String::from_utf16_lossy(&self.text[position..end]));
+        if text.chars().count() <= length {
+            return text;
+        }
+        let mut excerpt: String = text.chars().take(length).collect();
+        if let Some(space) = excerpt.rfind(' ') {
+            excerpt.truncate(space);
+        }
+        excerpt.push('…');
+        return excerpt;
+    }
+
+    // Find the first occurrence of `quote`, ignoring differences in whitespace
+    // Returns the start and end position in the flattened text
+    pub fn find_text(&self, quote: &str) -> Option<(usize, usize)> {
+        let quote: Vec<u16> = collapse_whitespace(quote).encode_utf16().collect();
+        if quote.is_empty() {
+            return None;
+        }
+
+        // Normalized text and a map from normalized index back to the original
+        let mut normalized: Vec<u16> = Vec::new();
+        let mut positions: Vec<usize> = Vec::new();
+        let mut last_was_space = true;
+        for (i, unit) in self.text.iter().enumerate() {
+            let is_space = char::from_u32(*unit as u32).map(|c| c.is_whitespace()).unwrap_or(false);
+            if is_space {
+                if !last_was_space {
+                    normalized.push(' ' as u16);
+                    positions.push(i);
+                }
+                last_was_space = true;
+            } else {
+                normalized.push(*unit);
+                positions.push(i);
+                last_was_space = false;
+            }
+        }
+
+        let found = normalized.windows(quote.len()).position(|window| window == quote.as_slice())?;
+        let start = positions[found];
+        let end = positions[found + quote.len() - 1] + 1;
+        return Some((start, end));
+    }
+
+    // Build the content path pointing at a position in the flattened text
+    pub fn path_for(&self, position: usize) -> CfiPath {
+        let mut path = CfiPath::default();
+        let mut current = &self.root;
+
+        loop {
+            let (children, parent_end) = match current {
+                Node::Element { children, end, .. } => (children, *end),
+                Node::Text { .. } => break,
+            };
+
+            let mut elements_seen = 0;
+            let mut chunk_start: Option<usize> = None;
+            let mut next = None;
+            for (i, child) in children.iter().enumerate() {
+                match child {
+                    Node::Element { start, end, .. } => {
+                        if position >= *start && position < *end {
+                            next = Some((child, elements_seen + 1));
+                            break;
+                        }
+                        elements_seen += 1;
+                        chunk_start = None;
+                    }
+                    Node::Text { start } => {
+                        let chunk = *chunk_start.get_or_insert(*start);
+                        let text_end = children.get(i + 1).map(node_start).unwrap_or(parent_end);
+                        // Positions on a boundary belong to the text before the element
+                        if position >= chunk && position <= text_end {
+                            path.steps.push(CfiStep {
+                                index: elements_seen * 2 + 1,
+                                assertion: None,
+                            });
+                            path.offset = Some(position - chunk);
+                            return path;
+                        }
+                    }
+                }
+            }
+
+            match next {
+                Some((child, element_index)) => {
+                    path.steps.push(step_for(child, element_index * 2));
+                    current = child;
+                }
+                None => {
+                    // No text node here, point at the gap after the last element
+                    path.steps.push(CfiStep {
+                        index: elements_seen * 2 + 1,
+                        assertion: None,
+                    });
+                    path.offset = Some(0);
+                    break;
+                }
+            }
+        }
+        return path;
+    }
+
+    // xpointer path below the document element for a position, e.g. body/div/p[3]/text().14
+    // Like KOReader, siblings are only numbered when there are several with the same name
+    // and offsets count characters rather than UTF-16 code units
+    pub fn xpointer_for(&self, position: usize) -> String {
+        let mut segments: Vec<String> = Vec::new();
+        let mut current = &self.root;
+        while let Node::Element { children, end: parent_end, .. } = current {
+            let next = children
+                .iter()
+                .find(|child| matches!(child, Node::Element { start, end, .. } if position >= *start && position < *end));
+            if let Some(child) = next {
+                if let Node::Element { name, .. } = child {
+                    let same_name: Vec<&Node> = children
+                        .iter()
+                        .filter(|sibling| matches!(sibling, Node::Element { name: other, .. } if other == name))
+                        .collect();
+                    let index = same_name.iter().position(|sibling| std::ptr::eq(*sibling, child)).unwrap_or(0) + 1;
+                    segments.push(if same_name.len() > 1 { format!("{}[{}]", name, index) } else { name.clone() });
+                }
+                current = child;
+                continue;
+            }
+
+            // The position is in one of this element's own text nodes
+            let texts: Vec<(usize, usize)> = children
+                .iter()
+                .enumerate()
+                .filter_map(|(i, child)| match child {
+                    Node::Text { start } => Some((*start, children.get(i + 1).map(node_start).unwrap_or(*parent_end))),
+                    Node::Element { .. } => None,
+                })
+                .collect();
+            let found = texts.iter().rposition(|(start, _)| *start <= position);
+            if let Some(index) = found {
+                let (start, end) = texts[index];
+                let offset = String::from_utf16_lossy(&self.text[start..position.min(end)]).chars().count();
+                let node = if texts.len() > 1 { format!("text()[{}]", index + 1) } else { "text()".to_string() };
+                segments.push(format!("{}.{}", node, offset));
+            }
+            break;
+        }
+        return segments.join("/");
+    }
+
+    // Position of an xpointer path below the document element, the inverse of xpointer_for
+    // Steps that no longer exist fall back to the start of the closest element found
+    pub fn resolve_xpointer(&self, path: &str) -> Option<usize> {
+        let mut current = &self.root;
+        for segment in path.split('/').filter(|segment| segment.len() > 0) {
+            let (children, parent_end) = match current {
+                Node::Element { children, end, .. } => (children, *end),
+                Node::Text { .. } => break,
+            };
+            // Elements can carry an offset too, e.g. p[2].0
+            let (node, offset) = match segment.rsplit_once('.') {
+                Some((node, offset)) if offset.parse::<usize>().is_ok() => (node, offset.parse::<usize>().unwrap_or(0)),
+                _ => (segment, 0),
+            };
+            let (name, index) = match node.split_once('[') {
+                Some((name, index)) => (name, index.trim_end_matches(']').parse::<usize>().unwrap_or(1).max(1)),
+                None => (node, 1),
+            };
+
+            if name == "text()" {
+                let texts: Vec<(usize, usize)> = children
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, child)| match child {
+                        Node::Text { start } => Some((*start, children.get(i + 1).map(node_start).unwrap_or(parent_end))),
+                        Node::Element { .. } => None,
+                    })
+                    .collect();
+                let (start, end) = match texts.get(index - 1) {
+                    Some(text) => *text,
+                    None => break,
+                };
+                let units: usize = String::from_utf16_lossy(&self.text[start..end]).chars().take(offset).map(char::len_utf16).sum();
+                return Some(start + units);
+            }
+
+            let found = children
+                .iter()
+                .filter(|child| matches!(child, Node::Element { name: other, .. } if other.eq_ignore_ascii_case(name)))
+                .nth(index - 1);
+            match found {
+                Some(child) => current = child,
+                None => break,
+            }
+        }
+        return Some(node_start(current));
+    }
+
+    // Range CFI for a span of text inside the spine item at `spine_index`
+    pub fn range_cfi(&self, spine_index: usize, spine_id: Option<String>, start: usize, end: usize) -> Cfi {
+        let start_path = self.path_for(start);
+        let end_path = self.path_for(end);
+
+        // The parent is the longest common prefix, excluding each side's final step
+        let mut common = 0;
+        while common + 1 < start_path.steps.len()
+            && common + 1 < end_path.steps.len()
+            && start_path.steps[common] == end_path.steps[common]
+        {
+            common += 1;
+        }
+
+        return Cfi {
+            package: vec![
+                CfiStep {
+                    index: 6,
+                    assertion: None,
+                },
+                CfiStep {
+                    index: (spine_index + 1) * 2,
+                    assertion: spine_id,
+                },
+            ],
+            parent: CfiPath {
+                steps: start_path.steps[..common].to_vec(),
+                offset: None,
+            },
+            start: Some(CfiPath {
+                steps: start_path.steps[common..].to_vec(),
+                offset: start_path.offset,
+            }),
+            end: Some(CfiPath {
+                steps: end_path.steps[common..].to_vec(),
+                offset: end_path.offset,
+            }),
+        };
+    }
+}
+
+fn node_start(node: &Node) -> usize {
+    match node {
+        Node::Element { start, .. } => *start,
+        Node::Text { start } => *start,
+    }
+}
+
+fn step_for(node: &Node, index: usize) -> CfiStep {
+    let assertion = match node {
+        Node::Element { id, .. } => id.clone(),
+        Node::Text { .. } => None,
+    };
+    return CfiStep { index, assertion };
+}
+
+// Start of the odd numbered text chunk `index` inside `element`
+fn chunk_start(element: &Node, index: usize) -> Option<usize> {
+    let (children, element_start, element_end) = match element {
+        Node::Element { children, start, end, .. } => (children, *start, *end),
+        Node::Text { .. } => return None,
+    };
+    let elements_before = (index - 1) / 2;
+    let mut seen = 0;
+    let mut position = element_start;
+    for child in children {
+        match child {
+            Node::Element { end, .. } => {
+                if seen == elements_before {
+                    return Some(position);
+                }
+                seen += 1;
+                position = *end;
+            }
+            Node::Text { start } => {
+                if seen == elements_before {
+                    return Some(*start);
+                }
+            }
+        }
+    }
+    if seen == elements_before {
+        return Some(position);
+    }
+    return Some(element_end);
+}
+
+fn find_by_id<'a>(node: &'a Node, target: &str) -> Option<&'a Node> {
+    if let Node::Element { id, children, .. } = node {
+        if id.as_deref() == Some(target) {
+            return Some(node);
+        }
+        for child in children {
+            if let Some(found) = find_by_id(child, target) {
+                return Some(found);
+            }
+        }
+    }
+    return None;
+}
+
+pub fn collapse_whitespace(value: &str) -> String {
+    return value.split_whitespace().collect::<Vec<&str>>().join(" ");
+}
+
+// Wraps an opened EPUB so multiple CFIs from the same book can be resolved cheaply
+pub struct CfiResolver {
+    doc: EpubDoc<std::io::BufReader<std::fs::File>>,
+    documents: HashMap<usize, Option<ContentDocument>>,
+    chapters: HashMap<String, String>,
+}
+
+impl CfiResolver {
+    pub fn open(epub_path: &Path) -> Option<CfiResolver> {
+        let doc = EpubDoc::new(epub_path).ok()?;
+
+        let mut chapters = HashMap::new();
+        for nav_point in doc.toc.iter() {
+            let content = nav_point.content.to_string_lossy().to_string();
+            let content = content.split('#').next().unwrap_or("").to_string();
+            chapters.entry(content).or_insert(nav_point.label.trim().to_string());
+        }
+
+        return Some(CfiResolver {
+            doc,
+            documents: HashMap::new(),
+            chapters,
+        });
+    }
+
+    pub fn spine_len(&self) -> usize {
+        return self.doc.spine.len();
+    }
+
+    pub fn spine_id(&self, spine_index: usize) -> Option<String> {
+        return self.doc.spine.get(spine_index).cloned();
+    }
+
+    pub fn document(&mut self, spine_index: usize) -> Option<&ContentDocument> {
+        if !self.documents.contains_key(&spine_index) {
+            let id = self.doc.spine.get(spine_index)?.clone();
+            let document = self
+                .doc
+                .get_resource_str(&id)
+                .ok()
+                .and_then(|xhtml| ContentDocument::parse(&xhtml));
+            self.documents.insert(spine_index, document);
+        }
+        return self.documents.get(&spine_index)?.as_ref();
+    }
+
+    // Label of the table of contents entry covering a spine item
+    // Spine items without their own entry inherit the closest previous one
+    pub fn chapter(&self, spine_index: usize) -> Option<String> {
+        for index in (0..=spine_index).rev() {
+            let id = match self.doc.spine.get(index) {
+                Some(id) => id,
+                None => continue,
+            };
+            if let Some((path, _mime)) = self.doc.resources.get(id) {
+                if let Some(label) = self.chapters.get(&path.to_string_lossy().to_string()) {
+                    return Some(label.clone());
+                }
+            }
+        }
+        return None;
+    }
+
+    pub fn text(&mut self, cfi: &str) -> Option<String> {
+        let cfi = Cfi::parse(cfi)?;
+        let document = self.document(cfi.spine_index()?)?;
+        return document.text_for(&cfi);
+    }
+
+    pub fn excerpt(&mut self, cfi: &str, length: usize) -> Option<String> {
+        let cfi = Cfi::parse(cfi)?;
+        let document = self.document(cfi.spine_index()?)?;
+        let position = document.resolve(&cfi.start_path())?;
+        return Some(document.excerpt_at(position, length));
+    }
+
+    pub fn chapter_for(&self, cfi: &str) -> Option<String> {
+        let cfi = Cfi::parse(cfi)?;
+        return self.chapter(cfi.spine_index()?);
+    }
+
+    // KOReader xpointer for the start of a CFI, e.g. /body/DocFragment[12]/body/p[3]/text().14
+    pub fn xpointer(&mut self, cfi: &str) -> Option<String> {
+        let cfi = Cfi::parse(cfi)?;
+        let spine_index = cfi.spine_index()?;
+        let document = self.document(spine_index)?;
+        let position = document.resolve(&cfi.start_path())?;
+        return Some(format!("/body/DocFragment[{}]/{}", spine_index + 1, document.xpointer_for(position)));
+    }
+
+    // Point CFI for a KOReader xpointer
+    pub fn cfi_for_xpointer(&mut self, xpointer: &str) -> Option<String> {
+        let spine_index = doc_fragment(xpointer)?.checked_sub(1)?;
+        let spine_id = self.spine_id(spine_index);
+        let fragment = xpointer.find("DocFragment[")?;
+        let path = xpointer[fragment..].split_once(']').map(|(_, path)| path).unwrap_or("");
+        let document = self.document(spine_index)?;
+        let position = document.resolve_xpointer(path)?;
+        let cfi = Cfi {
+            package: vec![
+                CfiStep {
+                    index: 6,
+                    assertion: None,
+                },
+                CfiStep {
+                    index: (spine_index + 1) * 2,
+                    assertion: spine_id,
+                },
+            ],
+            parent: document.path_for(position),
+            start: None,
+            end: None,
+        };
+        return Some(cfi.to_string());
+    }
+}
+
+// KOReader positions are xpointers like /body/DocFragment[12]/body/p[3]/text().0
+// The fragment is the 1 based index into the spine
+pub fn doc_fragment(xpointer: &str) -> Option<usize> {
+    let start = xpointer.find("DocFragment[")? + "DocFragment[".len();
+    let end = xpointer[start..].find(']')? + start;
+    return xpointer[start..end].parse().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>One</title></head><body><p id="first">Hello&nbsp;world</p><p>Second <em>long</em> paragraph</p></body></html>"#;
+
+    #[test]
+    fn parses_and_formats_range_cfis() {
+        let value = "epubcfi(/6/14[chap05]!/4/2/10,/1:0,/1:120)";
+        let cfi = Cfi::parse(value).unwrap();
+        assert_eq!(cfi.spine_index(), Some(6));
+        assert_eq!(cfi.package[1].assertion.as_deref(), Some("chap05"));
+        assert_eq!(cfi.start_path().steps.len(), 4);
+        assert_eq!(cfi.end_path().offset, Some(120));
+        assert_eq!(cfi.to_string(), value);
+    }
+
+    #[test]
+    fn rejects_malformed_cfis() {
+        assert!(Cfi::parse("/6/14!/4/2").is_none());
+        assert!(Cfi::parse("epubcfi(/6/x!/4/2)").is_none());
+    }
+
+    #[test]
+    fn resolves_text_for_a_range() {
+        let document = ContentDocument::parse(CHAPTER).unwrap();
+        let cfi = Cfi::parse("epubcfi(/6/2!/4/2[first],/1:0,/1:5)").unwrap();
+        assert_eq!(document.text_for(&cfi).as_deref(), Some("Hello"));
+
+        // The id assertion wins when the index is stale
+        let cfi = Cfi::parse("epubcfi(/6/2!/4/4[first],/1:6,/1:11)").unwrap();
+        assert_eq!(document.text_for(&cfi).as_deref(), Some("world"));
+    }
+
+    #[test]
+    fn builds_a_range_cfi_that_resolves_back() {
+        let document = ContentDocument::parse(CHAPTER).unwrap();
+        let (start, end) = document.find_text("long  paragraph").unwrap();
+        let cfi = document.range_cfi(3, Some("chap04".to_string()), start, end);
+        assert_eq!(cfi.spine_index(), Some(3));
+        assert_eq!(document.text_for(&cfi).as_deref(), Some("long paragraph"));
+        assert_eq!(document.text_for(&Cfi::parse(&cfi.to_string()).unwrap()).as_deref(), Some("long paragraph"));
+    }
+
+    #[test]
+    fn converts_xpointers_both_ways() {
+        let document = ContentDocument::parse(CHAPTER).unwrap();
+        let (start, _) = document.find_text("paragraph").unwrap();
+        let xpointer = document.xpointer_for(start);
+        assert_eq!(xpointer, "body/p[2]/text()[2].1");
+        assert_eq!(document.resolve_xpointer(&xpointer), Some(start));
+        assert_eq!(doc_fragment("/body/DocFragment[3]/body/p[2]/text().4"), Some(3));
+    }
+
+    #[test]
+    fn missing_xpointer_steps_fall_back_to_the_closest_element() {
+        let document = ContentDocument::parse(CHAPTER).unwrap();
+        let (start, _) = document.find_text("Second").unwrap();
+        assert_eq!(document.resolve_xpointer("body/p[2]/span[4]/text().3"), Some(start));
+    }
+}
@@ -0,0 +1,444 @@
+// Headless subcommands of the app binary, e.g. `alexandria import ~/Books` or `alexandria list --json`
+// They are declared in tauri.conf.json and run against the same Alexandria_Data as the app, before
+// any window is created. Without a subcommand the app starts as usual and the frontend reads the
+// optional `source` argument itself.
+// Exit codes: 0 on success, 1 when the command failed, 2 on invalid arguments.
+// On Windows the release build is a GUI program, its output only shows when redirected or piped.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use epub::doc::EpubDoc;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tauri::api::cli::{get_matches, Matches};
+
+use crate::asset_server::PRIVATE_EXTENSIONS;
+use crate::{
+    annotations, backup, book_state, create_or_load_data, current_time_millis, delete_book, fontsJSON, fontsJSONVersion, get_config_path,
+    get_font_folder_path, import_book, init_paths, migrations, read_fonts_json, storage, write_fonts_json,
+};
+
+const SUBCOMMANDS: [&str; 7] = ["import", "list", "export-annotations", "backup", "restore", "delete", "repair"];
+
+// Books are imported from the formats the app's import dialog offers, see SUPPORTED_FORMATS in TauriActions.ts
+const BOOK_FORMATS: &str = include_str!("../../src/shared/bookFormats.json");
+
+// Refreshed by the running app, see mark_running
+const RUNNING_MARKER: &str = ".running";
+const RUNNING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct ListedBook {
+    hash: String,
+    title: String,
+    author: String,
+    progress: f64,
+    modified: u64,
+}
+
+// The exit code when a subcommand ran, None to start the app
+pub fn run<A: tauri::Assets>(context: &tauri::Context<A>) -> Option<i32> {
+    let cli_config = context.config().tauri.cli.as_ref()?;
+    let matches = match get_matches(cli_config, context.package_info()) {
+        Ok(matches) => matches,
+        Err(error) => {
+            // Launchers may pass arguments of their own, only a subcommand's mistakes are ours to report
+            let first = std::env::args().nth(1).unwrap_or_default();
+            if SUBCOMMANDS.contains(&first.as_str()) {
+                eprintln!("{}", error);
+                return Some(2);
+            }
+            return None;
+        }
+    };
+    for name in ["help", "version"] {
+        if let Some(text) = matches.args.get(name).and_then(|arg| arg.value.as_str()) {
+            println!("{}", text);
+            return Some(0);
+        }
+    }
+    let subcommand = matches.subcommand?;
+
+    init_paths(&context.config());
+    // The app would write its cached copies back over replaced or removed books
+    let closed = match subcommand.name.as_str() {
+        "restore" | "delete" | "repair" => ensure_app_closed(),
+        _ => Ok(()),
+    };
+    let result = closed
+        .and_then(|_| match subcommand.name.as_str() {
+            // Reading commands do not create a library where there is none
+            "list" | "export-annotations" if !get_config_path().exists() => Err(format!("Error: No library at \"{}\"", get_config_path().display())),
+            "list" | "export-annotations" => migrations::run().map(|_| ()),
+            _ => create_or_load_data().map(|_| ()),
+        })
+        .and_then(|_| dispatch(&subcommand.name, &subcommand.matches));
+    book_state::flush_all();
+
+    return match result {
+        Ok(_) => Some(0),
+        Err(error) => {
+            eprintln!("{}", error);
+            Some(1)
+        }
+    };
+}
+
+// Runs for the lifetime of the app on the tokio runtime started in main
+// A crashed app leaves a marker that is no longer refreshed, it is ignored once it is stale
+pub async fn mark_running() {
+    let marker = get_config_path().join(RUNNING_MARKER);
+    loop {
+        if let Err(error) = fs::write(&marker, current_time_millis().to_string()) {
+            println!("Error: Could not write \"{}\" : {}", marker.display(), error);
+        }
+        tokio::time::sleep(RUNNING_INTERVAL).await;
+    }
+}
+
+// Called when the app exits
+pub fn clear_running() {
+    let _ = fs::remove_file(get_config_path().join(RUNNING_MARKER));
+}
+
+fn ensure_app_closed() -> Result<(), String> {
+    let written = fs::read_to_string(get_config_path().join(RUNNING_MARKER))
+        .ok()
+        .and_then(|time| time.trim().parse::<u64>().ok());
+    if let Some(written) = written {
+        if written + 3 * RUNNING_INTERVAL.as_millis() as u64 > current_time_millis() {
+            return Err("Error: Alexandria is running, close it before changing the library".to_string());
+        }
+    }
+    return Ok(());
+}
+
+fn dispatch(name: &str, matches: &Matches) -> Result<(), String> {
+    return match name {
+        "import" => import(strings(matches, "paths")),
+        "list" => list(flag(matches, "json")),
+        "export-annotations" => export_annotations(
+            &string(matches, "book").unwrap_or_default(),
+            &string(matches, "format").unwrap_or("markdown".to_string()),
+            string(matches, "output"),
+        ),
+        "backup" => create_backup(&string(matches, "path").unwrap_or_default(), flag(matches, "data")),
+        "restore" => restore(&string(matches, "path").unwrap_or_default(), flag(matches, "merge")),
+        "delete" => delete(&string(matches, "hash").unwrap_or_default()),
+        "repair" => repair(),
+        other => Err(format!("Error: Unknown command - {}", other)),
+    };
+}
+
+fn string(matches: &Matches, name: &str) -> Option<String> {
+    return matches.args.get(name).and_then(|arg| arg.value.as_str()).map(|value| value.to_string());
+}
+
+fn strings(matches: &Matches, name: &str) -> Vec<String> {
+    return match matches.args.get(name).map(|arg| &arg.value) {
+        Some(Value::Array(values)) => values.iter().filter_map(|value| value.as_str()).map(|value| value.to_string()).collect(),
+        Some(Value::String(value)) => vec![value.clone()],
+        _ => Vec::new(),
+    };
+}
+
+fn flag(matches: &Matches, name: &str) -> bool {
+    return matches.args.get(name).and_then(|arg| arg.value.as_bool()).unwrap_or(false);
+}
+
+fn is_book_file(path: &Path) -> bool {
+    let formats: Vec<String> = serde_json::from_str(BOOK_FORMATS).unwrap_or_default();
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+    return path.is_file() && formats.contains(&extension);
+}
+
+fn import(paths: Vec<String>) -> Result<(), String> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(&path)
+                .map_err(|e| format!("Error: Could not read \"{}\" : {}", path.display(), e))?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| is_book_file(path))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path);
+        }
+    }
+
+    let (mut imported, mut duplicates, mut failed) = (0, 0, 0);
+    for file in files {
+        if !is_book_file(&file) {
+            failed += 1;
+            eprintln!("Error: Not a book file - \"{}\"", file.display());
+            continue;
+        }
+        match import_book(file.to_string_lossy().to_string()) {
+            Ok(book) => {
+                imported += 1;
+                println!("Imported {} - {}", book.hash, book.title);
+            }
+            // Already in the library, not a failure
+            Err(error) if error.starts_with("Error: Book is duplicate") => duplicates += 1,
+            Err(error) => {
+                failed += 1;
+                eprintln!("{} - \"{}\"", error, file.display());
+            }
+        }
+    }
+    println!("{} imported, {} already in the library, {} failed", imported, duplicates, failed);
+    if failed > 0 {
+        return Err(format!("Error: {} books could not be imported", failed));
+    }
+    return Ok(());
+}
+
+fn list(as_json: bool) -> Result<(), String> {
+    let books: Vec<ListedBook> = backup::library_books()
+        .into_iter()
+        .map(|book| {
+            let (progress, modified) = match book_state::get(&book.hash) {
+                Ok(payload) => (payload.data.progress, payload.modified),
+                Err(_) => (0.0, 0),
+            };
+            return ListedBook {
+                hash: book.hash,
+                title: book.title,
+                author: book.author,
+                progress,
+                modified,
+            };
+        })
+        .collect();
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&books).map_err(|e| format!("Error: {}", e))?);
+        return Ok(());
+    }
+    for book in books {
+        let author = if book.author.len() > 0 { format!(" - {}", book.author) } else { String::new() };
+        println!("{}  {:>3}%  {}{}", book.hash, (book.progress * 100.0).round(), book.title, author);
+    }
+    return Ok(());
+}
+
+fn export_annotations(book: &str, format: &str, output: Option<String>) -> Result<(), String> {
+    let hashes = if book == "all" {
+        None
+    } else {
+        if !get_config_path().join("books").join(book).is_dir() {
+            return Err(format!("Error: Book not found - {}", book));
+        }
+        Some(vec![book.to_string()])
+    };
+    let (rendered, report) = annotations::render_annotations(hashes, format)?;
+    for hash in report.skipped_books.iter() {
+        if book != "all" {
+            return Err(format!("Error: Could not read the annotations of {}", hash));
+        }
+        eprintln!("Error: Could not read the annotations of {}", hash);
+    }
+    match output {
+        Some(path) => {
+            storage::write_atomic(Path::new(&path), rendered.as_bytes()).map_err(|e| format!("Error: Could not write \"{}\" : {}", path, e))?;
+            println!("Exported {} annotations to {}", report.exported, path);
+        }
+        None => print!("{}", rendered),
+    }
+    return Ok(());
+}
+
+fn create_backup(path: &str, data_only: bool) -> Result<(), String> {
+    let manifest = if data_only {
+        backup::export_data_backup(path.to_string())?
+    } else {
+        backup::export_backup(path.to_string())?
+    };
+    println!("Backed up {} books to {}", manifest.books.len(), path);
+    return Ok(());
+}
+
+// The kind of backup decides how it is applied, reading data is always merged
+fn restore(path: &str, merge: bool) -> Result<(), String> {
+    let mut archive = backup::open_archive(Path::new(path))?;
+    let manifest = backup::read_manifest(&mut archive)?;
+    drop(archive);
+
+    if manifest.kind == "data" {
+        let report = backup::import_data_backup(path.to_string())?;
+        println!("Updated {} books, {} are not in the library", report.books_updated, report.books_missing.len());
+        for missing in report.books_missing {
+            println!("  {}", missing);
+        }
+        return Ok(());
+    }
+    let mode = if merge { "merge" } else { "restore" };
+    let report = backup::import_backup(path.to_string(), Some(mode.to_string()))?;
+    println!("{} books added, {} merged, {} fonts added", report.books_added, report.books_merged, report.fonts_added);
+    return Ok(());
+}
+
+fn delete(hash: &str) -> Result<(), String> {
+    let folder = get_config_path().join("books").join(hash);
+    // Anything else would reach outside the books folder
+    if hash.len() == 0 || hash.contains(['/', '\\', '.']) || !folder.is_dir() {
+        return Err(format!("Error: Book not found - {}", hash));
+    }
+    delete_book(hash)?;
+    println!("Deleted {}", hash);
+    return Ok(());
+}
+
+// Files a book folder holds besides the book itself
+fn is_book_metadata(path: &Path) -> bool {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+    return name == "cover.jpg" || name.ends_with(".broken") || PRIVATE_EXTENSIONS.iter().any(|extension| name.ends_with(extension));
+}
+
+// Fresh data for a book whose JSON is gone, the same as on import
+fn rebuild_book_data(hash: &str, files: &[PathBuf]) -> Result<(), String> {
+    let book = files.iter().find(|path| path.extension().map(|extension| extension == "epub").unwrap_or(false)).unwrap_or(&files[0]);
+    let mut title = book.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let mut author = String::new();
+    if let Ok(doc) = EpubDoc::new(book) {
+        title = doc.mdata("title").unwrap_or(title);
+        author = doc.mdata("creator").unwrap_or(author);
+    }
+    let initial_data = json!({
+        "version": migrations::BOOK_DATA_VERSION,
+        "title": title,
+        "author": author,
+        "modified": current_time_millis(),
+        "data": {
+            "progress": 0,
+            "cfi": "",
+        }
+    });
+    let path = get_config_path().join("books").join(hash).join(format!("{}.json", hash));
+    return storage::write_json(&path, &initial_data);
+}
+
+fn repair() -> Result<(), String> {
+    let config = get_config_path();
+    let mut fixed = 0;
+
+    // A restore that was interrupted while swapping, the library it replaced is put back unless the
+    // restored one is complete. Loading the library already does this, it is repeated for the report.
+    if backup::recover_restore()? {
+        fixed += 1;
+    }
+    // Only a copy of the archive being restored
+    let staging = config.join(".backup_restore");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(|e| format!("Error: Could not remove \"{}\" : {}", staging.display(), e))?;
+        println!("Removed {}", staging.display());
+        fixed += 1;
+    }
+
+    let mut folders: Vec<PathBuf> = fs::read_dir(config.join("books"))
+        .map_err(|e| format!("Error: Could not read the books folder : {}", e))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    folders.sort();
+    let mut problems = 0;
+    for folder in folders {
+        let hash = folder.file_name().unwrap().to_string_lossy().to_string();
+        let mut files: Vec<PathBuf> = fs::read_dir(&folder).map(|entries| entries.flatten().map(|entry| entry.path()).collect()).unwrap_or_default();
+        files.sort();
+
+        // Half written files, the originals or their .bak copies are still in place
+        for temp in files.iter().filter(|path| path.to_string_lossy().ends_with(".tmp")) {
+            if fs::remove_file(temp).is_ok() {
+                println!("Removed {}", temp.display());
+                fixed += 1;
+            }
+        }
+
+        let books: Vec<PathBuf> = files.iter().filter(|path| path.is_file() && !is_book_metadata(path)).cloned().collect();
+        if books.len() == 0 {
+            println!("{} has no book file, delete it with `delete {}`", hash, hash);
+            problems += 1;
+            continue;
+        }
+
+        // Reading falls back to the .bak copy by itself, only data that is lost for good is rebuilt
+        let data_path = folder.join(format!("{}.json", hash));
+        if let Err(error) = book_state::get(&hash) {
+            if data_path.exists() {
+                let broken = folder.join(format!("{}.json.broken", hash));
+                fs::rename(&data_path, &broken).map_err(|e| format!("Error: Could not move \"{}\" : {}", data_path.display(), e))?;
+                println!("{} - kept as \"{}\"", error, broken.display());
+            }
+            rebuild_book_data(&hash, &books)?;
+            book_state::evict(&hash);
+            println!("Rebuilt the data of {}", hash);
+            fixed += 1;
+        }
+    }
+
+    // fonts.json lists downloaded fonts as true and system fonts as false, only downloaded ones have a folder
+    let mut fonts = read_fonts_json().unwrap_or(fontsJSON {
+        fonts: HashMap::new(),
+        version: fontsJSONVersion(),
+    });
+    let folders: Vec<String> = fs::read_dir(get_font_folder_path())
+        .map(|entries| entries.flatten().filter(|entry| entry.path().is_dir()).map(|entry| entry.file_name().to_string_lossy().to_string()).collect())
+        .unwrap_or_default();
+    let before: HashMap<String, bool> = fonts.fonts.clone();
+    fonts.fonts.retain(|name, downloaded| !*downloaded || folders.contains(name));
+    for name in folders {
+        fonts.fonts.insert(name, true);
+    }
+    if fonts.fonts != before || !get_font_folder_path().join("fonts.json").exists() {
+        write_fonts_json(&fonts)?;
+        println!("Updated the font list");
+        fixed += 1;
+    }
+
+    println!("{} problems fixed, {} need attention", fixed, problems);
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_formats_the_app_imports() {
+        let folder = std::env::temp_dir().join(format!("alexandria-cli-{}-{}", current_time_millis(), rand::random::<u32>()));
+        fs::create_dir_all(folder.join("folder.epub")).unwrap();
+        for name in ["book.epub", "Book.AZW3", "comic.cbz", "story.fb2", "notes.txt", "data.json", "cover.jpg", "no_extension"] {
+            fs::write(folder.join(name), "").unwrap();
+        }
+        let accepted: Vec<&str> = ["book.epub", "Book.AZW3", "comic.cbz", "story.fb2", "notes.txt", "data.json", "cover.jpg", "no_extension", "folder.epub"]
+            .into_iter()
+            .filter(|name| is_book_file(&folder.join(name)))
+            .collect();
+        assert_eq!(accepted, vec!["book.epub", "Book.AZW3", "comic.cbz", "story.fb2", "notes.txt"]);
+    }
+
+    #[test]
+    fn deletes_books() {
+        let _library = crate::shared_test_library();
+        let hash = crate::test_book("delete", json!({"title": "Delete", "data": {"progress": 0.0}}));
+        book_state::get(&hash).unwrap();
+
+        delete(&hash).unwrap();
+        assert!(!get_config_path().join("books").join(&hash).exists());
+        assert!(book_state::get(&hash).is_err());
+        assert!(delete(&hash).is_err());
+        assert!(delete("..").is_err());
+        assert!(delete_book("../fonts").is_err());
+        assert!(get_config_path().join("fonts").exists());
+    }
+}
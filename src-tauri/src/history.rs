@@ -0,0 +1,337 @@
+// Per book journal of highlight edits, kept next to the book data as annotation_history.json
+// Every save replaces the whole book file, so this is the only way back after a bad write
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage;
+use crate::{book_state, current_time_millis, get_config_path, highlightData, updateBookPayload};
+
+// Oldest entries are dropped once a book has more changes than this
+const MAX_HISTORY: usize = 200;
+
+pub const HISTORY_FILE: &str = "annotation_history.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnnotationChange {
+    id: u64,
+    time: u64,
+    // "add", "recolor", "edit_note", "retag", "delete" or "undo"
+    kind: String,
+    cfi: String,
+    #[serde(default)]
+    before: Option<highlightData>,
+    #[serde(default)]
+    after: Option<highlightData>,
+    // For "undo" entries, the change that was reverted
+    #[serde(default)]
+    undoes: Option<u64>,
+    #[serde(default)]
+    undone: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct AnnotationHistory {
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default)]
+    changes: Vec<AnnotationChange>,
+}
+
+fn history_path(hash: &str) -> PathBuf {
+    return get_config_path().join("books").join(hash).join(HISTORY_FILE);
+}
+
+// Errors when the journal and its backup are both damaged
+fn load_history(hash: &str) -> Result<AnnotationHistory, String> {
+    if !history_path(hash).exists() {
+        return Ok(AnnotationHistory::default());
+    }
+    return storage::read_json(&history_path(hash));
+}
+
+// A damaged journal is kept as annotation_history.json.broken and a new one is started,
+// so it does not stop highlight edits from being journaled
+fn load_or_set_aside(hash: &str) -> Result<AnnotationHistory, String> {
+    let error = match load_history(hash) {
+        Ok(history) => return Ok(history),
+        Err(error) => error,
+    };
+    let path = history_path(hash);
+    let broken = path.with_file_name(format!("{}.broken", HISTORY_FILE));
+    fs::rename(&path, &broken).map_err(|e| format!("Error: Could not move \"{}\" : {}", path.display(), e))?;
+    println!("{} - kept as \"{}\"", error, broken.display());
+    return Ok(AnnotationHistory::default());
+}
+
+fn save_history(hash: &str, history: &mut AnnotationHistory) -> Result<(), String> {
+    if history.changes.len() > MAX_HISTORY {
+        let overflow = history.changes.len() - MAX_HISTORY;
+        history.changes.drain(..overflow);
+    }
+    storage::write_json(&history_path(hash), history)
+}
+
+fn change_kind(before: &Option<highlightData>, after: &Option<highlightData>) -> Option<&'static str> {
+    match (before, after) {
+        (None, Some(_)) => Some("add"),
+        (Some(_), None) => Some("delete"),
+        (Some(before), Some(after)) => {
+            if before.note != after.note {
+                Some("edit_note")
+            } else if before.color != after.color {
+                Some("recolor")
+            } else if before.tags != after.tags {
+                Some("retag")
+            } else {
+                None
+            }
+        }
+        (None, None) => None,
+    }
+}
+
+// Compare the highlights before and after a save and journal every difference
+// Called once the change is on disk, see book_state::update_journaled
+pub fn record_changes(
+    hash: &str,
+    previous: Option<&updateBookPayload>,
+    current: &updateBookPayload,
+) -> Result<(), String> {
+    let empty = HashMap::new();
+    let before_highlights = previous.map(|previous| &previous.data.highlights).unwrap_or(&empty);
+    let after_highlights = &current.data.highlights;
+
+    let mut cfis: Vec<&String> = before_highlights.keys().chain(after_highlights.keys()).collect::<HashSet<_>>().into_iter().collect();
+    cfis.sort();
+
+    let mut history = load_or_set_aside(hash)?;
+    let now = current_time_millis();
+    let mut changed = false;
+    for cfi in cfis {
+        let before = before_highlights.get(cfi).cloned();
+        let after = after_highlights.get(cfi).cloned();
+        if let Some(kind) = change_kind(&before, &after) {
+            history.next_id += 1;
+            history.changes.push(AnnotationChange {
+                id: history.next_id,
+                time: now,
+                kind: kind.to_string(),
+                cfi: cfi.clone(),
+                before,
+                after,
+                undoes: None,
+                undone: false,
+            });
+            changed = true;
+        }
+    }
+
+    if changed {
+        save_history(hash, &mut history)?;
+    }
+    return Ok(());
+}
+
+// Newest change first
+#[tauri::command]
+pub fn list_annotation_history(hash: String) -> Result<Vec<AnnotationChange>, String> {
+    let mut changes = load_history(&hash)?.changes;
+    changes.reverse();
+    return Ok(changes);
+}
+
+// Restore the highlight touched by a change to the state before it
+// Without an id the most recent change that is not an undo and was not undone is reverted
+// The journal is read within the book's update and saved once the restored highlight is on disk,
+// the same critical section saves journal their changes in, so none of their entries are lost
+#[tauri::command]
+pub fn undo_annotation_change(hash: String, id: Option<u64>) -> Result<updateBookPayload, String> {
+    let change = |payload: &mut updateBookPayload, _previous: &updateBookPayload| {
+        let history = load_history(&hash)?;
+
+        let index = match id {
+            Some(id) => history.changes.iter().position(|change| change.id == id),
+            None => history
+                .changes
+                .iter()
+                .rposition(|change| !change.undone && change.undoes.is_none()),
+        }
+        .ok_or("Error: No annotation change to undo")?;
+
+        let change = history.changes[index].clone();
+        if change.undone {
+            return Err(format!("Error: Change {} was already undone", change.id));
+        }
+
+        match &change.before {
+            Some(before) => {
+                let mut restored = before.clone();
+                restored.modified = current_time_millis();
+                payload.data.highlights.insert(change.cfi.clone(), restored);
+            }
+            None => {
+                payload.data.highlights.remove(&change.cfi);
+            }
+        }
+        return Ok((history, index));
+    };
+
+    let journal = |previous: &updateBookPayload, payload: &updateBookPayload, (mut history, index): (AnnotationHistory, usize)| {
+        let change: AnnotationChange = history.changes[index].clone();
+        history.changes[index].undone = true;
+        history.next_id += 1;
+        history.changes.push(AnnotationChange {
+            id: history.next_id,
+            time: current_time_millis(),
+            kind: "undo".to_string(),
+            cfi: change.cfi.clone(),
+            before: previous.data.highlights.get(&change.cfi).cloned(),
+            after: payload.data.highlights.get(&change.cfi).cloned(),
+            undoes: Some(change.id),
+            undone: false,
+        });
+        return save_history(&hash, &mut history);
+    };
+
+    return book_state::update_then(&hash, None, change, journal);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn highlight(color: &str, note: &str) -> highlightData {
+        return highlightData {
+            color: color.to_string(),
+            note: note.to_string(),
+            ..Default::default()
+        };
+    }
+
+    fn payload(highlights: serde_json::Value) -> updateBookPayload {
+        return serde_json::from_value(json!({"title": "History", "data": {"progress": 0.0, "highlights": highlights}})).unwrap();
+    }
+
+    fn book() -> String {
+        return crate::test_book("history", json!({"title": "History", "data": {"progress": 0.0}}));
+    }
+
+    #[test]
+    fn names_each_kind_of_change() {
+        let yellow = highlight("yellow", "");
+        assert_eq!(change_kind(&None, &Some(yellow.clone())), Some("add"));
+        assert_eq!(change_kind(&Some(yellow.clone()), &None), Some("delete"));
+        assert_eq!(change_kind(&Some(yellow.clone()), &Some(highlight("red", ""))), Some("recolor"));
+        // A note edit wins when both changed
+        assert_eq!(change_kind(&Some(yellow.clone()), &Some(highlight("red", "note"))), Some("edit_note"));
+        let mut tagged = yellow.clone();
+        tagged.tags = vec!["quote".to_string()];
+        assert_eq!(change_kind(&Some(yellow.clone()), &Some(tagged)), Some("retag"));
+        let mut touched = yellow.clone();
+        touched.modified = 5;
+        assert_eq!(change_kind(&Some(yellow), &Some(touched)), None);
+        assert_eq!(change_kind(&None, &None), None);
+    }
+
+    #[test]
+    fn records_every_changed_highlight() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        let before = payload(json!({"a": {"color": "yellow"}, "b": {"color": "yellow"}, "c": {"color": "yellow"}}));
+        let after = payload(json!({"a": {"color": "red"}, "c": {"color": "yellow"}, "d": {"color": "cyan"}}));
+        record_changes(&hash, Some(&before), &after).unwrap();
+
+        let changes = list_annotation_history(hash.clone()).unwrap();
+        let summary: Vec<(u64, &str, &str)> = changes.iter().map(|change| (change.id, change.kind.as_str(), change.cfi.as_str())).collect();
+        assert_eq!(summary, vec![(3, "add", "d"), (2, "delete", "b"), (1, "recolor", "a")]);
+        assert_eq!(changes[1].before.as_ref().unwrap().color, "yellow");
+        assert!(changes[1].after.is_none());
+
+        // Saves without highlight changes leave the journal alone
+        record_changes(&hash, Some(&after), &after).unwrap();
+        assert_eq!(list_annotation_history(hash).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn keeps_the_newest_changes() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        let mut history = AnnotationHistory::default();
+        for id in 1..=MAX_HISTORY as u64 + 5 {
+            history.next_id = id;
+            history.changes.push(AnnotationChange {
+                id,
+                time: id,
+                kind: "add".to_string(),
+                cfi: format!("cfi-{}", id),
+                before: None,
+                after: Some(highlight("yellow", "")),
+                undoes: None,
+                undone: false,
+            });
+        }
+        save_history(&hash, &mut history).unwrap();
+
+        let changes = list_annotation_history(hash.clone()).unwrap();
+        assert_eq!(changes.len(), MAX_HISTORY);
+        assert_eq!(changes.first().unwrap().id, MAX_HISTORY as u64 + 5);
+        assert_eq!(changes.last().unwrap().id, 6);
+
+        // Ids keep counting after the oldest entries are dropped
+        record_changes(&hash, None, &payload(json!({"new": {"color": "red"}}))).unwrap();
+        assert_eq!(list_annotation_history(hash).unwrap()[0].id, MAX_HISTORY as u64 + 6);
+    }
+
+    #[test]
+    fn undoes_changes() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        book_state::upsert_highlight(hash.clone(), "a".to_string(), highlight("yellow", ""), None).unwrap();
+        book_state::upsert_highlight(hash.clone(), "a".to_string(), highlight("red", ""), None).unwrap();
+        book_state::upsert_highlight(hash.clone(), "b".to_string(), highlight("cyan", ""), None).unwrap();
+
+        // The latest change is the added "b"
+        let payload = undo_annotation_change(hash.clone(), None).unwrap();
+        assert!(!payload.data.highlights.contains_key("b"));
+        assert_eq!(payload.data.highlights["a"].color, "red");
+
+        // Then the recolor of "a", undo entries themselves are skipped
+        let payload = undo_annotation_change(hash.clone(), None).unwrap();
+        assert_eq!(payload.data.highlights["a"].color, "yellow");
+        assert_eq!(crate::read_book_data(&hash).unwrap().data.highlights["a"].color, "yellow");
+
+        let changes = list_annotation_history(hash.clone()).unwrap();
+        assert_eq!(changes[0].kind, "undo");
+        assert_eq!(changes[0].undoes, Some(2));
+        assert_eq!(changes[0].before.as_ref().unwrap().color, "red");
+        assert_eq!(changes[0].after.as_ref().unwrap().color, "yellow");
+        assert!(changes.iter().find(|change| change.id == 2).unwrap().undone);
+
+        assert!(undo_annotation_change(hash.clone(), Some(2)).unwrap_err().contains("already undone"));
+        assert!(undo_annotation_change(hash.clone(), Some(99)).is_err());
+        // An undo can itself be undone by id
+        let payload = undo_annotation_change(hash, Some(changes[0].id)).unwrap();
+        assert_eq!(payload.data.highlights["a"].color, "red");
+    }
+
+    #[test]
+    fn sets_a_damaged_journal_aside() {
+        let _library = crate::shared_test_library();
+        let hash = book();
+        std::fs::write(history_path(&hash), "{\"changes\": [").unwrap();
+        assert!(list_annotation_history(hash.clone()).is_err());
+        assert!(undo_annotation_change(hash.clone(), None).is_err());
+
+        record_changes(&hash, None, &payload(json!({"a": {"color": "red"}}))).unwrap();
+        assert_eq!(list_annotation_history(hash.clone()).unwrap().len(), 1);
+        let broken = history_path(&hash).with_file_name(format!("{}.broken", HISTORY_FILE));
+        assert_eq!(std::fs::read_to_string(broken).unwrap(), "{\"changes\": [");
+    }
+}
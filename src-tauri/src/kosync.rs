@@ -0,0 +1,317 @@
+// Reading progress sync with KOReader through a kosync server
+// https://github.com/koreader/koreader-sync-server
+// Books are identified by KOReader's document digest, an MD5 of samples of the file or of its
+// file name, depending on the `kosyncDigest` setting. Positions are converted between our CFIs
+// and KOReader's xpointers, the percentage maps to `progress` directly.
+
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::asset_server::PRIVATE_EXTENSIONS;
+use crate::cfi::CfiResolver;
+use crate::settings::{self, Settings};
+use crate::{book_state, current_time_millis, get_config_path};
+
+const ACCEPT_V1: &str = "application/vnd.koreader.v1+json";
+
+// What the server stores for a document, fields are missing when nothing was pushed yet
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct RemoteProgress {
+    document: String,
+    progress: String,
+    percentage: f64,
+    device: String,
+    device_id: String,
+    // Seconds
+    timestamp: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KosyncStatus {
+    document: String,
+    percentage: f64,
+    xpointer: String,
+    // None when the xpointer could not be found in our copy of the book
+    cfi: Option<String>,
+    device: String,
+    timestamp: u64,
+    // Whether pulling moved our position
+    applied: bool,
+}
+
+// KOReader's partial MD5: 1 KiB samples at 0 and at 1 KiB * 4^i for i up to 10
+// https://github.com/koreader/koreader/blob/master/frontend/util.lua (partialMD5)
+fn partial_md5(path: &Path) -> Result<String, String> {
+    let mut file = fs::File::open(path).map_err(|e| format!("Error: Could not open \"{}\" : {}", path.display(), e))?;
+    let mut context = md5::Context::new();
+    let mut buffer = [0u8; 1024];
+    for i in -1..=10 {
+        let offset: u64 = if i < 0 { 0 } else { 1024 << (2 * i) };
+        file.seek(SeekFrom::Start(offset)).map_err(|e| format!("Error: {}", e))?;
+        let read = file.read(&mut buffer).map_err(|e| format!("Error: {}", e))?;
+        if read == 0 {
+            break;
+        }
+        context.consume(&buffer[..read]);
+    }
+    return Ok(format!("{:x}", context.compute()));
+}
+
+// The imported file and, for converted books, the EPUB we read positions from
+fn book_files(hash: &str) -> Result<(PathBuf, Option<PathBuf>), String> {
+    let folder = get_config_path().join("books").join(hash);
+    let mut files: Vec<PathBuf> = fs::read_dir(&folder)
+        .map_err(|e| format!("Error: Book not found - {} : {}", hash, e))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+            return path.is_file() && name != "cover.jpg" && !PRIVATE_EXTENSIONS.iter().any(|extension| name.ends_with(extension));
+        })
+        .collect();
+    files.sort();
+    let is_epub = |path: &PathBuf| path.extension().map(|extension| extension.eq_ignore_ascii_case("epub")).unwrap_or(false);
+    let epub = files.iter().find(|path| is_epub(path)).cloned();
+    // Mobi and azw3 books are converted on import, KOReader opens the original
+    let original = files.iter().find(|path| !is_epub(path)).cloned().or(epub.clone());
+    return Ok((original.ok_or(format!("Error: Book not found - {}", hash))?, epub));
+}
+
+fn document_digest(settings: &Settings, file: &Path) -> Result<String, String> {
+    if settings.kosyncDigest == "filename" {
+        let name = file.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        return Ok(format!("{:x}", md5::compute(name.as_bytes())));
+    }
+    return partial_md5(file);
+}
+
+fn request(settings: &Settings, method: Method, path: &str) -> RequestBuilder {
+    return reqwest::Client::new()
+        .request(method, format!("{}{}", settings.kosyncServer.trim_end_matches('/'), path))
+        .header("accept", ACCEPT_V1)
+        .header("x-auth-user", &settings.kosyncUsername)
+        .header("x-auth-key", &settings.kosyncKey);
+}
+
+async fn send(builder: RequestBuilder) -> Result<reqwest::Response, String> {
+    let response = builder.send().await.map_err(|e| format!("Error: Could not reach the KOReader sync server : {}", e))?;
+    return match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err("Error: KOReader sync server rejected the username or password".to_string()),
+        status => {
+            let body = response.text().await.unwrap_or_default();
+            Err(format!("Error: KOReader sync server answered {} {}", status, body))
+        }
+    };
+}
+
+fn logged_in_settings() -> Result<Settings, String> {
+    let settings = settings::load();
+    if settings.kosyncUsername.len() == 0 || settings.kosyncKey.len() == 0 {
+        return Err("Error: Not logged in to a KOReader sync server".to_string());
+    }
+    return Ok(settings);
+}
+
+// Checks the credentials, optionally registering the account first, and saves them
+#[tauri::command]
+pub async fn kosync_login(server: String, username: String, password: String, register: bool) -> Result<(), String> {
+    let mut settings = settings::load();
+    settings.kosyncServer = server;
+    settings.kosyncUsername = username;
+    settings.kosyncKey = format!("{:x}", md5::compute(password.as_bytes()));
+
+    if register {
+        let body = json!({ "username": settings.kosyncUsername, "password": settings.kosyncKey });
+        let created = send(request(&settings, Method::POST, "/users/create").header("content-type", "application/json").body(body.to_string())).await;
+        // 402 when the username is taken, the auth check below tells whether it is ours
+        if let Err(error) = created {
+            if !error.contains("402") {
+                return Err(error);
+            }
+        }
+    }
+    send(request(&settings, Method::GET, "/users/auth")).await?;
+
+    let mut patch = Map::new();
+    patch.insert("kosyncServer".to_string(), Value::from(settings.kosyncServer));
+    patch.insert("kosyncUsername".to_string(), Value::from(settings.kosyncUsername));
+    patch.insert("kosyncKey".to_string(), Value::from(settings.kosyncKey));
+    if settings.kosyncDeviceId.len() == 0 {
+        let device_id: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        patch.insert("kosyncDeviceId".to_string(), Value::from(device_id));
+    }
+    settings::update_settings(patch)?;
+    return Ok(());
+}
+
+// Sends our position in a book to the server
+#[tauri::command]
+pub async fn kosync_push(hash: String) -> Result<KosyncStatus, String> {
+    let settings = logged_in_settings()?;
+    let (file, epub) = book_files(&hash)?;
+    let document = document_digest(&settings, &file)?;
+    let data = book_state::get(&hash)?;
+    let xpointer = epub
+        .and_then(|epub| CfiResolver::open(&epub))
+        .and_then(|mut resolver| resolver.xpointer(&data.data.cfi))
+        .ok_or(format!("Error: Could not find the reading position of {} in the book", data.title))?;
+
+    let body = json!({
+        "document": document,
+        "progress": xpointer,
+        "percentage": data.data.progress,
+        "device": settings.kosyncDevice,
+        "device_id": settings.kosyncDeviceId,
+    });
+    send(request(&settings, Method::PUT, "/syncs/progress").header("content-type", "application/json").body(body.to_string())).await?;
+    return Ok(KosyncStatus {
+        document,
+        percentage: data.data.progress,
+        xpointer,
+        cfi: Some(data.data.cfi),
+        device: settings.kosyncDevice,
+        timestamp: current_time_millis() / 1000,
+        applied: false,
+    });
+}
+
+// Fetches the server's position for a book and moves ours there when it is newer
+// None when no device has pushed progress for the book yet
+#[tauri::command]
+pub async fn kosync_pull(hash: String) -> Result<Option<KosyncStatus>, String> {
+    let settings = logged_in_settings()?;
+    let (file, epub) = book_files(&hash)?;
+    let document = document_digest(&settings, &file)?;
+
+    let response = send(request(&settings, Method::GET, &format!("/syncs/progress/{}", document))).await?;
+    let body = response.text().await.map_err(|e| format!("Error: {}", e))?;
+    let remote: RemoteProgress = serde_json::from_str(&body).map_err(|e| format!("Malformed Data: {}", e))?;
+    if remote.progress.len() == 0 {
+        return Ok(None);
+    }
+
+    let cfi = epub
+        .and_then(|epub| CfiResolver::open(&epub))
+        .and_then(|mut resolver| resolver.cfi_for_xpointer(&remote.progress));
+    let local = book_state::get(&hash)?;
+    let newer = remote.timestamp * 1000 > local.modified && remote.device_id != settings.kosyncDeviceId;
+    let mut applied = false;
+    if let (Some(cfi), true) = (&cfi, newer) {
+        book_state::update(&hash, None, |payload, _previous| {
+            payload.data.progress = remote.percentage;
+            payload.data.cfi = cfi.clone();
+            payload.modified = remote.timestamp * 1000;
+            return Ok(());
+        })?;
+        applied = true;
+    }
+    return Ok(Some(KosyncStatus {
+        document,
+        percentage: remote.percentage,
+        xpointer: remote.progress,
+        cfi,
+        device: remote.device,
+        timestamp: remote.timestamp,
+        applied,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("alexandria-kosync-{}-{}", name, current_time_millis()));
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join(name);
+        fs::write(&path, contents).unwrap();
+        return path;
+    }
+
+    // Answers a single request with `status`
+    fn answer_once(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    line.clear();
+                }
+                let mut stream = stream;
+                let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: 4\r\nConnection: close\r\n\r\ntext", status);
+            }
+        });
+        return url;
+    }
+
+    #[test]
+    fn partial_md5_of_a_small_file_is_its_md5() {
+        let path = temp_file("small.epub", b"not much of a book");
+        assert_eq!(partial_md5(&path).unwrap(), format!("{:x}", md5::compute(b"not much of a book")));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn partial_md5_samples_like_koreader() {
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let path = temp_file("large.epub", &contents);
+
+        let mut samples = Vec::new();
+        for offset in [0usize, 1024, 4096, 16384, 65536, 262144] {
+            samples.extend_from_slice(&contents[offset..(offset + 1024).min(contents.len())]);
+        }
+        assert_eq!(partial_md5(&path).unwrap(), format!("{:x}", md5::compute(&samples)));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn digests_by_file_name_when_configured() {
+        let path = temp_file("Some Book.epub", b"contents");
+        let settings = Settings {
+            kosyncDigest: "filename".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(document_digest(&settings, &path).unwrap(), format!("{:x}", md5::compute("Some Book.epub")));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn authenticates_requests() {
+        let settings = Settings {
+            kosyncServer: "https://sync.koreader.rocks/".to_string(),
+            kosyncUsername: "reader".to_string(),
+            kosyncKey: "key".to_string(),
+            ..Default::default()
+        };
+        let request = request(&settings, Method::GET, "/users/auth").build().unwrap();
+        assert_eq!(request.url().as_str(), "https://sync.koreader.rocks/users/auth");
+        assert_eq!(request.headers()["accept"], ACCEPT_V1);
+        assert_eq!(request.headers()["x-auth-user"], "reader");
+        assert_eq!(request.headers()["x-auth-key"], "key");
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_credentials() {
+        let url = answer_once("401 Unauthorized");
+        let error = send(reqwest::Client::new().get(url)).await.unwrap_err();
+        assert_eq!(error, "Error: KOReader sync server rejected the username or password");
+
+        let url = answer_once("402 Payment Required");
+        let error = send(reqwest::Client::new().post(url)).await.unwrap_err();
+        assert!(error.contains("402"));
+    }
+}
@@ -11,7 +11,7 @@ use std::{
     collections::{HashMap, HashSet},
     env::{self, current_dir},
     fs::{self, File},
-    io::{BufReader, Read, Write},
+    io::{Read, Write},
     path::{Path, PathBuf},
 };
 
@@ -20,18 +20,28 @@ use libmobi_rs::convertToEpubWrapper;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use axum::{
-    http::{HeaderValue, Method},
-    routing::get,
-    Router,
-};
-use tower_http::cors::CorsLayer;
-use tower_http::services::{ServeDir, ServeFile};
-
 extern crate reqwest;
 
 use font_kit::source::SystemSource;
 
+mod annotation_import;
+mod annotations;
+mod asset_server;
+mod auto_backup;
+mod backup;
+mod book_state;
+mod calibre;
+mod cfi;
+mod cli;
+mod history;
+mod kosync;
+mod merge;
+mod migrations;
+mod opds;
+mod opds_client;
+mod settings;
+mod storage;
+mod sync;
 
 use std::io;
 use tauri::{api::path::app_data_dir, Manager};
@@ -42,6 +52,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
     static app_data_platform_dir: OnceLock<PathBuf> = OnceLock::new();
     static config_path: OnceLock<PathBuf> = OnceLock::new();
     static font_folder: OnceLock<PathBuf> = OnceLock::new();
+    static app_handle: OnceLock<tauri::AppHandle> = OnceLock::new();
 
     fn get_config_path() -> PathBuf{
         return config_path.get().unwrap().clone();
@@ -52,56 +63,99 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 
     
+// Resolves Alexandria_Data for the app and for the command line
+fn init_paths(config: &tauri::Config) {
+    let appDataDir = app_data_dir(config).unwrap();
+
+    if cfg!(target_os = "windows") || cfg!(dev) {
+        let currentDir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        
+        let program_files_path = Path::new("C:\\Program Files");
+        // If the parent directory is Program Files, The file was installed
+        // Otherwise, Run in portable
+        if(currentDir.parent().unwrap() == program_files_path){
+            app_data_platform_dir.set(appDataDir);
+        }else{
+            app_data_platform_dir.set(currentDir);
+        }
+    } else {
+        app_data_platform_dir.set(appDataDir);
+    }
+
+    config_path.set(app_data_platform_dir.get().unwrap().join("Alexandria_Data"));
+    font_folder.set(get_config_path().join("fonts"));
+}
+
+// Tests share one Alexandria_Data in the temp folder, each test uses its own book hashes
+#[cfg(test)]
+fn test_config_path() -> PathBuf {
+    let path = config_path.get_or_init(|| {
+        let path = env::temp_dir().join(format!("alexandria-test-{}-{}", std::process::id(), current_time_millis()));
+        fs::create_dir_all(path.join("books")).unwrap();
+        fs::create_dir_all(path.join("fonts")).unwrap();
+        return path;
+    });
+    font_folder.get_or_init(|| path.join("fonts"));
+    return path.clone();
+}
+
+// Tests that write or read the whole library, such as backups, hold the library exclusively,
+// tests that only touch their own books share it
+#[cfg(test)]
+static test_library: std::sync::RwLock<()> = std::sync::RwLock::new(());
+
+#[cfg(test)]
+fn shared_test_library() -> std::sync::RwLockReadGuard<'static, ()> {
+    test_config_path();
+    return test_library.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+}
+
+#[cfg(test)]
+fn exclusive_test_library() -> std::sync::RwLockWriteGuard<'static, ()> {
+    test_config_path();
+    return test_library.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+}
+
+// Adds a book with the given data to the test library and returns its hash
+#[cfg(test)]
+fn test_book(name: &str, data: serde_json::Value) -> String {
+    let hash = format!("{}-{}", name, rand::random::<u32>());
+    fs::create_dir_all(test_config_path().join("books").join(&hash)).unwrap();
+    write_book_data(&hash, &serde_json::from_value(data).unwrap()).unwrap();
+    return hash;
+}
+
 #[tokio::main]
 async fn main() {
+    let context = tauri::generate_context!();
+    // Subcommands such as `app list` manage the library without opening a window, see cli.rs
+    if let Some(code) = cli::run(&context) {
+        std::process::exit(code);
+    }
+
     tauri::Builder::default()
         .setup(|app| {
             println!("Loading Config Directory");
-            let appDataDir = app_data_dir( app.config().as_ref()).unwrap();
-
-            if cfg!(target_os = "windows") || cfg!(dev) {
-                let currentDir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
-                
-                let program_files_path = Path::new("C:\\Program Files");
-                // If the parent directory is Program Files, The file was installed
-                // Otherwise, Run in portable
-                if(currentDir.parent().unwrap() == program_files_path){
-                    app_data_platform_dir.set(appDataDir);
-                }else{
-                    app_data_platform_dir.set(currentDir);
-                }
-            } else {
-                app_data_platform_dir.set(appDataDir);
-            }
-
-            config_path.set(app_data_platform_dir.get().unwrap().join("Alexandria_Data"));
+            init_paths(app.config().as_ref());
 
             // Required to allow client side to access the config path
             app.fs_scope().allow_directory(get_config_path(), true);
-            font_folder.set(get_config_path().join("fonts"));
+            app_handle.set(app.handle());
             
 
 
-            create_or_load_data();
+            create_or_load_data()?;
 
+            tokio::spawn(cli::mark_running());
+            tokio::spawn(auto_backup::run_scheduler());
+            tokio::spawn(sync::run_scheduler());
+            opds::apply_saved();
 
-            // https://github.com/tranxuanthang/lrcget/commit/0a2fe9943e40503a1dc5d9bf291314f31ea66941
-            // https://github.com/tauri-apps/tauri/issues/3725#issuecomment-1552804332
+            // Without the server books are loaded through the asset protocol instead
             #[cfg(target_os = "linux")]
-            tokio::spawn(async move {
-                println!("Serving {}", app_data_platform_dir.get().unwrap().display());
-                let serve_dir = ServeDir::new(app_data_platform_dir.get().unwrap());
-
-                let axum_app = Router::new().nest_service("/", serve_dir).layer(
-                    CorsLayer::new()
-                        .allow_origin("*".parse::<HeaderValue>().unwrap())
-                        .allow_methods([Method::GET]),
-                );
-                axum::Server::bind(&"127.0.0.1:16780".parse().unwrap())
-                    .serve(axum_app.into_make_service())
-                    .await
-                    .unwrap();
-            });
+            if let Err(error) = asset_server::start() {
+                println!("{}", error);
+            }
 
             Ok(())
         })
@@ -118,15 +172,54 @@ async fn main() {
             delete_font,
             set_global_themes,
             get_global_themes,
-            set_settings,
-            get_settings,
+            settings::get_settings,
+            asset_server::get_asset_server_url,
+            opds::get_opds_url,
+            opds_client::browse_opds,
+            opds_client::search_opds,
+            opds_client::import_opds_book,
+            calibre::import_calibre_library,
+            kosync::kosync_login,
+            kosync::kosync_push,
+            kosync::kosync_pull,
+            sync::sync_now,
+            sync::get_sync_status,
+            settings::update_settings,
             delete_book,
             get_config_path_js,
             add_system_font,
-            list_system_fonts
+            list_system_fonts,
+            annotations::export_annotations,
+            annotations::list_annotations,
+            annotation_import::import_annotations,
+            annotations::rename_bookmark,
+            annotations::reorder_bookmarks,
+            history::list_annotation_history,
+            history::undo_annotation_change,
+            book_state::set_progress,
+            book_state::upsert_highlight,
+            book_state::delete_highlight,
+            book_state::toggle_bookmark,
+            book_state::set_book_theme,
+            backup::export_backup,
+            backup::import_backup,
+            backup::export_data_backup,
+            backup::import_data_backup,
+            auto_backup::list_backups,
+            auto_backup::create_backup,
+            auto_backup::restore_backup
         ])
-        .run(tauri::generate_context!()) // Create a ../dist folder if it there is an error on this line
-        .expect("error while running tauri application");
+        .build(context) // Create a ../dist folder if it there is an error on the generate_context line
+        .expect("error while running tauri application")
+        .run(|_app, event| {
+            // Deferred book data writes must not be lost when the last window closes
+            if let tauri::RunEvent::Exit = event {
+                book_state::flush_all();
+                asset_server::stop();
+                opds::stop();
+                cli::clear_running();
+            }
+        });
 }
 
 
@@ -137,12 +230,17 @@ enum DataExists {
     LOADED,
 }
 
-fn create_or_load_data() -> Option<DataExists> {
+// Creates Alexandria_Data on first launch, otherwise brings its files up to the current version
+fn create_or_load_data() -> Result<DataExists, String> {
 
     let data_exists: bool = config_path.get().unwrap().exists();
     // println!("THIS IS THE CONFIG PATH: {}", config_path.as_str());
     if (data_exists) {
-        return Some(DataExists::LOADED);
+        // A restore that was cut off must be undone before its half swapped files are read
+        backup::recover_restore()?;
+        let migrated = migrations::run()?;
+        println!("Migrated {} data files", migrated);
+        return Ok(DataExists::LOADED);
     } else {
         println!("{:?}",&*config_path.get().unwrap());
         std::fs::create_dir_all(&*config_path.get().unwrap()).unwrap();
@@ -151,22 +249,30 @@ fn create_or_load_data() -> Option<DataExists> {
 
         std::fs::create_dir(get_config_path().join("fonts")).unwrap();
 
-        std::fs::write(get_config_path().join("settings.json"), "{}").unwrap();
-        std::fs::write(get_config_path().join("ReaderThemes.json"), "{}").unwrap();
-        std::fs::write(get_config_path().join("GlobalThemes.json"), "{}").unwrap();
-        std::fs::write(get_config_path().join("fonts").join("fonts.json"), "{}").unwrap();
+        storage::write_json(&get_config_path().join("settings.json"), &json!({ "version": migrations::SETTINGS_VERSION }))?;
+        storage::write_json(&get_config_path().join("ReaderThemes.json"), &json!({ "version": migrations::THEMES_VERSION }))?;
+        storage::write_json(&get_config_path().join("GlobalThemes.json"), &json!({ "version": migrations::THEMES_VERSION }))?;
+        storage::write_json(&get_config_path().join("fonts").join("fonts.json"), &json!({ "fonts": {}, "version": migrations::FONTS_VERSION }))?;
 
-        return Some(DataExists::CREATED);
+        return Ok(DataExists::CREATED);
     }
 }
 
 #[tauri::command]
 fn import_book(payload: String) -> Result<BookHydrate, String> {
     let path = Path::new(&payload);
-    let mut f = File::open(&path).map_err(|e| format!("Error: Could not access file \"{}\" : {}",path.file_name().unwrap().to_str().unwrap(),  e))?;
+    // The extension decides how the book is read, files without one can not be imported
+    let name = path.file_name().and_then(|name| name.to_str());
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let stem = path.file_stem().and_then(|stem| stem.to_str());
+    let (bookFileName, file_extension_unwrapped, file_stem_unwrapped) = match (name, extension, stem) {
+        (Some(name), Some(extension), Some(stem)) => (name, extension, stem),
+        _ => return Err(format!("Error: Not a book file - \"{}\"", path.display())),
+    };
+    let mut f = File::open(&path).map_err(|e| format!("Error: Could not access file \"{}\" : {}", bookFileName, e))?;
     let mut buffer = Vec::new();
     // read the whole file
-    f.read_to_end(&mut buffer).unwrap();
+    f.read_to_end(&mut buffer).map_err(|e| format!("Error: Could not read \"{}\" : {}", bookFileName, e))?;
 
     let checksum = get_hash(&buffer);
 
@@ -183,13 +289,14 @@ fn import_book(payload: String) -> Result<BookHydrate, String> {
             return Err(format!("Error: Book is duplicate - {checksum}").to_string());
         }
     };
-    let bookFileName = path.file_name().unwrap().to_str().unwrap();
     let bookLocation = hashed_book_folder.join(&bookFileName);
     let hashed_book_folder_unwrapped = hashed_book_folder.to_str().unwrap();
-    let file_extension_unwrapped = path.extension().unwrap().to_str().unwrap();
-    let file_stem_unwrapped = path.file_stem().unwrap().to_str().unwrap();
 
-    std::fs::write(&bookLocation, &buffer).unwrap();
+    if let Err(error) = std::fs::write(&bookLocation, &buffer) {
+        // Otherwise the empty folder would make the next attempt a duplicate
+        let _ = std::fs::remove_dir_all(&hashed_book_folder);
+        return Err(format!("Error: Could not save \"{}\" : {}", bookFileName, error));
+    }
 
     // This variable will hold whether or not file processing can be done on the back end
     let mut is_parsable = bookFileName.contains(".epub") || bookFileName.contains(".epub");
@@ -242,7 +349,9 @@ fn import_book(payload: String) -> Result<BookHydrate, String> {
      let mut doc = match EpubDoc::new(&docLocation) {
         Ok(v) => v,
         Err(_error) => {
-            delete_book(checksum.as_str());
+            if let Err(error) = delete_book(checksum.as_str()) {
+                println!("{}", error);
+            }
             return Err(format!("Error: Import of {} Failed", &file_stem_unwrapped));
         }
     };
@@ -290,6 +399,7 @@ fn import_book(payload: String) -> Result<BookHydrate, String> {
     println!("Milliseconds since the epoch (u64): {}", milliseconds_u64);
 
     let initial_data = json!({
+        "version": migrations::BOOK_DATA_VERSION,
         "title": title,
         "author": author,
         "modified": milliseconds_u64,
@@ -299,13 +409,7 @@ fn import_book(payload: String) -> Result<BookHydrate, String> {
         }
     });
 
-    let initial_data = serde_json::to_string_pretty(&initial_data).unwrap();
-
-    std::fs::write(
-        hashed_book_folder.join(format!("{checksum}.json")),
-        initial_data,
-    )
-    .unwrap();
+    storage::write_json(&hashed_book_folder.join(format!("{checksum}.json")), &initial_data)?;
 
     let response = BookHydrate {
         cover_url: if coverExists {
@@ -324,6 +428,11 @@ fn import_book(payload: String) -> Result<BookHydrate, String> {
     return Ok(response);
 }
 
+fn current_time_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+    return since_epoch.as_millis().min(u64::MAX as u128) as u64;
+}
+
 fn get_hash(data: &Vec<u8>) -> String {
     let c: &[u8] = &data;
     let checksum = crc32fast::hash(c);
@@ -371,18 +480,21 @@ fn get_books() -> Vec<BookHydrate> {
         for book_file in book_folder {
             let book_file = book_file.unwrap().path().display().to_string();
             let is_epub = book_file.contains(".epub");
-            let is_data = book_file.contains(".json") && !book_file.contains("locations_cache.json");
+            // Only <hash>.json holds book data, other json files are caches and journals
+            let is_data = book_file.ends_with(&format!("{}.json", file_hash));
             let is_cover = book_file.contains(".jpg");
 
             if is_epub {
                 epub_path.push_str(&book_file);
             } else if is_data {
                 println!("PRINTING JSON FILE: {}", &book_file);
-                let file = File::open(&book_file).unwrap();
-                let reader = BufReader::new(file);
-
-                let json: serde_json::Value =
-                    serde_json::from_reader(reader).expect("JSON was not well-formatted");
+                let json: serde_json::Value = match storage::read_json(Path::new(&book_file)) {
+                    Ok(json) => json,
+                    Err(error) => {
+                        println!("{}", error);
+                        serde_json::Value::Null
+                    }
+                };
                 match json.get("title") {
                     Some(value) => title.push_str(value.as_str().unwrap_or("unknown")),
                     None => title.push_str("unknown"),
@@ -395,7 +507,7 @@ fn get_books() -> Vec<BookHydrate> {
                 let t = &json["data"]["progress"];
 
                 let t = t.as_f64();
-                progress = t.unwrap();
+                progress = t.unwrap_or(0.0);
 
                 let now = SystemTime::now();
                 let since_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
@@ -454,12 +566,87 @@ fn get_book_by_hash(bookHash: String) -> String {
     return "".to_string();
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 struct highlightData {
+    #[serde(default)]
     color: String,
+    #[serde(default)]
     note: String,
+    // Fields below were added after 0.13, older files will not contain them
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    chapter: String,
+    #[serde(default)]
+    created: u64,
+    #[serde(default)]
+    modified: u64,
+    #[serde(default)]
+    tags: Vec<String>,
+    // Fields from other versions of Alexandria, written back unchanged
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(from = "bookmarkFormat")]
+struct bookmarkData {
+    cfi: String,
+    label: String,
+    excerpt: String,
+    chapter: String,
+    created: u64,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+// Bookmarks used to be saved as bare CFI strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum bookmarkFormat {
+    Cfi(String),
+    Full {
+        #[serde(default)]
+        cfi: String,
+        #[serde(default)]
+        label: String,
+        #[serde(default)]
+        excerpt: String,
+        #[serde(default)]
+        chapter: String,
+        #[serde(default)]
+        created: u64,
+        #[serde(flatten)]
+        extra: HashMap<String, serde_json::Value>,
+    },
+}
+
+impl From<bookmarkFormat> for bookmarkData {
+    fn from(format: bookmarkFormat) -> Self {
+        match format {
+            bookmarkFormat::Cfi(cfi) => bookmarkData {
+                cfi,
+                ..Default::default()
+            },
+            bookmarkFormat::Full {
+                cfi,
+                label,
+                excerpt,
+                chapter,
+                created,
+                extra,
+            } => bookmarkData {
+                cfi,
+                label,
+                excerpt,
+                chapter,
+                created,
+                extra,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct themePayload {
     #[serde(default)]
     themeName: String,
@@ -479,24 +666,39 @@ struct themePayload {
     paragraphSpacing: i64,
     #[serde(default)]
     textAlign: String,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 
 
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct updateDataPayload {
     progress: f64,
     #[serde(default)]
     cfi: String,
     #[serde(default)]
-    bookmarks: Vec<String>,
+    bookmarks: Vec<bookmarkData>,
     #[serde(default)]
     highlights: HashMap<String, highlightData>,
+    // Time each deleted annotation was deleted, by CFI, so merging with an older copy does not restore it
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    deletedHighlights: HashMap<String, u64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    deletedBookmarks: HashMap<String, u64>,
     #[serde(default)]
     theme: themePayload,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct updateBookPayload {
+    // Bumped by the backend on every save, views send back the revision they loaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revision: Option<u64>,
+    // Schema version of the file, see migrations.rs
+    #[serde(default = "bookDataVersion")]
+    version: String,
     #[serde(default)]
     title: String,
     #[serde(default)]
@@ -505,37 +707,51 @@ struct updateBookPayload {
     modified: u64,
     #[serde(default)]
     data: updateDataPayload,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+fn bookDataVersion() -> String {
+    migrations::BOOK_DATA_VERSION.to_string()
 }
 
+// `source` identifies the calling view in the change event so it can skip its own writes
 #[tauri::command]
-fn update_data_by_hash(payload: updateBookPayload, hash: String) {
-    // println!("{:?}", payload);
-    println!("{:?}", serde_json::to_string_pretty(&payload).unwrap());
+fn update_data_by_hash(payload: updateBookPayload, hash: String, source: Option<String>) -> Result<u64, String> {
+    let checksum = hash;
 
+    let saved = book_state::save(&checksum, payload, source)?;
+    println!("Saved {} at revision {:?}", checksum, saved.revision);
 
-    let checksum = hash;
+    return Ok(saved.revision.unwrap_or(0));
+}
 
+fn write_book_data(checksum: &str, payload: &updateBookPayload) -> Result<(), String> {
     let hashed_book_folder = get_config_path().join("books").join(format!("{checksum}/{checksum}.json"));
 
-    std::fs::write(
-        hashed_book_folder,
-        serde_json::to_string_pretty(&payload).unwrap(),
-    )
-    .unwrap();
+    storage::write_json(&hashed_book_folder, payload)
 }
 
-#[tauri::command]
-fn load_book_data(checksum: &str) -> Result<updateBookPayload, String> {
+fn read_book_data(checksum: &str) -> Result<updateBookPayload, String> {
     let file_path = get_config_path().join("books").join(checksum).join(format!("{}.json", checksum));
-    let file = File::open(&file_path).unwrap();
+    let bookPayload: updateBookPayload = storage::read_json(&file_path)?;
+    return Ok(bookPayload);
+}
 
-    let reader = BufReader::new(file);
+// Path of the epub version of a book, if one exists
+fn get_epub_path(checksum: &str) -> Option<PathBuf> {
+    let book_path = PathBuf::from(get_book_by_hash(checksum.to_string()));
+    let is_epub = book_path.extension().map(|ext| ext == "epub" || ext == "epub3").unwrap_or(false);
+    if is_epub {
+        return Some(book_path);
+    }
+    return None;
+}
 
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
+#[tauri::command]
+fn load_book_data(checksum: &str) -> Result<updateBookPayload, String> {
     println!("About to check malformed");
-    let bookPayload: updateBookPayload =
-        serde_json::from_value(json).map_err(|e| format!("Malformed Data: {}", e))?;
+    let bookPayload: updateBookPayload = book_state::get(checksum)?;
     if (bookPayload.data.cfi == "") {
         println!("RETURNING FIRST READ");
         return Err(String::from("First Read"));
@@ -552,9 +768,16 @@ fn load_book_data(checksum: &str) -> Result<updateBookPayload, String> {
 }
 
 #[tauri::command]
-fn delete_book(checksum: &str) {
+fn delete_book(checksum: &str) -> Result<(), String> {
+    // Anything else would reach outside the books folder
+    if checksum.len() == 0 || checksum.contains(['/', '\\', '.']) {
+        return Err(format!("Error: Book not found - {}", checksum));
+    }
     let file_path = get_config_path().join("books").join(checksum);
-    fs::remove_dir_all(file_path).unwrap();
+    let removed = fs::remove_dir_all(file_path);
+    // Evicted even when only some files could be removed
+    book_state::evict(checksum);
+    return removed.map_err(|e| format!("Error: Could not delete book {} : {}", checksum, e));
 }
 
 #[tauri::command]
@@ -616,98 +839,78 @@ struct fontsJSON {
     version: String
 }
 fn fontsJSONVersion() -> String {
-    "0.11".to_string()
+    migrations::FONTS_VERSION.to_string()
+}
+
+fn read_fonts_json() -> Result<fontsJSON, String> {
+    return storage::read_json(&get_font_folder_path().join("fonts.json"));
+}
+
+fn write_fonts_json(fontsPayload: &fontsJSON) -> Result<(), String> {
+    return storage::write_json(&get_font_folder_path().join("fonts.json"), fontsPayload);
 }
 
 #[tauri::command]
 async fn download_font(url: &str, name: &str, weight: &str) -> Result<String, String> {
+    let file_name = format!("{name} - {weight}.ttf");
+    if !sync::is_plain_name(name) || !sync::is_plain_name(&file_name) {
+        return Err(format!("Malformed Data: Font name \"{}\" is not a valid folder name", name));
+    }
 
     let resp = reqwest::get(url).await.map_err(|e| format!("Malformed Data: {}", e))?;
     let body = resp.bytes().await.map_err(|e| format!("Malformed Data: {}", e))?;
-    fs::create_dir_all(get_font_folder_path().join(name));
-
-    std::fs::write(get_font_folder_path().join(name).join(format!("{name} - {weight}.ttf")), &body);
-
-    let file = File::open(get_font_folder_path().join("fonts.json")).unwrap();
-
-    let reader = BufReader::new(file);
+    let folder_path = get_font_folder_path().join(name);
+    fs::create_dir_all(&folder_path).map_err(|e| format!("Error: Could not create folder for font {} : {}", name, e))?;
 
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
+    storage::write_atomic(&folder_path.join(file_name), &body)
+        .map_err(|e| format!("Error: Could not save font {} : {}", name, e))?;
 
-    let mut fontsPayload: fontsJSON = serde_json::from_value(json).unwrap();
-    // println!("File Hash: {}", &fontsPayload);
-    println!("{:?}", serde_json::to_string_pretty(&fontsPayload).unwrap());
+    let mut fontsPayload: fontsJSON = read_fonts_json()?;
 
     fontsPayload.fonts.insert(format!("{name}"), true);
 
-    std::fs::write(
-        get_font_folder_path().join("fonts.json"),
-        serde_json::to_string_pretty(&fontsPayload).unwrap(),
-    );
+    write_fonts_json(&fontsPayload)?;
     return Ok("Ok".to_string());
 }
 
 #[tauri::command]
 async fn add_system_font(name: &str) -> Result<String, String> {
 
-
-
-    let file = File::open(get_font_folder_path().join("fonts.json")).unwrap();
-
-    let reader = BufReader::new(file);
-
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
-
-    let mut fontsPayload: fontsJSON = serde_json::from_value(json).unwrap();
-    // println!("File Hash: {}", &fontsPayload);
-    println!("{:?}", serde_json::to_string_pretty(&fontsPayload).unwrap());
+    let mut fontsPayload: fontsJSON = read_fonts_json()?;
 
     fontsPayload.fonts.insert(format!("{name}"), false);
 
-    std::fs::write(
-        get_font_folder_path().join("fonts.json"),
-        serde_json::to_string_pretty(&fontsPayload).unwrap(),
-    );
+    write_fonts_json(&fontsPayload)?;
     return Ok("Ok".to_string());
 }
 
 #[tauri::command]
-fn delete_font(name: &str) {
+fn delete_font(name: &str) -> Result<(), String> {
+    if !sync::is_plain_name(name) {
+        return Err(format!("Malformed Data: Font name \"{}\" is not a valid folder name", name));
+    }
 
     let folder_path = get_font_folder_path().join(name);
     if folder_path.exists() {
-        std::fs::remove_dir_all(folder_path);
+        std::fs::remove_dir_all(folder_path).map_err(|e| format!("Error: Could not delete font {} : {}", name, e))?;
     }
     
+    let mut fontsPayload: fontsJSON = read_fonts_json()?;
 
-    let file = File::open(get_font_folder_path().join("fonts.json")).unwrap();
-
-    let reader = BufReader::new(file);
+    fontsPayload.fonts.remove(name);
 
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
-
-    let mut fontsPayload: fontsJSON = serde_json::from_value(json).unwrap();
-
-    fontsPayload.fonts.remove(name).unwrap();
-
-    std::fs::write(
-        get_font_folder_path().join("fonts.json"),
-        serde_json::to_string_pretty(&fontsPayload).unwrap(),
-    );
+    write_fonts_json(&fontsPayload)
 }
 
 #[tauri::command]
 fn list_fonts() ->  HashMap<String, bool> {
-    let file = File::open(get_font_folder_path().join("fonts.json")).unwrap();
-    let reader = BufReader::new(file);
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
-
-    let mut fontsPayload: fontsJSON = serde_json::from_value(json).unwrap();
-    return fontsPayload.fonts;
+    return match read_fonts_json() {
+        Ok(fontsPayload) => fontsPayload.fonts,
+        Err(error) => {
+            println!("{}", error);
+            HashMap::new()
+        }
+    };
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -717,7 +920,9 @@ struct ReaderThemeBody {
     #[serde(default)]
     color: String,
     #[serde(default)]
-    link: String
+    link: String,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 
 }
 
@@ -726,7 +931,9 @@ struct ReaderImages {
     #[serde(default)]
     mixBlendMode: String,
     #[serde(default)]
-    invert: bool
+    invert: bool,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -734,7 +941,9 @@ struct ReaderTheme {
     #[serde(default)]
     body: ReaderThemeBody,
     #[serde(default)]
-    image: ReaderImages
+    image: ReaderImages,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 
@@ -750,6 +959,8 @@ struct uiTheme {
     primaryText: String,
     #[serde(default)]
     secondaryText: String,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 
@@ -758,87 +969,147 @@ struct AppTheme {
     #[serde(default)]
     ui: uiTheme,
     #[serde(default)]
-    reader: ReaderTheme
+    reader: ReaderTheme,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct AppThemes {
     #[serde(default)]
     themes: HashMap<String, AppTheme>,
+    #[serde(default = "themesVersion")]
+    version: String,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+fn themesVersion() -> String {
+    migrations::THEMES_VERSION.to_string()
 }
 
 #[tauri::command]
-fn set_global_themes(payload: HashMap<String, AppTheme>) {
+fn set_global_themes(payload: HashMap<String, AppTheme>) -> Result<(), String> {
     println!("Themes Set: {:?}", payload);
 
-    let t = AppThemes { themes: payload };
-
-    std::fs::write(
-        get_config_path().join("GlobalThemes.json"),
-        serde_json::to_string_pretty(&t).unwrap(),
-    )
-    .unwrap();
+    // Keys other builds added to the file are kept
+    let path = get_config_path().join("GlobalThemes.json");
+    let extra = storage::read_json::<AppThemes>(&path).map(|themes| themes.extra).unwrap_or_default();
+    let t = AppThemes { themes: payload, version: themesVersion(), extra };
 
-    // return themesPayload
+    return storage::write_json(&path, &t);
 }
 
 #[tauri::command]
-fn get_global_themes() -> AppThemes {
-
-    let file = File::open(get_config_path().join("GlobalThemes.json")).unwrap();
-
-    let reader = BufReader::new(file);
+fn get_global_themes() -> Result<AppThemes, String> {
 
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
+    let themesPayload: AppThemes = storage::read_json(&get_config_path().join("GlobalThemes.json"))?;
 
-    let mut themesPayload: AppThemes = serde_json::from_value(json).unwrap();
-
-    return themesPayload;
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct SettingsConfig {
-    #[serde(default)]
-    selectedTheme: String,
-    #[serde(default)]
-    sortDirection: String,
-    #[serde(default)]
-    sortBy: String,
-    #[serde(default)]
-    readerMargins: i64,
+    return Ok(themesPayload);
 }
 
 #[tauri::command]
-fn set_settings(payload: HashMap<String, String>) {
-    println!("{:?}", payload);
-
-    std::fs::write(
-        get_config_path().join("settings.json"),
-        serde_json::to_string_pretty(&payload).unwrap(),
-    )
-    .unwrap();
+fn get_config_path_js() -> String {
 
-    // return themesPayload
+    return get_config_path().display().to_string();
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn keeps_unknown_book_fields() {
+        let _library = shared_test_library();
+        let hash = test_book(
+            "extra",
+            json!({
+                "title": "Extra",
+                "shelf": "favourites",
+                "data": {
+                    "progress": 0.1,
+                    "readingTime": 300,
+                    "theme": {"font": "Serif", "hyphenate": true},
+                    "highlights": {"epubcfi(/6/2!/4/2,/1:0,/1:5)": {"color": "red", "style": "underline"}},
+                    "bookmarks": [{"cfi": "epubcfi(/6/4!/4/2)", "label": "Start", "pinned": true}]
+                }
+            }),
+        );
+
+        let mut payload = book_state::get(&hash).unwrap();
+        payload.data.progress = 0.5;
+        update_data_by_hash(payload, hash.clone(), None).unwrap();
+
+        let saved: Value = storage::read_json(&get_config_path().join("books").join(&hash).join(format!("{}.json", hash))).unwrap();
+        assert_eq!(saved["data"]["progress"], 0.5);
+        assert_eq!(saved["shelf"], "favourites");
+        assert_eq!(saved["data"]["readingTime"], 300);
+        assert_eq!(saved["data"]["theme"]["hyphenate"], true);
+        assert_eq!(saved["data"]["highlights"]["epubcfi(/6/2!/4/2,/1:0,/1:5)"]["style"], "underline");
+        assert_eq!(saved["data"]["bookmarks"][0]["pinned"], true);
+    }
 
-#[tauri::command]
-fn get_settings() -> SettingsConfig {
-
-    let file = File::open(get_config_path().join("settings.json")).unwrap();
-
-    let reader = BufReader::new(file);
+    #[test]
+    fn keeps_unknown_theme_fields() {
+        let _library = exclusive_test_library();
+        let path = get_config_path().join("GlobalThemes.json");
+        storage::write_json(&path, &json!({"themes": {}, "sharedBy": "another build"})).unwrap();
+
+        let themes: HashMap<String, AppTheme> = serde_json::from_value(json!({
+            "Dusk": {
+                "icon": "moon",
+                "ui": {"primaryBackground": "#111", "accent": "#f80"},
+                "reader": {
+                    "body": {"background": "#000", "fontFamily": "Serif"},
+                    "image": {"invert": true, "opacity": 0.8},
+                    "shadow": 2
+                }
+            }
+        }))
+        .unwrap();
+        set_global_themes(themes).unwrap();
+
+        let saved: Value = storage::read_json(&path).unwrap();
+        assert_eq!(saved["sharedBy"], "another build");
+        let dusk = &saved["themes"]["Dusk"];
+        assert_eq!(dusk["icon"], "moon");
+        assert_eq!(dusk["ui"]["accent"], "#f80");
+        assert_eq!(dusk["reader"]["body"]["fontFamily"], "Serif");
+        assert_eq!(dusk["reader"]["image"]["opacity"], 0.8);
+        assert_eq!(dusk["reader"]["shadow"], 2);
+        assert_eq!(serde_json::to_value(get_global_themes().unwrap()).unwrap(), saved);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(storage::backup_path(&path)).ok();
+    }
 
-    let json: serde_json::Value =
-        serde_json::from_reader(reader).expect("JSON was not well-formatted");
-        println!("PRINTING GET SETTINGS: {:?}", json);
-    let mut payload: SettingsConfig = serde_json::from_value(json).unwrap();
+    #[test]
+    fn reads_highlights_saved_before_text_and_timestamps() {
+        let highlight: highlightData = serde_json::from_value(json!({"color": "red", "note": "Old note"})).unwrap();
+        assert_eq!((highlight.color.as_str(), highlight.note.as_str()), ("red", "Old note"));
+        assert_eq!((highlight.text.as_str(), highlight.chapter.as_str()), ("", ""));
+        assert_eq!((highlight.created, highlight.modified), (0, 0));
+        assert!(highlight.tags.is_empty() && highlight.extra.is_empty());
+
+        let highlight: highlightData = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(highlight.color, "");
+        let saved = serde_json::to_value(&highlight).unwrap();
+        assert_eq!(saved, json!({"color": "", "note": "", "text": "", "chapter": "", "created": 0, "modified": 0, "tags": []}));
+    }
 
-    return payload;
+    #[test]
+    fn reads_bookmarks_saved_as_cfis() {
+        let data: updateDataPayload = serde_json::from_value(json!({
+            "progress": 0.2,
+            "bookmarks": ["epubcfi(/6/2!/4/2)", {"cfi": "epubcfi(/6/4!/4/2)", "label": "Start", "created": 5, "pinned": true}]
+        }))
+        .unwrap();
+        assert_eq!(data.bookmarks[0], bookmarkData { cfi: "epubcfi(/6/2!/4/2)".to_string(), ..Default::default() });
+        assert_eq!((data.bookmarks[1].label.as_str(), data.bookmarks[1].created), ("Start", 5));
+        assert_eq!(data.bookmarks[1].extra["pinned"], true);
+
+        // They are written back in the new format
+        let saved = serde_json::to_value(&data).unwrap();
+        assert_eq!(saved["bookmarks"][0], json!({"cfi": "epubcfi(/6/2!/4/2)", "label": "", "excerpt": "", "chapter": "", "created": 0}));
+        assert!(serde_json::from_value::<updateDataPayload>(json!({"progress": 0, "bookmarks": [5]})).is_err());
+    }
 }
-
-#[tauri::command]
-fn get_config_path_js() -> String {
-
-    return get_config_path().display().to_string();
-}
\ No newline at end of file
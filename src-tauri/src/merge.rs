@@ -0,0 +1,315 @@
+// Merging two copies of a book's data that changed independently
+// Used for writes from reader views that are behind, sync, backup imports and annotation imports.
+// Highlights and bookmarks are matched by CFI. A copy either has an annotation, deleted it, or never
+// had it. Deletions are kept in the book data as tombstones with the time of deletion, so a merge
+// with a copy that still has the annotation does not bring it back.
+// When the copy both sides started from is known, a side that did not touch an annotation since
+// then gives way to the side that did. Otherwise, or when both changed it, the most recent change
+// wins: the annotation's modification time against the other's or against the tombstone's.
+// An edit and a deletion made at the same time keep the edit.
+
+use std::collections::HashMap;
+
+use crate::{bookmarkData, highlightData, updateBookPayload};
+
+enum State<'a, T> {
+    Live(&'a T),
+    // Time of the deletion, 0 when it is not known
+    Deleted(u64),
+    Missing,
+}
+
+impl<'a, T: PartialEq> PartialEq for State<'a, T> {
+    fn eq(&self, other: &Self) -> bool {
+        return match (self, other) {
+            (State::Live(a), State::Live(b)) => a == b,
+            (State::Deleted(a), State::Deleted(b)) => a == b,
+            (State::Missing, State::Missing) => true,
+            _ => false,
+        };
+    }
+}
+
+struct Annotations<'a, T> {
+    live: HashMap<&'a String, &'a T>,
+    deleted: &'a HashMap<String, u64>,
+}
+
+impl<'a, T> Annotations<'a, T> {
+    fn state(&self, cfi: &String, in_base: bool) -> State<'a, T> {
+        if let Some(annotation) = self.live.get(cfi) {
+            return State::Live(*annotation);
+        }
+        if let Some(deleted) = self.deleted.get(cfi) {
+            return State::Deleted(*deleted);
+        }
+        // Removed since the base without a tombstone, by a reader view that does not know about them
+        if in_base {
+            return State::Deleted(0);
+        }
+        return State::Missing;
+    }
+}
+
+fn highlights(payload: &updateBookPayload) -> Annotations<'_, highlightData> {
+    return Annotations {
+        live: payload.data.highlights.iter().collect(),
+        deleted: &payload.data.deletedHighlights,
+    };
+}
+
+fn bookmarks(payload: &updateBookPayload) -> Annotations<'_, bookmarkData> {
+    return Annotations {
+        live: payload.data.bookmarks.iter().map(|bookmark| (&bookmark.cfi, bookmark)).collect(),
+        deleted: &payload.data.deletedBookmarks,
+    };
+}
+
+fn highlight_time(highlight: &highlightData) -> u64 {
+    return highlight.modified.max(highlight.created);
+}
+
+fn bookmark_time(bookmark: &bookmarkData) -> u64 {
+    return bookmark.created;
+}
+
+// `ours` wins ties
+fn resolve<'a, T: PartialEq>(
+    base: Option<State<'a, T>>,
+    ours: State<'a, T>,
+    theirs: State<'a, T>,
+    time: impl Fn(&T) -> u64,
+) -> State<'a, T> {
+    if let Some(base) = base {
+        if ours == base {
+            return theirs;
+        }
+        if theirs == base {
+            return ours;
+        }
+    }
+    return match (ours, theirs) {
+        (State::Live(a), State::Live(b)) => State::Live(if time(a) >= time(b) { a } else { b }),
+        (State::Live(a), State::Deleted(deleted)) | (State::Deleted(deleted), State::Live(a)) => {
+            if deleted > time(a) {
+                State::Deleted(deleted)
+            } else {
+                State::Live(a)
+            }
+        }
+        (State::Deleted(a), State::Deleted(b)) => State::Deleted(a.max(b)),
+        (state, State::Missing) | (State::Missing, state) => state,
+    };
+}
+
+// Merged annotations in the order of `ours` then `theirs`, and the tombstones of those deleted
+fn merge_annotations<'a, T: PartialEq + Clone>(
+    base: Option<&Annotations<'a, T>>,
+    ours: &Annotations<'a, T>,
+    theirs: &Annotations<'a, T>,
+    order: Vec<&'a String>,
+    time: impl Fn(&T) -> u64 + Copy,
+) -> (Vec<(String, T)>, HashMap<String, u64>) {
+    let mut cfis: Vec<&String> = order;
+    cfis.extend(ours.deleted.keys());
+    cfis.extend(theirs.deleted.keys());
+    if let Some(base) = base {
+        cfis.extend(base.live.keys());
+    }
+    let mut seen: Vec<&String> = Vec::new();
+    cfis.retain(|cfi| {
+        if seen.contains(cfi) {
+            return false;
+        }
+        seen.push(cfi);
+        return true;
+    });
+
+    let mut live = Vec::new();
+    let mut deleted = HashMap::new();
+    for cfi in cfis {
+        let in_base = base.map(|base| base.live.contains_key(cfi)).unwrap_or(false);
+        let base_state = base.map(|base| base.state(cfi, false));
+        match resolve(base_state, ours.state(cfi, in_base), theirs.state(cfi, in_base), time) {
+            State::Live(annotation) => live.push((cfi.clone(), annotation.clone())),
+            State::Deleted(time) if time > 0 => {
+                deleted.insert(cfi.clone(), time);
+            }
+            _ => {}
+        }
+    }
+    return (live, deleted);
+}
+
+// Three way merge of two copies, everything but the annotations comes from `incoming`
+// Without a base nothing without a tombstone is deleted, both sides are united
+pub fn merge_book(base: Option<&updateBookPayload>, current: &updateBookPayload, incoming: &updateBookPayload) -> updateBookPayload {
+    let mut merged = incoming.clone();
+
+    let base_highlights = base.map(highlights);
+    let mut order: Vec<&String> = current.data.highlights.keys().collect();
+    order.extend(incoming.data.highlights.keys());
+    let (live, deleted) = merge_annotations(base_highlights.as_ref(), &highlights(current), &highlights(incoming), order, highlight_time);
+    merged.data.highlights = live.into_iter().collect();
+    merged.data.deletedHighlights = deleted;
+
+    let base_bookmarks = base.map(bookmarks);
+    let mut order: Vec<&String> = current.data.bookmarks.iter().map(|bookmark| &bookmark.cfi).collect();
+    order.extend(incoming.data.bookmarks.iter().map(|bookmark| &bookmark.cfi));
+    let (live, deleted) = merge_annotations(base_bookmarks.as_ref(), &bookmarks(current), &bookmarks(incoming), order, bookmark_time);
+    merged.data.bookmarks = live.into_iter().map(|(_, bookmark)| bookmark).collect();
+    merged.data.deletedBookmarks = deleted;
+
+    return merged;
+}
+
+// Union of two independent copies of a book's data, such as one restored from a backup
+// Progress and theme come from whichever copy was modified last
+pub fn merge_copies(local: &updateBookPayload, other: &updateBookPayload) -> updateBookPayload {
+    return merge_synced(None, local, other);
+}
+
+// Same as `merge_copies` when both copies descend from `base`
+pub fn merge_synced(base: Option<&updateBookPayload>, local: &updateBookPayload, other: &updateBookPayload) -> updateBookPayload {
+    if other.modified > local.modified {
+        return merge_book(base, local, other);
+    }
+    return merge_book(base, other, local);
+}
+
+// Three way merge of a single value that has no tombstones, such as a synced setting
+// `prefer_ours` breaks ties when both changed
+pub fn merge_value<'a, T: PartialEq>(
+    known_base: bool,
+    base: Option<&'a T>,
+    ours: Option<&'a T>,
+    theirs: Option<&'a T>,
+    prefer_ours: impl Fn(&T, &T) -> bool,
+) -> Option<&'a T> {
+    if !known_base {
+        return match (ours, theirs) {
+            (Some(ours), Some(theirs)) => Some(if prefer_ours(ours, theirs) { ours } else { theirs }),
+            (ours, theirs) => ours.or(theirs),
+        };
+    }
+    // Whichever side did not change since the base loses to the side that did
+    if ours == base {
+        return theirs;
+    }
+    if theirs == base {
+        return ours;
+    }
+    return match (ours, theirs) {
+        (Some(ours), Some(theirs)) => Some(if prefer_ours(ours, theirs) { ours } else { theirs }),
+        // One side deleted and the other edited, keep the edit
+        (ours, theirs) => ours.or(theirs),
+    };
+}
+
+// Keeps the tombstones of `previous` and adds one for every annotation removed since, at `now`
+// Called on every change to a book, reader views send their data without tombstones
+pub fn record_tombstones(previous: &updateBookPayload, payload: &mut updateBookPayload, now: u64) {
+    for (cfi, deleted) in previous.data.deletedHighlights.iter() {
+        let time = payload.data.deletedHighlights.entry(cfi.clone()).or_insert(*deleted);
+        *time = (*time).max(*deleted);
+    }
+    for cfi in previous.data.highlights.keys() {
+        if !payload.data.highlights.contains_key(cfi) {
+            payload.data.deletedHighlights.entry(cfi.clone()).or_insert(now);
+        }
+    }
+    let highlights = &payload.data.highlights;
+    payload.data.deletedHighlights.retain(|cfi, _| !highlights.contains_key(cfi));
+
+    for (cfi, deleted) in previous.data.deletedBookmarks.iter() {
+        let time = payload.data.deletedBookmarks.entry(cfi.clone()).or_insert(*deleted);
+        *time = (*time).max(*deleted);
+    }
+    for bookmark in previous.data.bookmarks.iter() {
+        if !payload.data.bookmarks.iter().any(|other| other.cfi == bookmark.cfi) {
+            payload.data.deletedBookmarks.entry(bookmark.cfi.clone()).or_insert(now);
+        }
+    }
+    let bookmarks = &payload.data.bookmarks;
+    payload.data.deletedBookmarks.retain(|cfi, _| !bookmarks.iter().any(|bookmark| &bookmark.cfi == cfi));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Book data with highlights and tombstones given as (cfi, time)
+    fn book(modified: u64, highlights: &[(&str, u64)], deleted: &[(&str, u64)]) -> updateBookPayload {
+        let highlights: serde_json::Map<String, serde_json::Value> = highlights
+            .iter()
+            .map(|(cfi, time)| (cfi.to_string(), serde_json::json!({ "color": "yellow", "created": time, "modified": time })))
+            .collect();
+        let deleted: HashMap<&str, u64> = deleted.iter().cloned().collect();
+        return serde_json::from_value(serde_json::json!({
+            "modified": modified,
+            "data": {
+                "progress": 0.0,
+                "highlights": highlights,
+                "deletedHighlights": deleted,
+                "bookmarks": []
+            }
+        }))
+        .unwrap();
+    }
+
+    fn cfis(payload: &updateBookPayload) -> Vec<&str> {
+        let mut cfis: Vec<&str> = payload.data.highlights.keys().map(|cfi| cfi.as_str()).collect();
+        cfis.sort();
+        return cfis;
+    }
+
+    #[test]
+    fn unites_copies_without_a_base() {
+        let merged = merge_copies(&book(1, &[("a", 1)], &[]), &book(2, &[("b", 2)], &[]));
+        assert_eq!(cfis(&merged), vec!["a", "b"]);
+        assert!(merged.data.deletedHighlights.is_empty());
+    }
+
+    #[test]
+    fn newer_tombstone_wins_over_an_older_highlight() {
+        let merged = merge_copies(&book(1, &[("a", 5)], &[]), &book(2, &[], &[("a", 10)]));
+        assert!(cfis(&merged).is_empty());
+        assert_eq!(merged.data.deletedHighlights.get("a"), Some(&10));
+    }
+
+    #[test]
+    fn newer_edit_wins_over_an_older_tombstone() {
+        let merged = merge_copies(&book(1, &[("a", 20)], &[]), &book(2, &[], &[("a", 10)]));
+        assert_eq!(cfis(&merged), vec!["a"]);
+        assert!(merged.data.deletedHighlights.is_empty());
+    }
+
+    #[test]
+    fn untouched_side_gives_way_to_the_base() {
+        let base = book(1, &[("a", 1), ("b", 1)], &[]);
+        // A reader view removed "a" without recording a tombstone
+        let current = book(2, &[("b", 1)], &[]);
+        let incoming = book(3, &[("a", 1), ("b", 1), ("c", 3)], &[]);
+        let merged = merge_book(Some(&base), &current, &incoming);
+        assert_eq!(cfis(&merged), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn records_and_clears_tombstones() {
+        let previous = book(1, &[("a", 1), ("b", 1)], &[("c", 4)]);
+        let mut payload = book(2, &[("b", 1), ("c", 6)], &[]);
+        record_tombstones(&previous, &mut payload, 9);
+        assert_eq!(payload.data.deletedHighlights.get("a"), Some(&9));
+        // Re-adding an annotation drops its tombstone
+        assert!(!payload.data.deletedHighlights.contains_key("c"));
+    }
+
+    #[test]
+    fn merges_single_values() {
+        let newer = |a: &u64, b: &u64| a >= b;
+        assert_eq!(merge_value(true, Some(&1), Some(&1), Some(&2), newer), Some(&2));
+        assert_eq!(merge_value(true, Some(&1), Some(&3), Some(&1), newer), Some(&3));
+        assert_eq!(merge_value(true, Some(&1), None, Some(&2), newer), Some(&2));
+        assert_eq!(merge_value(false, None, Some(&4), Some(&2), newer), Some(&4));
+    }
+}
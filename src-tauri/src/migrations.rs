@@ -0,0 +1,300 @@
+// Versioned JSON files in Alexandria_Data and the steps that upgrade them
+// Every file carries a "version" field. On startup each file older than its current version
+// is copied to backups/migrations/ and upgraded in place, one step at a time.
+// Files written by a newer release are never touched, the app refuses to start instead of
+// overwriting data it does not understand.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{Map, Value};
+
+use crate::{get_config_path, storage, updateBookPayload};
+
+pub const BOOK_DATA_VERSION: &str = "0.13";
+pub const FONTS_VERSION: &str = "0.11";
+pub const SETTINGS_VERSION: &str = "0.13";
+pub const THEMES_VERSION: &str = "0.13";
+
+// Files written before versioning was introduced
+const UNVERSIONED: &str = "unversioned";
+
+struct Migration {
+    // None for files without a version field
+    from: Option<&'static str>,
+    to: &'static str,
+    apply: fn(&mut Map<String, Value>),
+}
+
+pub struct FileKind {
+    name: &'static str,
+    current: &'static str,
+    migrations: &'static [Migration],
+}
+
+pub const BOOK_DATA: FileKind = FileKind {
+    name: "book data",
+    current: BOOK_DATA_VERSION,
+    migrations: &[Migration {
+        from: None,
+        to: "0.13",
+        apply: book_line_height_ratio,
+    }],
+};
+
+pub const FONTS: FileKind = FileKind {
+    name: "fonts",
+    current: FONTS_VERSION,
+    migrations: &[Migration {
+        from: None,
+        to: "0.11",
+        apply: fonts_flatten_font_map,
+    }],
+};
+
+pub const SETTINGS: FileKind = FileKind {
+    name: "settings",
+    current: SETTINGS_VERSION,
+    migrations: &[Migration {
+        from: None,
+        to: "0.13",
+        apply: no_changes,
+    }],
+};
+
+pub const THEMES: FileKind = FileKind {
+    name: "themes",
+    current: THEMES_VERSION,
+    migrations: &[Migration {
+        from: None,
+        to: "0.13",
+        apply: no_changes,
+    }],
+};
+
+fn no_changes(_json: &mut Map<String, Value>) {}
+
+// Line height used to be saved as a percentage
+// Earlier releases dropped the version on every save, so only values still on the old scale are converted
+fn book_line_height_ratio(json: &mut Map<String, Value>) {
+    let theme = json
+        .get_mut("data")
+        .and_then(|data| data.get_mut("theme"))
+        .and_then(|theme| theme.as_object_mut());
+    if let Some(theme) = theme {
+        if let Some(line_height) = theme.get("lineHeight").and_then(|value| value.as_f64()) {
+            if line_height > 10.0 {
+                theme.insert("lineHeight".to_string(), Value::from(line_height / 100.0));
+            }
+        }
+    }
+}
+
+// { fonts: { fontMap: {...} } } became { fonts: {...} }
+fn fonts_flatten_font_map(json: &mut Map<String, Value>) {
+    let font_map = json
+        .get("fonts")
+        .and_then(|fonts| fonts.get("fontMap"))
+        .cloned()
+        .unwrap_or(Value::Object(Map::new()));
+    let is_flat = json
+        .get("fonts")
+        .and_then(|fonts| fonts.as_object())
+        .map(|fonts| !fonts.contains_key("fontMap"))
+        .unwrap_or(false);
+    if !is_flat {
+        json.insert("fonts".to_string(), font_map);
+    }
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    return version.split('.').map(|part| part.trim().parse().unwrap_or(0)).collect();
+}
+
+fn is_newer(version: &str, than: &str) -> bool {
+    return parse_version(version) > parse_version(than);
+}
+
+fn file_version(json: &Map<String, Value>) -> Option<String> {
+    return match json.get("version") {
+        Some(Value::String(version)) => Some(version.clone()),
+        Some(Value::Number(version)) => Some(version.to_string()),
+        _ => None,
+    };
+}
+
+// Bring a file's contents up to the current version
+// Returns the version it was at, or an error for files from a newer release
+pub fn upgrade(kind: &FileKind, json: &mut Value) -> Result<Option<String>, String> {
+    let object = match json.as_object_mut() {
+        Some(object) => object,
+        None => return Err(format!("Malformed Data: {} is not a JSON object", kind.name)),
+    };
+
+    let original = file_version(object);
+    let mut version = original.clone();
+    if let Some(version) = &version {
+        if is_newer(version, kind.current) {
+            return Err(format!(
+                "Error: {} was saved by a newer version of Alexandria ({} > {}), please update",
+                kind.name, version, kind.current
+            ));
+        }
+    }
+
+    for migration in kind.migrations {
+        if version.as_deref() == migration.from {
+            (migration.apply)(object);
+            version = Some(migration.to.to_string());
+        }
+    }
+
+    // Versions between known steps have nothing left to convert
+    object.insert("version".to_string(), Value::String(kind.current.to_string()));
+    return Ok(original);
+}
+
+// Kind of a state file by name, book data files are named after their hash instead
+pub fn kind_for_file(name: &str) -> Option<&'static FileKind> {
+    return match name {
+        "fonts.json" => Some(&FONTS),
+        "settings.json" => Some(&SETTINGS),
+        "GlobalThemes.json" | "ReaderThemes.json" => Some(&THEMES),
+        _ => None,
+    };
+}
+
+// Parse book data from another library, such as a backup, upgrading it on the way
+pub fn book_data_from_slice(contents: &[u8]) -> Result<updateBookPayload, String> {
+    let mut json: Value = serde_json::from_slice(contents).map_err(|e| format!("Malformed Data: {}", e))?;
+    upgrade(&BOOK_DATA, &mut json)?;
+    return serde_json::from_value(json).map_err(|e| format!("Malformed Data: {}", e));
+}
+
+// Copies are kept in the library's backups folder, also for files of a library being restored
+fn backup_copy(root: &Path, path: &Path, version: &str) -> Result<(), String> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let mut backup = get_config_path().join("backups").join("migrations").join(relative).into_os_string();
+    backup.push(format!(".{}", version));
+    let backup = PathBuf::from(backup);
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Error: Could not create \"{}\" : {}", parent.display(), e))?;
+    }
+    fs::copy(path, &backup).map_err(|e| format!("Error: Could not back up \"{}\" : {}", path.display(), e))?;
+    return Ok(());
+}
+
+fn migrate_file(root: &Path, kind: &FileKind, path: &Path) -> Result<bool, String> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let mut json: Value = storage::read_json(path)?;
+    let before = json.clone();
+    let original = upgrade(kind, &mut json)?;
+    if json == before {
+        return Ok(false);
+    }
+
+    let from = original.unwrap_or(UNVERSIONED.to_string());
+    backup_copy(root, path, &from)?;
+    storage::write_json(path, &json)?;
+    println!("Migrated {} \"{}\" : {} -> {}", kind.name, path.display(), from, kind.current);
+    return Ok(true);
+}
+
+fn book_data_files(root: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(root.join("books")) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    return entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.path().join(format!("{}.json", entry.file_name().to_string_lossy())))
+        .collect();
+}
+
+// Upgrade every versioned file, called from create_or_load_data before anything reads them
+// Damaged files are reported and skipped, a file from a newer release stops the migration
+pub fn run() -> Result<usize, String> {
+    return run_in(&get_config_path());
+}
+
+// Same as `run` for a copy of Alexandria_Data at `root`, such as a backup being restored
+pub fn run_in(root: &Path) -> Result<usize, String> {
+    let config = root;
+    let mut files: Vec<(&FileKind, PathBuf)> = vec![
+        (&FONTS, config.join("fonts").join("fonts.json")),
+        (&SETTINGS, config.join("settings.json")),
+        (&THEMES, config.join("GlobalThemes.json")),
+        (&THEMES, config.join("ReaderThemes.json")),
+    ];
+    files.extend(book_data_files(root).into_iter().map(|path| (&BOOK_DATA, path)));
+
+    let mut migrated = 0;
+    for (kind, path) in files {
+        match migrate_file(root, kind, &path) {
+            Ok(true) => migrated += 1,
+            Ok(false) => {}
+            Err(error) if error.starts_with("Malformed Data") => println!("{} - \"{}\"", error, path.display()),
+            Err(error) => return Err(format!("{} - \"{}\"", error, path.display())),
+        }
+    }
+    return Ok(migrated);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn converts_line_height_percentages() {
+        let mut book = json!({ "data": { "theme": { "lineHeight": 150 } } });
+        assert_eq!(upgrade(&BOOK_DATA, &mut book), Ok(None));
+        assert_eq!(book["data"]["theme"]["lineHeight"], json!(1.5));
+        assert_eq!(book["version"], json!(BOOK_DATA_VERSION));
+
+        // Saved by a release that dropped the version but already used ratios
+        let mut book = json!({ "data": { "theme": { "lineHeight": 1.4 } } });
+        upgrade(&BOOK_DATA, &mut book).unwrap();
+        assert_eq!(book["data"]["theme"]["lineHeight"], json!(1.4));
+    }
+
+    #[test]
+    fn flattens_the_font_map() {
+        let mut fonts = json!({ "fonts": { "fontMap": { "Serif": "serif.ttf" } } });
+        upgrade(&FONTS, &mut fonts).unwrap();
+        assert_eq!(fonts, json!({ "fonts": { "Serif": "serif.ttf" }, "version": FONTS_VERSION }));
+    }
+
+    #[test]
+    fn leaves_current_files_alone() {
+        let mut settings = json!({ "version": SETTINGS_VERSION, "sortBy": "title" });
+        let before = settings.clone();
+        assert_eq!(upgrade(&SETTINGS, &mut settings), Ok(Some(SETTINGS_VERSION.to_string())));
+        assert_eq!(settings, before);
+    }
+
+    #[test]
+    fn refuses_files_from_a_newer_release() {
+        let mut themes = json!({ "version": "9.0" });
+        let error = upgrade(&THEMES, &mut themes).unwrap_err();
+        assert!(error.starts_with("Error:"));
+        assert_eq!(themes, json!({ "version": "9.0" }));
+
+        let error = upgrade(&THEMES, &mut json!([])).unwrap_err();
+        assert!(error.starts_with("Malformed Data"));
+    }
+
+    #[test]
+    fn upgrades_book_data_from_other_libraries() {
+        let contents = br#"{ "title": "Book", "data": { "progress": 0.5, "theme": { "lineHeight": 120 } } }"#;
+        let book = book_data_from_slice(contents).unwrap();
+        assert_eq!(book.version, BOOK_DATA_VERSION);
+        assert_eq!(book.data.theme.lineHeight, 1.2);
+        assert!(book_data_from_slice(b"not json").is_err());
+    }
+}
@@ -0,0 +1,854 @@
+// OPDS catalog of the library for e-readers and reading apps on other devices
+// Off unless the `opdsEnabled` setting is on. The server listens on `opdsAddress`:`opdsPort`
+// and asks for `opdsUsername`/`opdsPassword` with basic auth when a username is set.
+// Without a username it only listens on a loopback address, other devices need the credentials.
+// The same feeds are served as OPDS 1.2 (Atom) under /opds/v1 and OPDS 2.0 (JSON) under /opds/v2,
+// book files and covers are downloaded from /opds/books/<hash>/<file>.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    sync::{Mutex, OnceLock},
+    time::UNIX_EPOCH,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{TimeZone, Utc};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tower_http::services::ServeDir;
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
+
+use crate::asset_server::PRIVATE_EXTENSIONS;
+use crate::settings::{self, Settings};
+use crate::{book_state, get_config_path};
+
+const PAGE_SIZE: usize = 50;
+
+// Characters escaped in URL path segments and query values
+const URL_ESCAPED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+const ATOM_NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ATOM_ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPEN_SEARCH: &str = "application/opensearchdescription+xml";
+const OPDS_JSON: &str = "application/opds+json";
+
+const REL_ACQUISITION: &str = "http://opds-spec.org/acquisition";
+const REL_IMAGE: &str = "http://opds-spec.org/image";
+const REL_THUMBNAIL: &str = "http://opds-spec.org/image/thumbnail";
+
+struct OpdsServer {
+    address: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    // Same socket the server accepts on, a restart on the same address takes it over instead of
+    // binding again while the old server may still hold the port
+    listener: TcpListener,
+}
+
+// None while the catalog is off
+static opds_server: Mutex<Option<OpdsServer>> = Mutex::new(None);
+// Settings changes restart the server from command threads that are not on the runtime
+static runtime: OnceLock<tokio::runtime::Handle> = OnceLock::new();
+// Books as of the last feed by hash, each is read again when its folder or data file changes
+static library_index: Mutex<Option<HashMap<String, IndexedBook>>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    fn root(&self) -> &'static str {
+        return match self {
+            Version::V1 => "/opds/v1",
+            Version::V2 => "/opds/v2",
+        };
+    }
+}
+
+#[derive(Clone)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Clone)]
+struct Book {
+    hash: String,
+    title: String,
+    author: String,
+    collections: Vec<String>,
+    added: u64,
+    modified: u64,
+    // File names in books/<hash>/, the epub first when the book was converted
+    files: Vec<String>,
+    cover: Option<String>,
+}
+
+struct IndexedBook {
+    // Modification times of the book's folder and data file
+    stamp: (u64, u64),
+    // None for folders without a book file
+    book: Option<Book>,
+}
+
+struct Navigation {
+    title: String,
+    path: String,
+    count: usize,
+    // Links to another navigation feed rather than a list of books
+    nested: bool,
+}
+
+struct Feed {
+    id: String,
+    title: String,
+    // Relative to the version root, without the query
+    path: String,
+    navigation: Vec<Navigation>,
+    books: Vec<Book>,
+    page: usize,
+    total: usize,
+    // Extra query kept on page links, e.g. the search terms
+    query: Option<(String, String)>,
+}
+
+impl Feed {
+    fn new(id: &str, title: &str, path: &str) -> Feed {
+        return Feed {
+            id: id.to_string(),
+            title: title.to_string(),
+            path: path.to_string(),
+            navigation: Vec::new(),
+            books: Vec::new(),
+            page: 1,
+            total: 0,
+            query: None,
+        };
+    }
+
+    fn paginate_books(mut self, mut books: Vec<Book>, query: &HashMap<String, String>) -> Feed {
+        self.page = page_number(query);
+        self.total = books.len();
+        self.books = books.drain(..).skip((self.page - 1) * PAGE_SIZE).take(PAGE_SIZE).collect();
+        return self;
+    }
+
+    fn paginate_navigation(mut self, mut navigation: Vec<Navigation>, query: &HashMap<String, String>) -> Feed {
+        self.page = page_number(query);
+        self.total = navigation.len();
+        self.navigation = navigation.drain(..).skip((self.page - 1) * PAGE_SIZE).take(PAGE_SIZE).collect();
+        return self;
+    }
+
+    fn is_navigation(&self) -> bool {
+        return self.books.len() == 0 && self.navigation.len() > 0;
+    }
+
+    fn page_href(&self, version: Version, page: usize) -> String {
+        let mut href = format!("{}{}?page={}", version.root(), self.path, page);
+        if let Some((key, value)) = &self.query {
+            href.push_str(&format!("&{}={}", key, encode(value)));
+        }
+        return href;
+    }
+
+    fn has_next(&self) -> bool {
+        return self.page * PAGE_SIZE < self.total;
+    }
+}
+
+fn page_number(query: &HashMap<String, String>) -> usize {
+    return query.get("page").and_then(|page| page.parse().ok()).unwrap_or(1).max(1);
+}
+
+fn encode(text: &str) -> String {
+    return utf8_percent_encode(text, URL_ESCAPED).to_string();
+}
+
+fn timestamp(millis: u64) -> String {
+    return Utc
+        .timestamp_millis_opt(millis as i64)
+        .single()
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+}
+
+fn mime_type(file: &str) -> &'static str {
+    let extension = file.rsplit('.').next().unwrap_or("").to_lowercase();
+    return match extension.as_str() {
+        "epub" | "epub3" => "application/epub+zip",
+        "mobi" | "prc" | "pdb" => "application/x-mobipocket-ebook",
+        "azw" | "azw3" => "application/vnd.amazon.ebook",
+        "pdf" => "application/pdf",
+        "fb2" => "application/x-fictionbook+xml",
+        "fbz" => "application/x-zip-compressed-fb2",
+        "cbz" => "application/vnd.comicbook+zip",
+        "cbr" => "application/vnd.comicbook-rar",
+        "cb7" => "application/x-cb7",
+        "cbt" => "application/x-cbt",
+        "txt" => "text/plain",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    };
+}
+
+fn file_modified(path: &std::path::Path) -> u64 {
+    return fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_millis() as u64)
+        .unwrap_or(0);
+}
+
+// A book folder with its downloadable files, None when it has none
+// Collections are the `collections` list in the book data, filled in by library imports
+fn read_book(hash: String, folder: &std::path::Path) -> Option<Book> {
+    let data = match book_state::get(&hash) {
+        Ok(data) => data,
+        Err(error) => {
+            println!("{}", error);
+            return None;
+        }
+    };
+
+    let mut files = Vec::new();
+    let mut cover = None;
+    let mut added = 0;
+    for entry in fs::read_dir(folder).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let lowercase = name.to_lowercase();
+        if !entry.path().is_file() || PRIVATE_EXTENSIONS.iter().any(|extension| lowercase.ends_with(extension)) {
+            continue;
+        }
+        if lowercase == "cover.jpg" {
+            cover = Some(name);
+            continue;
+        }
+        added = added.max(file_modified(&entry.path()));
+        files.push(name);
+    }
+    if files.len() == 0 {
+        return None;
+    }
+    files.sort_by_key(|name| !name.to_lowercase().ends_with(".epub"));
+
+    let collections = data
+        .extra
+        .get("collections")
+        .and_then(|collections| collections.as_array())
+        .map(|collections| collections.iter().filter_map(|name| name.as_str().map(|name| name.to_string())).collect())
+        .unwrap_or_default();
+    return Some(Book {
+        title: if data.title.len() > 0 { data.title } else { files[0].clone() },
+        author: data.author,
+        collections,
+        added,
+        modified: data.modified,
+        hash,
+        files,
+        cover,
+    });
+}
+
+// Every book in Alexandria_Data/books, blocking, feeds are built on the blocking thread pool
+// Only books whose folder or data file changed since the last feed are read again
+fn library() -> Vec<Book> {
+    let folders = match fs::read_dir(get_config_path().join("books")) {
+        Ok(folders) => folders,
+        Err(_) => return Vec::new(),
+    };
+    let mut previous = library_index.lock().map(|mut index| index.take()).ok().flatten().unwrap_or_default();
+    let mut index = HashMap::new();
+    for folder in folders.flatten().filter(|folder| folder.path().is_dir()) {
+        let hash = folder.file_name().to_string_lossy().to_string();
+        let stamp = (file_modified(&folder.path()), file_modified(&folder.path().join(format!("{}.json", hash))));
+        let indexed = match previous.remove(&hash) {
+            Some(indexed) if indexed.stamp == stamp => indexed,
+            _ => IndexedBook { stamp, book: read_book(hash.clone(), &folder.path()) },
+        };
+        index.insert(hash, indexed);
+    }
+
+    let mut books: Vec<Book> = index.values().filter_map(|indexed| indexed.book.clone()).collect();
+    books.sort_by(|a, b| a.title.to_lowercase().cmp(&b.title.to_lowercase()));
+    if let Ok(mut cached) = library_index.lock() {
+        *cached = Some(index);
+    }
+    return books;
+}
+
+fn book_href(book: &Book, file: &str) -> String {
+    return format!("/opds/books/{}/{}", book.hash, encode(file));
+}
+
+fn atom_link(rel: &str, href: &str, kind: &str) -> String {
+    return format!(
+        "<link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n",
+        escape_str_attribute(rel),
+        escape_str_attribute(href),
+        escape_str_attribute(kind)
+    );
+}
+
+fn render_atom(feed: &Feed) -> Response {
+    let root = Version::V1.root();
+    let kind = if feed.is_navigation() { ATOM_NAVIGATION } else { ATOM_ACQUISITION };
+    let now = timestamp(crate::current_time_millis());
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" xmlns:opds=\"http://opds-spec.org/2010/catalog\" xmlns:opensearch=\"http://a9.com/-/spec/opensearch/1.1/\">\n");
+    xml.push_str(&format!("<id>urn:alexandria:{}</id>\n", escape_str_pcdata(&feed.id)));
+    xml.push_str(&format!("<title>{}</title>\n", escape_str_pcdata(&feed.title)));
+    xml.push_str(&format!("<updated>{}</updated>\n", now));
+    xml.push_str("<author><name>Alexandria</name></author>\n");
+    xml.push_str(&atom_link("self", &feed.page_href(Version::V1, feed.page), kind));
+    xml.push_str(&atom_link("start", root, ATOM_NAVIGATION));
+    xml.push_str(&atom_link("search", &format!("{}/opensearch.xml", root), OPEN_SEARCH));
+    if feed.page > 1 {
+        xml.push_str(&atom_link("previous", &feed.page_href(Version::V1, feed.page - 1), kind));
+    }
+    if feed.has_next() {
+        xml.push_str(&atom_link("next", &feed.page_href(Version::V1, feed.page + 1), kind));
+    }
+    xml.push_str(&format!("<opensearch:totalResults>{}</opensearch:totalResults>\n", feed.total));
+    xml.push_str(&format!("<opensearch:itemsPerPage>{}</opensearch:itemsPerPage>\n", PAGE_SIZE));
+
+    for navigation in feed.navigation.iter() {
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_str_pcdata(&navigation.title)));
+        xml.push_str(&format!("<id>urn:alexandria:{}{}</id>\n", escape_str_pcdata(&feed.id), escape_str_pcdata(&navigation.path)));
+        xml.push_str(&format!("<updated>{}</updated>\n", now));
+        let (noun, kind) = if navigation.nested { ("entries", ATOM_NAVIGATION) } else { ("books", ATOM_ACQUISITION) };
+        xml.push_str(&format!("<content type=\"text\">{} {}</content>\n", navigation.count, noun));
+        xml.push_str(&atom_link("subsection", &format!("{}{}", root, navigation.path), kind));
+        xml.push_str("</entry>\n");
+    }
+    for book in feed.books.iter() {
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_str_pcdata(&book.title)));
+        xml.push_str(&format!("<id>urn:alexandria:book:{}</id>\n", book.hash));
+        xml.push_str(&format!("<updated>{}</updated>\n", timestamp(book.modified.max(book.added))));
+        xml.push_str(&format!("<published>{}</published>\n", timestamp(book.added)));
+        if book.author.len() > 0 {
+            xml.push_str(&format!("<author><name>{}</name></author>\n", escape_str_pcdata(&book.author)));
+        }
+        for collection in book.collections.iter() {
+            xml.push_str(&format!(
+                "<category term=\"{}\" label=\"{}\"/>\n",
+                escape_str_attribute(collection),
+                escape_str_attribute(collection)
+            ));
+        }
+        if let Some(cover) = &book.cover {
+            xml.push_str(&atom_link(REL_IMAGE, &book_href(book, cover), "image/jpeg"));
+            xml.push_str(&atom_link(REL_THUMBNAIL, &book_href(book, cover), "image/jpeg"));
+        }
+        for file in book.files.iter() {
+            xml.push_str(&atom_link(REL_ACQUISITION, &book_href(book, file), mime_type(file)));
+        }
+        xml.push_str("</entry>\n");
+    }
+    xml.push_str("</feed>\n");
+
+    let content_type = format!("{};charset=utf-8", kind);
+    return ([(header::CONTENT_TYPE, content_type)], xml).into_response();
+}
+
+fn json_link(rel: &str, href: &str, kind: &str) -> Value {
+    return json!({ "rel": rel, "href": href, "type": kind });
+}
+
+fn render_json(feed: &Feed) -> Response {
+    let root = Version::V2.root();
+    let mut links = vec![
+        json_link("self", &feed.page_href(Version::V2, feed.page), OPDS_JSON),
+        json_link("start", root, OPDS_JSON),
+        json!({ "rel": "search", "href": format!("{}/search{{?query}}", root), "type": OPDS_JSON, "templated": true }),
+    ];
+    if feed.page > 1 {
+        links.push(json_link("previous", &feed.page_href(Version::V2, feed.page - 1), OPDS_JSON));
+    }
+    if feed.has_next() {
+        links.push(json_link("next", &feed.page_href(Version::V2, feed.page + 1), OPDS_JSON));
+    }
+
+    let mut body = json!({
+        "metadata": {
+            "title": feed.title,
+            "numberOfItems": feed.total,
+            "itemsPerPage": PAGE_SIZE,
+            "currentPage": feed.page,
+        },
+        "links": links,
+    });
+    if feed.is_navigation() {
+        body["navigation"] = feed
+            .navigation
+            .iter()
+            .map(|navigation| {
+                json!({
+                    "title": navigation.title,
+                    "href": format!("{}{}", root, navigation.path),
+                    "type": OPDS_JSON,
+                    "rel": "subsection",
+                    "properties": { "numberOfItems": navigation.count },
+                })
+            })
+            .collect();
+    } else {
+        body["publications"] = feed
+            .books
+            .iter()
+            .map(|book| {
+                let mut metadata = json!({
+                    "@type": "http://schema.org/Book",
+                    "identifier": format!("urn:alexandria:book:{}", book.hash),
+                    "title": book.title,
+                    "modified": timestamp(book.modified.max(book.added)),
+                    "published": timestamp(book.added),
+                    "subject": book.collections.iter().map(|name| json!({ "name": name })).collect::<Vec<Value>>(),
+                });
+                if book.author.len() > 0 {
+                    metadata["author"] = json!([{ "name": book.author }]);
+                }
+                let images: Vec<Value> = book.cover.iter().map(|cover| json!({ "href": book_href(book, cover), "type": "image/jpeg" })).collect();
+                let acquisitions: Vec<Value> = book.files.iter().map(|file| json_link(REL_ACQUISITION, &book_href(book, file), mime_type(file))).collect();
+                return json!({ "metadata": metadata, "links": acquisitions, "images": images });
+            })
+            .collect();
+    }
+    return ([(header::CONTENT_TYPE, OPDS_JSON)], body.to_string()).into_response();
+}
+
+fn render(version: Version, feed: Feed) -> Response {
+    return match version {
+        Version::V1 => render_atom(&feed),
+        Version::V2 => render_json(&feed),
+    };
+}
+
+fn start_feed() -> Feed {
+    let books = library();
+    let authors = groups(&books, "/authors", |book| vec![author_name(book)]).len();
+    let collections = groups(&books, "/collections", |book| book.collections.clone()).len();
+    let entry = |title: &str, path: &str, count: usize, nested: bool| Navigation { title: title.to_string(), path: path.to_string(), count, nested };
+    let mut feed = Feed::new("root", "Alexandria Library", "");
+    feed.navigation = vec![
+        entry("Recently Added", "/recent", books.len(), false),
+        entry("All Books", "/all", books.len(), false),
+        entry("Authors", "/authors", authors, true),
+        entry("Collections", "/collections", collections, true),
+    ];
+    feed.total = feed.navigation.len();
+    return feed;
+}
+
+fn recent_feed(query: &HashMap<String, String>) -> Feed {
+    let mut books = library();
+    books.sort_by(|a, b| b.added.cmp(&a.added));
+    return Feed::new("recent", "Recently Added", "/recent").paginate_books(books, query);
+}
+
+fn all_feed(query: &HashMap<String, String>) -> Feed {
+    return Feed::new("all", "All Books", "/all").paginate_books(library(), query);
+}
+
+// Navigation entries for every distinct value of a grouping, sorted by name
+fn groups(books: &[Book], path: &str, names: impl Fn(&Book) -> Vec<String>) -> Vec<Navigation> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for book in books {
+        for name in names(book) {
+            *counts.entry(name).or_insert(0) += 1;
+        }
+    }
+    return counts
+        .into_iter()
+        .map(|(name, count)| Navigation { path: format!("{}/{}", path, encode(&name)), title: name, count, nested: false })
+        .collect();
+}
+
+fn author_name(book: &Book) -> String {
+    return if book.author.len() > 0 { book.author.clone() } else { "Unknown".to_string() };
+}
+
+fn authors_feed(query: &HashMap<String, String>) -> Feed {
+    let navigation = groups(&library(), "/authors", |book| vec![author_name(book)]);
+    return Feed::new("authors", "Authors", "/authors").paginate_navigation(navigation, query);
+}
+
+fn author_feed(name: &str, query: &HashMap<String, String>) -> Feed {
+    let books = library().into_iter().filter(|book| author_name(book) == name).collect();
+    return Feed::new(&format!("authors/{}", name), name, &format!("/authors/{}", encode(name))).paginate_books(books, query);
+}
+
+fn collections_feed(query: &HashMap<String, String>) -> Feed {
+    let navigation = groups(&library(), "/collections", |book| book.collections.clone());
+    return Feed::new("collections", "Collections", "/collections").paginate_navigation(navigation, query);
+}
+
+fn collection_feed(name: &str, query: &HashMap<String, String>) -> Feed {
+    let books = library().into_iter().filter(|book| book.collections.iter().any(|collection| collection == name)).collect();
+    return Feed::new(&format!("collections/{}", name), name, &format!("/collections/{}", encode(name))).paginate_books(books, query);
+}
+
+// Books whose title, author or collections contain every word of the query
+fn search_feed(query: &HashMap<String, String>) -> Feed {
+    let terms = query.get("q").or(query.get("query")).cloned().unwrap_or_default();
+    let words: Vec<String> = terms.to_lowercase().split_whitespace().map(|word| word.to_string()).collect();
+    let books = library()
+        .into_iter()
+        .filter(|book| {
+            let text = format!("{} {} {}", book.title, book.author, book.collections.join(" ")).to_lowercase();
+            return words.len() > 0 && words.iter().all(|word| text.contains(word));
+        })
+        .collect();
+    let mut feed = Feed::new(&format!("search/{}", terms), &format!("Search: {}", terms), "/search").paginate_books(books, query);
+    feed.query = Some(("q".to_string(), terms));
+    return feed;
+}
+
+async fn open_search() -> Response {
+    let root = Version::V1.root();
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<OpenSearchDescription xmlns=\"http://a9.com/-/spec/opensearch/1.1/\">\n<ShortName>Alexandria</ShortName>\n<Description>Search the Alexandria library</Description>\n<InputEncoding>UTF-8</InputEncoding>\n<OutputEncoding>UTF-8</OutputEncoding>\n<Url type=\"{}\" template=\"{}/search?q={{searchTerms}}\"/>\n</OpenSearchDescription>\n",
+        escape_str_attribute(ATOM_ACQUISITION),
+        root
+    );
+    return ([(header::CONTENT_TYPE, OPEN_SEARCH)], xml).into_response();
+}
+
+// Reading the library blocks, so feeds are built off the async workers
+async fn serve_feed(version: Version, feed: impl FnOnce() -> Feed + Send + 'static) -> Response {
+    return match tokio::task::spawn_blocking(feed).await {
+        Ok(feed) => render(version, feed),
+        Err(error) => {
+            println!("Error: Could not build OPDS feed : {}", error);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    };
+}
+
+fn feed_routes(version: Version) -> Router {
+    type Params = Query<HashMap<String, String>>;
+    let router = Router::new()
+        .route("/", get(move || serve_feed(version, start_feed)))
+        .route("/recent", get(move |Query(query): Params| serve_feed(version, move || recent_feed(&query))))
+        .route("/all", get(move |Query(query): Params| serve_feed(version, move || all_feed(&query))))
+        .route("/authors", get(move |Query(query): Params| serve_feed(version, move || authors_feed(&query))))
+        .route(
+            "/authors/:name",
+            get(move |Path(name): Path<String>, Query(query): Params| serve_feed(version, move || author_feed(&name, &query))),
+        )
+        .route("/collections", get(move |Query(query): Params| serve_feed(version, move || collections_feed(&query))))
+        .route(
+            "/collections/:name",
+            get(move |Path(name): Path<String>, Query(query): Params| serve_feed(version, move || collection_feed(&name, &query))),
+        )
+        .route("/search", get(move |Query(query): Params| serve_feed(version, move || search_feed(&query))));
+    return match version {
+        Version::V1 => router.route("/opensearch.xml", get(open_search)),
+        Version::V2 => router,
+    };
+}
+
+fn is_authorized<B>(credentials: &Credentials, request: &Request<B>) -> bool {
+    let value = match request.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+        Some(value) => value,
+        None => return false,
+    };
+    let (scheme, encoded) = value.split_once(' ').unwrap_or(("", ""));
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return false;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).unwrap_or_default();
+    return constant_time_eq(&decoded, format!("{}:{}", credentials.username, credentials.password).as_bytes());
+}
+
+// Looks at every byte, so the time taken does not tell how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b.iter()).fold(0u8, |difference, (x, y)| difference | (x ^ y)) == 0;
+}
+
+async fn guard<B>(State(credentials): State<Option<Credentials>>, request: Request<B>, next: Next<B>) -> Response {
+    if let Some(credentials) = &credentials {
+        if !is_authorized(credentials, &request) {
+            return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"Alexandria\"")]).into_response();
+        }
+    }
+    let path = request.uri().path().to_lowercase();
+    if PRIVATE_EXTENSIONS.iter().any(|extension| path.ends_with(extension)) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    return next.run(request).await;
+}
+
+fn router(credentials: Option<Credentials>) -> Router {
+    return Router::new()
+        .route("/opds", get(|| serve_feed(Version::V1, start_feed)))
+        .nest("/opds/v1", feed_routes(Version::V1))
+        .nest("/opds/v2", feed_routes(Version::V2))
+        .nest_service("/opds/books", ServeDir::new(get_config_path().join("books")))
+        .layer(middleware::from_fn_with_state(credentials, guard));
+}
+
+fn server_address(settings: &Settings) -> Result<SocketAddr, String> {
+    let ip: IpAddr = settings
+        .opdsAddress
+        .parse()
+        .map_err(|_| format!("Error: \"{}\" is not an IP address", settings.opdsAddress))?;
+    return Ok(SocketAddr::new(ip, settings.opdsPort));
+}
+
+// The catalog is only open to other devices behind a username and password
+pub fn check(settings: &Settings) -> Result<(), String> {
+    if !settings.opdsEnabled {
+        return Ok(());
+    }
+    let address = server_address(settings)?;
+    if !address.ip().is_loopback() && settings.opdsUsername.len() == 0 {
+        return Err(format!(
+            "Error: Set an OPDS username and password before serving the catalog on {}, or use 127.0.0.1",
+            address.ip()
+        ));
+    }
+    return Ok(());
+}
+
+fn start(settings: &Settings, handle: &tokio::runtime::Handle, previous: Option<(SocketAddr, TcpListener)>) -> Result<(), String> {
+    check(settings)?;
+    let address = server_address(settings)?;
+    let listener = match previous {
+        Some((previous_address, listener)) if previous_address == address => listener,
+        _ => TcpListener::bind(address).map_err(|e| format!("Error: Could not start OPDS server on {} : {}", address, e))?,
+    };
+    listener.set_nonblocking(true).map_err(|e| format!("Error: {}", e))?;
+    let kept = listener.try_clone().map_err(|e| format!("Error: {}", e))?;
+
+    let credentials = if settings.opdsUsername.len() > 0 {
+        Some(Credentials { username: settings.opdsUsername.clone(), password: settings.opdsPassword.clone() })
+    } else {
+        None
+    };
+    let app = router(credentials);
+
+    // Binding the listener to the reactor needs the runtime
+    let _runtime = handle.enter();
+    let server = axum::Server::from_tcp(listener).map_err(|e| format!("Error: Could not start OPDS server : {}", e))?;
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    *opds_server.lock().unwrap() = Some(OpdsServer { address, shutdown, listener: kept });
+    println!("Serving OPDS catalog on {}", address);
+    handle.spawn(async move {
+        let result = server
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                stopped.await.ok();
+            })
+            .await;
+        if let Err(error) = result {
+            println!("Error: OPDS server stopped : {}", error);
+            opds_server.lock().unwrap().take();
+        }
+    });
+    return Ok(());
+}
+
+// Returns the address and socket the server listened on
+fn shut_down() -> Option<(SocketAddr, TcpListener)> {
+    let server = opds_server.lock().unwrap().take()?;
+    server.shutdown.send(()).ok();
+    println!("Stopped OPDS server on {}", server.address);
+    return Some((server.address, server.listener));
+}
+
+pub fn stop() {
+    shut_down();
+}
+
+// Start, restart or stop the server to match the settings
+// Called from setup on the runtime, and again whenever an OPDS setting changes
+pub fn apply(settings: &Settings) -> Result<(), String> {
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        runtime.get_or_init(|| handle);
+    }
+    let previous = shut_down();
+    if !settings.opdsEnabled {
+        return Ok(());
+    }
+    let handle = runtime.get().ok_or("Error: OPDS server started before the async runtime".to_string())?;
+    return start(settings, handle, previous);
+}
+
+// Address other devices can reach, for listeners on every interface this is the address of the
+// interface that routes to the network. Nothing is sent by connecting a UDP socket.
+fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    return socket.local_addr().ok().map(|address| address.ip());
+}
+
+// URL to enter in a reading app, None while the catalog is off
+#[tauri::command]
+pub fn get_opds_url() -> Option<String> {
+    let address = opds_server.lock().unwrap().as_ref()?.address;
+    let ip = if address.ip().is_unspecified() { lan_address().unwrap_or(address.ip()) } else { address.ip() };
+    return Some(format!("http://{}/opds", SocketAddr::new(ip, address.port())));
+}
+
+// Starts the catalog from setup when it is turned on
+pub fn apply_saved() {
+    if let Err(error) = apply(&settings::load()) {
+        println!("{}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::blocking::Client;
+
+    fn settings(address: &str, username: &str) -> Settings {
+        return Settings {
+            opdsEnabled: true,
+            opdsAddress: address.to_string(),
+            opdsUsername: username.to_string(),
+            opdsPassword: "secret".to_string(),
+            ..Settings::default()
+        };
+    }
+
+    // Serves the catalog on a free port, the server stops with the returned runtime
+    fn serve(credentials: Option<Credentials>) -> (tokio::runtime::Runtime, String) {
+        let server_runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        {
+            let _entered = server_runtime.enter();
+            let server = axum::Server::from_tcp(listener).unwrap().serve(router(credentials).into_make_service());
+            server_runtime.spawn(server);
+        }
+        return (server_runtime, format!("http://127.0.0.1:{}", port));
+    }
+
+    fn add_book(title: &str) -> String {
+        let hash = crate::test_book(
+            "opds",
+            serde_json::json!({"title": title, "author": "Quill Writer", "collections": ["Zebras"], "data": {"progress": 0.0}}),
+        );
+        let folder = get_config_path().join("books").join(&hash);
+        fs::write(folder.join("tale.pdf"), "pdf").unwrap();
+        fs::write(folder.join("tale.epub"), "epub").unwrap();
+        fs::write(folder.join("cover.jpg"), "cover").unwrap();
+        return hash;
+    }
+
+    #[test]
+    fn needs_credentials_off_loopback() {
+        assert!(check(&settings("0.0.0.0", "")).is_err());
+        assert!(check(&settings("192.168.1.20", "")).is_err());
+        assert_eq!(check(&settings("0.0.0.0", "reader")), Ok(()));
+        assert_eq!(check(&settings("127.0.0.1", "")), Ok(()));
+        assert_eq!(check(&settings("::1", "")), Ok(()));
+        assert!(check(&settings("localhost", "")).is_err());
+        assert_eq!(check(&Settings { opdsEnabled: false, ..settings("0.0.0.0", "") }), Ok(()));
+    }
+
+    #[test]
+    fn checks_basic_auth() {
+        let credentials = Credentials { username: "reader".to_string(), password: "secret".to_string() };
+        let request = |value: &str| Request::builder().header(header::AUTHORIZATION, value).body(()).unwrap();
+        assert!(is_authorized(&credentials, &request(&format!("Basic {}", STANDARD.encode("reader:secret")))));
+        assert!(is_authorized(&credentials, &request(&format!("basic  {}", STANDARD.encode("reader:secret")))));
+        assert!(!is_authorized(&credentials, &request(&format!("Basic {}", STANDARD.encode("reader:secreT")))));
+        assert!(!is_authorized(&credentials, &request(&format!("Basic {}", STANDARD.encode("reader:secret2")))));
+        assert!(!is_authorized(&credentials, &request(&format!("Bearer {}", STANDARD.encode("reader:secret")))));
+        assert!(!is_authorized(&credentials, &request("Basic not base64")));
+        assert!(!is_authorized(&credentials, &Request::builder().body(()).unwrap()));
+    }
+
+    #[test]
+    fn serves_atom_and_json_feeds() {
+        let _library = crate::shared_test_library();
+        let title = format!("Zebra Tale {}", rand::random::<u32>());
+        let hash = add_book(&title);
+        let (_server, base) = serve(None);
+        let client = Client::new();
+        let get = |path: &str| client.get(format!("{}{}", base, path)).send().unwrap();
+
+        let search = get(&format!("/opds/v1/search?q={}", encode(&title.to_lowercase())));
+        assert_eq!(search.headers()[header::CONTENT_TYPE], format!("{};charset=utf-8", ATOM_ACQUISITION));
+        let atom = search.text().unwrap();
+        assert!(atom.contains(&format!("<title>{}</title>", title)));
+        assert!(atom.contains("<opensearch:totalResults>1</opensearch:totalResults>"));
+        assert!(atom.contains("<author><name>Quill Writer</name></author>"));
+        assert!(atom.contains("<category term=\"Zebras\" label=\"Zebras\"/>"));
+        // The epub is offered first, the data file not at all
+        let epub = atom.find(&format!("href=\"/opds/books/{}/tale.epub\" type=\"application/epub+zip\"", hash)).unwrap();
+        let pdf = atom.find(&format!("href=\"/opds/books/{}/tale.pdf\" type=\"application/pdf\"", hash)).unwrap();
+        assert!(epub < pdf);
+        assert!(atom.contains(&format!("rel=\"{}\" href=\"/opds/books/{}/cover.jpg\"", REL_IMAGE, hash)));
+        assert!(!atom.contains(".json"));
+        assert!(get("/opds/v1/search?q=").text().unwrap().contains("<opensearch:totalResults>0</opensearch:totalResults>"));
+
+        let start = get("/opds").text().unwrap();
+        assert!(start.contains(ATOM_NAVIGATION) && start.contains("href=\"/opds/v1/authors\""));
+
+        let json: Value = serde_json::from_str(&get("/opds/v2/collections/Zebras?page=1").text().unwrap()).unwrap();
+        let publication = json["publications"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|publication| publication["metadata"]["title"] == title.as_str())
+            .unwrap()
+            .clone();
+        assert_eq!(publication["metadata"]["author"][0]["name"], "Quill Writer");
+        assert_eq!(publication["links"][0]["href"], format!("/opds/books/{}/tale.epub", hash));
+        assert_eq!(publication["images"][0]["href"], format!("/opds/books/{}/cover.jpg", hash));
+        let authors: Value = serde_json::from_str(&get("/opds/v2/authors").text().unwrap()).unwrap();
+        assert!(authors["navigation"].as_array().unwrap().iter().any(|author| author["href"] == "/opds/v2/authors/Quill%20Writer"));
+
+        assert_eq!(get(&format!("/opds/books/{}/tale.epub", hash)).text().unwrap(), "epub");
+        assert_eq!(get(&format!("/opds/books/{}/{}.json", hash, hash)).status(), StatusCode::NOT_FOUND);
+
+        // Changed books are read again
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        book_state::update(&hash, None, |payload, _previous| {
+            payload.title = format!("{} Renamed", title);
+            return Ok(());
+        })
+        .unwrap();
+        assert!(get(&format!("/opds/v1/search?q={}", encode(&title))).text().unwrap().contains(&format!("<title>{} Renamed</title>", title)));
+    }
+
+    #[test]
+    fn asks_for_credentials() {
+        let _library = crate::shared_test_library();
+        let (_server, base) = serve(Some(Credentials { username: "reader".to_string(), password: "secret".to_string() }));
+        let client = Client::new();
+
+        let response = client.get(format!("{}/opds/v2", base)).send().unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Basic realm=\"Alexandria\"");
+        let response = client.get(format!("{}/opds/v2", base)).basic_auth("reader", Some("wrong")).send().unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client.get(format!("{}/opds/v2", base)).basic_auth("reader", Some("secret")).send().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], OPDS_JSON);
+    }
+}
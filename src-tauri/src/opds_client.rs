@@ -0,0 +1,649 @@
+// Browsing remote OPDS catalogs, such as Calibre-Web or Kavita, and importing books from them
+// Catalogs are listed in the `opdsCatalogs` setting. Both OPDS 1.2 (Atom) and OPDS 2.0 (JSON)
+// feeds are read into the same page structure, with every link made absolute.
+// Credentials are only sent to the catalog's own host.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{header, Url};
+use serde::Serialize;
+use serde_json::Value;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+
+use crate::settings::{self, OpdsCatalog};
+use crate::{book_state, import_book, BookHydrate};
+
+const ACQUISITION: &str = "http://opds-spec.org/acquisition";
+const IMAGE: &str = "http://opds-spec.org/image";
+const THUMBNAIL: &str = "http://opds-spec.org/image/thumbnail";
+const OPEN_SEARCH: &str = "application/opensearchdescription+xml";
+
+#[derive(Serialize, Debug, Default)]
+pub struct OpdsLink {
+    href: String,
+    // Mime type of the file, e.g. application/epub+zip
+    kind: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct OpdsEntry {
+    id: String,
+    title: String,
+    author: String,
+    summary: String,
+    cover: Option<String>,
+    // Feed the entry opens, for navigation entries
+    navigation: Option<String>,
+    // Files that can be downloaded, for books
+    acquisitions: Vec<OpdsLink>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct OpdsPage {
+    url: String,
+    title: String,
+    entries: Vec<OpdsEntry>,
+    next: Option<String>,
+    previous: Option<String>,
+    // Search link as found in the feed, resolved by search_opds
+    search: Option<String>,
+}
+
+fn find_catalog(name: &str) -> Result<OpdsCatalog, String> {
+    return settings::load()
+        .opdsCatalogs
+        .into_iter()
+        .find(|catalog| catalog.name == name)
+        .ok_or(format!("Error: No OPDS catalog named \"{}\"", name));
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    return a.scheme() == b.scheme() && a.host_str() == b.host_str() && a.port_or_known_default() == b.port_or_known_default();
+}
+
+async fn fetch(catalog: &OpdsCatalog, url: &Url) -> Result<reqwest::Response, String> {
+    let mut request = reqwest::Client::new().get(url.clone());
+    let catalog_url = Url::parse(&catalog.url).map_err(|e| format!("Error: Invalid catalog URL \"{}\" : {}", catalog.url, e))?;
+    if catalog.username.len() > 0 && same_origin(&catalog_url, url) {
+        request = request.basic_auth(&catalog.username, Some(&catalog.password));
+    }
+    let response = request.send().await.map_err(|e| format!("Error: Could not reach \"{}\" : {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Error: \"{}\" answered {}", url, response.status()));
+    }
+    return Ok(response);
+}
+
+fn resolve(base: &Url, href: &str) -> Option<String> {
+    return base.join(href).ok().map(|url| url.to_string());
+}
+
+fn attribute(attributes: &[xml::attribute::OwnedAttribute], name: &str) -> String {
+    return attributes
+        .iter()
+        .find(|attribute| attribute.name.local_name == name)
+        .map(|attribute| attribute.value.clone())
+        .unwrap_or_default();
+}
+
+fn apply_feed_link(page: &mut OpdsPage, base: &Url, rel: &str, href: &str, kind: &str) {
+    match rel {
+        "next" => page.next = resolve(base, href),
+        "previous" | "prev" => page.previous = resolve(base, href),
+        // Feeds may link both an OpenSearch description and a direct template, the template saves a request
+        "search" if page.search.is_none() || kind != OPEN_SEARCH => page.search = resolve_template(base, href),
+        _ => {}
+    }
+}
+
+// Joining would escape the braces of a URL template
+fn resolve_template(base: &Url, href: &str) -> Option<String> {
+    let placeholder = "ALEXANDRIA_TEMPLATE";
+    let start = href.find('{');
+    let end = href.rfind('}');
+    return match (start, end) {
+        (Some(start), Some(end)) if start < end => {
+            let resolved = resolve(base, &format!("{}{}{}", &href[..start], placeholder, &href[end + 1..]))?;
+            Some(resolved.replace(placeholder, &href[start..=end]))
+        }
+        _ => resolve(base, href),
+    };
+}
+
+fn apply_entry_link(entry: &mut OpdsEntry, base: &Url, rel: &str, href: &str, kind: &str) {
+    let href = match resolve(base, href) {
+        Some(href) => href,
+        None => return,
+    };
+    if rel.starts_with(ACQUISITION) {
+        entry.acquisitions.push(OpdsLink { href, kind: kind.to_string() });
+    } else if rel == IMAGE || (rel == THUMBNAIL && entry.cover.is_none()) {
+        entry.cover = Some(href);
+    } else if kind.contains("profile=opds-catalog") || kind.starts_with("application/atom+xml") || kind == "application/opds+json" {
+        entry.navigation = Some(href);
+    }
+}
+
+fn parse_atom(base: &Url, body: &str) -> Result<OpdsPage, String> {
+    let config = ParserConfig::new()
+        .trim_whitespace(true)
+        .cdata_to_characters(true)
+        .ignore_comments(true)
+        .coalesce_characters(true);
+    let reader = EventReader::new_with_config(body.as_bytes(), config);
+
+    let mut page = OpdsPage { url: base.to_string(), ..Default::default() };
+    let mut path: Vec<String> = Vec::new();
+    let mut entry: Option<OpdsEntry> = None;
+    for event in reader {
+        match event.map_err(|e| format!("Malformed Data: {}", e))? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                if name.local_name == "entry" {
+                    entry = Some(OpdsEntry::default());
+                } else if name.local_name == "link" {
+                    let (rel, href, kind) = (attribute(&attributes, "rel"), attribute(&attributes, "href"), attribute(&attributes, "type"));
+                    match entry.as_mut() {
+                        Some(entry) => apply_entry_link(entry, base, &rel, &href, &kind),
+                        None => apply_feed_link(&mut page, base, &rel, &href, &kind),
+                    }
+                }
+                path.push(name.local_name);
+            }
+            XmlEvent::Characters(text) => {
+                let element = path.last().map(|name| name.as_str()).unwrap_or("");
+                let parent = if path.len() > 1 { path[path.len() - 2].as_str() } else { "" };
+                match entry.as_mut() {
+                    Some(entry) => match (parent, element) {
+                        ("entry", "title") => entry.title.push_str(&text),
+                        ("entry", "id") => entry.id.push_str(&text),
+                        ("entry", "summary") | ("entry", "content") if entry.summary.len() == 0 => entry.summary = text,
+                        ("author", "name") => {
+                            if entry.author.len() > 0 {
+                                entry.author.push_str(", ");
+                            }
+                            entry.author.push_str(&text);
+                        }
+                        _ => {}
+                    },
+                    None if (parent, element) == ("feed", "title") => page.title.push_str(&text),
+                    None => {}
+                }
+            }
+            XmlEvent::EndElement { name } => {
+                path.pop();
+                if name.local_name == "entry" {
+                    page.entries.extend(entry.take());
+                }
+            }
+            _ => {}
+        }
+    }
+    return Ok(page);
+}
+
+fn json_text(value: &Value) -> String {
+    return match value {
+        Value::String(text) => text.clone(),
+        // Localized strings are maps of language to text
+        Value::Object(map) => map.values().next().map(json_text).unwrap_or_default(),
+        _ => String::new(),
+    };
+}
+
+fn json_names(value: &Value) -> Vec<String> {
+    return match value {
+        Value::Array(items) => items.iter().flat_map(json_names).collect(),
+        Value::Object(map) if map.contains_key("name") => vec![json_text(&map["name"])],
+        value => vec![json_text(value)].into_iter().filter(|name| name.len() > 0).collect(),
+    };
+}
+
+fn json_links(value: &Value) -> Vec<(String, String, String)> {
+    return value
+        .as_array()
+        .map(|links| {
+            links
+                .iter()
+                .map(|link| {
+                    let rel = match &link["rel"] {
+                        Value::Array(rels) => rels.first().map(json_text).unwrap_or_default(),
+                        rel => json_text(rel),
+                    };
+                    return (rel, json_text(&link["href"]), json_text(&link["type"]));
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+}
+
+fn parse_json_collections(base: &Url, json: &Value, page: &mut OpdsPage) {
+    for navigation in json["navigation"].as_array().into_iter().flatten() {
+        page.entries.push(OpdsEntry {
+            title: json_text(&navigation["title"]),
+            navigation: resolve(base, &json_text(&navigation["href"])),
+            ..Default::default()
+        });
+    }
+    for publication in json["publications"].as_array().into_iter().flatten() {
+        let metadata = &publication["metadata"];
+        let mut entry = OpdsEntry {
+            id: json_text(&metadata["identifier"]),
+            title: json_text(&metadata["title"]),
+            author: json_names(&metadata["author"]).join(", "),
+            summary: json_text(&metadata["description"]),
+            ..Default::default()
+        };
+        for (rel, href, kind) in json_links(&publication["links"]) {
+            apply_entry_link(&mut entry, base, &rel, &href, &kind);
+        }
+        entry.cover = publication["images"]
+            .as_array()
+            .and_then(|images| images.first())
+            .and_then(|image| resolve(base, &json_text(&image["href"])))
+            .or(entry.cover);
+        page.entries.push(entry);
+    }
+    // Home pages group their entries, e.g. "New" and "Popular"
+    for group in json["groups"].as_array().into_iter().flatten() {
+        parse_json_collections(base, group, page);
+    }
+}
+
+fn parse_json(base: &Url, body: &str) -> Result<OpdsPage, String> {
+    let json: Value = serde_json::from_str(body).map_err(|e| format!("Malformed Data: {}", e))?;
+    let mut page = OpdsPage {
+        url: base.to_string(),
+        title: json_text(&json["metadata"]["title"]),
+        ..Default::default()
+    };
+    for (rel, href, kind) in json_links(&json["links"]) {
+        apply_feed_link(&mut page, base, &rel, &href, &kind);
+    }
+    parse_json_collections(base, &json, &mut page);
+    return Ok(page);
+}
+
+async fn load_page(catalog: &OpdsCatalog, url: &str) -> Result<OpdsPage, String> {
+    let url = Url::parse(url).map_err(|e| format!("Error: Invalid URL \"{}\" : {}", url, e))?;
+    let response = fetch(catalog, &url).await?;
+    // Redirects change the base of relative links
+    let base = response.url().clone();
+    let body = response.text().await.map_err(|e| format!("Malformed Data: {}", e))?;
+    if body.trim_start().starts_with('{') {
+        return parse_json(&base, &body);
+    }
+    return parse_atom(&base, &body);
+}
+
+// A page of a catalog, its start page when no URL is given
+// `url` is any next, previous or navigation link from an earlier page
+#[tauri::command]
+pub async fn browse_opds(catalog: String, url: Option<String>) -> Result<OpdsPage, String> {
+    let catalog = find_catalog(&catalog)?;
+    let url = url.unwrap_or(catalog.url.clone());
+    return load_page(&catalog, &url).await;
+}
+
+fn encode(text: &str) -> String {
+    return utf8_percent_encode(text, NON_ALPHANUMERIC).to_string();
+}
+
+// Search template of an OpenSearch description, preferring the one that returns an Atom feed
+async fn open_search_template(catalog: &OpdsCatalog, url: &str) -> Result<String, String> {
+    let url = Url::parse(url).map_err(|e| format!("Error: Invalid URL \"{}\" : {}", url, e))?;
+    let body = fetch(catalog, &url).await?.text().await.map_err(|e| format!("Malformed Data: {}", e))?;
+    let mut templates: Vec<(String, String)> = Vec::new();
+    for event in EventReader::new(body.as_bytes()) {
+        if let XmlEvent::StartElement { name, attributes, .. } = event.map_err(|e| format!("Malformed Data: {}", e))? {
+            if name.local_name == "Url" {
+                templates.push((attribute(&attributes, "type"), attribute(&attributes, "template")));
+            }
+        }
+    }
+    templates.sort_by_key(|(kind, _)| !kind.contains("atom"));
+    let template = templates.into_iter().next().map(|(_, template)| template);
+    return template
+        .and_then(|template| resolve_template(&url, &template))
+        .ok_or("Error: Catalog does not describe how to search it".to_string());
+}
+
+// Results of the catalog's search, which may span several pages
+#[tauri::command]
+pub async fn search_opds(catalog: String, query: String) -> Result<OpdsPage, String> {
+    let catalog = find_catalog(&catalog)?;
+    let start = load_page(&catalog, &catalog.url).await?;
+    let mut template = start.search.ok_or("Error: Catalog does not support search".to_string())?;
+    if !template.contains('{') {
+        template = open_search_template(&catalog, &template).await?;
+    }
+
+    let terms = encode(&query);
+    let url = if template.contains("{?query}") {
+        template.replace("{?query}", &format!("?query={}", terms))
+    } else {
+        // OpenSearch templates, optional parameters are left empty
+        let filled = template.replace("{searchTerms}", &terms).replace("{?searchTerms}", &terms);
+        let mut url = String::new();
+        let mut rest = filled.as_str();
+        while let Some(start) = rest.find('{') {
+            url.push_str(&rest[..start]);
+            rest = rest[start..].find('}').map(|end| &rest[start + end + 1..]).unwrap_or("");
+        }
+        url.push_str(rest);
+        url
+    };
+    return load_page(&catalog, &url).await;
+}
+
+fn extension_for(kind: &str) -> &'static str {
+    return match kind.split(';').next().unwrap_or("").trim() {
+        "application/epub+zip" => "epub",
+        "application/x-mobipocket-ebook" => "mobi",
+        "application/vnd.amazon.ebook" | "application/x-mobi8-ebook" => "azw3",
+        "application/pdf" => "pdf",
+        "application/x-fictionbook+xml" => "fb2",
+        "application/x-zip-compressed-fb2" => "fbz",
+        "application/vnd.comicbook+zip" | "application/x-cbz" => "cbz",
+        "application/vnd.comicbook-rar" | "application/x-cbr" => "cbr",
+        "application/x-cb7" => "cb7",
+        "application/x-cbt" => "cbt",
+        "text/plain" => "txt",
+        _ => "epub",
+    };
+}
+
+// Parameters of a header value, splitting on semicolons outside quoted strings
+fn split_parameters(value: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                parts.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(part) = parts.last_mut() {
+            part.push(c);
+        }
+    }
+    return parts;
+}
+
+// File name of a Content-Disposition header
+// The RFC 6266 extended form, e.g. filename*=UTF-8''Na%C3%AFve.epub, wins over the plain one
+fn disposition_name(disposition: &str) -> Option<String> {
+    let mut plain = None;
+    for part in split_parameters(disposition) {
+        let (key, value) = match part.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+        if key == "filename*" {
+            if let Some(encoded) = value.trim_matches('"').splitn(3, '\'').nth(2) {
+                return Some(percent_decode_str(encoded).decode_utf8_lossy().to_string());
+            }
+        } else if key == "filename" {
+            let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+            plain = Some(value.replace("\\\"", "\"").replace("\\\\", "\\"));
+        }
+    }
+    return plain;
+}
+
+// Never let a server pick the folder the file is written to
+fn safe_name(name: &str) -> String {
+    return name.replace(['/', '\\', ':'], "_").trim().trim_start_matches('.').to_string();
+}
+
+// Name to import a download under, the extension decides how it is read
+// Without a usable name from the server the entry's title and the content type are used
+fn file_name(disposition: Option<&str>, url: &Url, kind: &str, title: Option<&str>) -> String {
+    let from_url = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+        .filter(|segment| segment.contains('.'));
+    let name = disposition
+        .and_then(disposition_name)
+        .map(|name| safe_name(&name))
+        .filter(|name| name.len() > 0)
+        .or(from_url.map(|name| safe_name(&name)))
+        .unwrap_or_default();
+
+    let has_extension = Path::new(&name).extension().map(|extension| extension.len() > 0).unwrap_or(false);
+    if has_extension {
+        return name;
+    }
+    let title = title.map(safe_name).filter(|title| title.len() > 0);
+    let stem = title.or(Some(name).filter(|name| name.len() > 0)).unwrap_or("download".to_string());
+    return format!("{}.{}", stem, extension_for(kind));
+}
+
+fn download_name(response: &reqwest::Response, title: Option<&str>) -> String {
+    let headers = response.headers();
+    let disposition = headers.get(header::CONTENT_DISPOSITION).and_then(|value| value.to_str().ok());
+    let kind = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    return file_name(disposition, response.url(), kind, title);
+}
+
+// Download one of an entry's acquisition links and import it like a local file
+// The entry's title and author are kept for formats Alexandria can not read metadata from
+#[tauri::command]
+pub async fn import_opds_book(catalog: String, url: String, title: Option<String>, author: Option<String>) -> Result<BookHydrate, String> {
+    let catalog = find_catalog(&catalog)?;
+    let url = Url::parse(&url).map_err(|e| format!("Error: Invalid URL \"{}\" : {}", url, e))?;
+    let response = fetch(&catalog, &url).await?;
+    let name = download_name(&response, title.as_deref());
+    let body = response.bytes().await.map_err(|e| format!("Error: Download of {} failed : {}", name, e))?;
+
+    let folder: PathBuf = std::env::temp_dir().join(format!("alexandria-opds-{}", crate::current_time_millis()));
+    fs::create_dir_all(&folder).map_err(|e| format!("Error: Could not create \"{}\" : {}", folder.display(), e))?;
+    let path = folder.join(&name);
+    let result = match fs::write(&path, &body) {
+        Ok(_) => {
+            let payload = path.display().to_string();
+            tokio::task::spawn_blocking(move || import_book(payload))
+                .await
+                .map_err(|e| format!("Error: Import of {} failed : {}", name, e))
+                .and_then(|result| result)
+        }
+        Err(error) => Err(format!("Error: Could not save {} : {}", name, error)),
+    };
+    if let Err(error) = fs::remove_dir_all(&folder) {
+        println!("Error: Could not remove \"{}\" : {}", folder.display(), error);
+    }
+
+    let mut book = result?;
+    if book.title == name {
+        if let Some(title) = title.filter(|title| title.len() > 0) {
+            let author = author.unwrap_or_default();
+            book_state::update(&book.hash, None, |payload, _previous| {
+                payload.title = title.clone();
+                payload.author = author.clone();
+                return Ok(());
+            })?;
+            book.title = title;
+            book.author = author;
+        }
+    }
+    return Ok(book);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Calibre Library</title>
+  <link rel="next" href="?page=2" type="application/atom+xml;profile=opds-catalog"/>
+  <link rel="search" href="/opds/search/{searchTerms}" type="application/atom+xml"/>
+  <entry>
+    <title>The Book</title>
+    <id>urn:book:1</id>
+    <author><name>First Author</name></author>
+    <author><name>Second Author</name></author>
+    <summary>A summary</summary>
+    <link rel="http://opds-spec.org/acquisition" href="/get/epub/1" type="application/epub+zip"/>
+    <link rel="http://opds-spec.org/image/thumbnail" href="/thumb/1"/>
+    <link rel="http://opds-spec.org/image" href="/cover/1"/>
+  </entry>
+  <entry>
+    <title>By Author</title>
+    <link rel="subsection" href="authors" type="application/atom+xml;profile=opds-catalog;kind=navigation"/>
+  </entry>
+</feed>"#;
+
+    // Serves `routes` by path over plain HTTP and records each request line with its Authorization header
+    fn serve(routes: Vec<(&'static str, &'static str, &'static str)>) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    let lower = line.to_lowercase();
+                    if request.is_empty() || lower.starts_with("authorization") {
+                        request.push_str(line.trim_end());
+                        request.push(' ');
+                    }
+                    line.clear();
+                }
+                let path = request.split(' ').nth(1).unwrap_or("").to_string();
+                seen.lock().unwrap().push(request.trim_end().to_string());
+
+                let response = match routes.iter().find(|(route, _, _)| *route == path) {
+                    Some((_, kind, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        kind,
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                let mut stream = stream;
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        return (url, requests);
+    }
+
+    fn catalog(url: &Url) -> OpdsCatalog {
+        return OpdsCatalog {
+            name: "Test".to_string(),
+            url: url.join("opds").unwrap().to_string(),
+            username: "reader".to_string(),
+            password: "secret".to_string(),
+        };
+    }
+
+    #[test]
+    fn reads_atom_feeds() {
+        let base = Url::parse("http://books.local/opds").unwrap();
+        let page = parse_atom(&base, FEED).unwrap();
+        assert_eq!(page.title, "Calibre Library");
+        assert_eq!(page.next.as_deref(), Some("http://books.local/opds?page=2"));
+        assert_eq!(page.search.as_deref(), Some("http://books.local/opds/search/{searchTerms}"));
+
+        let book = &page.entries[0];
+        assert_eq!(book.title, "The Book");
+        assert_eq!(book.author, "First Author, Second Author");
+        assert_eq!(book.cover.as_deref(), Some("http://books.local/cover/1"));
+        assert_eq!(book.acquisitions[0].href, "http://books.local/get/epub/1");
+        assert_eq!(book.acquisitions[0].kind, "application/epub+zip");
+        assert_eq!(page.entries[1].navigation.as_deref(), Some("http://books.local/authors"));
+    }
+
+    #[test]
+    fn reads_json_feeds() {
+        let base = Url::parse("https://books.local/opds/v2/").unwrap();
+        let body = r#"{
+            "metadata": { "title": "Kavita" },
+            "links": [{ "rel": "search", "href": "search{?query}", "type": "application/opds+json", "templated": true }],
+            "navigation": [{ "title": "Libraries", "href": "libraries", "type": "application/opds+json" }],
+            "groups": [{
+                "publications": [{
+                    "metadata": { "title": { "en": "Grouped" }, "author": [{ "name": "Someone" }] },
+                    "links": [{ "rel": "http://opds-spec.org/acquisition", "href": "/download/2", "type": "application/pdf" }],
+                    "images": [{ "href": "cover/2.jpg" }]
+                }]
+            }]
+        }"#;
+        let page = parse_json(&base, body).unwrap();
+        assert_eq!(page.title, "Kavita");
+        assert_eq!(page.search.as_deref(), Some("https://books.local/opds/v2/search{?query}"));
+        assert_eq!(page.entries[0].navigation.as_deref(), Some("https://books.local/opds/v2/libraries"));
+        assert_eq!(page.entries[1].title, "Grouped");
+        assert_eq!(page.entries[1].author, "Someone");
+        assert_eq!(page.entries[1].acquisitions[0].href, "https://books.local/download/2");
+        assert_eq!(page.entries[1].cover.as_deref(), Some("https://books.local/opds/v2/cover/2.jpg"));
+    }
+
+    #[test]
+    fn names_downloads() {
+        let url = Url::parse("http://books.local/get/epub/1").unwrap();
+        let epub = "application/epub+zip";
+        assert_eq!(file_name(Some("attachment; filename=\"Book; Part 1.epub\""), &url, epub, None), "Book; Part 1.epub");
+        assert_eq!(
+            file_name(Some("attachment; filename=\"fallback.epub\"; filename*=UTF-8''Na%C3%AFve.epub"), &url, epub, None),
+            "Naïve.epub"
+        );
+        assert_eq!(file_name(Some("attachment; filename=\"../../evil.epub\""), &url, epub, None), "_.._evil.epub");
+        // Names without an extension take the title and the content type
+        assert_eq!(file_name(Some("attachment; filename=\"1\""), &url, "application/pdf", Some("A/B")), "A_B.pdf");
+        assert_eq!(file_name(None, &url, epub, Some("The Book")), "The Book.epub");
+        assert_eq!(file_name(Some("attachment; filename=\"\""), &url, epub, None), "download.epub");
+
+        let url = Url::parse("http://books.local/files/My%20Book.mobi?token=1").unwrap();
+        assert_eq!(file_name(None, &url, "application/octet-stream", Some("Title")), "My Book.mobi");
+    }
+
+    #[tokio::test]
+    async fn sends_credentials_to_the_catalog_only() {
+        let (url, requests) = serve(vec![("/opds", "application/atom+xml", FEED)]);
+        let page = load_page(&catalog(&url), url.join("opds").unwrap().as_str()).await.unwrap();
+        assert_eq!(page.entries.len(), 2);
+        assert!(requests.lock().unwrap()[0].contains("Basic cmVhZGVyOnNlY3JldA=="));
+
+        // Another origin, such as a download mirror, never gets the password
+        let (other, other_requests) = serve(vec![("/opds", "application/atom+xml", FEED)]);
+        load_page(&catalog(&url), other.join("opds").unwrap().as_str()).await.unwrap();
+        assert!(!other_requests.lock().unwrap()[0].to_lowercase().contains("authorization"));
+    }
+
+    #[tokio::test]
+    async fn finds_the_open_search_template() {
+        let description = r#"<?xml version="1.0"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <Url type="text/html" template="/search.html?q={searchTerms}"/>
+  <Url type="application/atom+xml" template="/opds/search?q={searchTerms}&amp;page={startPage?}"/>
+</OpenSearchDescription>"#;
+        let (url, _) = serve(vec![("/opensearch.xml", OPEN_SEARCH, description)]);
+        let template = open_search_template(&catalog(&url), url.join("opensearch.xml").unwrap().as_str()).await.unwrap();
+        assert_eq!(template, url.join("opds/search").unwrap().to_string() + "?q={searchTerms}&page={startPage?}");
+    }
+
+    #[tokio::test]
+    async fn reports_failed_requests() {
+        let (url, _) = serve(Vec::new());
+        let error = load_page(&catalog(&url), url.join("missing").unwrap().as_str()).await.unwrap_err();
+        assert!(error.starts_with("Error:") && error.contains("404"));
+    }
+}
@@ -0,0 +1,489 @@
+// Typed app settings stored in settings.json
+// Every key has a default, values are validated before they are saved, and each change
+// is broadcast with the "settings-changed" event so all windows apply it at once.
+// Passwords and keys never leave the backend: the webviews get REDACTED in their place and
+// backups are written without them.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use tauri::Manager;
+
+use crate::{app_handle, get_config_path, migrations, opds, storage};
+
+pub const SETTINGS_CHANGED: &str = "settings-changed";
+
+const SORT_FIELDS: [&str; 3] = ["title", "progress", "modified"];
+const SORT_DIRECTIONS: [&str; 2] = ["ASC", "DESC"];
+const KOSYNC_DIGESTS: [&str; 2] = ["binary", "filename"];
+const SYNC_TARGETS: [&str; 3] = ["", "folder", "webdav"];
+// OPDS catalogs also carry a password each
+const SECRET_SETTINGS: [&str; 3] = ["opdsPassword", "kosyncKey", "syncPassword"];
+// Stands in for a secret that is set, sent back unchanged it keeps the stored secret
+pub const REDACTED: &str = "********";
+
+// Held while a patch is applied, so patches from the webviews, sync and kosync do not undo each other
+static settings_lock: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub selectedTheme: String,
+    pub sortBy: String,
+    pub sortDirection: String,
+    // Width of the reader in percent of the window
+    #[serde(deserialize_with = "lenient_number")]
+    pub readerMargins: i64,
+    // Minutes between automatic backups, 0 turns them off
+    #[serde(deserialize_with = "lenient_number")]
+    pub backupInterval: u64,
+    #[serde(deserialize_with = "lenient_number")]
+    pub backupCount: u64,
+    // Empty for Alexandria_Data/backups
+    pub backupPath: String,
+    // Port of the local asset server on linux, 0 for any free port. Applies after a restart
+    #[serde(deserialize_with = "lenient_number")]
+    pub assetServerPort: u16,
+    // OPDS catalog for other devices, see opds.rs
+    pub opdsEnabled: bool,
+    pub opdsAddress: String,
+    #[serde(deserialize_with = "lenient_number")]
+    pub opdsPort: u16,
+    // Empty for no authentication
+    pub opdsUsername: String,
+    pub opdsPassword: String,
+    // Remote catalogs to browse and import from, see opds_client.rs
+    pub opdsCatalogs: Vec<OpdsCatalog>,
+    // KOReader progress sync, see kosync.rs
+    pub kosyncServer: String,
+    pub kosyncUsername: String,
+    // MD5 of the password, which is what the server expects
+    pub kosyncKey: String,
+    pub kosyncDevice: String,
+    pub kosyncDeviceId: String,
+    // "binary" or "filename", must match the document matching setting in KOReader
+    pub kosyncDigest: String,
+    // Sync with other devices, see sync.rs. "" turns it off, "folder" uses syncFolder, "webdav" syncUrl
+    pub syncTarget: String,
+    pub syncFolder: String,
+    pub syncUrl: String,
+    pub syncUsername: String,
+    pub syncPassword: String,
+    // Minutes between automatic syncs, 0 to only sync on request
+    #[serde(deserialize_with = "lenient_number")]
+    pub syncInterval: u64,
+    pub version: String,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct OpdsCatalog {
+    pub name: String,
+    pub url: String,
+    // Empty for catalogs without authentication
+    pub username: String,
+    pub password: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        return Settings {
+            selectedTheme: "Default Light".to_string(),
+            sortBy: "title".to_string(),
+            sortDirection: "ASC".to_string(),
+            readerMargins: 75,
+            backupInterval: 24 * 60,
+            backupCount: 7,
+            backupPath: String::new(),
+            assetServerPort: 0,
+            opdsEnabled: false,
+            opdsAddress: "127.0.0.1".to_string(),
+            opdsPort: 16781,
+            opdsUsername: String::new(),
+            opdsPassword: String::new(),
+            opdsCatalogs: Vec::new(),
+            kosyncServer: "https://sync.koreader.rocks".to_string(),
+            kosyncUsername: String::new(),
+            kosyncKey: String::new(),
+            kosyncDevice: "Alexandria".to_string(),
+            kosyncDeviceId: String::new(),
+            kosyncDigest: "binary".to_string(),
+            syncTarget: String::new(),
+            syncFolder: String::new(),
+            syncUrl: String::new(),
+            syncUsername: String::new(),
+            syncPassword: String::new(),
+            syncInterval: 15,
+            version: migrations::SETTINGS_VERSION.to_string(),
+            extra: HashMap::new(),
+        };
+    }
+}
+
+// Older releases saved every setting as a string
+fn lenient_number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr + de::DeserializeOwned,
+{
+    let value = Value::deserialize(deserializer)?;
+    return match value {
+        Value::String(text) => text.trim().parse().map_err(|_| de::Error::custom(format!("\"{}\" is not a number", text))),
+        value => serde_json::from_value(value).map_err(de::Error::custom),
+    };
+}
+
+impl Settings {
+    fn validate(&self) -> Result<(), String> {
+        if self.selectedTheme.trim().len() == 0 {
+            return Err("Error: selectedTheme can not be empty".to_string());
+        }
+        if !SORT_FIELDS.contains(&self.sortBy.as_str()) {
+            return Err(format!("Error: sortBy must be one of {:?}, got \"{}\"", SORT_FIELDS, self.sortBy));
+        }
+        if !SORT_DIRECTIONS.contains(&self.sortDirection.as_str()) {
+            return Err(format!("Error: sortDirection must be one of {:?}, got \"{}\"", SORT_DIRECTIONS, self.sortDirection));
+        }
+        if self.readerMargins < 5 || self.readerMargins > 100 {
+            return Err(format!("Error: readerMargins must be between 5 and 100, got {}", self.readerMargins));
+        }
+        if self.backupCount < 1 || self.backupCount > 1000 {
+            return Err(format!("Error: backupCount must be between 1 and 1000, got {}", self.backupCount));
+        }
+        if self.assetServerPort != 0 && self.assetServerPort < 1024 {
+            return Err(format!("Error: assetServerPort must be 0 or between 1024 and 65535, got {}", self.assetServerPort));
+        }
+        if self.opdsAddress.parse::<std::net::IpAddr>().is_err() {
+            return Err(format!("Error: opdsAddress must be an IP address, got \"{}\"", self.opdsAddress));
+        }
+        if self.opdsPort < 1024 {
+            return Err(format!("Error: opdsPort must be between 1024 and 65535, got {}", self.opdsPort));
+        }
+        if self.opdsUsername.len() > 0 && self.opdsPassword.len() == 0 {
+            return Err("Error: opdsPassword can not be empty when opdsUsername is set".to_string());
+        }
+        for (index, catalog) in self.opdsCatalogs.iter().enumerate() {
+            if catalog.name.trim().len() == 0 {
+                return Err("Error: OPDS catalogs need a name".to_string());
+            }
+            if self.opdsCatalogs[..index].iter().any(|other| other.name == catalog.name) {
+                return Err(format!("Error: There is already an OPDS catalog named \"{}\"", catalog.name));
+            }
+            if !catalog.url.starts_with("http://") && !catalog.url.starts_with("https://") {
+                return Err(format!("Error: OPDS catalog URL must start with http:// or https://, got \"{}\"", catalog.url));
+            }
+        }
+        if !self.kosyncServer.starts_with("http://") && !self.kosyncServer.starts_with("https://") {
+            return Err(format!("Error: kosyncServer must start with http:// or https://, got \"{}\"", self.kosyncServer));
+        }
+        if !KOSYNC_DIGESTS.contains(&self.kosyncDigest.as_str()) {
+            return Err(format!("Error: kosyncDigest must be one of {:?}, got \"{}\"", KOSYNC_DIGESTS, self.kosyncDigest));
+        }
+        if !SYNC_TARGETS.contains(&self.syncTarget.as_str()) {
+            return Err(format!("Error: syncTarget must be one of {:?}, got \"{}\"", SYNC_TARGETS, self.syncTarget));
+        }
+        if self.syncTarget == "folder" && self.syncFolder.trim().len() == 0 {
+            return Err("Error: syncFolder can not be empty when syncing with a folder".to_string());
+        }
+        if self.syncTarget == "webdav" && !self.syncUrl.starts_with("http://") && !self.syncUrl.starts_with("https://") {
+            return Err(format!("Error: syncUrl must start with http:// or https://, got \"{}\"", self.syncUrl));
+        }
+        return Ok(());
+    }
+
+    // Copy for the webviews
+    pub fn redacted(&self) -> Settings {
+        let mut settings = self.clone();
+        for secret in [&mut settings.opdsPassword, &mut settings.kosyncKey, &mut settings.syncPassword] {
+            if secret.len() > 0 {
+                *secret = REDACTED.to_string();
+            }
+        }
+        for catalog in settings.opdsCatalogs.iter_mut() {
+            if catalog.password.len() > 0 {
+                catalog.password = REDACTED.to_string();
+            }
+        }
+        return settings;
+    }
+
+    fn opds_changed(&self, other: &Settings) -> bool {
+        return self.opdsEnabled != other.opdsEnabled
+            || self.opdsAddress != other.opdsAddress
+            || self.opdsPort != other.opdsPort
+            || self.opdsUsername != other.opdsUsername
+            || self.opdsPassword != other.opdsPassword;
+    }
+}
+
+// Removes every secret from a settings object, such as the copy written to a backup
+pub fn strip_secrets(object: &mut Map<String, Value>) {
+    for key in SECRET_SETTINGS {
+        object.remove(key);
+    }
+    if let Some(Value::Array(catalogs)) = object.get_mut("opdsCatalogs") {
+        for catalog in catalogs.iter_mut() {
+            if let Value::Object(catalog) = catalog {
+                catalog.remove("password");
+            }
+        }
+    }
+}
+
+fn is_kept(value: Option<&Value>) -> bool {
+    return value.map(|value| value.as_str() == Some(REDACTED)).unwrap_or(true);
+}
+
+// Secrets missing from `object` or sent back as REDACTED are taken from `current`
+// Catalogs are matched by URL and username
+pub fn keep_secrets(object: &mut Map<String, Value>, current: &Settings) {
+    let current_values = match serde_json::to_value(current) {
+        Ok(Value::Object(values)) => values,
+        _ => Map::new(),
+    };
+    for key in SECRET_SETTINGS {
+        if is_kept(object.get(key)) {
+            object.insert(key.to_string(), current_values.get(key).cloned().unwrap_or(Value::from("")));
+        }
+    }
+    if let Some(Value::Array(catalogs)) = object.get_mut("opdsCatalogs") {
+        for catalog in catalogs.iter_mut() {
+            let catalog = match catalog {
+                Value::Object(catalog) => catalog,
+                _ => continue,
+            };
+            if !is_kept(catalog.get("password")) {
+                continue;
+            }
+            let url = catalog.get("url").and_then(|url| url.as_str()).unwrap_or("");
+            let username = catalog.get("username").and_then(|username| username.as_str()).unwrap_or("");
+            let password = current
+                .opdsCatalogs
+                .iter()
+                .find(|known| known.url == url && known.username == username)
+                .map(|known| known.password.clone())
+                .unwrap_or_default();
+            catalog.insert("password".to_string(), Value::from(password));
+        }
+    }
+}
+
+// Settings file of a library being restored from a backup, which has no secrets, gets ours
+pub fn keep_secrets_in(path: &std::path::Path) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut object: Map<String, Value> = storage::read_json(path)?;
+    keep_secrets(&mut object, &load());
+    return storage::write_json(path, &object);
+}
+
+fn settings_path() -> std::path::PathBuf {
+    return get_config_path().join("settings.json");
+}
+
+fn read_object() -> Map<String, Value> {
+    return storage::read_json(&settings_path()).unwrap_or_default();
+}
+
+// Keys that are missing or do not parse fall back to their default one by one,
+// so a single bad value does not reset every other setting
+pub fn load() -> Settings {
+    let stored = read_object();
+    let mut settings = Settings::default();
+    for (key, value) in stored {
+        let mut patched = serde_json::to_value(&settings).unwrap_or(Value::Null);
+        if let Value::Object(object) = &mut patched {
+            object.insert(key.clone(), value);
+        }
+        match serde_json::from_value::<Settings>(patched) {
+            Ok(parsed) => settings = parsed,
+            Err(error) => println!("Error: Ignoring setting {} : {}", key, error),
+        }
+    }
+    return settings;
+}
+
+#[tauri::command]
+pub fn get_settings() -> Settings {
+    return load().redacted();
+}
+
+// Applies only the keys in `patch`, the result is validated as a whole before it is saved
+// Returns the settings as the webviews see them
+#[tauri::command]
+pub fn update_settings(patch: Map<String, Value>) -> Result<Settings, String> {
+    let _updating = settings_lock.lock().map_err(|_| "Error: Settings are unavailable after a failed update".to_string())?;
+    let current = load();
+    let mut object = match serde_json::to_value(&current) {
+        Ok(Value::Object(object)) => object,
+        _ => Map::new(),
+    };
+    for (key, value) in patch {
+        object.insert(key, value);
+    }
+    keep_secrets(&mut object, &current);
+
+    let settings: Settings = serde_json::from_value(Value::Object(object)).map_err(|e| format!("Error: Invalid setting : {}", e))?;
+    settings.validate()?;
+    if settings == current {
+        return Ok(settings.redacted());
+    }
+
+    if settings.opds_changed(&current) {
+        opds::check(&settings)?;
+    }
+    storage::write_json(&settings_path(), &settings)?;
+    apply_change(&current, &settings);
+    return Ok(settings.redacted());
+}
+
+// Restarts what depends on the settings and tells every window, after settings.json was written
+pub fn apply_change(previous: &Settings, settings: &Settings) {
+    if settings.opds_changed(previous) {
+        if let Err(error) = opds::apply(settings) {
+            println!("{}", error);
+        }
+    }
+    if let Some(handle) = app_handle.get() {
+        if let Err(error) = handle.emit_all(SETTINGS_CHANGED, settings.redacted()) {
+            println!("Error: Could not broadcast settings change: {:?}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn object(value: Value) -> Map<String, Value> {
+        return value.as_object().unwrap().clone();
+    }
+
+    fn with(patch: Value) -> Settings {
+        let mut values = object(serde_json::to_value(Settings::default()).unwrap());
+        values.extend(object(patch));
+        return serde_json::from_value(Value::Object(values)).unwrap();
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+        assert!(with(json!({"sortBy": "author"})).validate().is_err());
+        assert!(with(json!({"sortDirection": "asc"})).validate().is_err());
+        assert!(with(json!({"readerMargins": 4})).validate().is_err());
+        assert!(with(json!({"backupCount": 0})).validate().is_err());
+        assert!(with(json!({"assetServerPort": 80})).validate().is_err());
+        assert!(with(json!({"opdsAddress": "localhost"})).validate().is_err());
+        assert!(with(json!({"opdsUsername": "reader"})).validate().is_err());
+        assert!(with(json!({"kosyncServer": "sync.koreader.rocks"})).validate().is_err());
+        assert!(with(json!({"syncTarget": "folder"})).validate().is_err());
+        assert!(with(json!({"syncTarget": "webdav", "syncUrl": "dav.example.com"})).validate().is_err());
+        assert_eq!(with(json!({"syncTarget": "webdav", "syncUrl": "https://dav.example.com"})).validate(), Ok(()));
+
+        let catalog = json!({"name": "Shelf", "url": "https://example.com/opds"});
+        assert_eq!(with(json!({"opdsCatalogs": [catalog]})).validate(), Ok(()));
+        assert!(with(json!({"opdsCatalogs": [catalog, catalog]})).validate().is_err());
+        assert!(with(json!({"opdsCatalogs": [{"name": " ", "url": "https://example.com"}]})).validate().is_err());
+        assert!(with(json!({"opdsCatalogs": [{"name": "Shelf", "url": "ftp://example.com"}]})).validate().is_err());
+    }
+
+    #[test]
+    fn reads_numbers_saved_as_strings() {
+        let settings: Settings = serde_json::from_value(json!({"readerMargins": " 60 ", "opdsPort": "8080", "syncInterval": 15})).unwrap();
+        assert_eq!((settings.readerMargins, settings.opdsPort, settings.syncInterval), (60, 8080, 15));
+        assert!(serde_json::from_value::<Settings>(json!({"readerMargins": "wide"})).is_err());
+        assert!(serde_json::from_value::<Settings>(json!({"opdsPort": "70000"})).is_err());
+    }
+
+    #[test]
+    fn strips_and_keeps_secrets() {
+        let current = with(json!({
+            "opdsPassword": "opds",
+            "kosyncKey": "key",
+            "opdsCatalogs": [{"name": "Shelf", "url": "https://example.com/opds", "username": "reader", "password": "shelf"}]
+        }));
+        let mut stripped = object(serde_json::to_value(&current).unwrap());
+        strip_secrets(&mut stripped);
+        assert!(!stripped.contains_key("opdsPassword") && !stripped.contains_key("kosyncKey") && !stripped.contains_key("syncPassword"));
+        assert_eq!(stripped["opdsCatalogs"][0], json!({"name": "Shelf", "url": "https://example.com/opds", "username": "reader"}));
+
+        keep_secrets(&mut stripped, &current);
+        assert_eq!(serde_json::from_value::<Settings>(Value::Object(stripped)).unwrap(), current);
+
+        // Redacted values are kept, new values replace them, and catalogs only get the password of the same account
+        let mut sent = object(serde_json::to_value(current.redacted()).unwrap());
+        sent.insert("kosyncKey".to_string(), Value::from("new"));
+        sent.insert(
+            "opdsCatalogs".to_string(),
+            json!([
+                {"name": "Shelf", "url": "https://example.com/opds", "username": "reader", "password": REDACTED},
+                {"name": "Other", "url": "https://example.com/opds", "username": "other", "password": REDACTED}
+            ]),
+        );
+        keep_secrets(&mut sent, &current);
+        assert_eq!(sent["opdsPassword"], "opds");
+        assert_eq!(sent["kosyncKey"], "new");
+        assert_eq!(sent["syncPassword"], "");
+        assert_eq!(sent["opdsCatalogs"][0]["password"], "shelf");
+        assert_eq!(sent["opdsCatalogs"][1]["password"], "");
+    }
+
+    #[test]
+    fn loads_and_updates_settings() {
+        let _library = crate::exclusive_test_library();
+        storage::write_json(&settings_path(), &json!({"readerMargins": "wide", "sortBy": "progress", "opdsPassword": "opds"})).unwrap();
+
+        // A bad value falls back to its default without resetting the others
+        let loaded = load();
+        assert_eq!(loaded.readerMargins, Settings::default().readerMargins);
+        assert_eq!(loaded.sortBy, "progress");
+        assert_eq!(get_settings().opdsPassword, REDACTED);
+
+        assert!(update_settings(object(json!({"readerMargins": 500}))).is_err());
+        let updated = update_settings(object(json!({"readerMargins": 50, "opdsPassword": REDACTED}))).unwrap();
+        assert_eq!(updated.opdsPassword, REDACTED);
+        assert_eq!(load().opdsPassword, "opds");
+        assert_eq!(load().sortBy, "progress");
+
+        // Patches made at the same time all end up in settings.json
+        let patches = [
+            json!({"readerMargins": 60}),
+            json!({"sortBy": "title"}),
+            json!({"sortDirection": "DESC"}),
+            json!({"backupCount": 3}),
+            json!({"syncInterval": 30}),
+        ];
+        let threads: Vec<_> = patches.into_iter().map(|patch| std::thread::spawn(move || update_settings(object(patch)).unwrap())).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let settings = load();
+        assert_eq!(
+            (settings.readerMargins, settings.sortBy.as_str(), settings.sortDirection.as_str(), settings.backupCount, settings.syncInterval),
+            (60, "title", "DESC", 3, 30)
+        );
+
+        fs::remove_file(settings_path()).unwrap();
+        fs::remove_file(storage::backup_path(&settings_path())).ok();
+    }
+
+    #[test]
+    fn keeps_unknown_settings() {
+        let _library = crate::exclusive_test_library();
+        storage::write_json(&settings_path(), &json!({"sortBy": "progress", "futureOption": {"enabled": true}})).unwrap();
+
+        update_settings(object(json!({"sortBy": "title"}))).unwrap();
+        let saved: Value = storage::read_json(&settings_path()).unwrap();
+        assert_eq!(saved["sortBy"], "title");
+        assert_eq!(saved["futureOption"], json!({"enabled": true}));
+
+        fs::remove_file(settings_path()).unwrap();
+        fs::remove_file(storage::backup_path(&settings_path())).ok();
+    }
+}
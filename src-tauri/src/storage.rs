@@ -0,0 +1,154 @@
+// Crash safe reads and writes for the JSON state files in Alexandria_Data
+// Writes go to <file>.tmp, are synced to disk and then renamed over the original,
+// so a crash leaves either the old or the new file but never a truncated one.
+// The previous version is kept as <file>.bak and is used when the primary does not parse.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    return path.with_file_name(name);
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    return with_suffix(path, ".bak");
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    return file.sync_all();
+}
+
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = with_suffix(path, ".tmp");
+    write_synced(&temp_path, contents)?;
+
+    // Only keep a backup of files that are themselves readable
+    // It is replaced the same way, a crash must not leave a truncated backup either
+    if path.exists() {
+        if let Ok(previous) = fs::read(path) {
+            if serde_json::from_slice::<serde_json::Value>(&previous).is_ok() {
+                let backup = backup_path(path);
+                let backup_temp = with_suffix(&backup, ".tmp");
+                write_synced(&backup_temp, &previous)?;
+                fs::rename(&backup_temp, &backup)?;
+            }
+        }
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    // Persist the rename itself, directories cannot be opened this way on windows
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        if let Ok(directory) = File::open(parent) {
+            let _ = directory.sync_all();
+        }
+    }
+    return Ok(());
+}
+
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(value).map_err(|e| format!("Error: {}", e))?;
+    write_atomic(path, contents.as_bytes()).map_err(|e| format!("Error: Could not write \"{}\" : {}", path.display(), e))
+}
+
+fn parse_file<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let contents = fs::read(path).map_err(|e| format!("Error: Could not read \"{}\" : {}", path.display(), e))?;
+    return serde_json::from_slice(&contents).map_err(|e| format!("Malformed Data: {}", e));
+}
+
+// Reads a JSON file, falling back to the backup and restoring it when the primary is damaged
+// Errors are about the primary, the backup is only a fallback
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let error = match parse_file(path) {
+        Ok(value) => return Ok(value),
+        Err(error) => error,
+    };
+
+    let backup = backup_path(path);
+    if !backup.exists() {
+        return Err(error);
+    }
+    println!("{} - using backup \"{}\"", error, backup.display());
+    let value: T = parse_file(&backup).map_err(|backup_error| {
+        println!("{} - backup \"{}\"", backup_error, backup.display());
+        return error;
+    })?;
+    if let Ok(contents) = fs::read(&backup) {
+        if let Err(restore_error) = write_atomic(path, &contents) {
+            println!("Error: Could not restore \"{}\" : {}", path.display(), restore_error);
+        }
+    }
+    return Ok(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn folder() -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("alexandria-storage-{}-{}", crate::current_time_millis(), rand::random::<u32>()));
+        fs::create_dir_all(&folder).unwrap();
+        return folder;
+    }
+
+    #[test]
+    fn write_atomic_keeps_the_previous_version() {
+        let path = folder().join("data.json");
+        write_json(&path, &json!({"version": 1})).unwrap();
+        assert!(!backup_path(&path).exists());
+
+        write_json(&path, &json!({"version": 2})).unwrap();
+        assert_eq!(read_json::<Value>(&path).unwrap(), json!({"version": 2}));
+        assert_eq!(parse_file::<Value>(&backup_path(&path)).unwrap(), json!({"version": 1}));
+        assert!(!with_suffix(&path, ".tmp").exists());
+        assert!(!with_suffix(&backup_path(&path), ".tmp").exists());
+    }
+
+    #[test]
+    fn write_atomic_does_not_back_up_damaged_files() {
+        let path = folder().join("data.json");
+        write_json(&path, &json!({"version": 1})).unwrap();
+        write_json(&path, &json!({"version": 2})).unwrap();
+        fs::write(&path, "{\"version\": 3").unwrap();
+
+        write_json(&path, &json!({"version": 4})).unwrap();
+        assert_eq!(parse_file::<Value>(&backup_path(&path)).unwrap(), json!({"version": 1}));
+    }
+
+    #[test]
+    fn read_json_falls_back_to_the_backup() {
+        let path = folder().join("data.json");
+        write_json(&path, &json!({"version": 1})).unwrap();
+        write_json(&path, &json!({"version": 2})).unwrap();
+        fs::write(&path, "{\"version\": 2").unwrap();
+
+        assert_eq!(read_json::<Value>(&path).unwrap(), json!({"version": 1}));
+        // The primary is restored from the backup
+        assert_eq!(parse_file::<Value>(&path).unwrap(), json!({"version": 1}));
+    }
+
+    #[test]
+    fn read_json_reports_the_primary_error() {
+        let path = folder().join("data.json");
+        assert!(read_json::<Value>(&path).unwrap_err().starts_with("Error: Could not read"));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(read_json::<Value>(&path).unwrap_err().starts_with("Malformed Data"));
+
+        fs::write(backup_path(&path), "not json either").unwrap();
+        assert!(read_json::<Value>(&path).unwrap_err().starts_with("Malformed Data"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not json");
+    }
+}
@@ -0,0 +1,1020 @@
+// Sync of the reading state between devices through a shared folder or a WebDAV server
+// The target holds books/<hash>.json for every book, settings.json, GlobalThemes.json and
+// fonts.json as records that each carry their own modification time, and the files of
+// downloaded fonts. What the target looked like at the last sync is kept in Alexandria_Data/.sync
+// and is the base of a three way merge, so highlights added or deleted on two devices in between
+// are all kept or deleted instead of one copy overwriting the other. When both devices changed
+// the same record the newer change wins.
+// A file is only written if it did not change since it was read, otherwise it is merged again.
+// Book files are not synced, books this library does not have are left alone.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{blocking::Client, header, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tauri::Manager;
+
+use crate::settings::{self, Settings};
+use crate::{app_handle, backup, book_state, current_time_millis, get_config_path, get_font_folder_path, get_hash, merge, migrations, storage};
+use crate::{read_fonts_json, updateBookPayload, write_fonts_json};
+
+pub const SYNC_FINISHED: &str = "sync-finished";
+
+const SYNC_FORMAT: &str = "alexandria-sync";
+// Bump when the layout of the target changes, older versions refuse to sync with it
+const SYNC_SCHEMA_VERSION: u32 = 1;
+const MARKER_FILE: &str = "alexandria-sync.json";
+const BASE_FOLDER: &str = ".sync";
+// How often the scheduler checks whether a sync is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Times a file is merged again when another device writes it at the same time
+const MAX_ATTEMPTS: usize = 3;
+
+// Settings that describe this device rather than how the reader likes to read
+// Credentials stay too, the target stores everything in plain text. Catalogs carry their passwords.
+const LOCAL_SETTINGS: [&str; 20] = [
+    "version",
+    "backupPath",
+    "assetServerPort",
+    "opdsEnabled",
+    "opdsAddress",
+    "opdsPort",
+    "opdsUsername",
+    "opdsPassword",
+    "opdsCatalogs",
+    "kosyncServer",
+    "kosyncUsername",
+    "kosyncKey",
+    "kosyncDevice",
+    "kosyncDeviceId",
+    "syncTarget",
+    "syncFolder",
+    "syncUrl",
+    "syncUsername",
+    "syncPassword",
+    "syncInterval",
+];
+
+const PATH_ESCAPED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+// Held while a sync runs, the scheduler and the sync_now command never run at the same time
+static sync_lock: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize)]
+struct SyncMarker {
+    format: String,
+    schema_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SyncRecord {
+    modified: u64,
+    value: Value,
+    // Files that belong to the record, the font files of a downloaded font
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    files: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SyncedRecords {
+    #[serde(default)]
+    records: BTreeMap<String, SyncRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct SyncState {
+    // The base copies belong to this target, they are thrown away when it changes
+    target: String,
+    lastSync: u64,
+    lastError: String,
+    // Tags of the remote files as they were last read or written, by path
+    etags: HashMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SyncStatus {
+    target: String,
+    lastSync: u64,
+    lastError: String,
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct SyncReport {
+    pub books_uploaded: usize,
+    // Books whose local data changed
+    pub books_updated: usize,
+    pub settings_updated: usize,
+    pub themes_updated: usize,
+    pub fonts_updated: usize,
+    // Files that could not be synced and why, they are tried again next time
+    pub failed: Vec<String>,
+    pub finished: u64,
+}
+
+enum Target {
+    Folder(PathBuf),
+    WebDav {
+        url: String,
+        username: String,
+        password: String,
+        client: Client,
+    },
+}
+
+enum Fetched {
+    Missing,
+    // Still matches the tag it was asked with
+    Unchanged,
+    Found(Vec<u8>, Option<String>),
+}
+
+// What a remote file was when it was read, it is only replaced if it still is
+enum Expected {
+    Missing,
+    Tag(String),
+    // The server gave no tag to compare with
+    Any,
+}
+
+enum Written {
+    Done(Option<String>),
+    Conflict,
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> String {
+    return format!("Error: Could not access \"{}\" : {}", path.display(), error);
+}
+
+impl Target {
+    fn from_settings(settings: &Settings) -> Result<Target, String> {
+        return match settings.syncTarget.as_str() {
+            "folder" => Ok(Target::Folder(PathBuf::from(&settings.syncFolder))),
+            "webdav" => Ok(Target::WebDav {
+                url: settings.syncUrl.trim_end_matches('/').to_string(),
+                username: settings.syncUsername.clone(),
+                password: settings.syncPassword.clone(),
+                client: Client::builder()
+                    .timeout(Duration::from_secs(60))
+                    .build()
+                    .map_err(|e| format!("Error: Could not start WebDAV client : {}", e))?,
+            }),
+            _ => Err("Error: Sync is turned off, choose a folder or WebDAV server first".to_string()),
+        };
+    }
+
+    fn id(&self) -> String {
+        return match self {
+            Target::Folder(folder) => format!("folder:{}", folder.display()),
+            Target::WebDav { url, username, .. } => format!("webdav:{}@{}", username, url),
+        };
+    }
+
+    fn request(&self, method: Method, path: &str) -> reqwest::blocking::RequestBuilder {
+        return match self {
+            Target::WebDav { url, username, password, client } => {
+                let encoded: Vec<String> = path.split('/').map(|part| utf8_percent_encode(part, PATH_ESCAPED).to_string()).collect();
+                let request = client.request(method, format!("{}/{}", url, encoded.join("/")));
+                if username.len() > 0 {
+                    request.basic_auth(username, Some(password))
+                } else {
+                    request
+                }
+            }
+            Target::Folder(_) => unreachable!("folders are not requested over http"),
+        };
+    }
+
+    fn fetch(&self, path: &str, etag: Option<&String>) -> Result<Fetched, String> {
+        if let Target::Folder(folder) = self {
+            let file = folder.join(path);
+            return match fs::read(&file) {
+                Ok(contents) => {
+                    let tag = get_hash(&contents);
+                    Ok(Fetched::Found(contents, Some(tag)))
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Fetched::Missing),
+                Err(error) => Err(io_error(&file, error)),
+            };
+        }
+
+        let mut request = self.request(Method::GET, path);
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = request.send().map_err(|e| format!("Error: Could not reach the WebDAV server : {}", e))?;
+        return match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(Fetched::Unchanged),
+            StatusCode::NOT_FOUND => Ok(Fetched::Missing),
+            StatusCode::UNAUTHORIZED => Err("Error: WebDAV server rejected the username or password".to_string()),
+            status if status.is_success() => {
+                let tag = response.headers().get(header::ETAG).and_then(|tag| tag.to_str().ok()).map(|tag| tag.to_string());
+                let contents = response.bytes().map_err(|e| format!("Error: Could not download {} : {}", path, e))?;
+                Ok(Fetched::Found(contents.to_vec(), tag))
+            }
+            status => Err(format!("Error: WebDAV server answered {} for {}", status, path)),
+        };
+    }
+
+    fn put(&self, path: &str, contents: &[u8], expected: &Expected) -> Result<Written, String> {
+        if let Target::Folder(folder) = self {
+            let file = folder.join(path);
+            // Folders have no locking, this only narrows the window in which a write can be lost
+            let current = fs::read(&file).ok();
+            let unchanged = match (expected, &current) {
+                (Expected::Missing, current) => current.is_none(),
+                (Expected::Tag(tag), Some(current)) => &get_hash(current) == tag,
+                (Expected::Tag(_), None) => false,
+                (Expected::Any, _) => true,
+            };
+            if !unchanged {
+                return Ok(Written::Conflict);
+            }
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+            }
+            // Renamed into place so other devices never pick up half a file
+            let temp = PathBuf::from(format!("{}.tmp", file.display()));
+            fs::write(&temp, contents).map_err(|e| io_error(&temp, e))?;
+            fs::rename(&temp, &file).map_err(|e| io_error(&file, e))?;
+            return Ok(Written::Done(Some(get_hash(&contents.to_vec()))));
+        }
+
+        for attempt in 0..2 {
+            let mut request = self.request(Method::PUT, path).body(contents.to_vec());
+            request = match expected {
+                Expected::Missing => request.header(header::IF_NONE_MATCH, "*"),
+                Expected::Tag(tag) => request.header(header::IF_MATCH, tag),
+                Expected::Any => request,
+            };
+            let response = request.send().map_err(|e| format!("Error: Could not reach the WebDAV server : {}", e))?;
+            match response.status() {
+                StatusCode::PRECONDITION_FAILED => return Ok(Written::Conflict),
+                StatusCode::UNAUTHORIZED => return Err("Error: WebDAV server rejected the username or password".to_string()),
+                // The folders of the file do not exist yet
+                StatusCode::NOT_FOUND | StatusCode::CONFLICT if attempt == 0 => self.create_folders(path)?,
+                status if status.is_success() => {
+                    let tag = response.headers().get(header::ETAG).and_then(|tag| tag.to_str().ok()).map(|tag| tag.to_string());
+                    return Ok(Written::Done(tag));
+                }
+                status => return Err(format!("Error: WebDAV server answered {} for {}", status, path)),
+            }
+        }
+        return Err(format!("Error: Could not create the folders of {} on the WebDAV server", path));
+    }
+
+    fn create_folders(&self, path: &str) -> Result<(), String> {
+        let parts: Vec<&str> = path.split('/').collect();
+        // The root first, in case the configured folder itself is missing
+        for depth in 0..parts.len() {
+            let folder = parts[..depth].join("/");
+            let response = self
+                .request(Method::from_bytes(b"MKCOL").unwrap(), &folder)
+                .send()
+                .map_err(|e| format!("Error: Could not reach the WebDAV server : {}", e))?;
+            // 405 when the folder already exists
+            let status = response.status();
+            if !status.is_success() && status != StatusCode::METHOD_NOT_ALLOWED && !status.is_redirection() {
+                return Err(format!("Error: WebDAV server answered {} creating folder \"{}\"", status, folder));
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn base_folder() -> PathBuf {
+    return get_config_path().join(BASE_FOLDER);
+}
+
+fn state_path() -> PathBuf {
+    return base_folder().join("state.json");
+}
+
+fn read_state() -> SyncState {
+    return storage::read_json(&state_path()).unwrap_or_default();
+}
+
+fn write_state(state: &SyncState) -> Result<(), String> {
+    fs::create_dir_all(base_folder()).map_err(|e| io_error(&base_folder(), e))?;
+    return storage::write_json(&state_path(), state);
+}
+
+fn modified_time(path: &Path) -> u64 {
+    return fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_millis() as u64)
+        .unwrap_or_else(current_time_millis);
+}
+
+// Creates the marker on an empty target, and refuses targets made by something else or a newer version
+fn check_marker(target: &Target) -> Result<(), String> {
+    let contents = match target.fetch(MARKER_FILE, None)? {
+        Fetched::Found(contents, _) => contents,
+        _ => {
+            let marker = SyncMarker {
+                format: SYNC_FORMAT.to_string(),
+                schema_version: SYNC_SCHEMA_VERSION,
+            };
+            let contents = serde_json::to_vec_pretty(&marker).map_err(|e| format!("Error: {}", e))?;
+            // Another device creating it at the same time is just as good
+            target.put(MARKER_FILE, &contents, &Expected::Missing)?;
+            return Ok(());
+        }
+    };
+    let marker: SyncMarker = serde_json::from_slice(&contents).map_err(|e| format!("Error: Invalid sync marker {} : {}", MARKER_FILE, e))?;
+    if marker.format != SYNC_FORMAT {
+        return Err(format!("Error: Sync target is not an Alexandria sync folder - {}", marker.format));
+    }
+    if marker.schema_version > SYNC_SCHEMA_VERSION {
+        return Err(format!(
+            "Error: Sync target was written by a newer version of Alexandria (schema {}), please update",
+            marker.schema_version
+        ));
+    }
+    return Ok(());
+}
+
+// Reads the remote copy of `path` and lets `merge` combine it with the local state
+// `merge` gets the copy from the last sync and the remote one, and returns what to upload, if anything,
+// along with what to apply locally. When another device writes the file in between it is merged again.
+// Also returns whether something was uploaded
+fn exchange<T, F>(target: &Target, state: &mut SyncState, path: &str, mut merge: F) -> Result<(T, bool), String>
+where
+    F: FnMut(Option<&[u8]>, Option<&[u8]>) -> Result<(Option<Vec<u8>>, T), String>,
+{
+    let base_path = base_folder().join(path);
+    let base = fs::read(&base_path).ok();
+    // Without the base copy a matching tag says nothing about the contents
+    let known = base.as_ref().and(state.etags.get(path).cloned());
+
+    for _ in 0..MAX_ATTEMPTS {
+        let (remote, expected) = match target.fetch(path, known.as_ref())? {
+            Fetched::Missing => (None, Expected::Missing),
+            Fetched::Unchanged => (base.clone(), Expected::Tag(known.clone().unwrap_or_default())),
+            Fetched::Found(contents, tag) => (Some(contents), tag.map(Expected::Tag).unwrap_or(Expected::Any)),
+        };
+        let (upload, result) = merge(base.as_deref(), remote.as_deref())?;
+
+        let uploaded = upload.is_some();
+        let (stored, tag) = match upload {
+            Some(upload) => match target.put(path, &upload, &expected)? {
+                Written::Conflict => continue,
+                Written::Done(tag) => (upload, tag),
+            },
+            None => match remote {
+                Some(remote) => (remote, if let Expected::Tag(tag) = expected { Some(tag) } else { None }),
+                None => return Ok((result, false)),
+            },
+        };
+
+        match tag {
+            Some(tag) => state.etags.insert(path.to_string(), tag),
+            None => state.etags.remove(path),
+        };
+        if let Some(parent) = base_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+        }
+        storage::write_atomic(&base_path, &stored).map_err(|e| io_error(&base_path, e))?;
+        return Ok((result, uploaded));
+    }
+    return Err(format!("Error: {} kept changing on the sync target, it will be synced next time", path));
+}
+
+// Book data as it is stored on the target, the revision only means something on this device
+fn book_json(payload: &updateBookPayload) -> Result<Vec<u8>, String> {
+    let mut payload = payload.clone();
+    payload.revision = None;
+    return serde_json::to_vec_pretty(&payload).map_err(|e| format!("Error: {}", e));
+}
+
+fn same_book_data(a: &updateBookPayload, b: &updateBookPayload) -> bool {
+    let value = |payload: &updateBookPayload| {
+        let mut payload = payload.clone();
+        payload.revision = None;
+        return serde_json::to_value(payload).ok();
+    };
+    return value(a) == value(b);
+}
+
+fn sync_book(target: &Target, state: &mut SyncState, hash: &str, report: &mut SyncReport) -> Result<(), String> {
+    let local = book_state::get(hash)?;
+    let (merged, uploaded) = exchange(target, state, &format!("books/{}.json", hash), |base, remote| {
+        let remote = match remote {
+            Some(remote) => migrations::book_data_from_slice(remote)?,
+            None => return Ok((Some(book_json(&local)?), None)),
+        };
+        let base = base.map(migrations::book_data_from_slice).transpose()?;
+        let merged = merge::merge_synced(base.as_ref(), &local, &remote);
+        let upload = if same_book_data(&merged, &remote) { None } else { Some(book_json(&merged)?) };
+        let apply = if same_book_data(&merged, &local) { None } else { Some(merged) };
+        return Ok((upload, apply));
+    })?;
+
+    if uploaded {
+        report.books_uploaded += 1;
+    }
+    if let Some(merged) = merged {
+        book_state::update(hash, None, |payload, _previous| {
+            // Keep what changed while the book was syncing, such as the reading position
+            *payload = merge::merge_synced(Some(&local), payload, &merged);
+            return Ok(());
+        })?;
+        report.books_updated += 1;
+    }
+    return Ok(());
+}
+
+fn read_records(contents: &[u8]) -> Result<SyncedRecords, String> {
+    return serde_json::from_slice(contents).map_err(|e| format!("Malformed Data: {}", e));
+}
+
+// Local values as records, those that differ from the base copy changed at `changed`
+fn local_records<F>(values: &Map<String, Value>, base: &SyncedRecords, changed: u64, files: &F) -> BTreeMap<String, SyncRecord>
+where
+    F: Fn(&str, &Value) -> Vec<String>,
+{
+    let mut records = BTreeMap::new();
+    for (key, value) in values.iter() {
+        let files = files(key, value);
+        let record = match base.records.get(key) {
+            Some(record) if &record.value == value && record.files == files => record.clone(),
+            _ => SyncRecord {
+                modified: changed,
+                value: value.clone(),
+                files,
+            },
+        };
+        records.insert(key.clone(), record);
+    }
+    return records;
+}
+
+// Syncs an object of named values, such as the themes by name, one record per key
+// Returns the merged records when they differ from the local values, and whether they were uploaded
+// Keys that are not `shared` are dropped, also when another device or an older version uploaded them
+fn sync_records<F>(
+    target: &Target,
+    state: &mut SyncState,
+    path: &str,
+    values: &Map<String, Value>,
+    changed: u64,
+    files: F,
+    shared: fn(&str) -> bool,
+) -> Result<(Option<BTreeMap<String, SyncRecord>>, bool), String>
+where
+    F: Fn(&str, &Value) -> Vec<String>,
+{
+    return exchange(target, state, path, |base, remote| {
+        let known_base = base.is_some();
+        let base = base.map(read_records).transpose()?.unwrap_or_default();
+        let remote = remote.map(read_records).transpose()?;
+        let local = local_records(values, &base, changed, &files);
+        let theirs = remote.as_ref().map(|remote| &remote.records);
+
+        let mut keys: Vec<&String> = local.keys().collect();
+        keys.extend(theirs.map(|records| records.keys().collect::<Vec<&String>>()).unwrap_or_default());
+        keys.extend(base.records.keys());
+        keys.sort();
+        keys.dedup();
+        keys.retain(|key| shared(key));
+
+        let mut merged = BTreeMap::new();
+        for key in keys {
+            let value = merge::merge_value(
+                known_base,
+                base.records.get(key),
+                local.get(key),
+                theirs.and_then(|records| records.get(key)),
+                |ours, theirs| ours.modified >= theirs.modified,
+            );
+            if let Some(value) = value {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+
+        let upload = if theirs == Some(&merged) {
+            None
+        } else {
+            let records = SyncedRecords { records: merged.clone() };
+            Some(serde_json::to_vec_pretty(&records).map_err(|e| format!("Error: {}", e))?)
+        };
+        let changed_locally = merged.len() != values.len() || merged.iter().any(|(key, record)| values.get(key) != Some(&record.value));
+        return Ok((upload, if changed_locally { Some(merged) } else { None }));
+    });
+}
+
+fn no_files(_key: &str, _value: &Value) -> Vec<String> {
+    return Vec::new();
+}
+
+fn all_shared(_key: &str) -> bool {
+    return true;
+}
+
+fn is_shared_setting(key: &str) -> bool {
+    return !LOCAL_SETTINGS.contains(&key);
+}
+
+fn sync_settings(target: &Target, state: &mut SyncState, report: &mut SyncReport) -> Result<(), String> {
+    let mut values = match serde_json::to_value(settings::load()) {
+        Ok(Value::Object(values)) => values,
+        _ => Map::new(),
+    };
+    values.retain(|key, _| is_shared_setting(key));
+
+    let changed = modified_time(&get_config_path().join("settings.json"));
+    let (merged, _) = sync_records(target, state, "settings.json", &values, changed, no_files, is_shared_setting)?;
+    if let Some(merged) = merged {
+        // Settings can not be removed, only changed
+        let mut patch = Map::new();
+        for (key, record) in merged {
+            if values.get(&key) != Some(&record.value) {
+                patch.insert(key, record.value);
+            }
+        }
+        report.settings_updated = patch.len();
+        settings::update_settings(patch)?;
+    }
+    return Ok(());
+}
+
+fn sync_themes(target: &Target, state: &mut SyncState, report: &mut SyncReport) -> Result<(), String> {
+    let path = get_config_path().join("GlobalThemes.json");
+    let mut file: Map<String, Value> = storage::read_json(&path).unwrap_or_default();
+    let values = match file.get("themes") {
+        Some(Value::Object(themes)) => themes.clone(),
+        _ => Map::new(),
+    };
+
+    let (merged, _) = sync_records(target, state, "GlobalThemes.json", &values, modified_time(&path), no_files, all_shared)?;
+    if let Some(merged) = merged {
+        let themes: Map<String, Value> = merged.into_iter().map(|(name, record)| (name, record.value)).collect();
+        report.themes_updated = themes.iter().filter(|(name, theme)| values.get(*name) != Some(theme)).count()
+            + values.keys().filter(|name| !themes.contains_key(*name)).count();
+        file.insert("themes".to_string(), Value::Object(themes));
+        storage::write_json(&path, &file)?;
+    }
+    return Ok(());
+}
+
+fn font_files(name: &str) -> Vec<String> {
+    let mut files: Vec<String> = match fs::read_dir(get_font_folder_path().join(name)) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|file| !file.ends_with(".tmp") && !file.ends_with(".bak"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    return files;
+}
+
+// Files of downloaded fonts, system fonts are only a name
+fn downloaded_font_files(name: &str, downloaded: &Value) -> Vec<String> {
+    if downloaded.as_bool().unwrap_or(false) {
+        return font_files(name);
+    }
+    return Vec::new();
+}
+
+// Font names and file names become paths in the font folder, they must not reach outside of it
+pub fn is_plain_name(name: &str) -> bool {
+    if name.contains('/') || name.contains('\\') || name.contains("..") {
+        return false;
+    }
+    let mut components = Path::new(name).components();
+    return matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+}
+
+fn check_font_paths(name: &str, files: &[String]) -> Result<(), String> {
+    if !is_plain_name(name) {
+        return Err(format!("Malformed Data: Font name \"{}\" is not a valid folder name", name));
+    }
+    if let Some(file) = files.iter().find(|file| !is_plain_name(file)) {
+        return Err(format!("Malformed Data: Font file \"{}\" of {} is not a valid file name", file, name));
+    }
+    return Ok(());
+}
+
+fn sync_fonts(target: &Target, state: &mut SyncState, report: &mut SyncReport) -> Result<(), String> {
+    let fonts = read_fonts_json()?;
+    let values: Map<String, Value> = fonts.fonts.iter().map(|(name, downloaded)| (name.clone(), Value::from(*downloaded))).collect();
+
+    // Font files go up first so no device sees a font it can not download
+    let base = fs::read(base_folder().join("fonts.json")).ok().map(|contents| read_records(&contents)).transpose()?.unwrap_or_default();
+    for (name, downloaded) in values.iter() {
+        let files = downloaded_font_files(name, downloaded);
+        check_font_paths(name, &files)?;
+        if base.records.get(name).map(|record| record.files == files).unwrap_or(false) {
+            continue;
+        }
+        for file in files {
+            let local = get_font_folder_path().join(name).join(&file);
+            let contents = fs::read(&local).map_err(|e| io_error(&local, e))?;
+            target.put(&format!("fonts/{}/{}", name, file), &contents, &Expected::Any)?;
+        }
+    }
+
+    let changed = modified_time(&get_font_folder_path().join("fonts.json"));
+    let (merged, _) = sync_records(target, state, "fonts.json", &values, changed, downloaded_font_files, all_shared)?;
+    let merged = match merged {
+        Some(merged) => merged,
+        None => return Ok(()),
+    };
+
+    // Checked before anything is written, the records come from the target
+    for (name, record) in merged.iter() {
+        check_font_paths(name, &record.files)?;
+    }
+    for (name, record) in merged.iter() {
+        let folder = get_font_folder_path().join(name);
+        for file in record.files.iter() {
+            if folder.join(file).exists() {
+                continue;
+            }
+            match target.fetch(&format!("fonts/{}/{}", name, file), None)? {
+                Fetched::Found(contents, _) => {
+                    fs::create_dir_all(&folder).map_err(|e| io_error(&folder, e))?;
+                    storage::write_atomic(&folder.join(file), &contents).map_err(|e| io_error(&folder.join(file), e))?;
+                }
+                _ => return Err(format!("Error: Font file {} of {} is missing from the sync target", file, name)),
+            }
+        }
+    }
+    for name in values.keys().filter(|name| !merged.contains_key(*name)) {
+        let folder = get_font_folder_path().join(name);
+        if folder.exists() {
+            fs::remove_dir_all(&folder).map_err(|e| io_error(&folder, e))?;
+        }
+    }
+
+    let mut fonts = fonts;
+    report.fonts_updated = merged.iter().filter(|(name, record)| values.get(*name) != Some(&record.value)).count()
+        + values.keys().filter(|name| !merged.contains_key(*name)).count();
+    fonts.fonts = merged.into_iter().map(|(name, record)| (name, record.value.as_bool().unwrap_or(false))).collect();
+    return write_fonts_json(&fonts);
+}
+
+fn run_sync(settings: &Settings) -> Result<SyncReport, String> {
+    let _running = sync_lock.try_lock().map_err(|_| "Error: A sync is already running".to_string())?;
+    let target = Target::from_settings(settings)?;
+    check_marker(&target)?;
+
+    let mut state = read_state();
+    if state.target != target.id() {
+        // Base copies from another target would turn everything it lacks into deletions
+        let _ = fs::remove_dir_all(base_folder());
+        state = SyncState {
+            target: target.id(),
+            ..Default::default()
+        };
+    }
+
+    let mut report = SyncReport::default();
+    for book in backup::library_books() {
+        if let Err(error) = sync_book(&target, &mut state, &book.hash, &mut report) {
+            println!("{}", error);
+            report.failed.push(format!("{} : {}", if book.title.len() > 0 { &book.title } else { &book.hash }, error));
+        }
+    }
+    let files: [(&str, fn(&Target, &mut SyncState, &mut SyncReport) -> Result<(), String>); 3] =
+        [("settings.json", sync_settings), ("GlobalThemes.json", sync_themes), ("fonts.json", sync_fonts)];
+    for (name, sync_file) in files {
+        if let Err(error) = sync_file(&target, &mut state, &mut report) {
+            println!("{}", error);
+            report.failed.push(format!("{} : {}", name, error));
+        }
+    }
+
+    report.finished = current_time_millis();
+    state.lastSync = report.finished;
+    state.lastError = report.failed.first().cloned().unwrap_or_default();
+    write_state(&state)?;
+
+    if let Some(handle) = app_handle.get() {
+        if let Err(error) = handle.emit_all(SYNC_FINISHED, report.clone()) {
+            println!("Error: Could not broadcast sync: {:?}", error);
+        }
+    }
+    println!("Synced with {} : {:?}", target.id(), report);
+    return Ok(report);
+}
+
+// Syncs and remembers the error of a failed sync for get_sync_status
+fn sync_and_record(settings: Settings) -> Result<SyncReport, String> {
+    let result = run_sync(&settings);
+    if let Err(error) = &result {
+        let mut state = read_state();
+        if Target::from_settings(&settings).map(|target| target.id() == state.target).unwrap_or(false) {
+            state.lastError = error.clone();
+            if let Err(error) = write_state(&state) {
+                println!("{}", error);
+            }
+        }
+    }
+    return result;
+}
+
+#[tauri::command]
+pub async fn sync_now() -> Result<SyncReport, String> {
+    let settings = settings::load();
+    return tokio::task::spawn_blocking(move || sync_and_record(settings))
+        .await
+        .map_err(|e| format!("Error: Sync failed : {}", e))?;
+}
+
+#[tauri::command]
+pub fn get_sync_status() -> SyncStatus {
+    let state = read_state();
+    return SyncStatus {
+        target: state.target,
+        lastSync: state.lastSync,
+        lastError: state.lastError,
+    };
+}
+
+// Runs for the lifetime of the app on the tokio runtime started in main, like the backup scheduler
+// The first sync happens at startup
+pub async fn run_scheduler() {
+    let mut last_attempt: u64 = 0;
+    loop {
+        let settings = settings::load();
+        let due = last_attempt + settings.syncInterval * 60 * 1000 <= current_time_millis();
+        if settings.syncTarget.len() > 0 && settings.syncInterval > 0 && due {
+            last_attempt = current_time_millis();
+            let result = tokio::task::spawn_blocking(move || sync_and_record(settings)).await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(error)) => println!("{}", error),
+                Err(error) => println!("Error: Sync task failed : {}", error),
+            }
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("alexandria-{}-{}-{}", name, current_time_millis(), rand::random::<u32>()));
+        fs::create_dir_all(&folder).unwrap();
+        return folder;
+    }
+
+    fn record(modified: u64, value: Value) -> SyncRecord {
+        return SyncRecord {
+            modified,
+            value,
+            files: Vec::new(),
+        };
+    }
+
+    fn object(value: Value) -> Map<String, Value> {
+        return value.as_object().unwrap().clone();
+    }
+
+    fn remote_records(folder: &Path, path: &str) -> BTreeMap<String, SyncRecord> {
+        return read_records(&fs::read(folder.join(path)).unwrap()).unwrap().records;
+    }
+
+    #[test]
+    fn checks_plain_names() {
+        assert!(is_plain_name("Open Sans"));
+        assert!(is_plain_name("Open Sans - 400.ttf"));
+        assert!(!is_plain_name(""));
+        assert!(!is_plain_name("."));
+        assert!(!is_plain_name(".."));
+        assert!(!is_plain_name("../fonts.json"));
+        assert!(!is_plain_name("fonts/Open Sans"));
+        assert!(!is_plain_name("fonts\\Open Sans"));
+        assert!(!is_plain_name("/etc"));
+        assert!(check_font_paths("Open Sans", &["../../settings.json".to_string()]).is_err());
+    }
+
+    #[test]
+    fn dates_only_changed_records() {
+        let mut base = SyncedRecords::default();
+        base.records.insert("same".to_string(), record(10, json!(1)));
+        base.records.insert("edited".to_string(), record(10, json!(1)));
+        let values = object(json!({"same": 1, "edited": 2, "added": 3}));
+
+        let local = local_records(&values, &base, 50, &no_files);
+        assert_eq!(local["same"], record(10, json!(1)));
+        assert_eq!(local["edited"], record(50, json!(2)));
+        assert_eq!(local["added"], record(50, json!(3)));
+    }
+
+    #[test]
+    fn merges_records_three_ways() {
+        let _library = crate::exclusive_test_library();
+        let target_folder = temp_folder("sync-records");
+        let target = Target::Folder(target_folder.clone());
+        let mut state = SyncState::default();
+        let path = format!("records-{}.json", rand::random::<u32>());
+
+        let values = object(json!({"kept": 1, "ours": 1, "theirs": 1, "both": 1, "deleted": 1, "local": false}));
+        let (merged, uploaded) = sync_records(&target, &mut state, &path, &values, 100, no_files, |key| key != "local").unwrap();
+        assert!(uploaded);
+        // The local only key is dropped from what is uploaded and so from the merged values
+        assert!(!merged.unwrap().contains_key("local"));
+        assert!(!remote_records(&target_folder, &path).contains_key("local"));
+
+        // Another device edits and deletes in between
+        let mut remote = SyncedRecords {
+            records: remote_records(&target_folder, &path),
+        };
+        remote.records.insert("theirs".to_string(), record(200, json!(2)));
+        remote.records.insert("both".to_string(), record(300, json!("theirs")));
+        remote.records.insert("added".to_string(), record(200, json!(5)));
+        remote.records.remove("deleted");
+        fs::write(target_folder.join(&path), serde_json::to_vec(&remote).unwrap()).unwrap();
+
+        let values = object(json!({"kept": 1, "ours": 2, "theirs": 1, "both": "ours", "deleted": 1}));
+        let (merged, uploaded) = sync_records(&target, &mut state, &path, &values, 250, no_files, all_shared).unwrap();
+        assert!(uploaded);
+        let merged: BTreeMap<String, Value> = merged.unwrap().into_iter().map(|(key, record)| (key, record.value)).collect();
+        let expected: BTreeMap<String, Value> =
+            serde_json::from_value(json!({"kept": 1, "ours": 2, "theirs": 2, "both": "theirs", "added": 5})).unwrap();
+        assert_eq!(merged, expected);
+        assert_eq!(remote_records(&target_folder, &path)["ours"], record(250, json!(2)));
+
+        // Nothing changed on either side
+        let values: Map<String, Value> = expected.into_iter().collect();
+        let (merged, uploaded) = sync_records(&target, &mut state, &path, &values, 400, no_files, all_shared).unwrap();
+        assert!(merged.is_none());
+        assert!(!uploaded);
+
+        fs::remove_file(base_folder().join(&path)).unwrap();
+    }
+
+    #[test]
+    fn merges_again_after_a_conflict() {
+        let _library = crate::exclusive_test_library();
+        let target_folder = temp_folder("sync-conflict");
+        let target = Target::Folder(target_folder.clone());
+        let mut state = SyncState::default();
+        let path = format!("conflict-{}.json", rand::random::<u32>());
+        fs::write(target_folder.join(&path), "first").unwrap();
+
+        let mut seen: Vec<String> = Vec::new();
+        let (_, uploaded) = exchange(&target, &mut state, &path, |_base, remote| {
+            let remote = String::from_utf8(remote.unwrap().to_vec()).unwrap();
+            if seen.len() == 0 {
+                // Another device writes the file between reading and writing it
+                fs::write(target_folder.join(&path), "second").unwrap();
+            }
+            seen.push(remote.clone());
+            return Ok((Some(format!("{} merged", remote).into_bytes()), ()));
+        })
+        .unwrap();
+        assert!(uploaded);
+        assert_eq!(seen, vec!["first".to_string(), "second".to_string()]);
+        assert_eq!(fs::read_to_string(target_folder.join(&path)).unwrap(), "second merged");
+        assert_eq!(fs::read_to_string(base_folder().join(&path)).unwrap(), "second merged");
+
+        // A file that changes on every attempt is left for the next sync
+        let mut attempts = 0;
+        let result = exchange(&target, &mut state, &path, |_base, _remote| {
+            attempts += 1;
+            fs::write(target_folder.join(&path), format!("attempt {}", attempts)).unwrap();
+            return Ok((Some(b"mine".to_vec()), ()));
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_ATTEMPTS);
+        assert_eq!(fs::read_to_string(base_folder().join(&path)).unwrap(), "second merged");
+
+        fs::remove_file(base_folder().join(&path)).unwrap();
+    }
+
+    // Swaps what belongs to this device with the copy kept in `home`, so the test library acts as another device
+    fn switch_device(home: &Path, hash: &str) {
+        let config = get_config_path();
+        for entry in [BASE_FOLDER.to_string(), "settings.json".to_string(), "fonts".to_string(), format!("books/{}", hash)] {
+            let here = config.join(&entry);
+            let there = home.join(&entry);
+            let aside = home.join("switching");
+            if here.exists() {
+                fs::rename(&here, &aside).unwrap();
+            }
+            if there.exists() {
+                fs::rename(&there, &here).unwrap();
+            }
+            if aside.exists() {
+                fs::create_dir_all(there.parent().unwrap()).unwrap();
+                fs::rename(&aside, &there).unwrap();
+            }
+        }
+        book_state::evict(hash);
+    }
+
+    fn sync(settings: &Settings) -> SyncReport {
+        // Changes are dated by the modification time of their files
+        std::thread::sleep(Duration::from_millis(10));
+        return run_sync(settings).unwrap();
+    }
+
+    fn set_highlights(hash: &str, add: &str, remove: Option<&str>) {
+        book_state::update(hash, None, |payload, _previous| {
+            payload.data.highlights.insert(add.to_string(), serde_json::from_value(json!({"color": "yellow", "text": add})).unwrap());
+            if let Some(remove) = remove {
+                payload.data.highlights.remove(remove);
+            }
+            return Ok(());
+        })
+        .unwrap();
+    }
+
+    fn highlights(hash: &str) -> Vec<String> {
+        let mut highlights: Vec<String> = book_state::get(hash).unwrap().data.highlights.keys().cloned().collect();
+        highlights.sort();
+        return highlights;
+    }
+
+    fn fonts() -> Vec<String> {
+        return read_fonts_json().unwrap().fonts.keys().cloned().collect();
+    }
+
+    #[test]
+    fn syncs_two_devices_through_a_folder() {
+        let _library = crate::exclusive_test_library();
+        let config = get_config_path();
+        let target_folder = temp_folder("sync-target");
+        let other_device = temp_folder("sync-device");
+        let sync_settings = json!({"syncTarget": "folder", "syncFolder": target_folder.display().to_string(), "syncInterval": 0, "backupInterval": 0});
+
+        let hash = crate::test_book("sync", json!({"title": "Sync", "data": {"progress": 0.0, "highlights": {"h0": {"color": "red", "text": "h0"}}}}));
+        storage::write_json(&config.join("settings.json"), &sync_settings).unwrap();
+        let serif = get_font_folder_path().join("Serif");
+        fs::create_dir_all(&serif).unwrap();
+        fs::write(serif.join("Serif - 400.ttf"), "font").unwrap();
+        write_fonts_json(&serde_json::from_value(json!({"fonts": {"Serif": true}})).unwrap()).unwrap();
+
+        // The other device has the same book and no fonts
+        let other_book = other_device.join("books").join(&hash);
+        fs::create_dir_all(&other_book).unwrap();
+        for file in fs::read_dir(config.join("books").join(&hash)).unwrap().flatten() {
+            fs::copy(file.path(), other_book.join(file.file_name())).unwrap();
+        }
+        storage::write_json(&other_device.join("settings.json"), &sync_settings).unwrap();
+        fs::create_dir_all(other_device.join("fonts")).unwrap();
+        fs::write(other_device.join("fonts").join("fonts.json"), "{}").unwrap();
+
+        let settings = settings::load();
+        let first = sync(&settings);
+        assert!(first.books_uploaded >= 1);
+        switch_device(&other_device, &hash);
+        let report = sync(&settings);
+        assert_eq!(report.fonts_updated, 1);
+        assert_eq!(fonts(), vec!["Serif".to_string()]);
+        assert_eq!(fs::read_to_string(serif.join("Serif - 400.ttf")).unwrap(), "font");
+        switch_device(&other_device, &hash);
+
+        // Both devices change the library before syncing again
+        set_highlights(&hash, "h1", Some("h0"));
+        settings::update_settings(object(json!({"sortBy": "title"}))).unwrap();
+        switch_device(&other_device, &hash);
+        set_highlights(&hash, "h2", None);
+        std::thread::sleep(Duration::from_millis(10));
+        settings::update_settings(object(json!({"sortBy": "progress"}))).unwrap();
+        fs::remove_dir_all(&serif).unwrap();
+        write_fonts_json(&serde_json::from_value(json!({"fonts": {}})).unwrap()).unwrap();
+
+        sync(&settings);
+        switch_device(&other_device, &hash);
+        let report = sync(&settings);
+        assert_eq!(report.settings_updated, 1);
+        assert_eq!(report.fonts_updated, 1);
+        assert_eq!(highlights(&hash), vec!["h1".to_string(), "h2".to_string()]);
+        // The newer change wins
+        assert_eq!(settings::load().sortBy, "progress");
+        assert!(fonts().is_empty());
+        assert!(!serif.exists());
+
+        switch_device(&other_device, &hash);
+        sync(&settings);
+        assert_eq!(highlights(&hash), vec!["h1".to_string(), "h2".to_string()]);
+        assert_eq!(settings::load().sortBy, "progress");
+        switch_device(&other_device, &hash);
+
+        fs::remove_dir_all(config.join(BASE_FOLDER)).unwrap();
+        fs::remove_file(config.join("settings.json")).unwrap();
+        fs::remove_file(storage::backup_path(&config.join("settings.json"))).ok();
+        fs::remove_file(get_font_folder_path().join("fonts.json")).unwrap();
+        fs::remove_file(storage::backup_path(&get_font_folder_path().join("fonts.json"))).ok();
+    }
+}
@@ -1,17 +1,16 @@
 // eslint-disable-next-line @typescript-eslint/ban-ts-comment
 
 import { useAppSelector } from "@store/hooks"
-import { LoadThemes, SetLocalFontsList, SetMaximized, setSelectedTheme, SetSortSettings } from "@store/slices/appState"
+import { LoadThemes, SetLocalFontsList, SetMaximized, setReaderMargins, setSelectedTheme, SetSortSettings } from "@store/slices/appState"
 import { invoke } from "@tauri-apps/api"
 import { appWindow } from "@tauri-apps/api/window"
+import { listen } from "@tauri-apps/api/event"
 import React, { useEffect, useLayoutEffect, useState } from "react"
 import { useDispatch } from "react-redux"
 import styles from './InitializeStyles.module.scss'
 import toast, { Toaster } from 'react-hot-toast'
 import { getMatches } from '@tauri-apps/api/cli'
 import { importBook} from '@shared/scripts/TauriActions'
-// @ts-expect-error Migrations should have flexible datatypes, so JS will be easier.
-import performMigrations from './migrations.js'
 
 const InitializeApp = ({children}: JSX.ElementChildrenAttribute) =>{
   const themes = useAppSelector((state)=> state.appState.themes)
@@ -21,59 +20,68 @@ const InitializeApp = ({children}: JSX.ElementChildrenAttribute) =>{
   const dispatch = useDispatch()
   useEffect(()=>{
 
-    performMigrations().then(()=>{
     // Handles case where application gets launch parameters 
-      getMatches().then(async (matches) => {
-        let bookHash = null;
-
-        // Prevent infinite loop by only running code if on homescreen
-        if(window.location.pathname == "/"){
-          if(matches.args.source.value && typeof matches.args.source.value == "string"){
-            try {
-              const response = await importBook(matches.args.source.value)
-              console.log("Bookhash imported")
-              if(!response){
-                toast.error("Error: No importBook response")
-                return
-              }
-              bookHash = response.hash
-
-            } catch (error:any) {
-              // const error = error as string;
-              console.log(error)
-              if(!error.startsWith("Error: Book is duplicate")){
-                toast.error(error)
-                return
-              }else{
-                bookHash = error.split(" - ")[1]
-                console.log(bookHash)
-              }
+    getMatches().then(async (matches) => {
+      let bookHash = null;
+
+      // Prevent infinite loop by only running code if on homescreen
+      if(window.location.pathname == "/"){
+        if(matches.args.source.value && typeof matches.args.source.value == "string"){
+          try {
+            const response = await importBook(matches.args.source.value)
+            console.log("Bookhash imported")
+            if(!response){
+              toast.error("Error: No importBook response")
+              return
+            }
+            bookHash = response.hash
+
+          } catch (error:any) {
+            // const error = error as string;
+            console.log(error)
+            if(!error.startsWith("Error: Book is duplicate")){
+              toast.error(error)
+              return
+            }else{
+              bookHash = error.split(" - ")[1]
+              console.log(bookHash)
             }
-
-            window.location.pathname = ("/reader/" + bookHash)
           }
-        }
-      })
-      console.log("App Loading")
-      // invoke("get_reader_themes").then((response:any)=>{
-      //   dispatch(LoadReaderThemes(response))
-      // })
-      invoke("get_global_themes").then((response:any)=>{
-        dispatch(LoadThemes(response))
-      })
-      invoke("get_settings").then((response:any)=>{
-        dispatch(setSelectedTheme(response.selectedTheme))
-        dispatch(SetSortSettings({sortBy: response.sortBy, sortDirection:response.sortDirection}))
-      // dispatch(LoadGlobalThemes(response))
-      })
-
-      invoke("list_system_fonts").then((response)=>{
-        dispatch(SetLocalFontsList({fonts:response}));
-      })
 
+          window.location.pathname = ("/reader/" + bookHash)
+        }
+      }
+    })
+    console.log("App Loading")
+    // invoke("get_reader_themes").then((response:any)=>{
+    //   dispatch(LoadReaderThemes(response))
+    // })
+    invoke("get_global_themes").then((response:any)=>{
+      dispatch(LoadThemes(response))
+    }).catch((error)=>{
+      console.log("Error: Could not load themes", error)
+    })
+    const applySettings = (settings:any)=>{
+      dispatch(setSelectedTheme(settings.selectedTheme))
+      dispatch(SetSortSettings({sortBy: settings.sortBy, sortDirection:settings.sortDirection}))
+      dispatch(setReaderMargins(settings.readerMargins))
+    }
+    invoke("get_settings").then((response:any)=>{
+      applySettings(response)
+    // dispatch(LoadGlobalThemes(response))
+    })
+    // Settings changed in another window, the backend only sends this when a value actually changed
+    const unlistenSettings = listen("settings-changed", (event:any)=>{
+      applySettings(event.payload)
     })
 
+    invoke("list_system_fonts").then((response)=>{
+      dispatch(SetLocalFontsList({fonts:response}));
+    })
 
+    return ()=>{
+      unlistenSettings.then((unlisten)=>unlisten())
+    }
 
   }, [])
 
@@ -20,7 +20,7 @@ import { open } from '@tauri-apps/api/dialog';
 
 
 
-import { convertFileSrc, invoke } from '@tauri-apps/api/tauri'
+import { invoke } from '@tauri-apps/api/tauri'
 import { useAppDispatch, useAppSelector } from '@store/hooks'
 
 import SortIcon from '@resources/iconmonstr/iconmonstr-sort-25.svg'
@@ -33,10 +33,8 @@ import { SetDualReaderMode, SetSortSettings } from '@store/slices/appState';
 import AddFiles from "@resources/feathericons/folder-plus.svg"
 import toast from 'react-hot-toast';
 
-import { platform } from '@tauri-apps/api/os';
-import { importBook, SUPPORTED_FORMATS} from '@shared/scripts/TauriActions'
+import { assetUrl, importBook, loadAssetServer, SUPPORTED_FORMATS} from '@shared/scripts/TauriActions'
 
-let IS_LINUX = false
 interface BookData {
   author: string;
   title:string,
@@ -127,10 +125,7 @@ const Shelf = () =>{
   useEffect(()=>{
     console.log("Home Page Loaded")
     if(window.__TAURI__){
-      platform().then((result)=>{
-        if(result == "linux"){
-          IS_LINUX = true
-        }
+      loadAssetServer().then(()=>{
         invoke("get_books").then((data)=>{
           setBooks((data as BookData[]))
         })
@@ -233,7 +228,9 @@ const Shelf = () =>{
           onClick={()=>{
             for(const checksum of selectedBooks){
               console.log(checksum)
-              invoke("delete_book",{checksum})
+              invoke("delete_book",{checksum}).catch((error)=>{
+                console.log("Error: Could not delete book", error)
+              })
             }
             invoke("get_books").then((data)=>{
               setBooks((data as BookData[]))
@@ -351,7 +348,9 @@ const Shelf = () =>{
                       }} className={styles.bookOptionsReturn}><div>Back</div> <div><RightArrow/></div></div>
                     <div onClick={()=> navigate("/info/" + book.hash)} className={styles.bookOptionsInfo}>Info</div>
                     <div onClick={()=>{
-                      invoke("delete_book",{checksum:book.hash})
+                      invoke("delete_book",{checksum:book.hash}).catch((error)=>{
+                        console.log("Error: Could not delete book", error)
+                      })
                                   
                       setSelectedBooks(new Set([...selectedBooks].filter((item)=> item != book.hash)))
                       invoke("get_books").then((data)=>{
@@ -367,7 +366,7 @@ const Shelf = () =>{
                   </div>
 
                   {book.cover_url?
-                    <img className={styles.bookImage} style={{backgroundColor:"white"}} src={IS_LINUX? "http://127.0.0.1:16780/" + book.cover_url.split('/').slice(-4).join("/"): convertFileSrc(book.cover_url)}/>
+                    <img className={styles.bookImage} style={{backgroundColor:"white"}} src={assetUrl(book.cover_url)}/>
                     :
                     <FakeCover title={book.title} author={book.author}/>
                   }
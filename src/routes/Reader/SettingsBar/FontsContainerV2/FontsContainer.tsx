@@ -4,9 +4,8 @@ import styles from './FontsContainer.module.scss'
 
 import { useAppDispatch, useAppSelector } from '@store/hooks'
 import { invoke } from '@tauri-apps/api'
-import { convertFileSrc } from '@tauri-apps/api/tauri'
 import { setFontThunk } from '@store/slices/EpubJSBackend/data/theme/themeManager'
-import { platform } from '@tauri-apps/api/os';
+import { assetUrl, loadAssetServer } from '@shared/scripts/TauriActions'
 
 const FontsContainer = ()=>{
   const dispatch = useAppDispatch()
@@ -18,11 +17,7 @@ const FontsContainer = ()=>{
 
 
   useEffect(()=>{
-    platform().then((result)=>{
-      let IS_LINUX = false
-      if(result == "linux"){
-        IS_LINUX = true
-      }
+    loadAssetServer().then(()=>{
       invoke("list_fonts").then((payload)=>{
         const typedPayload = (payload as ListFontsType)
         const tempList:Array<string> = []
@@ -42,7 +37,7 @@ const FontsContainer = ()=>{
               }
               // this means if the name has an extension like .ttf
               const fontName = item.replaceAll(" ", "_")
-              const font = new FontFace(fontName, `url("${IS_LINUX?encodeURI("http://127.0.0.1:16780/" + typedPath.split('/').slice(-4).join("/")):convertFileSrc(typedPath)}")`);
+              const font = new FontFace(fontName, `url("${assetUrl(typedPath)}")`);
               // wait for font to be loaded
               font.load().then(()=>{
                 document.fonts.add(font);
@@ -11,8 +11,7 @@ import TashIcon from '@resources/feathericons/trash-2.svg'
 import CloudIcon from '@resources/iconmonstr/iconmonstr-cloud-download-thin.svg'
 import ComputerIcon from '@resources/iconmonstr/iconmonstr-computer-10.svg'
 import { invoke } from "@tauri-apps/api";
-import { convertFileSrc } from "@tauri-apps/api/tauri";
-import { platform } from '@tauri-apps/api/os';
+import { assetUrl, loadAssetServer } from "@shared/scripts/TauriActions";
 
 
 
@@ -45,11 +44,7 @@ const FontManager = (props:any)=>{
   const [localFontListFiltered, SetLocalFontsListFiltered] = useState(localFontList)
 
   useEffect(()=>{
-    platform().then((result)=>{
-      let IS_LINUX = false
-      if(result == "linux"){
-        IS_LINUX = true
-      }
+    loadAssetServer().then(()=>{
 
       invoke("list_fonts").then((response)=>{
         const typedResponse = response as ListFontsType
@@ -71,10 +66,7 @@ const FontManager = (props:any)=>{
             // // this means if the name has an extension like .ttf
             // if(item.includes(".")){
             const fontName = item.split(".")[0].replaceAll(" ", "_")
-            let fontSource = convertFileSrc(newPath)
-            if(IS_LINUX){
-              fontSource = encodeURI("http://127.0.0.1:16780/" + newPath.split('/').slice(-4).join("/"))
-            }
+            const fontSource = assetUrl(newPath)
             const font = new FontFace(fontName, `url("${fontSource}") format('truetype')`);
             //   // wait for font to be loaded
             font.load().then(()=>{
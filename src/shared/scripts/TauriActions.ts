@@ -1,19 +1,38 @@
 import { fs, invoke } from "@tauri-apps/api"
-import { platform } from "@tauri-apps/api/os"
 import { convertFileSrc } from "@tauri-apps/api/tauri"
 import parser from "@shared/scripts/Parser/parser"
 import epubjs from '@btpf/epubjs'
+import bookFormats from '@shared/bookFormats.json'
 
-export const getBookUrlByHash = async (bookHash:string)=>{
-  let bookUrl:string = await invoke("get_book_by_hash",{bookHash})
-  if(await platform() == "linux"){
-    const splitPath = bookUrl.split('/').slice(-4)
-    // Main Issue:https://github.com/tauri-apps/tauri/issues/3725
-    bookUrl = "http://127.0.0.1:16780/" + splitPath.join("/")
-  }else{
-    bookUrl = convertFileSrc(bookUrl)
+// Base URL of the backend's asset server, it only runs on linux
+// Main Issue:https://github.com/tauri-apps/tauri/issues/3725
+let assetServerUrl: string | null = null
+let assetServerLoad: Promise<void> | undefined
+
+export const loadAssetServer = ()=>{
+  if(!assetServerLoad){
+    assetServerLoad = invoke("get_asset_server_url").then((url)=>{
+      assetServerUrl = url as string | null
+    })
+  }
+  return assetServerLoad
+}
+
+// URL the webview can load a book, cover or font file in Alexandria_Data from
+// loadAssetServer has to be resolved first
+export const assetUrl = (path:string)=>{
+  if(assetServerUrl){
+    // Files are served relative to Alexandria_Data: books/<hash>/<file> or fonts/<name>/<file>
+    const relativePath = path.replaceAll("\\","/").split('/').slice(-3).map(encodeURIComponent).join("/")
+    return assetServerUrl + relativePath
   }
-  return bookUrl
+  return convertFileSrc(path)
+}
+
+export const getBookUrlByHash = async (bookHash:string)=>{
+  const bookUrl:string = await invoke("get_book_by_hash",{bookHash})
+  await loadAssetServer()
+  return assetUrl(bookUrl)
 }
 
 export const createBookInstance = async (bookUrl:string, bookHash:string, cbzLayout?:string)=>{
@@ -44,12 +63,8 @@ export const createBookInstance = async (bookUrl:string, bookHash:string, cbzLay
   
   }
 }
-export const SUPPORTED_FORMATS = [
-  'epub','epub3', 'azw3', "azw", "mobi", 'pdb', 'prc',
-  "fb2", "fbz",
-  "cbz", "cbr", "cb7", "cbt",
-  "txt"
-]
+// Shared with the command line import in src-tauri/src/cli.rs
+export const SUPPORTED_FORMATS: string[] = bookFormats
 const BACKEND_MANAGED = [
   'epub','epub3', 'azw3', "azw", "mobi", 'pdb', 'prc'
 ]
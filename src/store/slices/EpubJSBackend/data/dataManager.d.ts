@@ -1,6 +1,11 @@
 export interface highlightData {
     color: string,
-    note: string
+    note: string,
+    text?: string,
+    chapter?: string,
+    created?: number,
+    modified?: number,
+    tags?: string[]
   }
   
 export interface highlightAction extends highlightData {
@@ -14,6 +19,18 @@ export interface progressUpdate{
     cfi: string
   }
   
+export interface revisionUpdate {
+    view: number,
+    revision: number
+  }
+
+export interface remoteBookData {
+    view: number,
+    revision: number,
+    highlights: {[cfi: string]: highlightData},
+    bookmarks: string[]
+  }
+
 export interface bookmarkAction {
     view: number,
     bookmarkLocation: string
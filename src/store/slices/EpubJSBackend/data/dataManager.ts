@@ -1,6 +1,6 @@
 import { PayloadAction } from "@reduxjs/toolkit"
 import { epubjs_reducer } from "@store/slices/EpubJSBackend/epubjsManager.d"
-import { highlightAction, bookmarkAction, progressUpdate } from "./dataManager.d"
+import { highlightAction, bookmarkAction, progressUpdate, revisionUpdate, remoteBookData } from "./dataManager.d"
 
 
 
@@ -21,16 +21,27 @@ const ChangeHighlightColor:epubjs_reducer = (state, action: PayloadAction<highli
   console.log(action.payload.highlightRange)
   console.log(JSON.stringify(state[action.payload.view].data.highlights[action.payload.highlightRange]))
   console.log(state[action.payload.view].data.highlights[action.payload.highlightRange])
-  state[action.payload.view].data.highlights[action.payload.highlightRange] = {color:action.payload.color, note:state[action.payload.view].data.highlights[action.payload.highlightRange].note}
+  state[action.payload.view].data.highlights[action.payload.highlightRange] = {...state[action.payload.view].data.highlights[action.payload.highlightRange], color:action.payload.color}
 }
 const ChangeHighlightNote:epubjs_reducer = (state, action: PayloadAction<highlightAction>) =>{
-  state[action.payload.view].data.highlights[action.payload.highlightRange] = {color:state[action.payload.view].data.highlights[action.payload.highlightRange].color, note:action.payload.note}
+  state[action.payload.view].data.highlights[action.payload.highlightRange] = {...state[action.payload.view].data.highlights[action.payload.highlightRange], note:action.payload.note}
 }
 const SetProgress:epubjs_reducer = (state, action: PayloadAction<progressUpdate>) =>{
   state[action.payload.view].data.progress = action.payload.progress
   state[action.payload.view].data.cfi = action.payload.cfi
 
 }
+const SetRevision:epubjs_reducer = (state, action: PayloadAction<revisionUpdate>) =>{
+  if(state[action.payload.view]){
+    state[action.payload.view].revision = action.payload.revision
+  }
+}
+// Not a synced action, data written by another view must not be saved again from here
+const ApplyRemoteBookData:epubjs_reducer = (state, action: PayloadAction<remoteBookData>) =>{
+  state[action.payload.view].data.highlights = action.payload.highlights
+  state[action.payload.view].data.bookmarks = new Set(action.payload.bookmarks)
+  state[action.payload.view].revision = action.payload.revision
+}
 
 
 export const actions = {
@@ -39,5 +50,7 @@ export const actions = {
   DeleteHighlight,
   ChangeHighlightColor,
   ChangeHighlightNote,
-  SetProgress
+  SetProgress,
+  SetRevision,
+  ApplyRemoteBookData
 }
\ No newline at end of file
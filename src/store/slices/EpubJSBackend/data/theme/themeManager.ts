@@ -1,11 +1,8 @@
 import { createAsyncThunk, PayloadAction } from "@reduxjs/toolkit"
 import { RootState } from "@store/store";
 import { invoke } from "@tauri-apps/api";
-import { convertFileSrc } from "@tauri-apps/api/tauri";
 import { bookStateStructure, dataInterface, epubjs_reducer } from "../../epubjsManager.d"
-import { platform } from '@tauri-apps/api/os';
-
-let IS_LINUX:boolean|undefined = undefined
+import { assetUrl, loadAssetServer } from "@shared/scripts/TauriActions";
 
 type renderModeDispatchType = {
   view: number,
@@ -27,9 +24,7 @@ export const setFontThunk = createAsyncThunk(
   'bookState/setFontV2',
   async (fontPayload: fontDispatchType, thunkAPI) => {
 
-    if(IS_LINUX == undefined){
-      IS_LINUX = await platform() == "linux"
-    }
+    await loadAssetServer()
 
 
     const state = (thunkAPI.getState() as RootState)
@@ -80,7 +75,7 @@ export const setFontThunk = createAsyncThunk(
           fontsCache += 
             `@font-face {
               font-family: "${fontPayload.font}";
-              src: url("${IS_LINUX?"http://127.0.0.1:16780/" + path.split('/').slice(-4).join("/"):convertFileSrc(path)}") format("truetype");
+              src: url("${assetUrl(path)}") format("truetype");
               font-weight: ${myWeight};
               font-style: normal;
             }
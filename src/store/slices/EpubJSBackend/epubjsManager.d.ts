@@ -20,6 +20,8 @@ export interface bookStateHydrationStructure{
   title: string,
   author: string,
   modified: number,
+  // Backend revision the data was loaded at, sent back with every save
+  revision?: number,
   data:dataInterface
 }
 
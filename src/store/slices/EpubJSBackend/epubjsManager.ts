@@ -17,6 +17,30 @@ import { MoveNoteModal, SetModalCFI } from "../appState"
 
 export type SyncedAddRenditionPayload = {firstLoad?:boolean, saveData:bookStateHydrationStructure} & BackendInstance
 
+const drawHighlight = (renditionData: BackendInstance, cfiRange:string, color:string, dispatch:any) =>{
+  const renditionInstance = renditionData.instance
+  renditionInstance.annotations.highlight(cfiRange,{}, (e:MouseEvent) => {
+
+    // This will prevent page turning when clicking on highlight
+    dispatch(bookState.actions.SkipMouseEvent(renditionData.UID))
+
+    const {x, y} = CalculateBoxPosition(
+      renditionInstance,
+      cfiRange,
+      NOTE_MODAL_WIDTH,
+      NOTE_MODAL_HEIGHT)
+
+    dispatch(SetModalCFI(cfiRange))
+    dispatch(MoveNoteModal({
+      view: renditionData.UID,
+      x,
+      y,
+      visible: true
+    }))
+
+  }, '', {fill:color});
+}
+
 export const SyncedAddRendition = createAsyncThunk(
   'bookState/SyncedAddRendition',
   // if you type your function argument here
@@ -44,39 +68,19 @@ export const SyncedAddRendition = createAsyncThunk(
       for (const [cfiRange, value] of Object.entries(highlights)) {
         thunkAPI.dispatch(bookState.actions.AddHighlight({highlightRange:cfiRange, color:value.color, note:value.note, view:renditionData.UID}))
       
-        renditionInstance.annotations.highlight(cfiRange,{}, (e:MouseEvent) => {
-      
-          // This will prevent page turning when clicking on highlight
-          thunkAPI.dispatch(bookState.actions.SkipMouseEvent(renditionData.UID))
-      
-
-          const boundingBox = renditionInstance.getRange(cfiRange).getBoundingClientRect()
-          const {x, y} = CalculateBoxPosition(
-            renditionInstance,
-            cfiRange,
-            NOTE_MODAL_WIDTH,
-            NOTE_MODAL_HEIGHT)
-                
-          thunkAPI.dispatch(SetModalCFI(cfiRange))
-          thunkAPI.dispatch(MoveNoteModal({
-            view: renditionData.UID,
-            x,
-            y,
-            visible: true
-          }))
-      
-                
-        }, '', {fill:value.color});
+        drawHighlight(renditionData, cfiRange, value.color, thunkAPI.dispatch)
       }
 
-      bookmarks.forEach((bookmark)=>{
+      // Bookmarks are saved as objects, older files contain bare CFI strings
+      bookmarks.forEach((bookmark:string|{cfi:string})=>{
         thunkAPI.dispatch(bookState.actions.ToggleBookmark({
           view: renditionData.UID,
-          bookmarkLocation: bookmark
+          bookmarkLocation: typeof bookmark === "string" ? bookmark : bookmark.cfi
         }))
       })
       
       thunkAPI.dispatch(bookState.actions.SetProgress({view:renditionData.UID, progress:renditionData.saveData.data.progress, cfi: renditionData.saveData.data.cfi}))
+      thunkAPI.dispatch(bookState.actions.SetRevision({view:renditionData.UID, revision:renditionData.saveData.revision || 0}))
 
       
       await thunkAPI.dispatch(setFontThunk({
@@ -113,6 +117,38 @@ export const SyncedAddRendition = createAsyncThunk(
 
 
 
+// Another view saved this book, redraw the highlights that changed and take over its data
+export const SyncRemoteBookData = createAsyncThunk(
+  'bookState/SyncRemoteBookData',
+  async (update: {view:number, saveData:bookStateHydrationStructure}, thunkAPI) => {
+    const current = (thunkAPI.getState() as RootState).bookState[update.view] as bookStateStructure
+    if(!current || !current.data){
+      return
+    }
+    const renditionData = {instance: current.instance, UID: current.UID, hash: current.hash}
+    const highlights = update.saveData.data.highlights
+
+    for (const [cfiRange, value] of Object.entries(current.data.highlights)) {
+      if(!highlights[cfiRange] || highlights[cfiRange].color != value.color){
+        current.instance.annotations.remove(cfiRange, "highlight")
+      }
+    }
+    for (const [cfiRange, value] of Object.entries(highlights)) {
+      const previous = current.data.highlights[cfiRange]
+      if(!previous || previous.color != value.color){
+        drawHighlight(renditionData, cfiRange, value.color, thunkAPI.dispatch)
+      }
+    }
+
+    thunkAPI.dispatch(bookState.actions.ApplyRemoteBookData({
+      view: update.view,
+      revision: update.saveData.revision || 0,
+      highlights,
+      bookmarks: update.saveData.data.bookmarks.map((bookmark:string|{cfi:string})=> typeof bookmark === "string" ? bookmark : bookmark.cfi)
+    }))
+  }
+)
+
 export const RenditionBuilder = (builder:ActionReducerMapBuilder<BookInstances>) =>{
   builder.addCase(SyncedAddRendition.pending, (state, action) => {
     // const readerMarginsToUse = action?.meta?.arg?.saveData?.data?.theme?.readerMargins ? action.meta.arg.saveData.data.theme.readerMargins: 75
@@ -183,6 +183,7 @@ export const {
   ChangeHighlightColor, 
   ChangeHighlightNote, 
   SetProgress,
+  SetRevision,
 
   /* state manager */
   SkipMouseEvent,
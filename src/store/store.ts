@@ -6,26 +6,81 @@ import appState from './slices/appState'
 
 import {enableMapSet} from "immer"
 import { invoke } from '@tauri-apps/api'
+import { listen } from '@tauri-apps/api/event'
+import { appWindow } from '@tauri-apps/api/window'
 import { LOADSTATE } from './slices/constants'
-import { bookStateStructure } from './slices/EpubJSBackend/epubjsManager.d'
+import { bookStateHydrationStructure, bookStateStructure } from './slices/EpubJSBackend/epubjsManager.d'
+import { SyncRemoteBookData } from './slices/EpubJSBackend/epubjsManager'
+import { SetRevision } from './slices/bookState'
 import {debounce} from '@github/mini-throttle'
 
 enableMapSet()
 
 
 const saveAppStateLocally = debounce((currentState:any)=>{
-  invoke("set_global_themes", {payload:currentState.appState.themes})
+  invoke("set_global_themes", {payload:currentState.appState.themes}).catch((error)=>{
+    console.log("Error: Could not save themes", error)
+  })
 
 
-  invoke("set_settings", {payload:{
+  invoke("update_settings", {patch:{
     
     selectedTheme: currentState.appState.selectedTheme,
     sortBy: currentState.appState.sortBy,
-    sortDirection: currentState.appState.sortDirection
+    sortDirection: currentState.appState.sortDirection,
+    readerMargins: currentState.appState.readerMargins
   
-  }})
+  }}).catch((error)=>{
+    console.log("Error: Could not save settings", error)
+  })
 
 }, 500)
+// Identifies a reader view across windows in "book-data-changed" events
+const viewSource = (view:number|string) => appWindow.label + "-" + view
+
+// Sends only the part of the book data the action touched, the backend batches progress writes
+const saveBookChange = (action:any, currentBook:bookStateStructure, view:number) => {
+  const hash = currentBook.hash
+  const source = viewSource(view)
+  const data = currentBook.data as NonNullable<bookStateStructure["data"]>
+
+  switch (action.type) {
+  case "bookState/SetProgress":
+    return invoke("set_progress", {hash, progress: data.progress, cfi: data.cfi, source})
+  case "bookState/AddHighlight":
+  case "bookState/ChangeHighlightColor":
+  case "bookState/ChangeHighlightNote":
+    return invoke("upsert_highlight", {hash, cfi: action.payload.highlightRange, highlight: data.highlights[action.payload.highlightRange], source})
+  case "bookState/DeleteHighlight":
+    return invoke("delete_highlight", {hash, cfi: action.payload.highlightRange, source})
+  case "bookState/ToggleBookmark":
+    return invoke("toggle_bookmark", {hash, cfi: action.payload.bookmarkLocation, source})
+  case "bookState/setThemeV2/fulfilled":
+  case "bookState/setFontV2/fulfilled":
+  case "bookState/setWordSpacing/fulfilled":
+  case "bookState/setLineHeight/fulfilled":
+  case "bookState/setParagraphSpacing/fulfilled":
+  case "bookState/setTextAlignment/fulfilled":
+  case "bookState/setRenderMode":
+    return invoke("set_book_theme", {hash, theme: {...data.theme}, source})
+  }
+
+  const saveData = {
+    revision: currentBook.revision,
+    title: currentBook.title,
+    author: currentBook.author,
+    modified: Date.now(),
+    data:{
+      progress: data.progress,
+      cfi: data.cfi,
+      bookmarks: Array.from(data.bookmarks),
+      highlights: data.highlights,
+      theme:{...data.theme}
+    }
+  }
+  return invoke("update_data_by_hash", {payload:saveData, hash, source})
+}
+
 const store =  configureStore({
   reducer: {
     counter: counterSlice,
@@ -76,21 +131,11 @@ const store =  configureStore({
               console.log("Current progress null, returning")
               return
             }
-            const saveData = {
-              title: currentBook.title,
-              author: currentBook.author,
-              modified: Date.now(),
-              data:{
-                progress: currentBook.data.progress,
-                cfi: currentBook.data.cfi,
-                bookmarks: Array.from(currentBook.data.bookmarks),
-                highlights: currentBook.data.highlights,
-                theme:{...currentBook.data.theme}
-              }
-            }
-            console.log("This is the save data: ")
-            console.log(saveData)
-            invoke("update_data_by_hash", {payload:saveData, hash: currentBook.hash})
+            saveBookChange(action, currentBook, renditionToHandle).then((revision)=>{
+              storeAPI.dispatch(SetRevision({view: renditionToHandle, revision: revision as number}))
+            }).catch((error)=>{
+              console.log("Error: Could not save book data", error)
+            })
           }
 
         }else if (action.type.includes("appState")){
@@ -107,6 +152,30 @@ const store =  configureStore({
     }),
 })
 
+// Keep every open view of a book in step with saves made from other views and windows
+if(window.__TAURI__){
+  listen('book-data-changed', async (event: {payload: {hash:string, revision:number, source?:string}}) => {
+    const books = store.getState().bookState
+    for (const [view, book] of Object.entries(books) as [string, bookStateStructure][]) {
+      if(book.hash != event.payload.hash || event.payload.source == viewSource(view)){
+        continue
+      }
+      if((book.revision || 0) >= event.payload.revision || book.loadState != LOADSTATE.COMPLETE){
+        continue
+      }
+      try {
+        const saveData:bookStateHydrationStructure = await invoke("load_book_data", {checksum: book.hash})
+        store.dispatch(SyncRemoteBookData({view: Number(view), saveData}))
+      } catch (error) {
+        // "First Read" is returned while the book has no position yet, there is nothing to sync
+        if(error != "First Read"){
+          console.log("Error: Could not reload book data", error)
+        }
+      }
+    }
+  })
+}
+
 // Infer the `RootState` and `AppDispatch` types from the store itself
 export type RootState = ReturnType<typeof store.getState>
 // Inferred type: {posts: PostsState, comments: CommentsState, users: UsersState}
@@ -35,5 +35,6 @@ export default new Set<completeAppStateActionNames|completeBookStateActionNames>
   'bookState/setParagraphSpacing/fulfilled',
   "bookState/setRenderMode",
   "appState/SetSortSettings",
+  "appState/setReaderMargins",
   "bookState/setTextAlignment/fulfilled"
 ])